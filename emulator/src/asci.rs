@@ -12,6 +12,8 @@ use std::cell::RefCell;
 use std::io::{ErrorKind, Read, Write};
 
 use crate::bus::Bus;
+use crate::history::Source;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
 #[derive(PartialEq)]
@@ -46,6 +48,14 @@ impl ASCI {
         }
     }
 
+    // The channel number, naming the channel's inputs in a recorded run.
+    fn number(&self) -> u8 {
+        match self.channel {
+            Channel::CH0 => 0,
+            Channel::CH1 => 1,
+        }
+    }
+
     fn set_stat(&self, data: u8, mask: u8) {
         let mut stat = self.stat.borrow_mut();
         *stat = (*stat & !mask) | (data & mask);
@@ -102,47 +112,54 @@ impl ASCI {
  */
 
 impl Peripheral for ASCI {
-    fn cycle(&self, _bus: &Bus) -> Option<Interrupt> {
+    fn cycle(&self, bus: &Bus) -> Option<Interrupt> {
         let mut stat = self.stat.borrow_mut();
         let mut int = None;
 
         // if TDRE is reset, try to send a byte
         if *stat & 0b0000_0010 == 0 {
             let byte = *self.tdr.borrow();
-            match self.serial.borrow_mut().write(&[byte]) {
-                Ok(_) => {
-                    *stat |= 0b0000_0010;
-                    int = if (*stat & 0b0000_0001) != 0 {
-                        Some(Interrupt::ASCI0)
-                    } else {
+            let sent = bus.input(Source::SerialSend(self.number()), || {
+                match self.serial.borrow_mut().write(&[byte]) {
+                    Ok(_) => Some(1),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+                    Err(ref e) => {
+                        println!("Serial write error {}", e);
                         None
-                    };
-                }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(ref e) => {
-                    println!("Serial write error {}", e);
+                    }
                 }
+            });
+            if sent.is_some() {
+                *stat |= 0b0000_0010;
+                int = if (*stat & 0b0000_0001) != 0 {
+                    Some(Interrupt::ASCI0)
+                } else {
+                    None
+                };
             }
         }
 
         // if RDRF isn't set, try to read a byte
         if *stat & 0b1000_0000 == 0 {
-            let mut rx = self.serial.borrow_mut();
-            let mut buf = [0u8; 1];
-            match rx.read(&mut buf) {
-                Ok(_) => {
-                    *self.rdr.borrow_mut() = buf[0];
-                    *stat |= 0b1000_0000;
-                    int = if *stat & 0b0000_1000 == 0b0000_1000 {
-                        Some(Interrupt::ASCI0)
-                    } else {
+            let received = bus.input(Source::SerialReceive(self.number()), || {
+                let mut buf = [0u8; 1];
+                match self.serial.borrow_mut().read(&mut buf) {
+                    Ok(_) => Some(buf[0] as u64),
+                    Err(ref e) if e.kind() == ErrorKind::WouldBlock => None,
+                    Err(ref e) => {
+                        println!("Serial read error {}", e);
                         None
-                    };
-                }
-                Err(ref e) if e.kind() == ErrorKind::WouldBlock => (),
-                Err(ref e) => {
-                    println!("Serial read error {}", e);
+                    }
                 }
+            });
+            if let Some(byte) = received {
+                *self.rdr.borrow_mut() = byte as u8;
+                *stat |= 0b1000_0000;
+                int = if *stat & 0b0000_1000 == 0b0000_1000 {
+                    Some(Interrupt::ASCI0)
+                } else {
+                    None
+                };
             }
         }
 
@@ -182,6 +199,23 @@ impl Peripheral for ASCI {
             _ => (),
         };
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(*self.cntla.borrow());
+        state.u8(*self.cntlb.borrow());
+        state.u8(*self.stat.borrow());
+        state.u8(*self.tdr.borrow());
+        state.u8(*self.rdr.borrow());
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        *self.cntla.borrow_mut() = state.u8()?;
+        *self.cntlb.borrow_mut() = state.u8()?;
+        *self.stat.borrow_mut() = state.u8()?;
+        *self.tdr.borrow_mut() = state.u8()?;
+        *self.rdr.borrow_mut() = state.u8()?;
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,785 @@
+/**
+ * Assembler
+ *
+ * `assemble` turns Z180 assembly source into bytes, in two passes: the first finds the address of every label, and
+ * the second encodes each line with every symbol known. Instructions are encoded by finding the opcode that `decode`
+ * turns back into the same instruction, so the assembler takes every instruction the disassembler writes, in Zilog or
+ * Hitachi syntax.
+ *
+ * A line is an optional label, ending in a colon or starting in the first column, then an instruction or directive,
+ * then an optional comment starting with a semicolon. The directives are:
+ *
+ *   org EXPR             assemble what follows at EXPR
+ *   NAME equ EXPR        define NAME as EXPR
+ *   db ITEM, ...         bytes, and strings in single or double quotes; also defb and defm
+ *   dw EXPR, ...         little endian words; also defw
+ *   ds COUNT [, FILL]    COUNT bytes of FILL, or of zero; also defs
+ *   end                  ignore the rest of the source
+ *
+ * Expressions are numbers, symbols, $ for the address of the line, and 'c' for a character, with the operators
+ * | ^ & << >> + - * / % and unary - and ~, and parentheses. Numbers are decimal unless written as $ff, 0xff, 0ffh,
+ * %1010 or 0b1010. Names are not case sensitive. The ports of in0 and out0 may be named for the Z180's internal I/O
+ * registers, as in out0 (CNTLA0), a.
+ *
+ * Known limitations:
+ *  1. The bytes assembled are one run from the first org, so an org can't go back, and going forward fills with zeros
+ *  2. The values given to org and ds must be known in the first pass, so can't use labels defined after them
+ *  3. Of the two encodings of ld hl, (nn) and ld (nn), hl, only the shorter is assembled
+ */
+use std::collections::HashSet;
+
+use crate::cpu::Register;
+use crate::disasm::{self, Condition, Instruction, Mnemonic, Operand};
+use crate::symbols::{self, Symbols};
+
+/// Bytes assembled from source, and the symbols the source defined.
+#[derive(Debug, Clone)]
+pub struct Assembly {
+    /// The address of the first byte.
+    pub origin: u16,
+    pub bytes: Vec<u8>,
+    pub symbols: Symbols,
+}
+
+/// Assemble source from address 0, or where its first org says.
+pub fn assemble(source: &str) -> Result<Assembly, String> {
+    assemble_at(source, 0, &Symbols::new())
+}
+
+/// Assemble source from `origin`, or where its first org says, with some symbols already defined.
+pub fn assemble_at(source: &str, origin: u16, symbols: &Symbols) -> Result<Assembly, String> {
+    let mut assembler = Assembler {
+        known: symbols,
+        defined: Symbols::new(),
+        unresolved: HashSet::new(),
+        templates: templates(),
+    };
+    assembler.pass(source, origin, false)?;
+    assembler.pass(source, origin, true)
+}
+
+// An instruction as decoded with zero for its values, and its bytes.
+struct Template {
+    instruction: Instruction,
+    bytes: Vec<u8>,
+}
+
+// Every instruction the disassembler decodes, unprefixed opcodes first so that the shorter of two encodings is found
+// first.
+fn templates() -> Vec<Template> {
+    let mut opcodes: Vec<Vec<u8>> = (0..=255u8)
+        .filter(|op| ![0xcb, 0xdd, 0xed, 0xfd].contains(op))
+        .map(|op| vec![op])
+        .collect();
+    for &prefix in [0xcb, 0xed].iter() {
+        opcodes.extend((0..=255u8).map(|op| vec![prefix, op]));
+    }
+    for &prefix in [0xdd, 0xfd].iter() {
+        opcodes.extend((0..=255u8).filter(|op| *op != 0xcb).map(|op| vec![prefix, op]));
+        opcodes.extend((0..=255u8).map(|op| vec![prefix, 0xcb, 0x00, op]));
+    }
+    opcodes
+        .into_iter()
+        .filter_map(|mut bytes| {
+            bytes.resize(4, 0);
+            let instruction = disasm::decode(&bytes);
+            bytes.truncate(instruction.length);
+            match instruction.mnemonic {
+                Mnemonic::Invalid => None,
+                _ => Some(Template { instruction, bytes }),
+            }
+        })
+        .collect()
+}
+
+// An operand as written, with the values of expressions, or None where they aren't known yet.
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Arg {
+    Register(Register),
+    Condition(Condition),
+    Indirect(Register),
+    PortC,
+    // an index register and displacement, and whether a displacement was written
+    Indexed(Register, Option<i64>, bool),
+    Memory(Option<i64>),
+    Value(Option<i64>),
+}
+
+// Where assembly is up to in a pass.
+struct State {
+    origin: Option<u16>,
+    here: u32,
+    // the address of the line being assembled
+    start: u32,
+    bytes: Vec<u8>,
+    // the lower cased names defined so far
+    names: HashSet<String>,
+}
+
+impl State {
+    fn emit(&mut self, bytes: &[u8]) -> Result<(), String> {
+        let origin = *self.origin.get_or_insert(self.here as u16) as u32;
+        let offset = (self.here - origin) as usize;
+        if offset < self.bytes.len() {
+            return Err(format!("${:04x} is before code already assembled", self.here));
+        }
+        if self.here + bytes.len() as u32 > 0x10000 {
+            return Err("assembled past $ffff".to_string());
+        }
+        self.bytes.resize(offset, 0);
+        self.bytes.extend_from_slice(bytes);
+        self.here += bytes.len() as u32;
+        Ok(())
+    }
+}
+
+struct Assembler<'a> {
+    known: &'a Symbols,
+    defined: Symbols,
+    // lower cased names defined by equ in the first pass with values not known yet
+    unresolved: HashSet<String>,
+    templates: Vec<Template>,
+}
+
+impl<'a> Assembler<'a> {
+    fn pass(&mut self, source: &str, origin: u16, last: bool) -> Result<Assembly, String> {
+        let mut state = State {
+            origin: None,
+            here: origin as u32,
+            start: origin as u32,
+            bytes: Vec::new(),
+            names: HashSet::new(),
+        };
+        for (number, line) in source.lines().enumerate() {
+            let more = self
+                .line(line, &mut state, last)
+                .map_err(|e| format!("line {}: {}", number + 1, e))?;
+            if !more {
+                break;
+            }
+        }
+        Ok(Assembly {
+            origin: state.origin.unwrap_or(origin),
+            bytes: state.bytes,
+            symbols: self.defined.clone(),
+        })
+    }
+
+    // Assemble a line, answering false at the end of the source.
+    fn line(&mut self, line: &str, state: &mut State, last: bool) -> Result<bool, String> {
+        let line = &line[..scan(line, |c, _| c == ';').unwrap_or(line.len())];
+        let (first, rest) = first_word(line);
+        if first.is_empty() {
+            return Ok(true);
+        }
+        let (second, after) = first_word(rest);
+        let (label, operation, operands) = if !line.starts_with(char::is_whitespace) || first.ends_with(':') {
+            (Some(first.trim_end_matches(':')), second, after)
+        } else if second.eq_ignore_ascii_case("equ") {
+            (Some(first), second, after)
+        } else {
+            (None, first, rest)
+        };
+        let operation = operation.to_ascii_lowercase();
+        state.start = state.here;
+
+        if let Some(label) = label {
+            let value = match operation.as_str() {
+                "equ" => self.expression(operands, state, last, false)?,
+                _ => Some(state.here as i64),
+            };
+            self.define(label, value, state)?;
+        }
+        match operation.as_str() {
+            "" | "equ" => (),
+            "end" => return Ok(false),
+            "org" => {
+                let value = self.expression(operands, state, last, false)?;
+                let value = value.ok_or("org needs an address known before it")?;
+                state.here = word(value)? as u32;
+            }
+            "db" | "defb" | "defm" => {
+                for item in split(operands) {
+                    match quoted(item) {
+                        Some(text) if text.chars().count() != 1 => state.emit(text.as_bytes())?,
+                        _ => {
+                            let value = self.expression(item, state, last, false)?;
+                            state.emit(&[byte(value.unwrap_or(0))?])?
+                        }
+                    }
+                }
+            }
+            "dw" | "defw" => {
+                for item in split(operands) {
+                    let value = self.expression(item, state, last, false)?;
+                    state.emit(&word(value.unwrap_or(0))?.to_le_bytes())?;
+                }
+            }
+            "ds" | "defs" => {
+                let items = split(operands);
+                let count = self.expression(items.first().copied().unwrap_or(""), state, last, false)?;
+                let count = count.ok_or("ds needs a count known before it")?;
+                let fill = match items.get(1) {
+                    Some(fill) => byte(self.expression(fill, state, last, false)?.unwrap_or(0))?,
+                    None => 0,
+                };
+                state.emit(&vec![fill; word(count)? as usize])?;
+            }
+            mnemonic => {
+                let bytes = self.instruction(mnemonic, operands, state, last)?;
+                state.emit(&bytes)?;
+            }
+        }
+        Ok(true)
+    }
+
+    fn define(&mut self, name: &str, value: Option<i64>, state: &mut State) -> Result<(), String> {
+        if !symbols::is_name(name) {
+            return Err(format!("invalid label {}", name));
+        }
+        let lower = name.to_ascii_lowercase();
+        if !state.names.insert(lower.clone()) {
+            return Err(format!("{} is defined twice", name));
+        }
+        match value {
+            Some(value) => {
+                self.defined.insert(name, word(value)?);
+                self.unresolved.remove(&lower);
+            }
+            None => {
+                self.unresolved.insert(lower);
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluate an expression, answering None for one using symbols not known yet in the first pass.
+    fn expression(&self, text: &str, state: &State, last: bool, ports: bool) -> Result<Option<i64>, String> {
+        let mut evaluator = Evaluator {
+            tokens: tokenize(text)?,
+            next: 0,
+            assembler: self,
+            here: state.start as i64,
+            last,
+            ports,
+            resolved: true,
+        };
+        let value = evaluator.binary(0)?;
+        if let Some(token) = evaluator.tokens.get(evaluator.next) {
+            return Err(format!("unexpected {}", token));
+        }
+        Ok(match evaluator.resolved {
+            true => Some(value),
+            false => None,
+        })
+    }
+
+    fn operand(&self, text: &str, state: &State, last: bool, ports: bool) -> Result<Arg, String> {
+        let lower = text.to_ascii_lowercase();
+        if let Ok(register) = lower.parse() {
+            return Ok(Arg::Register(register));
+        }
+        if let Some(condition) = Condition::from_name(&lower) {
+            return Ok(Arg::Condition(condition));
+        }
+        let inner = match lower.strip_prefix('(') {
+            // the parentheses must enclose the whole operand, and not just start it as in (1+2)*3
+            Some(_) if scan(&lower, |c, depth| c == ')' && depth == 0) == Some(lower.len() - 1) => {
+                text[1..text.len() - 1].trim()
+            }
+            _ => return Ok(Arg::Value(self.expression(text, state, last, false)?)),
+        };
+        let lower = inner.to_ascii_lowercase();
+        match lower.as_str() {
+            "c" => return Ok(Arg::PortC),
+            "hl" | "bc" | "de" | "sp" => return Ok(Arg::Indirect(lower.parse().unwrap())),
+            _ => (),
+        }
+        for &(name, register) in [("ix", Register::IX), ("iy", Register::IY)].iter() {
+            if let Some(displacement) = lower.strip_prefix(name) {
+                let displacement = displacement.trim();
+                if displacement.is_empty() {
+                    return Ok(Arg::Indexed(register, Some(0), false));
+                }
+                if displacement.starts_with(['+', '-']) {
+                    let value = self.expression(&format!("0{}", displacement), state, last, false)?;
+                    return Ok(Arg::Indexed(register, value, true));
+                }
+            }
+        }
+        Ok(Arg::Memory(self.expression(inner, state, last, ports)?))
+    }
+
+    fn instruction(&self, mnemonic: &str, operands: &str, state: &State, last: bool) -> Result<Vec<u8>, String> {
+        let ports = mnemonic == "in0" || mnemonic == "out0";
+        let args = split(operands)
+            .into_iter()
+            .map(|operand| self.operand(operand, state, last, ports))
+            .collect::<Result<Vec<Arg>, String>>()?;
+        let mut known = false;
+        for template in &self.templates {
+            let instruction = &template.instruction;
+            if instruction.mnemonic.name() != mnemonic {
+                continue;
+            }
+            known = true;
+            if instruction.operands.len() == args.len()
+                && args
+                    .iter()
+                    .zip(&instruction.operands)
+                    .all(|(arg, operand)| matches(arg, operand, instruction.mnemonic))
+            {
+                return encode(template, &args, state.here as i64, last);
+            }
+        }
+        match known {
+            true => Err(format!("invalid operands for {}: {}", mnemonic, operands)),
+            false => Err(format!("unknown instruction {}", mnemonic)),
+        }
+    }
+}
+
+// Answer whether an operand as written can be a template's operand.
+fn matches(arg: &Arg, operand: &Operand, mnemonic: Mnemonic) -> bool {
+    // a value not known yet matches any, as every choice has the same length
+    let is = |value: &Option<i64>, expected: u16| value.map_or(true, |value| value == expected as i64);
+    match (arg, *operand) {
+        (Arg::Register(register), Operand::Register(expected)) => *register == expected,
+        (Arg::Register(Register::C), Operand::Condition(Condition::Carry)) => true,
+        (Arg::Condition(condition), Operand::Condition(expected)) => *condition == expected,
+        (Arg::Indirect(register), Operand::Indirect(expected)) => *register == expected,
+        (Arg::PortC, Operand::PortC) => true,
+        // jp (ix) is written without a displacement
+        (Arg::Indexed(register, _, false), Operand::Indirect(expected)) => *register == expected,
+        (Arg::Indexed(register, _, _), Operand::Indexed(expected, _)) => *register == expected,
+        (Arg::Memory(_), Operand::Memory(_)) | (Arg::Memory(_), Operand::Port(_)) => true,
+        (Arg::Value(value), Operand::Address(target)) if mnemonic == Mnemonic::Rst => is(value, target),
+        (Arg::Value(value), Operand::Bit(bit)) => is(value, bit as u16),
+        (Arg::Value(value), Operand::Mode(mode)) => is(value, mode as u16),
+        (Arg::Value(_), Operand::Byte(_))
+        | (Arg::Value(_), Operand::Word(_))
+        | (Arg::Value(_), Operand::Address(_))
+        | (Arg::Value(_), Operand::Relative(_)) => true,
+        _ => false,
+    }
+}
+
+// Fill a template's bytes with the values of its operands.
+fn encode(template: &Template, args: &[Arg], here: i64, last: bool) -> Result<Vec<u8>, String> {
+    let mut bytes = template.bytes.clone();
+    let len = bytes.len();
+    for (arg, operand) in args.iter().zip(&template.instruction.operands) {
+        match (*arg, *operand) {
+            (_, Operand::Address(_)) if template.instruction.mnemonic == Mnemonic::Rst => (),
+            (Arg::Value(value), Operand::Byte(_)) => bytes[len - 1] = byte(value.unwrap_or(0))?,
+            (Arg::Memory(port), Operand::Port(_)) => match port.unwrap_or(0) {
+                port @ 0..=0xff => bytes[len - 1] = port as u8,
+                port => return Err(format!("port {} isn't from 0 to 255", port)),
+            },
+            (Arg::Value(value), Operand::Word(_))
+            | (Arg::Value(value), Operand::Address(_))
+            | (Arg::Memory(value), Operand::Memory(_)) => {
+                bytes[len - 2..].copy_from_slice(&word(value.unwrap_or(0))?.to_le_bytes())
+            }
+            (Arg::Value(target), Operand::Relative(_)) => {
+                let offset = target.unwrap_or(here) - (here + len as i64);
+                if last && !(-128..=127).contains(&offset) {
+                    return Err(format!(
+                        "${:04x} is too far away for a relative jump",
+                        target.unwrap_or(0) as u16
+                    ));
+                }
+                bytes[len - 1] = offset as u8;
+            }
+            (Arg::Indexed(_, displacement, _), Operand::Indexed(_, _)) => match displacement.unwrap_or(0) {
+                displacement @ -128..=127 => bytes[2] = displacement as u8,
+                displacement => return Err(format!("displacement {} isn't from -128 to 127", displacement)),
+            },
+            _ => (),
+        }
+    }
+    Ok(bytes)
+}
+
+fn byte(value: i64) -> Result<u8, String> {
+    match value {
+        -0x80..=0xff => Ok(value as u8),
+        _ => Err(format!("{} doesn't fit in a byte", value)),
+    }
+}
+
+fn word(value: i64) -> Result<u16, String> {
+    match value {
+        -0x8000..=0xffff => Ok(value as u16),
+        _ => Err(format!("{} doesn't fit in a word", value)),
+    }
+}
+
+// The first word of some text, and the text after it.
+fn first_word(text: &str) -> (&str, &str) {
+    let text = text.trim_start();
+    let end = text.find(char::is_whitespace).unwrap_or(text.len());
+    (&text[..end], text[end..].trim())
+}
+
+// Answer whether the quote at byte `i` ends an alternate register's name, as in af', rather than starting a string.
+fn is_prime(text: &str, i: usize) -> bool {
+    let bytes = text.as_bytes();
+    i >= 2
+        && ["af", "bc", "de", "hl"]
+            .iter()
+            .any(|name| bytes[i - 2..i].eq_ignore_ascii_case(name.as_bytes()))
+        && (i == 2 || !(bytes[i - 3].is_ascii_alphanumeric() || bytes[i - 3] == b'_'))
+}
+
+// Find the first character outside quotes that `stop` accepts, given how deeply nested in parentheses it is.
+fn scan<F: Fn(char, i32) -> bool>(text: &str, stop: F) -> Option<usize> {
+    let mut quote = None;
+    let mut depth = 0;
+    for (i, c) in text.char_indices() {
+        if let Some(open) = quote {
+            if c == open {
+                quote = None;
+            }
+            continue;
+        }
+        match c {
+            '\'' if is_prime(text, i) => (),
+            '\'' | '"' => {
+                quote = Some(c);
+                continue;
+            }
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            _ => (),
+        }
+        if stop(c, depth) {
+            return Some(i);
+        }
+    }
+    None
+}
+
+// Split operands at commas outside quotes and parentheses.
+fn split(text: &str) -> Vec<&str> {
+    let mut items = Vec::new();
+    let mut rest = text.trim();
+    while !rest.is_empty() {
+        match scan(rest, |c, depth| c == ',' && depth == 0) {
+            Some(comma) => {
+                items.push(rest[..comma].trim());
+                rest = rest[comma + 1..].trim_start();
+            }
+            None => {
+                items.push(rest.trim_end());
+                break;
+            }
+        }
+    }
+    items
+}
+
+// The text of a string in single or double quotes.
+fn quoted(text: &str) -> Option<&str> {
+    let quote = text.chars().next().filter(|c| *c == '\'' || *c == '"')?;
+    match text.len() >= 2 && text.ends_with(quote) {
+        true => Some(&text[1..text.len() - 1]),
+        false => None,
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Number(i64),
+    Name(String),
+    // $, the address of the line
+    Here,
+    Symbol(&'static str),
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Number(n) => write!(f, "{}", n),
+            Token::Name(name) => write!(f, "{}", name),
+            Token::Here => write!(f, "$"),
+            Token::Symbol(symbol) => write!(f, "{}", symbol),
+        }
+    }
+}
+
+const SYMBOLS: [&str; 14] = ["<<", ">>", "|", "^", "&", "+", "-", "*", "/", "%", "~", "(", ")", ","];
+
+// Binary operators by precedence, loosest first
+const PRECEDENCE: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+
+fn number(word: &str) -> Result<i64, String> {
+    let lower = word.to_ascii_lowercase();
+    let value = match lower.strip_prefix('%').or_else(|| lower.strip_prefix("0b")) {
+        Some(binary) if !lower.ends_with('h') => u32::from_str_radix(binary, 2).ok(),
+        _ => symbols::parse_number(&lower, 10),
+    };
+    value.map(i64::from).ok_or_else(|| format!("invalid number {}", word))
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens: Vec<Token> = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        // % is a binary number where a value is expected, and otherwise the remainder
+        let operand_expected =
+            matches!(tokens.last(), None | Some(Token::Symbol(_))) && tokens.last() != Some(&Token::Symbol(")"));
+        let is_number = c.is_ascii_digit()
+            || (c == '$' && rest[1..].starts_with(|c: char| c.is_ascii_hexdigit()))
+            || (c == '%' && operand_expected && rest[1..].starts_with(['0', '1']));
+        let len = if is_number {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .map_or(rest.len(), |end| end + 1);
+            tokens.push(Token::Number(number(&rest[..end])?));
+            end
+        } else if c == '$' {
+            tokens.push(Token::Here);
+            1
+        } else if c == '\'' || c == '"' {
+            let mut chars = rest[1..].chars();
+            match (chars.next(), chars.next()) {
+                (Some(value), Some(close)) if close == c => {
+                    tokens.push(Token::Number(value as i64));
+                    value.len_utf8() + 2
+                }
+                _ => return Err(format!("invalid character {}", rest)),
+            }
+        } else if c.is_ascii_alphabetic() || "_.?@".contains(c) {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && !"_.?@$".contains(c))
+                .unwrap_or(rest.len());
+            tokens.push(Token::Name(rest[..end].to_string()));
+            end
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected {}", c))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    next: usize,
+    assembler: &'a Assembler<'a>,
+    here: i64,
+    last: bool,
+    // whether names may be the Z180's internal I/O registers
+    ports: bool,
+    resolved: bool,
+}
+
+impl<'a> Evaluator<'a> {
+    fn take(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn binary(&mut self, level: usize) -> Result<i64, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match self.tokens.get(self.next) {
+                Some(Token::Symbol(s)) => PRECEDENCE[level].iter().find(|op| *op == s).copied(),
+                _ => None,
+            };
+            let op = match op {
+                Some(op) => op,
+                None => return Ok(left),
+            };
+            self.next += 1;
+            let right = self.binary(level + 1)?;
+            left = match op {
+                "|" => left | right,
+                "^" => left ^ right,
+                "&" => left & right,
+                "<<" => left.wrapping_shl(right as u32),
+                ">>" => left.wrapping_shr(right as u32),
+                "+" => left.wrapping_add(right),
+                "-" => left.wrapping_sub(right),
+                "*" => left.wrapping_mul(right),
+                // a value not known yet might be zero
+                _ if right == 0 && !self.resolved => 0,
+                _ if right == 0 => return Err("division by zero".to_string()),
+                "/" => left / right,
+                _ => left % right,
+            };
+        }
+    }
+
+    fn unary(&mut self) -> Result<i64, String> {
+        match self.tokens.get(self.next) {
+            Some(Token::Symbol("-")) => {
+                self.next += 1;
+                Ok(self.unary()?.wrapping_neg())
+            }
+            Some(Token::Symbol("~")) => {
+                self.next += 1;
+                Ok(!self.unary()?)
+            }
+            _ => self.primary(),
+        }
+    }
+
+    fn primary(&mut self) -> Result<i64, String> {
+        match self.take() {
+            Some(Token::Number(n)) => Ok(n),
+            Some(Token::Here) => Ok(self.here),
+            Some(Token::Symbol("(")) => {
+                let value = self.binary(0)?;
+                match self.take() {
+                    Some(Token::Symbol(")")) => Ok(value),
+                    _ => Err("expected )".to_string()),
+                }
+            }
+            Some(Token::Name(name)) => {
+                let assembler = self.assembler;
+                if !assembler.unresolved.contains(&name.to_ascii_lowercase()) {
+                    if let Some(value) = assembler.defined.lookup(&name).or_else(|| assembler.known.lookup(&name)) {
+                        return Ok(value as i64);
+                    }
+                }
+                if self.ports {
+                    if let Some(port) = (0..0x40)
+                        .find(|port| disasm::port_name(*port).is_some_and(|register| register.eq_ignore_ascii_case(&name)))
+                    {
+                        return Ok(port as i64);
+                    }
+                }
+                match self.last {
+                    true => Err(format!("unknown symbol {}", name)),
+                    false => {
+                        self.resolved = false;
+                        Ok(0)
+                    }
+                }
+            }
+            Some(token) => Err(format!("unexpected {}", token)),
+            None => Err("missing value".to_string()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::asm::*;
+    use crate::disasm::Syntax;
+
+    #[test]
+    fn assembles_instructions() {
+        let bytes = |source: &str| assemble(source).map(|assembly| assembly.bytes);
+        assert_eq!(bytes("\txor a\n\tdec a\n\tdaa"), Ok(vec![0xaf, 0x3d, 0x27]));
+        assert_eq!(bytes(" ld a, ($f600)"), Ok(vec![0x3a, 0x00, 0xf6]));
+        assert_eq!(bytes(" LD A,(0F600H)"), Ok(vec![0x3a, 0x00, 0xf6]));
+        assert_eq!(bytes(" ld (ix-1), 'A'"), Ok(vec![0xdd, 0x36, 0xff, 0x41]));
+        assert_eq!(bytes(" jp (iy)"), Ok(vec![0xfd, 0xe9]));
+        assert_eq!(bytes(" ex af, af' ; swap"), Ok(vec![0x08]));
+        assert_eq!(bytes(" ret c\n jp c, 0\n in a, (c)"), Ok(vec![0xd8, 0xda, 0, 0, 0xed, 0x78]));
+        assert_eq!(
+            bytes(" out0 (CNTLA0), a\n mlt hl\n rst 38h"),
+            Ok(vec![0xed, 0x39, 0x00, 0xed, 0x6c, 0xff])
+        );
+        assert_eq!(bytes(" ld hl, ($1234)"), Ok(vec![0x2a, 0x34, 0x12]));
+        assert_eq!(bytes(" set 7, (iy+(2*3))"), Ok(vec![0xfd, 0xcb, 0x06, 0xfe]));
+        assert_eq!(bytes(" ld a, (1+2)*3"), Ok(vec![0x3e, 0x09]));
+
+        assert_eq!(bytes(" ld a, hl"), Err("line 1: invalid operands for ld: a, hl".to_string()));
+        assert_eq!(bytes("\n frob"), Err("line 2: unknown instruction frob".to_string()));
+        assert_eq!(bytes(" ld a, 256"), Err("line 1: 256 doesn't fit in a byte".to_string()));
+        assert_eq!(bytes(" jp nowhere"), Err("line 1: unknown symbol nowhere".to_string()));
+        assert!(bytes(" jr $+200").is_err());
+    }
+
+    #[test]
+    fn assembles_programs() {
+        let assembly = assemble(
+            "\
+; print a message
+BDOS    equ 5
+        org $100
+start:  ld de, msg
+        ld c, PRINT
+        call BDOS
+loop    djnz loop
+        jr start
+msg:    db \"Hi, there$\", 13, 10
+        dw start, $ - msg
+        ds 2, $ff
+PRINT   equ 9
+        end
+        this isn't assembled
+",
+        )
+        .unwrap();
+        assert_eq!(assembly.origin, 0x0100);
+        assert_eq!(
+            assembly.bytes,
+            [
+                0x11, 0x0c, 0x01, 0x0e, 0x09, 0xcd, 0x05, 0x00, 0x10, 0xfe, 0x18, 0xf4, b'H', b'i', b',', b' ', b't', b'h',
+                b'e', b'r', b'e', b'$', 13, 10, 0x00, 0x01, 0x0c, 0x00, 0xff, 0xff
+            ]
+        );
+        assert_eq!(assembly.symbols.lookup("msg"), Some(0x010c));
+        assert_eq!(assembly.symbols.lookup("print"), Some(9));
+
+        assert!(assemble("a: nop\na: nop").unwrap_err().contains("defined twice"));
+        let mut symbols = Symbols::new();
+        symbols.insert("BIOS_READ", 0xf627);
+        let assembly = assemble_at(" call BIOS_READ", 0x8000, &symbols).unwrap();
+        assert_eq!((assembly.origin, assembly.bytes), (0x8000, vec![0xcd, 0x27, 0xf6]));
+    }
+
+    #[test]
+    fn assembles_what_disassembles() {
+        // every instruction the disassembler writes assembles back to the same bytes, in either syntax
+        for template in templates() {
+            let mut bytes = template.bytes.clone();
+            // give values a byte that isn't zero, keeping relative jumps in range, and leaving the displacement of
+            // DD CB and FD CB instructions, which come before their opcode
+            let mnemonic = template.instruction.mnemonic;
+            let has_value = template.instruction.operands.iter().any(|operand| match operand {
+                Operand::Address(_) => mnemonic != Mnemonic::Rst,
+                operand => matches!(
+                    operand,
+                    Operand::Byte(_)
+                        | Operand::Word(_)
+                        | Operand::Memory(_)
+                        | Operand::Port(_)
+                        | Operand::Relative(_)
+                        | Operand::Indexed(..)
+                ),
+            });
+            if has_value && !matches!(bytes[..], [_, 0xcb, ..]) {
+                *bytes.last_mut().unwrap() = 0x12;
+            }
+            let instruction = disasm::decode(&[&bytes[..], &[0, 0, 0]].concat());
+            if instruction.mnemonic == Mnemonic::Invalid || matches!(bytes[..], [0xed, 0x63, ..] | [0xed, 0x6b, ..]) {
+                continue;
+            }
+            for &syntax in [Syntax::Zilog, Syntax::Hitachi].iter() {
+                let text = instruction.text_as(syntax, 0x0100, &Symbols::new());
+                let source = format!("\torg\t$100\n\t{}", text);
+                assert_eq!(
+                    assemble(&source).map(|assembly| assembly.bytes),
+                    Ok(bytes.clone()),
+                    "{}",
+                    text
+                );
+            }
+        }
+    }
+}
@@ -1,9 +1,108 @@
+use std::io;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
+use crate::asci::{Channel, ASCI};
 use crate::bus::Bus;
 use crate::cpu::CPU;
+use crate::dma::DMA;
+use crate::prt::PRT;
+use crate::ram::{UninitPolicy, RAM};
+use crate::rom::{Chip, ROM};
+use crate::rtc::{TimeSource, RTC};
+use crate::sdcard::SDCard;
+use crate::state;
 use crate::types::Peripheral;
 
+/// The parts and options that make up a TRS-20, so that front ends can describe a machine and have it put together
+/// the same way.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// The ROM image, mapped at $80000.
+    pub rom: PathBuf,
+    /// The flash part to model for the ROM, or a plain ROM if none.
+    pub flash: Option<Chip>,
+    /// Battery-backed RAM regions as START:SIZE:FILE, addresses in hex.
+    pub nvram: Vec<String>,
+    /// Fill RAM with seeded random contents at power on.
+    pub ram_seed: Option<u64>,
+    pub uninit: UninitPolicy,
+    /// Add a DS1302 at port $70 reading this time source.
+    pub rtc: Option<TimeSource>,
+    /// Tie ASCI0 to this TTY device.
+    pub tty: Option<String>,
+}
+
+// Parse a START:SIZE:FILE battery-backed RAM description.
+fn parse_nvram(arg: &str) -> Result<RAM, io::Error> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidInput, format!("invalid nvram region {}", arg));
+    let mut parts = arg.splitn(3, ':');
+    let start = parts
+        .next()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .ok_or_else(invalid)?;
+    let size = parts
+        .next()
+        .and_then(|s| u32::from_str_radix(s, 16).ok())
+        .ok_or_else(invalid)?;
+    let file = parts.next().ok_or_else(invalid)?;
+    RAM::battery_backed(start, size, file)
+}
+
+impl Config {
+    pub fn new<P: Into<PathBuf>>(rom: P) -> Config {
+        Config {
+            rom: rom.into(),
+            flash: None,
+            nvram: Vec::new(),
+            ram_seed: None,
+            uninit: UninitPolicy::Ignore,
+            rtc: None,
+            tty: None,
+        }
+    }
+
+    /// Put the machine together and reset it.
+    pub fn build(&self) -> Result<(CPU, Bus), io::Error> {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+
+        let ram = Rc::new(RAM::new(0x00000, 0x80000));
+        if let Some(seed) = self.ram_seed {
+            ram.randomize(seed);
+        }
+        ram.track_uninitialised(self.uninit);
+
+        let rom_data = std::fs::read(&self.rom)?;
+        let rom = match self.flash {
+            Some(chip) => ROM::with_chip(0x80000, chip, rom_data),
+            None => ROM::new(0x80000, rom_data),
+        };
+        let rom = Rc::new(rom.map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?);
+        bus.add(rom); // ROM is first to allow address masking to work
+
+        // battery-backed regions shadow the main RAM, so they must come before it
+        for nvram in &self.nvram {
+            bus.add(Rc::new(parse_nvram(nvram)?));
+        }
+        bus.add(ram);
+
+        bus.add(Rc::new(PRT::new()));
+        bus.add(Rc::new(DMA::new()));
+        bus.add(Rc::new(SDCard::new()));
+
+        if let Some(source) = self.rtc {
+            bus.add(Rc::new(RTC::new(0x70, source)));
+        }
+        if let Some(tty) = &self.tty {
+            bus.add(Rc::new(ASCI::new(Channel::CH0, tty)));
+        }
+
+        cpu.reset();
+        Ok((cpu, bus))
+    }
+}
+
 pub struct Board<'a> {
     cpu: &'a mut CPU,
     bus: &'a mut Bus,
@@ -23,6 +122,18 @@ impl<'a> Board<'a> {
         self.bus.reset();
     }
 
+    /// Save the machine's state to a file, to be loaded into a machine built from the same configuration.
+    pub fn save_state<P: AsRef<Path>>(&self, path: P) -> Result<(), io::Error> {
+        std::fs::write(path, state::save(self.cpu, self.bus))
+    }
+
+    /// Load a state saved by `save_state`. A file from a machine built differently is refused, but may leave this one
+    /// partly loaded; reset it before running it again.
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), io::Error> {
+        let bytes = std::fs::read(path)?;
+        Ok(state::load(self.cpu, self.bus, &bytes)?)
+    }
+
     // Run a clock cycle. The CPU
     pub fn cycle(&mut self) {
         self.cpu.cycle(&mut self.bus);
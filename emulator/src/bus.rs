@@ -1,13 +1,95 @@
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell, RefMut};
+use std::fmt;
 use std::rc::Rc;
 
 use enumset::EnumSet;
 
+use crate::cpu::mmu::MMU;
+use crate::history::{InputLog, Source};
+use crate::state::{StateError, StateReader, StateWriter};
 pub use crate::types::*;
+use crate::watch::{Access, Hit, Space, Watchpoint};
+
+/// The rate at which clock cycles elapse on the emulated clock, for an 18.432MHz Z8S180. Each instruction moves the
+/// clock on by the T-states it takes.
+pub const CYCLES_PER_SECOND: u64 = 18_432_000;
+
+/// Convert a duration in microseconds into a count of clock cycles on the emulated clock.
+pub fn cycles_from_micros(micros: u64) -> u64 {
+    micros * CYCLES_PER_SECOND / 1_000_000
+}
+
+/// What made an access on the bus.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Origin {
+    /// The CPU fetching an opcode, with M1 asserted.
+    Fetch,
+    /// The CPU reading an operand or data, or doing I/O.
+    Cpu,
+    /// A peripheral as it cycles, which is only ever the DMA controller.
+    Dma,
+}
+
+impl Origin {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Origin::Fetch => "fetch",
+            Origin::Cpu => "cpu",
+            Origin::Dma => "dma",
+        }
+    }
+}
+
+/// A memory or I/O access made on the bus.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Transaction {
+    /// The bus clock when the access was made.
+    pub clock: u64,
+    pub origin: Origin,
+    /// Physical memory or I/O; never logical.
+    pub space: Space,
+    pub access: Access,
+    pub address: u32,
+    pub value: u8,
+}
+
+impl Transaction {
+    /// A short name for the access: rd or wr for memory, in or out for I/O.
+    pub fn kind(&self) -> &'static str {
+        match (self.space, self.access) {
+            (Space::Io, Access::Read) => "in",
+            (Space::Io, _) => "out",
+            (_, Access::Read) => "rd",
+            _ => "wr",
+        }
+    }
+}
+
+impl fmt::Display for Transaction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.space {
+            Space::Io => write!(f, "{} ${:04x}=${:02x}", self.kind(), self.address, self.value),
+            _ => write!(f, "{} ${:05x}=${:02x}", self.kind(), self.address, self.value),
+        }
+    }
+}
 
 pub struct Bus {
     peripherals: Vec<Rc<dyn Peripheral>>,
     ints: RefCell<EnumSet<Interrupt>>,
+    clock: Cell<u64>,
+    // the CPU's MMU, for matching accesses to logical watchpoints
+    mmu: Option<Rc<MMU>>,
+    watchpoints: Vec<(usize, Watchpoint)>,
+    next_watchpoint: usize,
+    hits: RefCell<Vec<Hit>>,
+    inputs: RefCell<InputLog>,
+    // accesses made since they were last taken, when collecting them for a trace
+    collecting: Cell<bool>,
+    transactions: RefCell<Vec<Transaction>>,
+    taps: Vec<Rc<dyn Tap>>,
+    // what's making accesses, apart from opcode fetches
+    origin: Cell<Origin>,
 }
 
 impl Bus {
@@ -15,9 +97,30 @@ impl Bus {
         Bus {
             peripherals: Vec::new(),
             ints: RefCell::new(EnumSet::new()),
+            clock: Cell::new(0),
+            mmu: None,
+            watchpoints: Vec::new(),
+            next_watchpoint: 1,
+            hits: RefCell::new(Vec::new()),
+            inputs: RefCell::new(InputLog::new()),
+            collecting: Cell::new(false),
+            transactions: RefCell::new(Vec::new()),
+            taps: Vec::new(),
+            origin: Cell::new(Origin::Cpu),
         }
     }
 
+    // The number of clock cycles run since the bus was created.
+    pub fn clock(&self) -> u64 {
+        self.clock.get()
+    }
+
+    /// Move the clock on without cycling the peripherals, for the clock cycles an instruction takes past the one
+    /// `cycle` counts.
+    pub fn advance(&self, cycles: u64) {
+        self.clock.set(self.clock.get() + cycles);
+    }
+
     pub fn reset(&self) {
         for peripheral in &self.peripherals {
             peripheral.reset();
@@ -25,7 +128,9 @@ impl Bus {
     }
 
     pub fn cycle(&self) -> Option<Interrupt> {
+        self.clock.set(self.clock.get() + 1);
         let mut ints = self.ints.borrow_mut();
+        self.origin.set(Origin::Dma);
         for peripheral in &self.peripherals {
             match peripheral.cycle(self) {
                 Some(int) => {
@@ -34,11 +139,48 @@ impl Bus {
                 None => (),
             }
         }
+        self.origin.set(Origin::Cpu);
 
         // service the next highest priority pending interrupt
         ints.iter().next()
     }
 
+    /// Take in a value from outside the machine, such as a byte arriving on a serial port. Peripherals read the host
+    /// through this so that a recorded run can be replayed; `live` is only called when the value isn't being replayed.
+    pub fn input<F: FnOnce() -> Option<u64>>(&self, source: Source, live: F) -> Option<u64> {
+        self.inputs.borrow_mut().input(self.clock.get(), source, live)
+    }
+
+    pub fn inputs(&self) -> RefMut<'_, InputLog> {
+        self.inputs.borrow_mut()
+    }
+
+    /// Write the clock, pending interrupts and every peripheral's state into a snapshot.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.clock.get());
+        state.u32(self.ints.borrow().as_u32());
+        state.u32(self.peripherals.len() as u32);
+        for peripheral in &self.peripherals {
+            peripheral.save_state(state);
+        }
+    }
+
+    /// Restore a snapshot written by `save_state` on a bus with the same peripherals.
+    pub fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        let clock = state.u64()?;
+        let ints = EnumSet::try_from_u32(state.u32()?).ok_or(StateError::Invalid("interrupt set"))?;
+        if state.u32()? as usize != self.peripherals.len() {
+            return Err(StateError::Invalid("peripheral count"));
+        }
+        for peripheral in &self.peripherals {
+            peripheral.load_state(state)?;
+        }
+        self.clock.set(clock);
+        *self.ints.borrow_mut() = ints;
+        self.hits.borrow_mut().clear();
+        Ok(())
+    }
+
     pub fn intack(&self, int: Interrupt) {
         *self.ints.borrow_mut() -= int;
     }
@@ -47,13 +189,126 @@ impl Bus {
         self.peripherals.push(peripheral);
     }
 
+    pub(crate) fn set_mmu(&mut self, mmu: Rc<MMU>) {
+        self.mmu = Some(mmu);
+    }
+
+    /// Watch for accesses, answering an ID for the watchpoint.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        let id = self.next_watchpoint;
+        self.next_watchpoint += 1;
+        self.watchpoints.push((id, watchpoint));
+        id
+    }
+
+    /// Remove a watchpoint, answering false if there was none with the ID.
+    pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+        let before = self.watchpoints.len();
+        self.watchpoints.retain(|(wp, _)| *wp != id);
+        self.watchpoints.len() != before
+    }
+
+    pub fn watchpoints(&self) -> impl Iterator<Item = (usize, Watchpoint)> + '_ {
+        self.watchpoints.iter().copied()
+    }
+
+    /// Collect the accesses watchpoints have caught since the last call.
+    pub fn take_hits(&self) -> Vec<Hit> {
+        self.hits.replace(Vec::new())
+    }
+
+    /// Start or stop collecting every memory and I/O access, to be taken with `take_transactions`.
+    pub fn collect_transactions(&self, collecting: bool) {
+        self.collecting.set(collecting);
+        if !collecting {
+            self.transactions.borrow_mut().clear();
+        }
+    }
+
+    /// Take the accesses collected since the last call.
+    pub fn take_transactions(&self) -> Vec<Transaction> {
+        self.transactions.replace(Vec::new())
+    }
+
+    /// Show every memory and I/O access to a tap, from now on.
+    pub fn add_tap(&mut self, tap: Rc<dyn Tap>) {
+        self.taps.push(tap);
+    }
+
+    fn collect(&self, origin: Origin, space: Space, address: u32, access: Access, value: u8) {
+        if !self.collecting.get() && self.taps.is_empty() {
+            return;
+        }
+        let transaction = Transaction {
+            clock: self.clock.get(),
+            origin,
+            space,
+            access,
+            address,
+            value,
+        };
+        for tap in &self.taps {
+            tap.access(&transaction);
+        }
+        if self.collecting.get() {
+            self.transactions.borrow_mut().push(transaction);
+        }
+    }
+
+    // Record a hit for each watchpoint that catches an access.
+    fn watch(&self, space: Space, address: u32, access: Access, value: u8) {
+        for &(id, wp) in &self.watchpoints {
+            if !wp.catches(access, value) {
+                continue;
+            }
+            let address = match (wp.space, space) {
+                (Space::Logical, Space::Physical) => match &self.mmu {
+                    Some(mmu) => match mmu.to_logical(address).find(|&a| wp.contains(a as u32)) {
+                        Some(logical) => logical as u32,
+                        None => continue,
+                    },
+                    None => continue,
+                },
+                (a, b) if a == b && wp.contains(address) => address,
+                _ => continue,
+            };
+            self.hits.borrow_mut().push(Hit {
+                id,
+                space: wp.space,
+                access,
+                address,
+                value,
+            });
+        }
+    }
+
     pub fn mem_read(&self, address: u32, m1: bool) -> u8 {
-        for peripheral in &self.peripherals {
-            match peripheral.mem_read(address, m1) {
-                Some(data) => {
-                    return data;
+        let origin = if m1 { Origin::Fetch } else { self.origin.get() };
+        let data = match self
+            .peripherals
+            .iter()
+            .find_map(|peripheral| peripheral.mem_read(address, m1).map(|data| (peripheral, data)))
+        {
+            Some((peripheral, data)) => {
+                if origin != Origin::Dma {
+                    peripheral.cpu_read(address);
                 }
-                None => (),
+                data
+            }
+            None => 255,
+        };
+        self.collect(origin, Space::Physical, address, Access::Read, data);
+        if !self.watchpoints.is_empty() {
+            self.watch(Space::Physical, address, Access::Read, data);
+        }
+        data
+    }
+
+    // Read memory without side effects, for debuggers and tracers.
+    pub fn peek(&self, address: u32) -> u8 {
+        for peripheral in &self.peripherals {
+            if let Some(data) = peripheral.peek(address) {
+                return data;
             }
         }
         255
@@ -63,21 +318,101 @@ impl Bus {
         for peripheral in &self.peripherals {
             peripheral.mem_write(address, data);
         }
+        self.collect(self.origin.get(), Space::Physical, address, Access::Write, data);
+        if !self.watchpoints.is_empty() {
+            self.watch(Space::Physical, address, Access::Write, data);
+        }
     }
 
     pub fn io_read(&self, address: u16) -> u8 {
-        for peripheral in &self.peripherals {
-            match peripheral.io_read(address) {
-                Some(data) => return data,
-                None => (),
-            }
+        let data = self
+            .peripherals
+            .iter()
+            .find_map(|peripheral| peripheral.io_read(address))
+            .unwrap_or(255);
+        self.collect(self.origin.get(), Space::Io, address as u32, Access::Read, data);
+        if !self.watchpoints.is_empty() {
+            self.watch(Space::Io, address as u32, Access::Read, data);
         }
-        255
+        data
     }
 
     pub fn io_write(&self, address: u16, data: u8) {
         for peripheral in &self.peripherals {
             peripheral.io_write(address, data);
         }
+        self.collect(self.origin.get(), Space::Io, address as u32, Access::Write, data);
+        if !self.watchpoints.is_empty() {
+            self.watch(Space::Io, address as u32, Access::Write, data);
+        }
+    }
+
+    // Collect the faults raised by peripherals since the last call.
+    pub fn take_faults(&self) -> Vec<Fault> {
+        let mut faults = Vec::new();
+        for peripheral in &self.peripherals {
+            faults.append(&mut peripheral.faults());
+        }
+        // replaying runs instructions again, and their faults were reported the first time
+        if self.inputs.borrow().replays(self.clock.get()) {
+            faults.clear();
+        }
+        faults
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::*;
+    use crate::cpu::CPU;
+    use crate::dma::DMA;
+    use crate::ram::RAM;
+    use crate::watch::Condition;
+
+    #[test]
+    fn watchpoints_catch_dma() {
+        let mut bus = Bus::new();
+        let _cpu = CPU::new(&mut bus);
+        bus.add(Rc::new(RAM::new(0x00000, 0x80000)));
+        bus.add(Rc::new(DMA::new()));
+
+        // map logical $0000-$efff to physical $10000-$1efff
+        bus.io_write(0x39, 0x10);
+        let logical = bus.add_watchpoint(Watchpoint::new(Space::Logical, 0x8000, 0x10, Access::Write));
+        let physical = bus.add_watchpoint(Watchpoint::new(Space::Physical, 0x00100, 1, Access::Read));
+        let io = bus.add_watchpoint(Watchpoint::new(Space::Io, 0x30, 1, Access::Write).when(Condition::Masked(0x40, 0x40)));
+
+        // DMA one byte from $00100 to $18004
+        for (port, value) in [
+            (0x20, 0x00),
+            (0x21, 0x01),
+            (0x22, 0x00),
+            (0x23, 0x04),
+            (0x24, 0x80),
+            (0x25, 0x01),
+        ]
+        .iter()
+        {
+            bus.io_write(*port, *value);
+        }
+        bus.io_write(0x26, 1);
+        bus.io_write(0x30, 0x00);
+        assert!(bus.take_hits().is_empty(), "DMA register writes don't match the condition");
+        bus.io_write(0x30, 0x40);
+        bus.cycle();
+
+        let hits: Vec<(usize, Access, u32)> = bus.take_hits().iter().map(|hit| (hit.id, hit.access, hit.address)).collect();
+        assert_eq!(
+            hits,
+            [
+                (io, Access::Write, 0x30),
+                (physical, Access::Read, 0x00100),
+                (logical, Access::Write, 0x8004)
+            ]
+        );
+
+        assert!(bus.remove_watchpoint(logical));
+        assert!(!bus.remove_watchpoint(logical));
+        assert_eq!(bus.watchpoints().count(), 2);
     }
 }
@@ -0,0 +1,195 @@
+/**
+ * Bus logging
+ *
+ * A `BusLog` taps the bus and writes a line for each memory or I/O access: the bus clock, what made the access, and
+ * the access itself, as in
+ *
+ *   `      123456 cpu   out $0040=$01`
+ *
+ * Accesses are made by the CPU fetching an opcode, by the CPU otherwise, or by DMA. Opcode fetches are left out unless
+ * asked for. Filters limit the log to ranges of physical memory or I/O ports. Nothing from the host goes into a line,
+ * so the logs of two runs of the same machine from the same state differ only where the runs do.
+ *
+ * Known limitations:
+ *  1. Accesses made by debuggers, such as writes to memory from the monitor, are logged as the CPU's
+ *  2. Write errors are only reported by `finish`, and stop the log
+ */
+use std::cell::{Ref, RefCell};
+use std::io::{self, Write};
+
+use crate::bus::{Origin, Transaction};
+use crate::types::Tap;
+use crate::watch::Space;
+
+/// Which accesses are logged.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Filter {
+    /// Physical memory addresses from the first to the second, inclusive.
+    Memory(u32, u32),
+    /// I/O ports from the first to the second, inclusive.
+    Io(u16, u16),
+}
+
+impl Filter {
+    /// Parse a filter as mem:ADDR[-ADDR] or io:PORT[-PORT], in hex.
+    pub fn parse(spec: &str) -> Result<Filter, String> {
+        let hex = |s: &str| u32::from_str_radix(s.trim_start_matches('$').trim_start_matches("0x"), 16);
+        let range = |range: &str, max: u32| {
+            let (start, end) = range.split_once('-').unwrap_or((range, range));
+            match (hex(start), hex(end)) {
+                (Ok(start), Ok(end)) if start <= end && end <= max => Ok((start, end)),
+                _ => Err(format!("invalid range {}", range)),
+            }
+        };
+        if let Some(addresses) = spec.strip_prefix("mem:") {
+            let (start, end) = range(addresses, 0xfffff)?;
+            Ok(Filter::Memory(start, end))
+        } else if let Some(ports) = spec.strip_prefix("io:") {
+            let (start, end) = range(ports, 0xffff)?;
+            Ok(Filter::Io(start as u16, end as u16))
+        } else {
+            Err(format!("{} isn't mem:ADDR[-ADDR] or io:PORT[-PORT]", spec))
+        }
+    }
+
+    pub fn matches(&self, transaction: &Transaction) -> bool {
+        match (*self, transaction.space) {
+            (Filter::Memory(start, end), Space::Physical) => (start..=end).contains(&transaction.address),
+            (Filter::Io(start, end), Space::Io) => (start as u32..=end as u32).contains(&transaction.address),
+            _ => false,
+        }
+    }
+}
+
+/// Writes a line for each access made on the bus it taps.
+pub struct BusLog<W: Write> {
+    output: RefCell<W>,
+    filters: Vec<Filter>,
+    fetches: bool,
+    error: RefCell<Option<io::Error>>,
+}
+
+impl<W: Write> BusLog<W> {
+    pub fn new(output: W) -> BusLog<W> {
+        BusLog {
+            output: RefCell::new(output),
+            filters: Vec::new(),
+            fetches: false,
+            error: RefCell::new(None),
+        }
+    }
+
+    /// Log accesses the filter matches. With no filters, every access is logged.
+    pub fn add_filter(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    /// Log the CPU's opcode fetches too.
+    pub fn log_fetches(&mut self, fetches: bool) {
+        self.fetches = fetches;
+    }
+
+    pub fn output(&self) -> Ref<'_, W> {
+        self.output.borrow()
+    }
+
+    /// Flush the log, answering the first error writing it, if any.
+    pub fn finish(&self) -> io::Result<()> {
+        match self.error.borrow_mut().take() {
+            Some(e) => Err(e),
+            None => self.output.borrow_mut().flush(),
+        }
+    }
+}
+
+impl<W: Write> Tap for BusLog<W> {
+    fn access(&self, transaction: &Transaction) {
+        if transaction.origin == Origin::Fetch && !self.fetches {
+            return;
+        }
+        if !self.filters.is_empty() && !self.filters.iter().any(|filter| filter.matches(transaction)) {
+            return;
+        }
+        let mut error = self.error.borrow_mut();
+        if error.is_none() {
+            let written = writeln!(
+                self.output.borrow_mut(),
+                "{:>12} {:<5} {}",
+                transaction.clock,
+                transaction.origin.name(),
+                transaction
+            );
+            *error = written.err();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::Bus;
+    use crate::buslog::*;
+    use crate::cpu::CPU;
+    use crate::dma::DMA;
+    use crate::ram::RAM;
+
+    #[test]
+    fn logs_accesses_by_origin() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x00000, 0x80000));
+        ram.write(
+            0x0000,
+            &[
+                0x3e, 0x42, //          0x0000  ld a, $42
+                0x32, 0x00, 0x80, //    0x0002  ld ($8000), a
+                0x76, //                0x0005  halt
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        bus.add(Rc::new(DMA::new()));
+        let mut log = BusLog::new(Vec::new());
+        log.add_filter(Filter::parse("mem:8000-80ff").unwrap());
+        log.add_filter(Filter::parse("io:20-32").unwrap());
+        let log = Rc::new(log);
+        bus.add_tap(log.clone());
+        cpu.reset();
+        for _ in 0..3 {
+            cpu.cycle(&mut bus);
+        }
+
+        // DMA one byte from $08000 to $08001
+        for (port, value) in [
+            (0x20, 0x00),
+            (0x21, 0x80),
+            (0x23, 0x01),
+            (0x24, 0x80),
+            (0x26, 0x01),
+            (0x30, 0x40),
+        ]
+        .iter()
+        {
+            bus.io_write(*port, *value);
+        }
+        bus.cycle();
+        log.finish().unwrap();
+
+        let text = String::from_utf8(log.output().clone()).unwrap();
+        let lines: Vec<&str> = text.lines().map(str::trim_start).collect();
+        assert_eq!(&lines[..2], ["7 cpu   wr $08000=$42", "22 cpu   out $0020=$00"]);
+        assert_eq!(
+            &lines[lines.len() - 2..],
+            ["23 dma   rd $08000=$42", "23 dma   wr $08001=$42"]
+        );
+    }
+
+    #[test]
+    fn filters() {
+        assert_eq!(Filter::parse("io:40"), Ok(Filter::Io(0x40, 0x40)));
+        assert_eq!(Filter::parse("mem:$f600-f6ff"), Ok(Filter::Memory(0xf600, 0xf6ff)));
+        assert!(Filter::parse("io:10000").is_err());
+        assert!(Filter::parse("8000").is_err());
+    }
+}
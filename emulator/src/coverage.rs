@@ -0,0 +1,393 @@
+/**
+ * Code coverage
+ *
+ * A `Coverage` records which instructions ran, by physical address, and for conditional jumps, calls and returns how
+ * often each way was taken. Call `record` before each instruction and `finish` when the run ends; each instruction is
+ * counted from the CPU's record of it once it has run.
+ *
+ * The report lists every instruction that ran, named by symbol. The lcov tracefile maps coverage onto the lines of
+ * assembler listings, with a record for each listing giving its lines of code, its conditional branches, and its
+ * functions, which are the symbols at the start of a line of code. Lines of data, from db, dw, ds and the like, aren't
+ * counted. Tools such as genhtml and lcov --summary read the tracefile, so test suites can check coverage with them.
+ *
+ * Known limitations:
+ *  1. Listings are matched by logical address, so code banked in at the same address is counted together
+ *  2. Block instructions that repeat, such as ldir, aren't counted as branches
+ *  3. The source file for a numbered listing is found beside it with the same name; failing that, it's the listing
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use crate::cpu::{Mode, CPU};
+use crate::disasm;
+use crate::listing::Listing;
+use crate::symbols::Symbols;
+
+// Source file extensions to look for beside a listing
+const SOURCE_EXTENSIONS: [&str; 5] = ["asm", "z80", "s", "mac", "a80"];
+
+// Directives that assemble data rather than code
+const DATA_DIRECTIVES: [&str; 16] = [
+    "db", "dw", "dd", "ds", "dm", "dz", "defb", "defw", "defd", "defs", "defm", "byte", "word", "text", "ascii", "incbin",
+];
+
+/// How often an instruction ran, and for a conditional branch, how often it was taken.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct Hits {
+    pub executed: u64,
+    pub taken: u64,
+}
+
+impl Hits {
+    fn merge(&mut self, other: Hits) {
+        self.executed += other.executed;
+        self.taken += other.taken;
+    }
+}
+
+#[derive(Debug, Default, Copy, Clone)]
+struct Site {
+    // the logical address last seen here
+    pc: u16,
+    branch: bool,
+    hits: Hits,
+}
+
+// A listing line's coverage, merged across the listing entries that share it.
+#[derive(Default)]
+struct Line {
+    executed: u64,
+    // taken and not taken counts for each branch, or None where the branch never ran
+    branches: Vec<Option<(u64, u64)>>,
+}
+
+/// Records which instructions a CPU runs.
+#[derive(Default)]
+pub struct Coverage {
+    sites: HashMap<u32, Site>,
+    // whether an instruction was about to run when last recorded
+    running: bool,
+}
+
+impl Coverage {
+    pub fn new() -> Coverage {
+        Default::default()
+    }
+
+    /// Count the last instruction, and note whether another is about to run.
+    pub fn record(&mut self, cpu: &CPU) {
+        self.finish(cpu);
+        self.running = cpu.mode == Mode::OpCodeFetch;
+    }
+
+    /// Count the last instruction, at the end of a run.
+    pub fn finish(&mut self, cpu: &CPU) {
+        let running = std::mem::take(&mut self.running);
+        let retired = match cpu.retired() {
+            Some(retired) if running => retired,
+            _ => return,
+        };
+        let site = self.sites.entry(retired.physical).or_default();
+        site.pc = retired.pc;
+        site.branch = disasm::decode(&retired.bytes).is_conditional();
+        site.hits.executed += 1;
+        if site.branch && retired.taken {
+            site.hits.taken += 1;
+        }
+    }
+
+    /// Read back the coverage a report was written from.
+    pub fn from_report(text: &str) -> Result<Coverage, String> {
+        let mut coverage = Coverage::new();
+        for line in text.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            // the lines of the table start with the physical address
+            let physical = match words.first().and_then(|word| word.strip_prefix('$')) {
+                Some(physical) => physical,
+                None => continue,
+            };
+            let invalid = || format!("invalid coverage report line: {}", line.trim());
+            if words.len() < 5 {
+                return Err(invalid());
+            }
+            let hex = |word: &str| word.strip_prefix('$').and_then(|hex| u32::from_str_radix(hex, 16).ok());
+            let physical = u32::from_str_radix(physical, 16).map_err(|_| invalid())?;
+            let pc = hex(words[1]).filter(|pc| *pc <= 0xffff).ok_or_else(invalid)? as u16;
+            let executed = words[2].parse().map_err(|_| invalid())?;
+            let (branch, taken) = match words[3] {
+                "-" => (false, 0),
+                taken => (true, taken.parse().map_err(|_| invalid())?),
+            };
+            coverage.sites.insert(
+                physical,
+                Site {
+                    pc,
+                    branch,
+                    hits: Hits { executed, taken },
+                },
+            );
+        }
+        Ok(coverage)
+    }
+
+    /// The logical addresses of the instructions that ran, in order.
+    pub fn addresses(&self) -> Vec<u16> {
+        let mut addresses: Vec<u16> = self.by_logical().into_keys().collect();
+        addresses.sort_unstable();
+        addresses
+    }
+
+    /// How often the instruction at a physical address ran.
+    pub fn at(&self, physical: u32) -> Hits {
+        self.sites.get(&physical).map(|site| site.hits).unwrap_or_default()
+    }
+
+    // How often the instructions at each logical address ran, in any bank.
+    fn by_logical(&self) -> HashMap<u16, Hits> {
+        let mut hits: HashMap<u16, Hits> = HashMap::new();
+        for site in self.sites.values() {
+            hits.entry(site.pc).or_default().merge(site.hits);
+        }
+        hits
+    }
+
+    /// Write a report of each instruction run, in address order.
+    pub fn report<W: Write>(&self, symbols: &Symbols, output: &mut W) -> io::Result<()> {
+        let mut sites: Vec<(&u32, &Site)> = self.sites.iter().collect();
+        sites.sort_by_key(|(physical, _)| **physical);
+        let branches: Vec<&Site> = sites.iter().map(|(_, site)| *site).filter(|site| site.branch).collect();
+        let directions: usize = branches
+            .iter()
+            .map(|site| (site.hits.taken > 0) as usize + (site.hits.taken < site.hits.executed) as usize)
+            .sum();
+        writeln!(
+            output,
+            "{} instructions covered, {} of {} branch directions taken",
+            sites.len(),
+            directions,
+            branches.len() * 2
+        )?;
+        writeln!(output)?;
+        writeln!(output, "  physical  logical          runs       taken   not taken  location")?;
+        for (physical, site) in sites {
+            let (taken, not_taken) = if site.branch {
+                (
+                    site.hits.taken.to_string(),
+                    (site.hits.executed - site.hits.taken).to_string(),
+                )
+            } else {
+                ("-".to_string(), "-".to_string())
+            };
+            let line = format!(
+                "    ${:05x}    ${:04x}  {:>12}  {:>10}  {:>10}  {}",
+                physical,
+                site.pc,
+                site.hits.executed,
+                taken,
+                not_taken,
+                symbols.describe(site.pc).unwrap_or_default()
+            );
+            writeln!(output, "{}", line.trim_end())?;
+        }
+        Ok(())
+    }
+
+    /// Write an lcov tracefile, with a record for each listing.
+    pub fn lcov<W: Write>(&self, symbols: &Symbols, listings: &[Listing], output: &mut W) -> io::Result<()> {
+        let hits = self.by_logical();
+        for listing in listings {
+            let mut lines: BTreeMap<u32, Line> = BTreeMap::new();
+            let mut code: HashMap<u16, u32> = HashMap::new();
+            for (number, address, bytes, text) in listing.lines() {
+                if is_data(text) {
+                    continue;
+                }
+                code.entry(address).or_insert(number);
+                let line = lines.entry(number).or_default();
+                let ran = hits.get(&address).copied().unwrap_or_default();
+                // a line is run as often as the most run of its instructions
+                line.executed = line.executed.max(ran.executed);
+                if disasm::decode(bytes).is_conditional() {
+                    line.branches.push(match ran.executed {
+                        0 => None,
+                        executed => Some((ran.taken, executed - ran.taken)),
+                    });
+                }
+            }
+
+            writeln!(output, "TN:")?;
+            writeln!(output, "SF:{}", source_of(listing).display())?;
+            let functions: Vec<(&str, u32, u64)> = symbols
+                .iter()
+                .filter_map(|(name, address)| {
+                    let line = code.get(&address)?;
+                    Some((name, *line, hits.get(&address).map(|hits| hits.executed).unwrap_or(0)))
+                })
+                .collect();
+            for (name, line, _) in &functions {
+                writeln!(output, "FN:{},{}", line, name)?;
+            }
+            for (name, _, executed) in &functions {
+                writeln!(output, "FNDA:{},{}", executed, name)?;
+            }
+            writeln!(output, "FNF:{}", functions.len())?;
+            writeln!(
+                output,
+                "FNH:{}",
+                functions.iter().filter(|(_, _, executed)| *executed > 0).count()
+            )?;
+
+            let (mut found, mut hit) = (0, 0);
+            for (number, line) in &lines {
+                for (block, branch) in line.branches.iter().enumerate() {
+                    let (taken, not_taken) = match branch {
+                        Some((taken, not_taken)) => (taken.to_string(), not_taken.to_string()),
+                        None => ("-".to_string(), "-".to_string()),
+                    };
+                    writeln!(output, "BRDA:{},{},0,{}", number, block, taken)?;
+                    writeln!(output, "BRDA:{},{},1,{}", number, block, not_taken)?;
+                    found += 2;
+                    hit += branch.map_or(0, |(taken, not_taken)| (taken > 0) as usize + (not_taken > 0) as usize);
+                }
+            }
+            writeln!(output, "BRF:{}", found)?;
+            writeln!(output, "BRH:{}", hit)?;
+
+            for (number, line) in &lines {
+                writeln!(output, "DA:{},{}", number, line.executed)?;
+            }
+            writeln!(output, "LF:{}", lines.len())?;
+            writeln!(output, "LH:{}", lines.values().filter(|line| line.executed > 0).count())?;
+            writeln!(output, "end_of_record")?;
+        }
+        Ok(())
+    }
+}
+
+// Answer whether a listing line's source assembles data, with the directive first or after a label.
+fn is_data(text: &str) -> bool {
+    text.split_whitespace().take(2).any(|word| {
+        let word = word.trim_start_matches('.').to_ascii_lowercase();
+        DATA_DIRECTIVES.contains(&word.as_str())
+    })
+}
+
+// The source file a listing was assembled from, where it can be found.
+fn source_of(listing: &Listing) -> PathBuf {
+    let path = listing.path();
+    if listing.is_numbered() {
+        for extension in SOURCE_EXTENSIONS.iter() {
+            let source = path.with_extension(extension);
+            if source.is_file() {
+                return source;
+            }
+        }
+    }
+    path.to_path_buf()
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::Bus;
+    use crate::coverage::*;
+    use crate::ram::RAM;
+
+    #[test]
+    fn covers_lines_and_branches() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(
+            0x0000,
+            &[
+                0x06, 0x02, //          0x0000  ld b, 2
+                0x10, 0xfe, //          0x0002  loop: djnz loop
+                0x3e, 0x01, //          0x0004  ld a, 1
+                0xb7, //                0x0006  or a
+                0x28, 0x01, //          0x0007  jr z, skip
+                0x76, //                0x0009  halt
+                0x00, //                0x000a  skip: nop
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        cpu.reset();
+        let mut coverage = Coverage::new();
+        loop {
+            coverage.record(&cpu);
+            if cpu.mode != Mode::OpCodeFetch {
+                break;
+            }
+            cpu.cycle(&mut bus);
+        }
+        coverage.finish(&cpu);
+
+        assert_eq!(coverage.at(0x0002), Hits { executed: 2, taken: 1 });
+        assert_eq!(coverage.at(0x0007), Hits { executed: 1, taken: 0 });
+        assert_eq!(coverage.at(0x000a), Hits::default());
+
+        let listing = Listing::parse(
+            "test.lst",
+            "\
+ 1    0000 06 02            ld b, 2
+ 2    0002 10 FE        loop: djnz loop
+ 3    0004 3E 01            ld a, 1
+ 4    0006 B7               or a
+ 5    0007 28 01            jr z, skip
+ 6    0009 76               halt
+ 7    000A 00           skip: nop
+ 8    000B 48 49        msg: db \"HI\"
+",
+        );
+        let mut symbols = Symbols::new();
+        symbols.insert("loop", 0x0002);
+        symbols.insert("skip", 0x000a);
+        symbols.insert("msg", 0x000b);
+        let mut lcov = Vec::new();
+        coverage.lcov(&symbols, &[listing], &mut lcov).unwrap();
+        assert_eq!(
+            String::from_utf8(lcov).unwrap(),
+            "\
+TN:
+SF:test.lst
+FN:2,loop
+FN:7,skip
+FNDA:2,loop
+FNDA:0,skip
+FNF:2
+FNH:1
+BRDA:2,0,0,1
+BRDA:2,0,1,1
+BRDA:5,0,0,0
+BRDA:5,0,1,1
+BRF:4
+BRH:3
+DA:1,1
+DA:2,2
+DA:3,1
+DA:4,1
+DA:5,1
+DA:6,1
+DA:7,0
+LF:7
+LH:6
+end_of_record
+"
+        );
+
+        let mut report = Vec::new();
+        coverage.report(&symbols, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("6 instructions covered, 3 of 4 branch directions taken\n"));
+        assert!(report.contains("    $00002    $0002             2           1           1  loop\n"));
+
+        let read = Coverage::from_report(&report).unwrap();
+        assert_eq!(read.at(0x0002), Hits { executed: 2, taken: 1 });
+        assert_eq!(read.at(0x0004), Hits { executed: 1, taken: 0 });
+        assert_eq!(read.addresses(), [0x0000, 0x0002, 0x0004, 0x0006, 0x0007, 0x0009]);
+        assert!(Coverage::from_report("    $00002    $0002  lots").is_err());
+    }
+}
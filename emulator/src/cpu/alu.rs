@@ -155,7 +155,8 @@ mod alu_test {
                 0x86, //               0x000d   add a, (hl)
                 0x06, 0x48, //         0x0010   ld b, 0x48
             ],
-        );
+        )
+        .unwrap();
         bus.add(Rc::new(rom));
         cpu.reset();
 
@@ -215,7 +216,8 @@ mod alu_test {
                 0x8e, //                0x0019  adc a, (hl)
                 0x8f, //                0x0019  adc a, a
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         for _ in 0..8 {
@@ -273,7 +275,8 @@ mod alu_test {
                 0xce, 0x8e, //          0x0015  add a, $8e ; set up 0x0e and carry
                 0xce, 0x01, //          0x0017  add a, $01 ; half-carry set with carry
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
 
@@ -328,7 +331,8 @@ mod alu_test {
                 0x2c, //                0x0016  inc l
                 0x3c, //                0x0017  inc a
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         for _ in 0..8 {
@@ -337,7 +341,7 @@ mod alu_test {
 
         // Hit up (HL) first so H, L don't get changed
         cpu.cycle(&mut bus);
-        assert_eq!(ram.mem_read(0x7fff), Some(0xf3));
+        assert_eq!(ram.mem_read(0x7fff, false), Some(0xf3));
         assert_eq!(cpu.flags(), Flags::SF);
 
         let expected = [
@@ -375,7 +379,8 @@ mod alu_test {
                 0xa7, //                and a
                 0xe6, 0x55, //          and $55
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
 
@@ -385,7 +390,7 @@ mod alu_test {
         cpu.write_reg(Register::E, 0b1000_0000);
         cpu.write_reg(Register::H, 0b0000_0000);
         cpu.write_reg(Register::L, 0b1111_1111);
-        ram.write(0x00ff, &[0b10101010]);
+        ram.write(0x00ff, &[0b10101010]).unwrap();
 
         let expected = [
             ("B", 0b1011_0000, Flags::SF | Flags::HF),
@@ -429,7 +434,8 @@ mod alu_test {
                 0x9e, //                sbc a, (hl)
                 0x9f, //                sbc a, a
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
 
@@ -447,7 +453,7 @@ mod alu_test {
         cpu.write_reg(Register::E, 0x44);
         cpu.write_reg(Register::H, 0xbe);
         cpu.write_reg(Register::L, 0xef);
-        ram.write(0xbeef, &[0xc0]);
+        ram.write(0xbeef, &[0xc0]).unwrap();
 
         let expected = [
             // sub m
@@ -479,7 +485,8 @@ mod alu_test {
             &[
                 0x9f, //                sbc a, a
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
 
@@ -86,7 +86,8 @@ mod alu_test {
                 0x23, //                0x0010  inc hl
                 0x33, //                0x0011  inc sp
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         for _ in 0..3 {
@@ -129,7 +130,8 @@ mod alu_test {
                 0xed, 0x5a, //          adc hl, de
                 0xed, 0x5a, //          adc hl, de
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         for _ in 0..4 {
@@ -176,7 +178,8 @@ mod alu_test {
                 0xed, 0x72, //          sbc hl, sp
                 0xed, 0x72, //          sbc hl, sp
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         for _ in 0..6 {
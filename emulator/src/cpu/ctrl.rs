@@ -7,9 +7,83 @@ use crate::cpu::*;
  * Program Control Instructions
  *
  * Table 45 of Z8018x specification.
+ *
+ * Calls, restarts and interrupts are also tracked on a shadow call stack, for debuggers to show how the CPU got where
+ * it is. Each frame remembers the stack slot holding its return address. Code that manipulates the stack directly is
+ * tolerated: a return pops the frame whose slot it returns through and discards any deeper frames, a return through
+ * a slot no frame owns (a computed jump by PUSH and RET) leaves the stack alone, and a call discards frames whose slots
+ * it reuses.
  */
 
+// Frames beyond this depth are dropped from the bottom, so that code that never returns can't grow the stack forever
+const MAX_CALL_DEPTH: usize = 1024;
+
+/// How a frame on the shadow call stack was entered.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum FrameKind {
+    Call,
+    Restart,
+    Interrupt,
+}
+
+/// A frame on the shadow call stack.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Frame {
+    pub kind: FrameKind,
+    /// Where the frame was entered: the address called, the restart vector, or the interrupt handler.
+    pub entry: u16,
+    /// The return address pushed on entry.
+    pub ret: u16,
+    /// The stack slot holding the return address.
+    pub sp: u16,
+}
+
+impl Frame {
+    /// The address of the instruction that made the call, or for an interrupt the instruction about to execute.
+    pub fn call_site(&self) -> u16 {
+        match self.kind {
+            FrameKind::Call => self.ret.wrapping_sub(3),
+            FrameKind::Restart => self.ret.wrapping_sub(1),
+            FrameKind::Interrupt => self.ret,
+        }
+    }
+}
+
 impl CPU {
+    /// The shadow call stack, outermost frame first. Frames whose stack slots are above SP have been abandoned, and
+    /// are left out.
+    pub fn call_stack(&self) -> Vec<Frame> {
+        self.calls.iter().filter(|frame| frame.sp >= self.sr.sp).copied().collect()
+    }
+
+    // Record entry to a frame, once its return address is pushed.
+    pub(super) fn enter_frame(&mut self, kind: FrameKind, entry: u16) {
+        let sp = self.sr.sp;
+        while self.calls.last().is_some_and(|frame| frame.sp <= sp) {
+            self.calls.pop();
+        }
+        if self.calls.len() == MAX_CALL_DEPTH {
+            self.calls.remove(0);
+        }
+        self.calls.push(Frame {
+            kind,
+            entry,
+            ret: self.sr.pc,
+            sp,
+        });
+    }
+
+    // Record a return through the stack slot at SP, before it's popped.
+    fn leave_frame(&mut self) {
+        let sp = self.sr.sp;
+        while self.calls.last().is_some_and(|frame| frame.sp < sp) {
+            self.calls.pop();
+        }
+        if self.calls.last().is_some_and(|frame| frame.sp == sp) {
+            self.calls.pop();
+        }
+    }
+
     // JP mn
     pub(super) fn jp(&mut self, bus: &mut Bus, src: Operand, condition: Option<Condition>) {
         let dest = self.load_operand(bus, src);
@@ -43,12 +117,14 @@ impl CPU {
             bus.mem_write(self.mmu.to_physical((sp - Wrapping(1)).0), (self.sr.pc >> 8) as u8);
             bus.mem_write(self.mmu.to_physical((sp - Wrapping(2)).0), self.sr.pc as u8);
             self.sr.sp = (sp - Wrapping(2)).0;
+            self.enter_frame(FrameKind::Call, dest);
             self.sr.pc = dest;
         }
     }
 
     pub(super) fn ret(&mut self, bus: &mut Bus, condition: Option<Condition>) {
         if self.is_condition(condition) {
+            self.leave_frame();
             let sp = Wrapping(self.sr.sp);
             let lo = bus.mem_read(self.mmu.to_physical(self.sr.sp), false) as u16;
             let hi = bus.mem_read(self.mmu.to_physical((sp + Wrapping(1)).0), false) as u16;
@@ -64,6 +140,7 @@ impl CPU {
         bus.mem_write(self.mmu.to_physical((sp - Wrapping(1)).0), (self.sr.pc >> 8) as u8);
         bus.mem_write(self.mmu.to_physical((sp - Wrapping(2)).0), self.sr.pc as u8);
         self.sr.sp = (sp - Wrapping(2)).0;
+        self.enter_frame(FrameKind::Restart, vec);
         self.sr.pc = vec;
     }
 
@@ -71,3 +148,72 @@ impl CPU {
         self.mode = Mode::Halt;
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::Bus;
+    use crate::cpu::{FrameKind, Register, CPU};
+    use crate::ram::RAM;
+
+    #[test]
+    fn shadow_call_stack() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(0x0000, &[0xc9]).unwrap(); //    0x0000  ret
+        ram.write(
+            0x0100,
+            &[
+                0x31, 0x00, 0x80, //    0x0100  ld sp, $8000
+                0xcd, 0x00, 0x02, //    0x0103  call $0200
+                0x76, //                0x0106  halt
+            ],
+        )
+        .unwrap();
+        ram.write(
+            0x0200,
+            &[
+                0xc7, //                0x0200  rst 0
+                0x21, 0x10, 0x02, //    0x0201  ld hl, $0210
+                0xe5, //                0x0204  push hl
+                0xc9, //                0x0205  ret
+            ],
+        )
+        .unwrap();
+        ram.write(
+            0x0210,
+            &[
+                0xe1, //                0x0210  pop hl
+                0xe9, //                0x0211  jp (hl)
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        cpu.reset();
+        cpu.write_reg(Register::PC, 0x0100);
+
+        cpu.cycle(&mut bus);
+        cpu.cycle(&mut bus);
+        cpu.cycle(&mut bus);
+        let stack = cpu.call_stack();
+        assert_eq!(stack.len(), 2);
+        assert_eq!(stack[0].kind, FrameKind::Call);
+        assert_eq!(stack[0].entry, 0x0200);
+        assert_eq!(stack[0].call_site(), 0x0103);
+        assert_eq!(stack[1].kind, FrameKind::Restart);
+        assert_eq!(stack[1].call_site(), 0x0200);
+
+        // the restart returns, then a computed jump by push and ret leaves the call in place
+        for _ in 0..4 {
+            cpu.cycle(&mut bus);
+        }
+        assert_eq!(cpu.reg(Register::PC), 0x0210);
+        assert_eq!(cpu.call_stack().len(), 1);
+
+        // the return address popped and jumped through abandons the frame
+        cpu.cycle(&mut bus);
+        assert!(cpu.call_stack().is_empty());
+    }
+}
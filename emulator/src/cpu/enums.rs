@@ -1,5 +1,6 @@
 use std::convert::{From, TryFrom};
 use std::fmt;
+use std::str::FromStr;
 
 #[derive(Debug, Eq, PartialEq, Copy, Clone)]
 #[allow(dead_code)]
@@ -22,6 +23,11 @@ pub enum Register {
     IY,
     SP,
     PC,
+    // the alternate register set
+    AltAF,
+    AltBC,
+    AltDE,
+    AltHL,
 }
 
 impl fmt::Display for Register {
@@ -30,6 +36,39 @@ impl fmt::Display for Register {
     }
 }
 
+impl FromStr for Register {
+    type Err = ();
+
+    // Parse a register name, ignoring case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "A" => Ok(Register::A),
+            "F" => Ok(Register::F),
+            "B" => Ok(Register::B),
+            "C" => Ok(Register::C),
+            "D" => Ok(Register::D),
+            "E" => Ok(Register::E),
+            "H" => Ok(Register::H),
+            "L" => Ok(Register::L),
+            "AF" => Ok(Register::AF),
+            "BC" => Ok(Register::BC),
+            "DE" => Ok(Register::DE),
+            "HL" => Ok(Register::HL),
+            "I" => Ok(Register::I),
+            "R" => Ok(Register::R),
+            "IX" => Ok(Register::IX),
+            "IY" => Ok(Register::IY),
+            "SP" => Ok(Register::SP),
+            "PC" => Ok(Register::PC),
+            "AF'" => Ok(Register::AltAF),
+            "BC'" => Ok(Register::AltBC),
+            "DE'" => Ok(Register::AltDE),
+            "HL'" => Ok(Register::AltHL),
+            _ => Err(()),
+        }
+    }
+}
+
 bitflags! {
     pub struct Flags: u8 {
         const CF = 0b0000_0001;     // carry
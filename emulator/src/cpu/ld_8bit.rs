@@ -41,7 +41,8 @@ mod ld_test {
                 0x36, 0xf2, //          0x000e  ld (hl), $f2
                 0x3e, 0xbe, //          0x0010  ld a, $be
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         cpu.cycle(&mut bus);
@@ -56,7 +57,7 @@ mod ld_test {
         assert_eq!(cpu.reg(Register::BC), 0x1422);
         assert_eq!(cpu.reg(Register::DE), 0x3f4a);
         assert_eq!(cpu.reg(Register::HL), 0x8974);
-        assert_eq!(ram.mem_read(0x8974), Some(0xf2));
+        assert_eq!(ram.mem_read(0x8974, false), Some(0xf2));
     }
 
     #[test]
@@ -75,7 +76,8 @@ mod ld_test {
                 0x1e, 0x4a, //          0x000b  ld e, $4a
                 0x12, //                0x000d  ld (de), a
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
 
@@ -83,11 +85,11 @@ mod ld_test {
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
-        assert_eq!(ram.mem_read(0x7eaf), Some(0xbe));
+        assert_eq!(ram.mem_read(0x7eaf, false), Some(0xbe));
 
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
-        assert_eq!(ram.mem_read(0x3f4a), Some(0xbe));
+        assert_eq!(ram.mem_read(0x3f4a, false), Some(0xbe));
     }
 }
@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::Peripheral;
 
 const CBR: u16 = 0x38;
@@ -39,6 +40,19 @@ impl MMU {
             addr as u32 + ((*self.cbr.borrow() as u32) << 12) as u32
         }
     }
+
+    // The logical addresses that map to a physical address: at most one in each of common area 0, the bank area, and
+    // common area 1.
+    pub fn to_logical(&self, addr: u32) -> impl Iterator<Item = u16> + '_ {
+        let offsets = [0, (*self.bbr.borrow() as u32) << 12, (*self.cbr.borrow() as u32) << 12];
+        (0..3).filter_map(move |area| {
+            let logical = addr.checked_sub(offsets[area])?;
+            if logical > 0xffff || offsets[..area].contains(&offsets[area]) {
+                return None;
+            }
+            Some(logical as u16).filter(|&logical| self.to_physical(logical) == addr)
+        })
+    }
 }
 
 impl Peripheral for MMU {
@@ -59,4 +73,17 @@ impl Peripheral for MMU {
             _ => (),
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(*self.cbr.borrow());
+        state.u8(*self.bbr.borrow());
+        state.u8(*self.cbar.borrow());
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        *self.cbr.borrow_mut() = state.u8()?;
+        *self.bbr.borrow_mut() = state.u8()?;
+        *self.cbar.borrow_mut() = state.u8()?;
+        Ok(())
+    }
 }
@@ -2,6 +2,8 @@ use std::num::Wrapping;
 use std::rc::Rc;
 
 use crate::bus::Bus;
+use crate::disasm;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
 // enums
@@ -23,9 +25,10 @@ mod stack;
 mod dispatch;
 
 // peripherals
-mod mmu;
+pub(crate) mod mmu;
 mod reg;
 
+pub use ctrl::{Frame, FrameKind};
 pub use enums::*;
 
 trait CheckFlags {
@@ -88,6 +91,20 @@ pub enum Mode {
     Reset,
     OpCodeFetch,
     Halt,
+    Break,
+}
+
+/// An instruction the CPU has run, for tracers and profilers to count.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Retired {
+    pub pc: u16,
+    pub physical: u32,
+    /// The instruction's bytes, and those after it to make up four.
+    pub bytes: [u8; 4],
+    /// Whether the instruction left PC anywhere but the instruction after it: a branch taken, or a block instruction
+    /// repeating. An interrupt taken after the instruction doesn't count.
+    pub taken: bool,
+    pub tstates: u32,
 }
 
 // General registers
@@ -122,6 +139,10 @@ pub struct CPU {
     sr: SR,
     ief1: bool,
     ief2: bool,
+    // the shadow call stack
+    calls: Vec<Frame>,
+    // the instruction run by the last cycle, if it ran one
+    retired: Option<Retired>,
 }
 
 impl CPU {
@@ -134,6 +155,7 @@ impl CPU {
     pub fn new(bus: &mut Bus) -> CPU {
         let mmu = Rc::new(mmu::MMU::new());
         bus.add(Rc::clone(&mmu) as Rc<dyn Peripheral>);
+        bus.set_mmu(Rc::clone(&mmu));
         let ivl = Rc::new(reg::Reg::new(0, 0x33));
         bus.add(Rc::clone(&ivl) as Rc<dyn Peripheral>);
         CPU {
@@ -164,6 +186,8 @@ impl CPU {
             },
             ief1: false,
             ief2: false,
+            calls: Vec::new(),
+            retired: None,
         }
     }
 
@@ -172,11 +196,91 @@ impl CPU {
         self.mode = Mode::OpCodeFetch;
         self.sr.pc = 0x0000;
         self.sr.sp = 0x0000;
+        self.calls.clear();
 
         // reset own peripherals
         self.mmu.reset();
     }
 
+    /// Write the registers, interrupt state and shadow call stack into a snapshot. The MMU and other on-chip
+    /// peripherals are on the bus, and saved with it.
+    pub fn save_state(&self, state: &mut StateWriter) {
+        state.u8(match self.mode {
+            Mode::Reset => 0,
+            Mode::OpCodeFetch => 1,
+            Mode::Halt => 2,
+            Mode::Break => 3,
+        });
+        for gr in [&self.gr, &self.gr_] {
+            state.u8(gr.a);
+            state.u8(gr.f);
+            state.u16(gr.bc);
+            state.u16(gr.de);
+            state.u16(gr.hl);
+        }
+        state.u8(self.sr.i);
+        state.u8(self.sr.r);
+        state.u16(self.sr.ix);
+        state.u16(self.sr.iy);
+        state.u16(self.sr.sp);
+        state.u16(self.sr.pc);
+        state.bool(self.ief1);
+        state.bool(self.ief2);
+        state.u32(self.calls.len() as u32);
+        for frame in &self.calls {
+            state.u8(match frame.kind {
+                FrameKind::Call => 0,
+                FrameKind::Restart => 1,
+                FrameKind::Interrupt => 2,
+            });
+            state.u16(frame.entry);
+            state.u16(frame.ret);
+            state.u16(frame.sp);
+        }
+    }
+
+    /// Restore a snapshot written by `save_state`.
+    pub fn load_state(&mut self, state: &mut StateReader) -> Result<(), StateError> {
+        self.mode = match state.u8()? {
+            0 => Mode::Reset,
+            1 => Mode::OpCodeFetch,
+            2 => Mode::Halt,
+            3 => Mode::Break,
+            _ => return Err(StateError::Invalid("CPU mode")),
+        };
+        for gr in [&mut self.gr, &mut self.gr_] {
+            gr.a = state.u8()?;
+            gr.f = state.u8()?;
+            gr.bc = state.u16()?;
+            gr.de = state.u16()?;
+            gr.hl = state.u16()?;
+        }
+        self.sr.i = state.u8()?;
+        self.sr.r = state.u8()?;
+        self.sr.ix = state.u16()?;
+        self.sr.iy = state.u16()?;
+        self.sr.sp = state.u16()?;
+        self.sr.pc = state.u16()?;
+        self.ief1 = state.bool()?;
+        self.ief2 = state.bool()?;
+        self.calls.clear();
+        for _ in 0..state.u32()? {
+            let kind = match state.u8()? {
+                0 => FrameKind::Call,
+                1 => FrameKind::Restart,
+                2 => FrameKind::Interrupt,
+                _ => return Err(StateError::Invalid("call frame")),
+            };
+            self.calls.push(Frame {
+                kind,
+                entry: state.u16()?,
+                ret: state.u16()?,
+                sp: state.u16()?,
+            });
+        }
+        Ok(())
+    }
+
     // Return the CPU flags
     pub fn flags(&self) -> Flags {
         Flags::from_bits_truncate(self.gr.f)
@@ -203,6 +307,10 @@ impl CPU {
             Register::IY => self.sr.iy,
             Register::SP => self.sr.sp,
             Register::PC => self.sr.pc,
+            Register::AltAF => (self.gr_.a as u16) << 8 | self.gr_.f as u16,
+            Register::AltBC => self.gr_.bc,
+            Register::AltDE => self.gr_.de,
+            Register::AltHL => self.gr_.hl,
         }
     }
 
@@ -230,21 +338,54 @@ impl CPU {
             Register::IY => self.sr.iy = v,
             Register::SP => self.sr.sp = v,
             Register::PC => self.sr.pc = v,
+            Register::AltAF => {
+                self.gr_.a = (v >> 8) as u8;
+                self.gr_.f = v as u8;
+            }
+            Register::AltBC => self.gr_.bc = v,
+            Register::AltDE => self.gr_.de = v,
+            Register::AltHL => self.gr_.hl = v,
         }
     }
 
     // Run one machine cycle. This will assert various signals on the bus to do its job.
     pub fn cycle(&mut self, bus: &mut Bus) {
         let mut intr = bus.cycle();
+        let pc = self.sr.pc;
+        self.retired = None;
 
         // Run the next machine cycle before checking the interrupt
         match self.mode {
             Mode::Reset => (),
             Mode::OpCodeFetch => {
+                // before it runs, as it may change the MMU or itself
+                let physical = self.mmu.to_physical(pc);
+                let bytes = self.current_opcodes(bus);
                 self.sr.r = (Wrapping(self.sr.r) + Wrapping(1)).0;
                 self.dispatch(bus);
+                let taken = self.sr.pc != pc.wrapping_add(disasm::length(&bytes) as u16);
+                let tstates = disasm::tstates(&bytes, taken);
+                self.retired = Some(Retired {
+                    pc,
+                    physical,
+                    bytes,
+                    taken,
+                    tstates,
+                });
+                // the bus cycle at the top counted one of the instruction's T-states
+                bus.advance(u64::from(tstates.saturating_sub(1)));
             }
             Mode::Halt => (),
+            Mode::Break => (),
+        }
+
+        for fault in bus.take_faults() {
+            if fault.halt {
+                self.mode = Mode::Break;
+                println!("Break: {} (PC=${:04x})", fault.message, pc);
+            } else {
+                println!("Warning: {} (PC=${:04x})", fault.message, pc);
+            }
         }
 
         if self.ief1 == false {
@@ -265,6 +406,7 @@ impl CPU {
                 // disable interrupts
                 self.ief1 = false;
                 self.ief2 = false;
+                self.enter_frame(FrameKind::Interrupt, handler);
                 // set pc to addr
                 self.sr.pc = handler;
                 bus.intack(Interrupt::ASCI0);
@@ -280,6 +422,9 @@ impl CPU {
     fn error(&mut self, cause: &str) {
         self.mode = Mode::Halt;
         println!("Illegal instruction (PC=${:04x}). Halt. {}", self.sr.pc, cause);
+        for frame in self.call_stack().iter().rev() {
+            println!("  in ${:04x}, entered from ${:04x}", frame.entry, frame.call_site());
+        }
     }
 
     // print a warning
@@ -287,17 +432,29 @@ impl CPU {
         println!("Warning: {} (PC=${:04x})", cause, self.sr.pc);
     }
 
-    pub fn get_current_opcodes(&mut self, bus: &mut Bus, opcodes: &mut [u8; 4]) {
-        opcodes[0] = bus.mem_read(self.mmu.to_physical(self.sr.pc), false);
-        opcodes[1] = bus.mem_read(self.mmu.to_physical(self.sr.pc + 1), false);
-        opcodes[2] = bus.mem_read(self.mmu.to_physical(self.sr.pc + 2), false);
-        opcodes[3] = bus.mem_read(self.mmu.to_physical(self.sr.pc + 3), false);
+    // The bytes of the instruction about to execute, and those after it to make up four.
+    pub fn current_opcodes(&self, bus: &Bus) -> [u8; 4] {
+        let mut opcodes = [0; 4];
+        for (offset, byte) in opcodes.iter_mut().enumerate() {
+            *byte = bus.peek(self.mmu.to_physical(self.sr.pc.wrapping_add(offset as u16)));
+        }
+        opcodes
+    }
+
+    /// The instruction the last cycle ran, or none if it didn't run one.
+    pub fn retired(&self) -> Option<&Retired> {
+        self.retired.as_ref()
     }
 
     pub fn get_cpu_mode(&self) -> Mode {
         return self.mode;
     }
 
+    // Translate a logical address to a physical address through the MMU.
+    pub fn to_physical(&self, address: u16) -> u32 {
+        self.mmu.to_physical(address)
+    }
+
     // Load an operand using an addressing mode.
     fn load_operand(&mut self, bus: &mut Bus, operand: Operand) -> u16 {
         match operand {
@@ -1,5 +1,6 @@
 use std::cell::RefCell;
 
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
 pub struct Reg {
@@ -34,4 +35,13 @@ impl Peripheral for Reg {
             *self.val.borrow_mut() = data;
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(*self.val.borrow());
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        *self.val.borrow_mut() = state.u8()?;
+        Ok(())
+    }
 }
@@ -125,7 +125,8 @@ mod test {
                 0x07, //                rlca
                 0x07, //                rlca
             ],
-        );
+        )
+        .unwrap();
         bus.add(ram.clone());
         cpu.reset();
         cpu.cycle(&mut bus);
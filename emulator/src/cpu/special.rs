@@ -73,7 +73,7 @@ mod alu_test {
         let mut bus = Bus::new();
         let mut cpu = CPU::new(&mut bus);
         let ram = Rc::new(RAM::new(0x0000, 0x10000));
-        ram.write(0x0000, &[0x00]);
+        ram.write(0x0000, &z180asm::asm!("nop")).unwrap();
         bus.add(ram);
         cpu.reset();
 
@@ -87,13 +87,19 @@ mod alu_test {
         let mut bus = Bus::new();
         let mut cpu = CPU::new(&mut bus);
         let ram = Rc::new(RAM::new(0x0000, 0x10000));
-        ram.write(0x0000, &[0xaf, 0x3d, 0x27]);
+        let program = z180asm::asm!(
+            "
+            xor a
+            dec a
+            daa"
+        );
+        ram.write(0x0000, &program).unwrap();
         bus.add(ram);
         cpu.reset();
 
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
         cpu.cycle(&mut bus);
-        assert_eq!(cpu.reg(Register::A), 0xf9);
+        assert_eq!(cpu.reg(Register::A), 0x99);
     }
 }
@@ -0,0 +1,872 @@
+/**
+ * Debug Adapter Protocol server
+ *
+ * Serves one DAP client, such as VS Code, over any pair of streams: stdio when the client launches the adapter, or a
+ * TCP connection for a client configured with a debug server port. The `launch` request describes the machine, and
+ * the server builds it from a `board::Config`:
+ *
+ *   rom          path to the ROM image (required)
+ *   flash        flash part to model for the ROM
+ *   nvram        list of START:SIZE:FILE battery-backed regions
+ *   ramSeed      fill RAM with seeded random contents
+ *   uninit       "warn" or "break" on reads of unwritten RAM
+ *   rtc          DS1302 time source
+ *   tty          device to tie ASCI0 to
+ *   listings     assembler listing files, for breakpoints on source lines
+ *   symbols      symbol files, as read by `symbols::Symbols`; listings are read for their labels too
+ *   state        machine state file to start from, saved by a board built the same way
+ *   stopOnEntry  stop before the first instruction
+ *   record       keep a history for stepping backwards, true unless set false
+ *
+ * Stepping is by source line when there are listings, unless the client asks for instruction granularity, and by
+ * instruction otherwise. Stepping back is always by instruction; reverse continue runs back to the last breakpoint or
+ * watchpoint a run forwards would have stopped at.
+ *
+ * Breakpoints may be set on source lines of files with a listing, on instruction addresses from the disassembly view,
+ * or as function breakpoints naming a symbol or an address. Any of them may have a condition, and source breakpoints
+ * may be log points, written in the `expr` language. Evaluate requests use the same language. The stack trace comes
+ * from the CPU's shadow call stack, with a frame for each call, restart or interrupt at the instruction it was made
+ * from, named by the nearest symbol. Registers are presented as variables, and memory references are logical
+ * addresses or symbols.
+ *
+ * Known limitations:
+ *  1. In stdio mode, anything the emulator prints goes to stdout and will confuse the client; use TCP if the machine
+ *     is configured to warn about anything
+ *  2. Disassembly before an address guesses where instructions start
+ *  3. Requests other than pause and disconnect wait until a running program stops
+ */
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::io::{BufRead, BufReader, Error, Read, Write};
+use std::path::Path;
+use std::rc::Rc;
+use std::sync::atomic::Ordering;
+use std::sync::mpsc::{channel, Receiver};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::board::{Board, Config};
+use crate::bus::Bus;
+use crate::cpu::{Register, CPU};
+use crate::debugger::{Breakpoint, Debugger, Stop};
+use crate::disasm;
+use crate::expr::{Context, Expr, Template};
+use crate::history::History;
+use crate::listing::Listing;
+use crate::ram::UninitPolicy;
+use crate::rom::Chip;
+use crate::rtc::TimeSource;
+use crate::symbols::{parse_number, Symbols};
+
+// There's one CPU, so one thread
+const THREAD: u64 = 1;
+
+// Variables references for the register scope and the flags within it
+const REGISTERS: u64 = 1;
+const FLAGS: u64 = 2;
+
+// Registers shown as variables, with their width in bytes
+const REGISTER_VARIABLES: [(&str, Register, usize); 15] = [
+    ("A", Register::A, 1),
+    ("F", Register::F, 1),
+    ("BC", Register::BC, 2),
+    ("DE", Register::DE, 2),
+    ("HL", Register::HL, 2),
+    ("IX", Register::IX, 2),
+    ("IY", Register::IY, 2),
+    ("SP", Register::SP, 2),
+    ("PC", Register::PC, 2),
+    ("I", Register::I, 1),
+    ("R", Register::R, 1),
+    ("AF'", Register::AltAF, 2),
+    ("BC'", Register::AltBC, 2),
+    ("DE'", Register::AltDE, 2),
+    ("HL'", Register::AltHL, 2),
+];
+
+const FLAG_VARIABLES: [(&str, u8); 6] = [("S", 0x80), ("Z", 0x40), ("H", 0x10), ("P/V", 0x04), ("N", 0x02), ("C", 0x01)];
+
+// Instructions to run between passing on log point messages
+const POLL_STEPS: u64 = 10_000;
+
+const BASE64: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::new();
+    for chunk in data.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(BASE64[(n >> (18 - 6 * i)) as usize & 0x3f] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+fn base64_decode(text: &str) -> Option<Vec<u8>> {
+    let digits: Vec<u32> = text
+        .trim_end_matches('=')
+        .bytes()
+        .map(|c| BASE64.iter().position(|&d| d == c).map(|d| d as u32))
+        .collect::<Option<_>>()?;
+    let mut out = Vec::new();
+    for chunk in digits.chunks(4) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &d)| n | d << (18 - 6 * i));
+        for i in 0..chunk.len().saturating_sub(1) {
+            out.push((n >> (16 - 8 * i)) as u8);
+        }
+    }
+    Some(out)
+}
+
+fn format_value(value: u16, width: usize) -> String {
+    if width == 1 {
+        format!("${:02x}", value)
+    } else {
+        format!("${:04x}", value)
+    }
+}
+
+fn reference(address: u16) -> String {
+    format!("0x{:04x}", address)
+}
+
+// Parse a memory or instruction reference, which is a number or a symbol, plus an optional offset into a logical
+// address.
+fn resolve_reference(args: &Value, key: &str, symbols: &Symbols) -> Result<u16, String> {
+    let reference = args[key].as_str().ok_or_else(|| format!("missing {}", key))?;
+    let base = parse_number(reference.trim(), 10)
+        .or_else(|| symbols.lookup(reference.trim()).map(u32::from))
+        .ok_or_else(|| format!("invalid reference {}", reference))?;
+    let offset = args["offset"].as_i64().unwrap_or(0);
+    Ok((base as i64 + offset) as u16)
+}
+
+fn stop_reason(stop: Stop) -> (&'static str, Option<&'static str>) {
+    match stop {
+        Stop::Step => ("step", None),
+        Stop::Breakpoint(_) => ("breakpoint", None),
+        Stop::Watchpoint(_) => ("data breakpoint", None),
+        Stop::Halt => ("exception", Some("CPU halted")),
+        Stop::Break => ("exception", Some("Execution stopped by a fault")),
+        Stop::Interrupted => ("pause", None),
+        Stop::Beginning => ("step", Some("Reached the oldest recorded state")),
+    }
+}
+
+// Build a board configuration from launch request arguments.
+fn launch_config(args: &Value) -> Result<Config, String> {
+    let rom = args["rom"].as_str().ok_or("launch needs a rom")?;
+    let mut config = Config::new(rom);
+    if let Some(flash) = args["flash"].as_str() {
+        config.flash = Some(Chip::from_name(flash).ok_or_else(|| format!("unknown flash part {}", flash))?);
+    }
+    if let Some(nvram) = args["nvram"].as_array() {
+        config.nvram = nvram.iter().filter_map(Value::as_str).map(String::from).collect();
+    }
+    config.ram_seed = args["ramSeed"].as_u64();
+    config.uninit = match args["uninit"].as_str() {
+        Some("warn") => UninitPolicy::Warn,
+        Some("break") => UninitPolicy::Break,
+        Some(other) => return Err(format!("unknown uninit action {}", other)),
+        None => UninitPolicy::Ignore,
+    };
+    if let Some(rtc) = args["rtc"].as_str() {
+        config.rtc = Some(TimeSource::from_name(rtc).ok_or_else(|| format!("invalid RTC source {}", rtc))?);
+    }
+    config.tty = args["tty"].as_str().map(String::from);
+    Ok(config)
+}
+
+// Build a breakpoint from a client's condition and log message.
+fn client_breakpoint(bp: &Value, symbols: &Symbols) -> Result<Breakpoint, String> {
+    let mut breakpoint = Breakpoint::default();
+    if let Some(condition) = bp["condition"].as_str().filter(|c| !c.trim().is_empty()) {
+        breakpoint.condition = Some(Expr::parse_with(condition, symbols)?);
+    }
+    if let Some(message) = bp["logMessage"].as_str() {
+        breakpoint.message = Some(Template::parse_with(message, symbols)?);
+    }
+    Ok(breakpoint)
+}
+
+// Read one Content-Length framed message, answering None when the stream ends or the message is malformed.
+fn read_message<R: BufRead>(input: &mut R) -> Option<Value> {
+    let mut length = None;
+    loop {
+        let mut header = String::new();
+        if input.read_line(&mut header).ok()? == 0 {
+            return None;
+        }
+        let header = header.trim();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+    let mut body = vec![0; length?];
+    input.read_exact(&mut body).ok()?;
+    serde_json::from_slice(&body).ok()
+}
+
+pub struct DapServer<W: Write> {
+    output: W,
+    requests: Receiver<Value>,
+    seq: u64,
+    debugger: Debugger,
+    machine: Option<(CPU, Bus)>,
+    stop_on_entry: bool,
+    // breakpoints for each source file, and those set by instruction and by function, which the client sets separately
+    source_breakpoints: HashMap<String, BTreeMap<u16, Breakpoint>>,
+    instruction_breakpoints: BTreeMap<u16, Breakpoint>,
+    function_breakpoints: BTreeMap<u16, Breakpoint>,
+    // log point messages waiting to go to the client
+    log: Rc<RefCell<Vec<String>>>,
+}
+
+impl<W: Write> DapServer<W> {
+    /// Serve requests read from `input`, writing responses and events to `output`. Requests are read on their own
+    /// thread, so that a pause can interrupt a running program.
+    pub fn new<R: Read + Send + 'static>(input: R, output: W) -> DapServer<W> {
+        let mut debugger = Debugger::new();
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let sink = log.clone();
+        debugger.set_trace_output(move |message| sink.borrow_mut().push(message.to_string()));
+        let interrupt = debugger.interrupt_handle();
+        let (sender, requests) = channel();
+        thread::spawn(move || {
+            let mut input = BufReader::new(input);
+            while let Some(request) = read_message(&mut input) {
+                if ["pause", "disconnect", "terminate"].contains(&request["command"].as_str().unwrap_or("")) {
+                    interrupt.store(true, Ordering::SeqCst);
+                }
+                if sender.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+        DapServer {
+            output,
+            requests,
+            seq: 0,
+            debugger,
+            machine: None,
+            stop_on_entry: false,
+            source_breakpoints: HashMap::new(),
+            instruction_breakpoints: BTreeMap::new(),
+            function_breakpoints: BTreeMap::new(),
+            log,
+        }
+    }
+
+    fn send(&mut self, mut message: Value) -> Result<(), Error> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(self.output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        self.output.flush()
+    }
+
+    fn respond(&mut self, request: &Value, result: Result<Value, String>) -> Result<(), Error> {
+        let mut response = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": request["command"],
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(body) => response["body"] = body,
+            Err(message) => response["message"] = json!(message),
+        }
+        self.send(response)
+    }
+
+    fn event(&mut self, event: &str, body: Value) -> Result<(), Error> {
+        self.send(json!({ "type": "event", "event": event, "body": body }))
+    }
+
+    fn flush_log(&mut self) -> Result<(), Error> {
+        let messages = self.log.replace(Vec::new());
+        for message in messages {
+            self.event("output", json!({ "category": "console", "output": format!("{}\n", message) }))?;
+        }
+        Ok(())
+    }
+
+    fn stopped(&mut self, reason: &str, text: Option<&str>) -> Result<(), Error> {
+        let mut body = json!({ "reason": reason, "threadId": THREAD, "allThreadsStopped": true });
+        if let Some(text) = text {
+            body["description"] = json!(text);
+            body["text"] = json!(text);
+        }
+        self.event("stopped", body)
+    }
+
+    fn machine(&self) -> Result<(&CPU, &Bus), String> {
+        self.machine
+            .as_ref()
+            .map(|(cpu, bus)| (cpu, bus))
+            .ok_or_else(|| "no program has been launched".to_string())
+    }
+
+    // Bring the debugger's breakpoints into line with those the client has set.
+    fn sync_breakpoints(&mut self) {
+        let wanted: BTreeMap<u16, Breakpoint> = self
+            .source_breakpoints
+            .values()
+            .flatten()
+            .chain(self.instruction_breakpoints.iter())
+            .chain(self.function_breakpoints.iter())
+            .map(|(&address, breakpoint)| (address, breakpoint.clone()))
+            .collect();
+        let existing: Vec<u16> = self.debugger.breakpoints().collect();
+        for address in existing.iter().filter(|address| !wanted.contains_key(address)) {
+            self.debugger.remove_breakpoint(*address);
+        }
+        for (address, mut breakpoint) in wanted {
+            // keep counting hits across changes
+            breakpoint.hits = self.debugger.breakpoint(address).map_or(0, |old| old.hits);
+            self.debugger.set_breakpoint(address, breakpoint);
+        }
+    }
+
+    fn launch(&mut self, args: &Value) -> Result<Value, String> {
+        let config = launch_config(args)?;
+        let listings = args["listings"].as_array().cloned().unwrap_or_default();
+        for path in listings.iter().filter_map(Value::as_str) {
+            let listing = Listing::load(path).map_err(|e| format!("{}: {}", path, e))?;
+            self.debugger.add_listing(listing);
+        }
+        let symbols = args["symbols"].as_array().cloned().unwrap_or_default();
+        for path in listings.iter().chain(symbols.iter()).filter_map(Value::as_str) {
+            self.debugger
+                .symbols_mut()
+                .load(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        let (mut cpu, mut bus) = config.build().map_err(|e| format!("{}: {}", config.rom.display(), e))?;
+        if let Some(path) = args["state"].as_str() {
+            Board::new(&mut cpu, &mut bus)
+                .load_state(path)
+                .map_err(|e| format!("{}: {}", path, e))?;
+        }
+        if args["record"].as_bool().unwrap_or(true) {
+            self.debugger.record(&bus, History::default());
+        }
+        self.machine = Some((cpu, bus));
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(json!({}))
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Result<Value, String> {
+        let path = args["source"]["path"].as_str().ok_or("breakpoints need a source path")?;
+        let mut addresses = BTreeMap::new();
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            let line = bp["line"].as_u64().unwrap_or(0);
+            let found = self
+                .debugger
+                .listings()
+                .iter()
+                .find(|listing| listing.covers(Path::new(path)))
+                .and_then(|listing| listing.address_of_line(line as u32));
+            breakpoints.push(match (found, client_breakpoint(&bp, self.debugger.symbols())) {
+                (Some((line, address)), Ok(breakpoint)) => {
+                    addresses.insert(address, breakpoint);
+                    json!({ "verified": true, "line": line, "instructionReference": reference(address) })
+                }
+                (None, _) => json!({ "verified": false, "line": line, "message": "no code at this line" }),
+                (_, Err(message)) => json!({ "verified": false, "line": line, "message": message }),
+            });
+        }
+        self.source_breakpoints.insert(path.to_string(), addresses);
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    // Instruction and function breakpoints both name addresses, in the `instructionReference` or `name` key; each
+    // request replaces only the breakpoints of its own kind.
+    fn set_address_breakpoints(&mut self, args: &Value, key: &str) -> Result<Value, String> {
+        let mut addresses = BTreeMap::new();
+        let mut breakpoints = Vec::new();
+        for bp in args["breakpoints"].as_array().cloned().unwrap_or_default() {
+            breakpoints.push(
+                match resolve_reference(&bp, key, self.debugger.symbols())
+                    .and_then(|a| Ok((a, client_breakpoint(&bp, self.debugger.symbols())?)))
+                {
+                    Ok((address, breakpoint)) => {
+                        addresses.insert(address, breakpoint);
+                        json!({ "verified": true, "instructionReference": reference(address) })
+                    }
+                    Err(message) => json!({ "verified": false, "message": message }),
+                },
+            );
+        }
+        if key == "name" {
+            self.function_breakpoints = addresses;
+        } else {
+            self.instruction_breakpoints = addresses;
+        }
+        self.sync_breakpoints();
+        Ok(json!({ "breakpoints": breakpoints }))
+    }
+
+    fn stack_frame(&self, id: usize, address: u16) -> Value {
+        let mut frame = json!({
+            "id": id,
+            "name": self.debugger.symbols().describe(address).unwrap_or_else(|| format!("${:04x}", address)),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": reference(address),
+        });
+        if let Some((listing, line)) = self.debugger.source_line(address) {
+            frame["source"] = json!({ "path": listing.path() });
+            frame["line"] = json!(line);
+            frame["column"] = json!(1);
+        }
+        frame
+    }
+
+    fn stack_trace(&self, args: &Value) -> Result<Value, String> {
+        let (cpu, _) = self.machine()?;
+        let mut frames = vec![self.stack_frame(0, cpu.reg(Register::PC))];
+        for frame in cpu.call_stack().iter().rev() {
+            frames.push(self.stack_frame(frames.len(), frame.call_site()));
+        }
+        let total = frames.len();
+        let start = args["startFrame"].as_u64().unwrap_or(0) as usize;
+        let levels = match args["levels"].as_u64() {
+            Some(0) | None => total,
+            Some(levels) => levels as usize,
+        };
+        let frames: Vec<Value> = frames.into_iter().skip(start).take(levels).collect();
+        Ok(json!({ "stackFrames": frames, "totalFrames": total }))
+    }
+
+    fn variables(&self, args: &Value) -> Result<Value, String> {
+        let (cpu, _) = self.machine()?;
+        let variables: Vec<Value> = match args["variablesReference"].as_u64() {
+            Some(REGISTERS) => REGISTER_VARIABLES
+                .iter()
+                .map(|&(name, reg, width)| {
+                    let value = cpu.reg(reg);
+                    let mut variable = json!({
+                        "name": name,
+                        "value": format_value(value, width),
+                        "variablesReference": if reg == Register::F { FLAGS } else { 0 },
+                    });
+                    if width == 2 {
+                        variable["memoryReference"] = json!(reference(value));
+                    }
+                    variable
+                })
+                .collect(),
+            Some(FLAGS) => FLAG_VARIABLES
+                .iter()
+                .map(|&(name, mask)| {
+                    let set = cpu.reg(Register::F) as u8 & mask != 0;
+                    json!({ "name": name, "value": if set { "1" } else { "0" }, "variablesReference": 0 })
+                })
+                .collect(),
+            _ => return Err("unknown variables reference".to_string()),
+        };
+        Ok(json!({ "variables": variables }))
+    }
+
+    fn set_variable(&mut self, args: &Value) -> Result<Value, String> {
+        self.machine()?;
+        let (cpu, _) = self.machine.as_mut().unwrap();
+        let name = args["name"].as_str().ok_or("missing name")?;
+        let text = args["value"].as_str().ok_or("missing value")?;
+        let value = parse_number(text.trim(), 10).ok_or_else(|| format!("invalid value {}", text))?;
+        match args["variablesReference"].as_u64() {
+            Some(REGISTERS) => {
+                let &(_, reg, width) = REGISTER_VARIABLES
+                    .iter()
+                    .find(|(n, _, _)| *n == name)
+                    .ok_or_else(|| format!("unknown register {}", name))?;
+                if value >= 1 << (width * 8) {
+                    return Err(format!("{} is out of range", text));
+                }
+                cpu.write_reg(reg, value as u16);
+                Ok(json!({ "value": format_value(value as u16, width) }))
+            }
+            Some(FLAGS) => {
+                let &(_, mask) = FLAG_VARIABLES
+                    .iter()
+                    .find(|(n, _)| *n == name)
+                    .ok_or_else(|| format!("unknown flag {}", name))?;
+                let flags = cpu.reg(Register::F) as u8 & !mask;
+                let flags = if value != 0 { flags | mask } else { flags };
+                cpu.write_reg(Register::F, flags as u16);
+                Ok(json!({ "value": if value != 0 { "1" } else { "0" } }))
+            }
+            _ => Err("unknown variables reference".to_string()),
+        }
+    }
+
+    // Evaluate an expression. Registers show as they do in the variables view, other values in hex and decimal.
+    fn evaluate(&self, args: &Value) -> Result<Value, String> {
+        let (cpu, bus) = self.machine()?;
+        let expression = args["expression"].as_str().ok_or("missing expression")?;
+        let value = Expr::parse_with(expression, self.debugger.symbols())?.eval(&Context::new(cpu, bus));
+        let register = REGISTER_VARIABLES
+            .iter()
+            .find(|(name, _, _)| name.eq_ignore_ascii_case(expression.trim()));
+        let text = match register {
+            Some(&(_, _, width)) => format_value(value as u16, width),
+            None => format!("${:x} ({})", value, value),
+        };
+        let mut result = json!({ "result": text, "variablesReference": 0 });
+        if (0..=0xffff).contains(&value) {
+            result["memoryReference"] = json!(reference(value as u16));
+        }
+        Ok(result)
+    }
+
+    fn read_memory(&self, args: &Value) -> Result<Value, String> {
+        let (cpu, bus) = self.machine()?;
+        let address = resolve_reference(args, "memoryReference", self.debugger.symbols())?;
+        let count = args["count"].as_u64().unwrap_or(0).min(0x10000) as u16;
+        let data: Vec<u8> = (0..count)
+            .map(|i| self.debugger.read_byte(cpu, bus, address.wrapping_add(i)))
+            .collect();
+        Ok(json!({ "address": reference(address), "data": base64_encode(&data) }))
+    }
+
+    fn write_memory(&mut self, args: &Value) -> Result<Value, String> {
+        self.machine()?;
+        let (cpu, bus) = self.machine.as_mut().unwrap();
+        let address = resolve_reference(args, "memoryReference", self.debugger.symbols())?;
+        let data = args["data"].as_str().and_then(base64_decode).ok_or("invalid memory data")?;
+        for (i, &byte) in data.iter().enumerate() {
+            bus.mem_write(cpu.to_physical(address.wrapping_add(i as u16)), byte);
+        }
+        Ok(json!({ "bytesWritten": data.len() }))
+    }
+
+    fn instruction_length(&self, cpu: &CPU, bus: &Bus, address: u16) -> u16 {
+        let opcodes: Vec<u8> = (0..4)
+            .map(|i| self.debugger.read_byte(cpu, bus, address.wrapping_add(i)))
+            .collect();
+        disasm::decode(&opcodes).length as u16
+    }
+
+    // Guess where the instruction before an address starts: the furthest back one that ends at the address.
+    fn previous_instruction(&self, cpu: &CPU, bus: &Bus, address: u16) -> u16 {
+        (1..=4)
+            .rev()
+            .map(|len| address.wrapping_sub(len))
+            .find(|&start| start.wrapping_add(self.instruction_length(cpu, bus, start)) == address)
+            .unwrap_or_else(|| address.wrapping_sub(1))
+    }
+
+    fn disassemble(&self, args: &Value) -> Result<Value, String> {
+        let (cpu, bus) = self.machine()?;
+        let mut address = resolve_reference(args, "memoryReference", self.debugger.symbols())?;
+        let offset = args["instructionOffset"].as_i64().unwrap_or(0);
+        let count = args["instructionCount"].as_u64().unwrap_or(0);
+        for _ in offset..0 {
+            address = self.previous_instruction(cpu, bus, address);
+        }
+        for _ in 0..offset {
+            address = address.wrapping_add(self.instruction_length(cpu, bus, address));
+        }
+        let mut instructions = Vec::new();
+        for _ in 0..count {
+            let opcodes: Vec<u8> = (0..4)
+                .map(|i| self.debugger.read_byte(cpu, bus, address.wrapping_add(i)))
+                .collect();
+            let decoded = disasm::decode(&opcodes);
+            let len = decoded.length;
+            let bytes: Vec<String> = opcodes[..len].iter().map(|b| format!("{:02x}", b)).collect();
+            let mut instruction = json!({
+                "address": reference(address),
+                "instructionBytes": bytes.join(" "),
+                "instruction": decoded.text(address, self.debugger.symbols()),
+            });
+            if let Some(name) = self.debugger.symbols().name_of(address) {
+                instruction["symbol"] = json!(name);
+            }
+            if let Some((listing, line)) = self.debugger.source_line(address) {
+                instruction["location"] = json!({ "path": listing.path() });
+                instruction["line"] = json!(line);
+            }
+            instructions.push(instruction);
+            address = address.wrapping_add(len as u16);
+        }
+        Ok(json!({ "instructions": instructions }))
+    }
+
+    // Run the machine as a step or continue request asks, stepping by source line or by instruction, then tell the
+    // client why it stopped.
+    fn resume(&mut self, command: &str, by_line: bool) -> Result<(), Error> {
+        // the machine is taken while it runs, so that log messages can be sent between runs
+        let (mut cpu, mut bus) = match self.machine.take() {
+            Some(machine) => machine,
+            None => return Ok(()),
+        };
+        self.debugger.clear_interrupt();
+        let stop = match command {
+            "next" if by_line => Ok(self.debugger.next_line(&mut cpu, &mut bus)),
+            "next" => Ok(self.debugger.next(&mut cpu, &mut bus)),
+            "stepIn" if by_line => Ok(self.debugger.step_line(&mut cpu, &mut bus)),
+            "stepIn" => Ok(self.debugger.step(&mut cpu, &mut bus)),
+            "stepOut" => Ok(self.debugger.finish(&mut cpu, &mut bus)),
+            "stepBack" => Ok(self.debugger.step_back(&mut cpu, &mut bus)),
+            "reverseContinue" => Ok(self.debugger.reverse_run(&mut cpu, &mut bus)),
+            _ => loop {
+                let stop = self.debugger.run_for(&mut cpu, &mut bus, POLL_STEPS);
+                if stop != Stop::Step {
+                    break Ok(stop);
+                }
+                if let Err(e) = self.flush_log() {
+                    break Err(e);
+                }
+            },
+        };
+        self.machine = Some((cpu, bus));
+        let stop = stop?;
+        self.flush_log()?;
+        let (reason, text) = stop_reason(stop);
+        self.stopped(reason, text)
+    }
+
+    // Handle one request, answering false when the session is over.
+    fn handle(&mut self, request: &Value) -> Result<bool, Error> {
+        let command = request["command"].as_str().unwrap_or("");
+        let args = &request["arguments"];
+        let result = match command {
+            "initialize" => Ok(json!({
+                "supportsConfigurationDoneRequest": true,
+                "supportsFunctionBreakpoints": true,
+                "supportsConditionalBreakpoints": true,
+                "supportsLogPoints": true,
+                "supportsInstructionBreakpoints": true,
+                "supportsSetVariable": true,
+                "supportsEvaluateForHovers": true,
+                "supportsReadMemoryRequest": true,
+                "supportsWriteMemoryRequest": true,
+                "supportsDisassembleRequest": true,
+                "supportsTerminateRequest": true,
+                "supportsSteppingGranularity": true,
+                "supportsStepBack": true,
+            })),
+            "launch" => self.launch(args),
+            "setBreakpoints" => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_address_breakpoints(args, "instructionReference"),
+            "setFunctionBreakpoints" => self.set_address_breakpoints(args, "name"),
+            "setExceptionBreakpoints" | "configurationDone" | "disconnect" | "terminate" => Ok(json!({})),
+            "threads" => Ok(json!({ "threads": [{ "id": THREAD, "name": "Z180" }] })),
+            "stackTrace" => self.stack_trace(args),
+            "scopes" => Ok(json!({
+                "scopes": [{ "name": "Registers", "presentationHint": "registers", "variablesReference": REGISTERS }]
+            })),
+            "variables" => self.variables(args),
+            "setVariable" => self.set_variable(args),
+            "evaluate" => self.evaluate(args),
+            "readMemory" => self.read_memory(args),
+            "writeMemory" => self.write_memory(args),
+            "disassemble" => self.disassemble(args),
+            "continue" => self.machine().map(|_| json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "stepBack" | "reverseContinue" | "pause" => self.machine().map(|_| json!({})),
+            _ => Err(format!("unsupported request {}", command)),
+        };
+        let success = result.is_ok();
+        self.respond(request, result)?;
+        if !success {
+            return Ok(true);
+        }
+
+        match command {
+            // configuration requests follow the initialized event, so send it once there's a machine to configure
+            "launch" => self.event("initialized", json!({}))?,
+            "configurationDone" if self.stop_on_entry => self.stopped("entry", None)?,
+            "configurationDone" => self.resume("continue", false)?,
+            "continue" | "next" | "stepIn" | "stepOut" => {
+                // step by line where there are listings, unless the client asks for instructions
+                let by_line = args["granularity"] != "instruction" && !self.debugger.listings().is_empty();
+                self.resume(command, by_line)?
+            }
+            "stepBack" | "reverseContinue" => self.resume(command, false)?,
+            "terminate" => self.event("terminated", json!({}))?,
+            "disconnect" => return Ok(false),
+            _ => (),
+        }
+        Ok(true)
+    }
+
+    /// Handle requests until the client disconnects or the input ends.
+    pub fn serve(&mut self) -> Result<(), Error> {
+        while let Ok(request) = self.requests.recv() {
+            if request["type"] != "request" {
+                continue;
+            }
+            if !self.handle(&request)? {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl DapServer<std::net::TcpStream> {
+    /// Serve a client connected over TCP.
+    pub fn tcp(stream: std::net::TcpStream) -> Result<DapServer<std::net::TcpStream>, Error> {
+        let input = stream.try_clone()?;
+        Ok(DapServer::new(input, stream))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Cursor;
+
+    use crate::dap::*;
+
+    fn frame(messages: &[Value]) -> Vec<u8> {
+        let mut input = Vec::new();
+        for (seq, message) in messages.iter().enumerate() {
+            let mut message = message.clone();
+            message["seq"] = json!(seq + 1);
+            message["type"] = json!("request");
+            let body = message.to_string();
+            write!(input, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+        }
+        input
+    }
+
+    #[test]
+    fn base64_round_trip() {
+        for data in [&b""[..], b"f", b"fo", b"foo", b"foob", b"\xff\x00\x80"].iter() {
+            assert_eq!(base64_decode(&base64_encode(data)).as_deref(), Some(*data));
+        }
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+    }
+
+    #[test]
+    fn instruction_and_function_breakpoints() {
+        let mut server = DapServer::new(Cursor::new(Vec::new()), Vec::new());
+        server.debugger.symbols_mut().insert("start", 0x0100);
+        let set = |server: &mut DapServer<Vec<u8>>, key: &str, bp: Value| {
+            let args = json!({ "breakpoints": [bp] });
+            server.set_address_breakpoints(&args, key).unwrap()["breakpoints"][0]["verified"] == true
+        };
+
+        assert!(set(
+            &mut server,
+            "instructionReference",
+            json!({ "instructionReference": "0x0004" })
+        ));
+        assert!(set(&mut server, "name", json!({ "name": "start" })));
+        assert_eq!(server.debugger.breakpoints().collect::<Vec<_>>(), [0x0004, 0x0100]);
+
+        assert!(set(
+            &mut server,
+            "instructionReference",
+            json!({ "instructionReference": "0x0008" })
+        ));
+        assert_eq!(
+            server.debugger.breakpoints().collect::<Vec<_>>(),
+            [0x0008, 0x0100],
+            "only instruction breakpoints are replaced"
+        );
+    }
+
+    #[test]
+    fn debug_session() {
+        let dir = std::env::temp_dir().join(format!("vtrs20-dap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom = dir.join("boot.bin");
+        let listing = dir.join("boot.lst");
+        std::fs::write(&rom, [0x3e, 0x05, 0x3c, 0x3c, 0x18, 0xfe]).unwrap();
+        std::fs::write(
+            &listing,
+            "\
+ 1    0000              start:
+ 2    0000 3E 05            ld a, 5
+ 3    0002 3C               inc a
+ 4    0003 3C               inc a
+ 5    0004 18 FE            jr $
+",
+        )
+        .unwrap();
+
+        let input = frame(&[
+            json!({ "command": "initialize", "arguments": { "adapterID": "vtrs20" } }),
+            json!({ "command": "launch", "arguments": { "rom": rom, "listings": [listing] } }),
+            json!({ "command": "setBreakpoints", "arguments": {
+                "source": { "path": dir.join("boot.asm") },
+                "breakpoints": [{ "line": 3 }, { "line": 9 }],
+            } }),
+            json!({ "command": "configurationDone" }),
+            json!({ "command": "stackTrace", "arguments": { "threadId": 1 } }),
+            json!({ "command": "next", "arguments": { "threadId": 1 } }),
+            json!({ "command": "variables", "arguments": { "variablesReference": 1 } }),
+            json!({ "command": "evaluate", "arguments": { "expression": "a == 6 && word(pc) == 0x183c" } }),
+            json!({ "command": "setVariable", "arguments": { "variablesReference": 1, "name": "A", "value": "0x40" } }),
+            json!({ "command": "readMemory", "arguments": { "memoryReference": "0x0002", "count": 3 } }),
+            json!({ "command": "disassemble", "arguments": {
+                "memoryReference": "0x0004", "instructionOffset": -2, "instructionCount": 3,
+            } }),
+        ]);
+        let mut output = Vec::new();
+        DapServer::new(Cursor::new(input), &mut output).serve().unwrap();
+
+        let mut output = Cursor::new(output);
+        let mut messages = Vec::new();
+        while let Some(message) = read_message(&mut output) {
+            messages.push(message);
+        }
+        let find = |command: &str| {
+            messages
+                .iter()
+                .find(|m| m["type"] == "response" && m["command"] == command)
+                .unwrap_or_else(|| panic!("no {} response", command))
+        };
+        let events: Vec<&Value> = messages.iter().filter(|m| m["type"] == "event").collect();
+
+        assert_eq!(find("initialize")["body"]["supportsReadMemoryRequest"], true);
+        assert_eq!(find("launch")["success"], true);
+        let breakpoints = &find("setBreakpoints")["body"]["breakpoints"];
+        assert_eq!(breakpoints[0]["verified"], true);
+        assert_eq!(breakpoints[0]["instructionReference"], "0x0002");
+        assert_eq!(breakpoints[1]["verified"], false);
+
+        assert_eq!(events[0]["event"], "initialized");
+        assert_eq!(events[1]["body"]["reason"], "breakpoint");
+        assert_eq!(events[2]["body"]["reason"], "step");
+
+        let frames = &find("stackTrace")["body"]["stackFrames"];
+        assert_eq!(frames[0]["line"], 3);
+        assert_eq!(frames[0]["name"], "start+2");
+        assert_eq!(frames[0]["instructionPointerReference"], "0x0002");
+
+        let registers = find("variables")["body"]["variables"].as_array().unwrap();
+        let a = registers.iter().find(|v| v["name"] == "A").unwrap();
+        assert_eq!(a["value"], "$06");
+        let pc = registers.iter().find(|v| v["name"] == "PC").unwrap();
+        assert_eq!(pc["value"], "$0003");
+        assert_eq!(find("evaluate")["body"]["result"], "$1 (1)");
+        assert_eq!(find("setVariable")["body"]["value"], "$40");
+
+        assert_eq!(find("readMemory")["body"]["data"], base64_encode(&[0x3c, 0x3c, 0x18]));
+
+        let instructions = find("disassemble")["body"]["instructions"].as_array().unwrap();
+        let addresses: Vec<&Value> = instructions.iter().map(|i| &i["address"]).collect();
+        assert_eq!(addresses, ["0x0002", "0x0003", "0x0004"]);
+        assert_eq!(instructions[2]["line"], 5);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
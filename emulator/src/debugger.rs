@@ -0,0 +1,723 @@
+/**
+ * Debugger execution control
+ *
+ * The parts of a debugger that don't depend on how it talks to the user: single stepping, stepping over calls,
+ * breakpoints, and running until something stops the CPU. Front ends such as the monitor drive a `Debugger` and
+ * present the results.
+ *
+ * With assembler listings, the debugger can also step by source line: `step_line` runs until the CPU reaches code
+ * from a different line, and `next_line` does the same while running calls to completion. Code without a listing
+ * is run through until the CPU reaches a line again.
+ *
+ * Breakpoints are on logical addresses, and stop the CPU before the instruction at that address executes. A breakpoint
+ * may have a condition, and only stops when the condition is true. A breakpoint with a message is a tracepoint: it
+ * logs the message and carries on. Watchpoints are set on the bus, and stop the CPU after the instruction during which
+ * one caught an access.
+ *
+ * When recording, the debugger keeps a history of snapshots as it runs the machine, and can go backwards: `step_back`
+ * returns to the state before the last instruction, and `reverse_run` goes back to the last place a run forwards would
+ * have stopped for a breakpoint or watchpoint.
+ */
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+
+use crate::bus::Bus;
+use crate::cpu::{Mode, Register, CPU};
+use crate::disasm;
+use crate::expr::{Context, Expr, Template};
+use crate::history::History;
+use crate::listing::Listing;
+use crate::symbols::Symbols;
+use crate::watch::Hit;
+
+/// Why execution stopped.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Stop {
+    /// A step completed.
+    Step,
+    /// The CPU reached a breakpoint.
+    Breakpoint(u16),
+    /// A watchpoint caught an access. If more than one did, this is the first.
+    Watchpoint(Hit),
+    /// The CPU halted, or hit an illegal instruction.
+    Halt,
+    /// A peripheral raised a fault that breaks execution.
+    Break,
+    /// The interrupt handle was set.
+    Interrupted,
+    /// Going backwards reached the oldest recorded state, or there's no history to go back into.
+    Beginning,
+}
+
+/// A breakpoint, or a tracepoint if it has a message.
+#[derive(Debug, Default, Clone)]
+pub struct Breakpoint {
+    /// Only stop, or log, when this is true.
+    pub condition: Option<Expr>,
+    /// Log this instead of stopping.
+    pub message: Option<Template>,
+    /// How many times the CPU has reached the breakpoint, whether or not the condition was true.
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn when(condition: Expr) -> Breakpoint {
+        Breakpoint {
+            condition: Some(condition),
+            ..Default::default()
+        }
+    }
+
+    pub fn trace(message: Template) -> Breakpoint {
+        Breakpoint {
+            message: Some(message),
+            ..Default::default()
+        }
+    }
+}
+
+type TraceOutput = Box<dyn FnMut(&str)>;
+
+pub struct Debugger {
+    breakpoints: BTreeMap<u16, Breakpoint>,
+    symbols: Symbols,
+    listings: Vec<Listing>,
+    interrupt: Arc<AtomicBool>,
+    // where tracepoint messages go, or stdout if nowhere
+    trace_output: Option<TraceOutput>,
+    history: Option<History>,
+}
+
+impl Default for Debugger {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Debugger {
+    pub fn new() -> Debugger {
+        Debugger {
+            breakpoints: BTreeMap::new(),
+            symbols: Symbols::new(),
+            listings: Vec::new(),
+            interrupt: Arc::new(AtomicBool::new(false)),
+            trace_output: None,
+            history: None,
+        }
+    }
+
+    /// A flag that stops a running `run` or `next` when set, for example from a signal handler.
+    pub fn interrupt_handle(&self) -> Arc<AtomicBool> {
+        self.interrupt.clone()
+    }
+
+    /// Forget an interrupt asked for while the CPU wasn't running.
+    pub fn clear_interrupt(&self) {
+        self.interrupt.store(false, Ordering::SeqCst);
+    }
+
+    /// The symbols front ends use to name addresses.
+    pub fn symbols(&self) -> &Symbols {
+        &self.symbols
+    }
+
+    pub fn symbols_mut(&mut self) -> &mut Symbols {
+        &mut self.symbols
+    }
+
+    pub fn add_listing(&mut self, listing: Listing) {
+        self.listings.push(listing);
+    }
+
+    pub fn listings(&self) -> &[Listing] {
+        &self.listings
+    }
+
+    /// The listing and line for the code at a logical address, if any listing has it.
+    pub fn source_line(&self, address: u16) -> Option<(&Listing, u32)> {
+        self.listings
+            .iter()
+            .find_map(|listing| listing.line_of_address(address).map(|line| (listing, line)))
+    }
+
+    /// The address of the first code at or after a line of a source file, if a listing covers the file.
+    pub fn address_of_line(&self, source: &Path, line: u32) -> Option<u16> {
+        self.listings
+            .iter()
+            .filter(|listing| listing.covers(source))
+            .find_map(|listing| listing.address_of_line(line))
+            .map(|(_, address)| address)
+    }
+
+    // Where the code at an address comes from, comparably: the index of its listing and its line.
+    fn location(&self, address: u16) -> Option<(usize, u32)> {
+        self.listings
+            .iter()
+            .enumerate()
+            .find_map(|(index, listing)| listing.line_of_address(address).map(|line| (index, line)))
+    }
+
+    /// Record a history of the machine as the debugger runs it, so that it can go backwards. Any history already
+    /// recorded is forgotten.
+    pub fn record(&mut self, bus: &Bus, history: History) {
+        bus.inputs().set_recording(false);
+        bus.inputs().set_recording(true);
+        self.history = Some(history);
+    }
+
+    /// Stop recording, and forget the history.
+    pub fn stop_recording(&mut self, bus: &Bus) {
+        bus.inputs().set_recording(false);
+        self.history = None;
+    }
+
+    pub fn history(&self) -> Option<&History> {
+        self.history.as_ref()
+    }
+
+    /// Send tracepoint messages somewhere other than stdout.
+    pub fn set_trace_output<F: FnMut(&str) + 'static>(&mut self, output: F) {
+        self.trace_output = Some(Box::new(output));
+    }
+
+    /// Add an unconditional breakpoint, answering false if there already was one at the address.
+    pub fn add_breakpoint(&mut self, address: u16) -> bool {
+        if self.breakpoints.contains_key(&address) {
+            return false;
+        }
+        self.breakpoints.insert(address, Breakpoint::default());
+        true
+    }
+
+    /// Set a breakpoint or tracepoint, replacing any already at the address.
+    pub fn set_breakpoint(&mut self, address: u16, breakpoint: Breakpoint) {
+        self.breakpoints.insert(address, breakpoint);
+    }
+
+    pub fn breakpoint(&self, address: u16) -> Option<&Breakpoint> {
+        self.breakpoints.get(&address)
+    }
+
+    pub fn breakpoint_mut(&mut self, address: u16) -> Option<&mut Breakpoint> {
+        self.breakpoints.get_mut(&address)
+    }
+
+    /// Remove a breakpoint, answering false if there wasn't one at the address.
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address).is_some()
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.keys().copied()
+    }
+
+    /// Count a visit to any breakpoint at PC, logging its message if it's a tracepoint whose condition holds. Answers
+    /// whether the CPU should stop here.
+    pub fn check(&mut self, cpu: &CPU, bus: &Bus) -> bool {
+        let breakpoint = match self.breakpoints.get_mut(&cpu.reg(Register::PC)) {
+            Some(breakpoint) => breakpoint,
+            None => return false,
+        };
+        breakpoint.hits += 1;
+        let mut ctx = Context::new(cpu, bus);
+        ctx.hits = breakpoint.hits;
+        match &breakpoint.condition {
+            Some(condition) if !condition.is_true(&ctx) => return false,
+            _ => (),
+        }
+        let message = match &breakpoint.message {
+            Some(message) => message.format(&ctx),
+            None => return true,
+        };
+        match self.trace_output.as_mut() {
+            Some(output) => output(&message),
+            None => println!("{}", message),
+        }
+        false
+    }
+
+    // Answer whether a breakpoint at PC would stop the CPU, without counting a hit or logging a tracepoint.
+    fn would_stop(&self, cpu: &CPU, bus: &Bus) -> bool {
+        let breakpoint = match self.breakpoints.get(&cpu.reg(Register::PC)) {
+            Some(breakpoint) if breakpoint.message.is_none() => breakpoint,
+            _ => return false,
+        };
+        let mut ctx = Context::new(cpu, bus);
+        ctx.hits = breakpoint.hits;
+        match &breakpoint.condition {
+            Some(condition) => condition.is_true(&ctx),
+            None => true,
+        }
+    }
+
+    /// Execute one instruction.
+    pub fn step(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        if let Some(history) = self.history.as_mut() {
+            history.record(cpu, bus);
+        }
+        // only this instruction's accesses count
+        bus.take_hits();
+        execute(cpu, bus);
+        match cpu.mode {
+            Mode::Halt | Mode::Reset => return Stop::Halt,
+            Mode::Break => return Stop::Break,
+            Mode::OpCodeFetch => (),
+        }
+        bus.take_hits()
+            .first()
+            .map(|&hit| Stop::Watchpoint(hit))
+            .unwrap_or(Stop::Step)
+    }
+
+    /// Execute one instruction, running any subroutine it calls to completion.
+    pub fn next(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        self.clear_interrupt();
+        self.step_over(cpu, bus)
+    }
+
+    fn step_over(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        let pc = cpu.reg(Register::PC);
+        let sp = cpu.reg(Register::SP);
+        let opcodes: Vec<u8> = (0..4).map(|i| self.read_byte(cpu, bus, pc.wrapping_add(i))).collect();
+        let ret = pc.wrapping_add(disasm::decode(&opcodes).length as u16);
+        let stop = self.step(cpu, bus);
+        if stop != Stop::Step {
+            return stop;
+        }
+
+        // a call, or an interrupt taken after the instruction, pushes the address of the next instruction and goes
+        // somewhere else
+        let called = cpu.reg(Register::SP) == sp.wrapping_sub(2)
+            && self.read_word(cpu, bus, sp.wrapping_sub(2)) == ret
+            && cpu.reg(Register::PC) != ret;
+        if !called {
+            return Stop::Step;
+        }
+        self.run_until(cpu, bus, None, |_, cpu| {
+            cpu.reg(Register::PC) == ret && cpu.reg(Register::SP) == sp
+        })
+    }
+
+    /// Run until the current subroutine returns, that is until the stack pointer rises above where it is now. Values
+    /// pushed since the subroutine was entered must be popped first, or a pop is mistaken for the return.
+    pub fn finish(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        let sp = cpu.reg(Register::SP);
+        self.clear_interrupt();
+        self.run_until(cpu, bus, None, |_, cpu| cpu.reg(Register::SP) > sp)
+    }
+
+    /// Run until the CPU reaches code from a source line other than the current one, following calls.
+    pub fn step_line(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        let start = self.location(cpu.reg(Register::PC));
+        self.clear_interrupt();
+        self.run_until(cpu, bus, None, |debugger, cpu| {
+            let here = debugger.location(cpu.reg(Register::PC));
+            here.is_some() && here != start
+        })
+    }
+
+    /// Run until the CPU reaches code from a source line other than the current one, running calls to completion.
+    pub fn next_line(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        let start = self.location(cpu.reg(Register::PC));
+        self.clear_interrupt();
+        loop {
+            let stop = self.step_over(cpu, bus);
+            if stop != Stop::Step {
+                return stop;
+            }
+            let here = self.location(cpu.reg(Register::PC));
+            if here.is_some() && here != start {
+                return Stop::Step;
+            }
+            if self.check(cpu, bus) {
+                return Stop::Breakpoint(cpu.reg(Register::PC));
+            }
+            if self.interrupt.swap(false, Ordering::SeqCst) {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    /// Run until the CPU reaches an address, as if there were a breakpoint there.
+    pub fn run_to(&mut self, cpu: &mut CPU, bus: &mut Bus, address: u16) -> Stop {
+        self.clear_interrupt();
+        self.run_until(cpu, bus, None, |_, cpu| cpu.reg(Register::PC) == address)
+    }
+
+    /// Run until a breakpoint, a watchpoint, a halt, a break, or an interrupt. At least one instruction executes, so
+    /// running from a breakpoint moves on from it.
+    pub fn run(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        self.clear_interrupt();
+        self.run_until(cpu, bus, None, |_, _| false)
+    }
+
+    /// Run as for `run`, but for at most `steps` instructions. Answers `Stop::Step` if nothing else stopped the CPU.
+    /// An interrupt asked for before the call isn't forgotten, so that one run can be made of many calls: clear it
+    /// with `clear_interrupt` once, when the run starts.
+    pub fn run_for(&mut self, cpu: &mut CPU, bus: &mut Bus, steps: u64) -> Stop {
+        self.run_until(cpu, bus, Some(steps), |_, _| false)
+    }
+
+    fn run_until<F: Fn(&Debugger, &CPU) -> bool>(
+        &mut self,
+        cpu: &mut CPU,
+        bus: &mut Bus,
+        limit: Option<u64>,
+        done: F,
+    ) -> Stop {
+        let mut steps = 0;
+        loop {
+            if limit == Some(steps) {
+                return Stop::Step;
+            }
+            steps += 1;
+            let stop = self.step(cpu, bus);
+            if stop != Stop::Step || done(self, cpu) {
+                return stop;
+            }
+            if self.check(cpu, bus) {
+                return Stop::Breakpoint(cpu.reg(Register::PC));
+            }
+            if self.interrupt.swap(false, Ordering::SeqCst) {
+                return Stop::Interrupted;
+            }
+        }
+    }
+
+    // Run one instruction of the recorded history again.
+    fn replay(&mut self, cpu: &mut CPU, bus: &mut Bus) {
+        if let Some(history) = self.history.as_mut() {
+            history.replayed();
+        }
+        execute(cpu, bus);
+    }
+
+    // Put the machine back how it was a number of instructions into its history, by restoring the snapshot before
+    // then and replaying from it.
+    fn go_to(&mut self, cpu: &mut CPU, bus: &mut Bus, step: u64) {
+        let history = match self.history.as_mut() {
+            Some(history) => history,
+            None => return,
+        };
+        history
+            .restore(cpu, bus, step)
+            .expect("a snapshot should restore on the machine that took it");
+        while self.history.as_ref().is_some_and(|history| history.step() < step) {
+            self.replay(cpu, bus);
+        }
+        bus.take_hits();
+    }
+
+    // The step the machine is at, if there's recorded history before it to go back into.
+    fn step_in_history(&self) -> Option<u64> {
+        let history = self.history.as_ref()?;
+        Some(history.step()).filter(|&step| history.oldest().is_some_and(|oldest| step > oldest))
+    }
+
+    /// Go back one instruction, to how the machine was before the last instruction stepped or run.
+    pub fn step_back(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        match self.step_in_history() {
+            Some(step) => {
+                self.go_to(cpu, bus, step - 1);
+                Stop::Step
+            }
+            None => Stop::Beginning,
+        }
+    }
+
+    /// Run backwards to the last point at which running forwards would have stopped for a breakpoint or watchpoint, or
+    /// else to the oldest recorded state. Tracepoints are passed over silently, and hit counts aren't changed.
+    pub fn reverse_run(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Stop {
+        let now = match self.step_in_history() {
+            Some(step) => step,
+            None => return Stop::Beginning,
+        };
+        self.clear_interrupt();
+        let starts: Vec<u64> = self
+            .history
+            .as_ref()
+            .unwrap()
+            .snapshots()
+            .filter(|&step| step < now)
+            .collect();
+
+        // search back a snapshot at a time, replaying each stretch forwards and keeping the last stop in it
+        for (i, &start) in starts.iter().enumerate().rev() {
+            let end = starts.get(i + 1).copied().unwrap_or(now - 1);
+            self.go_to(cpu, bus, start);
+            let mut found = None;
+            for step in start + 1..=end {
+                self.replay(cpu, bus);
+                if let Some(&hit) = bus.take_hits().first() {
+                    found = Some((step, Stop::Watchpoint(hit)));
+                } else if self.would_stop(cpu, bus) {
+                    found = Some((step, Stop::Breakpoint(cpu.reg(Register::PC))));
+                }
+                if self.interrupt.swap(false, Ordering::SeqCst) {
+                    return Stop::Interrupted;
+                }
+            }
+            if let Some((step, stop)) = found {
+                self.go_to(cpu, bus, step);
+                return stop;
+            }
+        }
+        self.go_to(cpu, bus, starts[0]);
+        Stop::Beginning
+    }
+
+    /// Read a byte at a logical address without side effects.
+    pub fn read_byte(&self, cpu: &CPU, bus: &Bus, address: u16) -> u8 {
+        bus.peek(cpu.to_physical(address))
+    }
+
+    /// Read a little-endian word at a logical address without side effects.
+    pub fn read_word(&self, cpu: &CPU, bus: &Bus, address: u16) -> u16 {
+        self.read_byte(cpu, bus, address) as u16 | (self.read_byte(cpu, bus, address.wrapping_add(1)) as u16) << 8
+    }
+}
+
+// Run one instruction, resuming from a fault break.
+fn execute(cpu: &mut CPU, bus: &mut Bus) {
+    if cpu.mode == Mode::Break {
+        cpu.mode = Mode::OpCodeFetch;
+    }
+    cpu.cycle(bus);
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::debugger::*;
+    use crate::history::History;
+    use crate::ram::RAM;
+    use crate::watch::{Access, Condition, Space, Watchpoint};
+
+    fn setup(code: &[u8]) -> (CPU, Bus) {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(0x0000, code).unwrap();
+        bus.add(ram);
+        cpu.reset();
+        cpu.write_reg(Register::SP, 0x8000);
+        (cpu, bus)
+    }
+
+    #[test]
+    fn next_steps_over_calls() {
+        let (mut cpu, mut bus) = setup(&[
+            0xcd, 0x10, 0x00, //    0x0000  call $0010
+            0x3c, //                0x0003  inc a
+            0x76, //                0x0004  halt
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, //
+            0x06, 0x05, //          0x0010  ld b, 5
+            0x10, 0xfe, //          0x0012  djnz $0012
+            0xc9, //                0x0014  ret
+        ]);
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.next(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0003);
+        assert_eq!(cpu.reg(Register::SP), 0x8000);
+        assert_eq!(cpu.reg(Register::B), 0);
+        assert_eq!(debugger.next(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::A), 1);
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Halt);
+    }
+
+    #[test]
+    fn finish_returns_to_caller() {
+        let (mut cpu, mut bus) = setup(&[
+            0xcd, 0x10, 0x00, //    0x0000  call $0010
+            0x76, //                0x0003  halt
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,    //
+            0xc5, //                0x0010  push bc
+            0xc1, //                0x0011  pop bc
+            0xc9, //                0x0012  ret
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.step(&mut cpu, &mut bus);
+        assert_eq!(debugger.finish(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0003);
+        assert_eq!(cpu.reg(Register::SP), 0x8000);
+    }
+
+    #[test]
+    fn run_to_breakpoint() {
+        let (mut cpu, mut bus) = setup(&[
+            0x3c, //                0x0000  inc a
+            0x18, 0xfd, //          0x0001  jr $0000
+        ]);
+        let mut debugger = Debugger::new();
+        assert!(debugger.add_breakpoint(0x0001));
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Breakpoint(0x0001));
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Breakpoint(0x0001));
+        assert_eq!(cpu.reg(Register::A), 2);
+
+        assert!(debugger.remove_breakpoint(0x0001));
+        let interrupt = debugger.interrupt_handle();
+        std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            interrupt.store(true, Ordering::SeqCst);
+        });
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Interrupted);
+
+        // a run polled a few steps at a time keeps an interrupt asked for between polls
+        debugger.clear_interrupt();
+        assert_eq!(debugger.run_for(&mut cpu, &mut bus, 10), Stop::Step);
+        debugger.interrupt_handle().store(true, Ordering::SeqCst);
+        assert_eq!(debugger.run_for(&mut cpu, &mut bus, 10), Stop::Interrupted);
+    }
+
+    #[test]
+    fn conditions_and_tracepoints() {
+        let (mut cpu, mut bus) = setup(&[
+            0x3c, //                0x0000  inc a
+            0x18, 0xfd, //          0x0001  jr $0000
+        ]);
+        let mut debugger = Debugger::new();
+        let log = Rc::new(std::cell::RefCell::new(Vec::new()));
+        let sink = log.clone();
+        debugger.set_trace_output(move |message| sink.borrow_mut().push(message.to_string()));
+        debugger.set_breakpoint(0x0001, Breakpoint::when(Expr::parse("a == 3 || hits == 5").unwrap()));
+        let mut trace = Breakpoint::trace(Template::parse("a={a}").unwrap());
+        trace.condition = Some(Expr::parse("a & 1").unwrap());
+        debugger.set_breakpoint(0x0000, trace);
+
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Breakpoint(0x0001));
+        assert_eq!(cpu.reg(Register::A), 3);
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Breakpoint(0x0001));
+        assert_eq!(cpu.reg(Register::A), 5, "stopped on the fifth hit");
+        assert_eq!(debugger.breakpoint(0x0000).unwrap().hits, 4);
+        assert_eq!(*log.borrow(), ["a=1", "a=3"]);
+    }
+
+    #[test]
+    fn watch_for_writes() {
+        let (mut cpu, mut bus) = setup(&[
+            0x32, 0x00, 0x80, //    0x0000  ld ($8000), a
+            0x3c, //                0x0003  inc a
+            0x18, 0xfa, //          0x0004  jr $0000
+        ]);
+        let mut debugger = Debugger::new();
+        let id = bus.add_watchpoint(Watchpoint::new(Space::Logical, 0x8000, 1, Access::Write).when(Condition::Equals(2)));
+        let hit = Hit {
+            id,
+            space: Space::Logical,
+            access: Access::Write,
+            address: 0x8000,
+            value: 2,
+        };
+        assert_eq!(debugger.run(&mut cpu, &mut bus), Stop::Watchpoint(hit));
+        assert_eq!(cpu.reg(Register::PC), 0x0003);
+        assert_eq!(debugger.read_byte(&cpu, &bus, 0x8000), 2);
+    }
+
+    #[test]
+    fn step_by_source_line() {
+        let (mut cpu, mut bus) = setup(&[
+            0x06, 0x02, //          0x0000  ld b, 2
+            0xcd, 0x10, 0x00, //    0x0002  call $0010
+            0x3c, //                0x0005  inc a
+            0x76, //                0x0006  halt
+            0, 0, 0, 0, 0, 0, 0, 0, 0,    //
+            0x0c, //                0x0010  inc c
+            0xc9, //                0x0011  ret
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.add_listing(Listing::parse(
+            "test.lst",
+            "\
+ 1    0000 06 02            ld b, 2
+ 2    0002 CD 10 00         call sub
+ 3    0005 3C               inc a
+ 4    0006 76               halt
+10    0010 0C         sub:  inc c
+11    0011 C9               ret
+",
+        ));
+        assert_eq!(debugger.next_line(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0002);
+        assert_eq!(debugger.step_line(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0010);
+        assert_eq!(debugger.source_line(0x0010).map(|(_, line)| line), Some(10));
+        assert_eq!(debugger.next_line(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(debugger.next_line(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0005, "returned to line 3");
+
+        let end = debugger.address_of_line(Path::new("test.asm"), 4).unwrap();
+        assert_eq!(debugger.run_to(&mut cpu, &mut bus, end), Stop::Step);
+        assert_eq!(cpu.reg(Register::PC), 0x0006);
+    }
+
+    #[test]
+    fn step_back_through_history() {
+        let (mut cpu, mut bus) = setup(&[
+            0x3e, 0x00, //          0x0000  ld a, 0
+            0x3c, //                0x0002  inc a
+            0x32, 0x00, 0x80, //    0x0003  ld ($8000), a
+            0x18, 0xfa, //          0x0006  jr $0002
+        ]);
+        let mut debugger = Debugger::new();
+        debugger.record(&bus, History::new(4, 5));
+        let mut seen = Vec::new();
+        let mut clocks = Vec::new();
+        for _ in 0..30 {
+            clocks.push(bus.clock());
+            seen.push((
+                cpu.reg(Register::PC),
+                cpu.reg(Register::A),
+                debugger.read_byte(&cpu, &bus, 0x8000),
+            ));
+            debugger.step(&mut cpu, &mut bus);
+        }
+
+        assert_eq!(debugger.step_back(&mut cpu, &mut bus), Stop::Step);
+        assert_eq!(
+            (
+                cpu.reg(Register::PC),
+                cpu.reg(Register::A),
+                debugger.read_byte(&cpu, &bus, 0x8000)
+            ),
+            seen[29]
+        );
+        assert_eq!(bus.clock(), clocks[29]);
+
+        // back to the last time the store was about to run, then to where A was 5 there, which is in an earlier
+        // stretch between snapshots
+        debugger.add_breakpoint(0x0003);
+        assert_eq!(debugger.reverse_run(&mut cpu, &mut bus), Stop::Breakpoint(0x0003));
+        assert_eq!(debugger.history().unwrap().step(), 26);
+        debugger.set_breakpoint(0x0003, Breakpoint::when(Expr::parse("a == 5").unwrap()));
+        assert_eq!(debugger.reverse_run(&mut cpu, &mut bus), Stop::Breakpoint(0x0003));
+        assert_eq!(debugger.history().unwrap().step(), 14);
+        assert_eq!(cpu.reg(Register::A), 5);
+        debugger.set_breakpoint(0x0003, Breakpoint::when(Expr::parse("a == 3").unwrap()));
+        assert_eq!(
+            debugger.reverse_run(&mut cpu, &mut bus),
+            Stop::Beginning,
+            "A was 3 before the oldest snapshot"
+        );
+        assert_eq!(debugger.history().unwrap().step(), 12);
+
+        // going forwards again replays the same run
+        for expected in &seen[12..] {
+            assert_eq!(
+                &(
+                    cpu.reg(Register::PC),
+                    cpu.reg(Register::A),
+                    debugger.read_byte(&cpu, &bus, 0x8000)
+                ),
+                expected
+            );
+            if debugger.step(&mut cpu, &mut bus) != Stop::Step {
+                break;
+            }
+        }
+        let hits = debugger.breakpoint(0x0003).unwrap().hits;
+        assert_eq!(hits, 0, "going backwards doesn't count hits");
+    }
+}
@@ -1,537 +1,1153 @@
-pub fn disasm(opcodes: &[u8]) -> String {
-    match opcodes[0] {
-        0b00_000_000 => "nop".to_string(),
-        0b00_000_001 => format!("ld\tbc, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b00_000_010 => "ld\t(bc), a".to_string(),
-        0b00_000_011 => "inc\tbc".to_string(),
-        0b00_000_100 => "inc\tb".to_string(),
-        0b00_000_101 => "dec\tb".to_string(),
-        0b00_000_110 => format!("ld\tb, ${:02x}", opcodes[1]),
-        0b00_000_111 => "rlca".to_string(),
-
-        0b00_001_000 => "ex\taf, af'".to_string(),
-        0b00_001_001 => "add\thl, bc".to_string(),
-        0b00_001_010 => "ld\ta, (bc)".to_string(),
-        0b00_001_011 => "dec\tbc".to_string(),
-        0b00_001_100 => "inc\tc".to_string(),
-        0b00_001_101 => "dec\tc".to_string(),
-        0b00_001_110 => format!("ld\tc, ${:02x}", opcodes[1]),
-        0b00_001_111 => "rrca".to_string(),
-
-        0b00_010_000 => format!("djnz\t{}", opcodes[1] as i8),
-        0b00_010_001 => format!("ld\tde, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b00_010_010 => "ld\t(de), a".to_string(),
-        0b00_010_011 => "inc\tde".to_string(),
-        0b00_010_100 => "inc\td".to_string(),
-        0b00_010_101 => "dec\td".to_string(),
-        0b00_010_110 => format!("ld\td, ${:02x}", opcodes[1]),
-        0b00_010_111 => "rla".to_string(),
-
-        0b00_011_000 => format!("jr\t{}", opcodes[1] as i8),
-        0b00_011_001 => "add\thl, de".to_string(),
-        0b00_011_010 => "ld\ta, (de)".to_string(),
-        0b00_011_011 => "dec\tde".to_string(),
-        0b00_011_100 => "inc\te".to_string(),
-        0b00_011_101 => "dec\te".to_string(),
-        0b00_011_110 => format!("ld\te, ${:02x}", opcodes[1]),
-        0b00_011_111 => "rra".to_string(),
-
-        0b00_100_000 => format!("jr\tnz, {}", opcodes[1] as i8),
-        0b00_100_001 => format!("ld\thl, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b00_100_010 => format!("ld\t(${:02x}{:02x}), hl", opcodes[2], opcodes[1]),
-        0b00_100_011 => "inc\thl".to_string(),
-        0b00_100_100 => "inc\th".to_string(),
-        0b00_100_101 => "dec\th".to_string(),
-        0b00_100_110 => format!("ld\th, ${:02x}", opcodes[1]),
-        0b00_100_111 => "daa".to_string(),
-
-        0b00_101_000 => format!("jr\tz, {}", opcodes[1] as i8),
-        0b00_101_001 => "add\thl, hl".to_string(),
-        0b00_101_010 => format!("ld\thl, (${:02x}{:02x})", opcodes[2], opcodes[1]),
-        0b00_101_011 => "dec\thl".to_string(),
-        0b00_101_100 => "inc\tl".to_string(),
-        0b00_101_101 => "dec\tl".to_string(),
-        0b00_101_110 => format!("ld\tl, ${:02x}", opcodes[1]),
-        0b00_101_111 => "cpl".to_string(),
-
-        0b00_110_000 => format!("jr\tnc, {}", opcodes[1] as i8),
-        0b00_110_001 => format!("ld\tsp, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b00_110_010 => format!("ld\t(${:02x}{:02x}), a", opcodes[2], opcodes[1]),
-        0b00_110_011 => "inc\tsp".to_string(),
-        0b00_110_100 => "inc\t(hl)".to_string(),
-        0b00_110_101 => "dec\t(hl)".to_string(),
-        0b00_110_110 => format!("ld\t(hl), ${:02x}", opcodes[1]),
-        0b00_110_111 => "scf".to_string(),
-
-        0b00_111_000 => format!("jr\tc, {}", opcodes[1] as i8),
-        0b00_111_001 => "add\thl, sp".to_string(),
-        0b00_111_010 => format!("ld\ta, (${:02x}{:02x})", opcodes[2], opcodes[1]),
-        0b00_111_011 => "dec\tsp".to_string(),
-        0b00_111_100 => "inc\ta".to_string(),
-        0b00_111_101 => "dec\ta".to_string(),
-        0b00_111_110 => format!("ld\ta, ${:02x}", opcodes[1]),
-        0b00_111_111 => "ccf".to_string(),
-
-        0b01_000_000 => "ld\tb, b".to_string(),
-        0b01_000_001 => "ld\tb, c".to_string(),
-        0b01_000_010 => "ld\tb, d".to_string(),
-        0b01_000_011 => "ld\tb, e".to_string(),
-        0b01_000_100 => "ld\tb, h".to_string(),
-        0b01_000_101 => "ld\tb, l".to_string(),
-        0b01_000_110 => "ld\tb, (hl)".to_string(),
-        0b01_000_111 => "ld\tb, a".to_string(),
-
-        0b01_001_000 => "ld\tc, b".to_string(),
-        0b01_001_001 => "ld\tc, c".to_string(),
-        0b01_001_010 => "ld\tc, d".to_string(),
-        0b01_001_011 => "ld\tc, e".to_string(),
-        0b01_001_100 => "ld\tc, h".to_string(),
-        0b01_001_101 => "ld\tc, l".to_string(),
-        0b01_001_110 => "ld\tc, (hl)".to_string(),
-        0b01_001_111 => "ld\tc, a".to_string(),
-
-        0b01_010_000 => "ld\td, b".to_string(),
-        0b01_010_001 => "ld\td, c".to_string(),
-        0b01_010_010 => "ld\td, d".to_string(),
-        0b01_010_011 => "ld\td, e".to_string(),
-        0b01_010_100 => "ld\td, h".to_string(),
-        0b01_010_101 => "ld\td, l".to_string(),
-        0b01_010_110 => "ld\td, (hl)".to_string(),
-        0b01_010_111 => "ld\td, a".to_string(),
-
-        0b01_011_000 => "ld\te, b".to_string(),
-        0b01_011_001 => "ld\te, c".to_string(),
-        0b01_011_010 => "ld\te, d".to_string(),
-        0b01_011_011 => "ld\te, e".to_string(),
-        0b01_011_100 => "ld\te, h".to_string(),
-        0b01_011_101 => "ld\te, l".to_string(),
-        0b01_011_110 => "ld\te, (hl)".to_string(),
-        0b01_011_111 => "ld\te, a".to_string(),
-
-        0b01_100_000 => "ld\th, b".to_string(),
-        0b01_100_001 => "ld\th, c".to_string(),
-        0b01_100_010 => "ld\th, d".to_string(),
-        0b01_100_011 => "ld\th, e".to_string(),
-        0b01_100_100 => "ld\th, h".to_string(),
-        0b01_100_101 => "ld\th, l".to_string(),
-        0b01_100_110 => "ld\th, (hl)".to_string(),
-        0b01_100_111 => "ld\th, a".to_string(),
-
-        0b01_101_000 => "ld\tl, b".to_string(),
-        0b01_101_001 => "ld\tl, c".to_string(),
-        0b01_101_010 => "ld\tl, d".to_string(),
-        0b01_101_011 => "ld\tl, e".to_string(),
-        0b01_101_100 => "ld\tl, h".to_string(),
-        0b01_101_101 => "ld\tl, l".to_string(),
-        0b01_101_110 => "ld\tl, (hl)".to_string(),
-        0b01_101_111 => "ld\tl, a".to_string(),
-
-        0b01_110_000 => "ld\t(hl), b".to_string(),
-        0b01_110_001 => "ld\t(hl), c".to_string(),
-        0b01_110_010 => "ld\t(hl), d".to_string(),
-        0b01_110_011 => "ld\t(hl), e".to_string(),
-        0b01_110_100 => "ld\t(hl), h".to_string(),
-        0b01_110_101 => "ld\t(hl), l".to_string(),
-        0b01_110_110 => "halt\t".to_string(),
-        0b01_110_111 => "ld\t(hl), a".to_string(),
-
-        0b01_111_000 => "ld\ta, b".to_string(),
-        0b01_111_001 => "ld\ta, c".to_string(),
-        0b01_111_010 => "ld\ta, d".to_string(),
-        0b01_111_011 => "ld\ta, e".to_string(),
-        0b01_111_100 => "ld\ta, h".to_string(),
-        0b01_111_101 => "ld\ta, l".to_string(),
-        0b01_111_110 => "ld\ta, (hl)".to_string(),
-        0b01_111_111 => "ld\ta, a".to_string(),
-
-        0b10_000_000 => "add\ta, b".to_string(),
-        0b10_000_001 => "add\ta, c".to_string(),
-        0b10_000_010 => "add\ta, d".to_string(),
-        0b10_000_011 => "add\ta, e".to_string(),
-        0b10_000_100 => "add\ta, h".to_string(),
-        0b10_000_101 => "add\ta, l".to_string(),
-        0b10_000_110 => "add\ta, (hl)".to_string(),
-        0b10_000_111 => "add\ta, a".to_string(),
-
-        0b10_001_000 => "add\ta, b".to_string(),
-        0b10_001_001 => "adc\ta, c".to_string(),
-        0b10_001_010 => "adc\ta, d".to_string(),
-        0b10_001_011 => "adc\ta, e".to_string(),
-        0b10_001_100 => "adc\ta, h".to_string(),
-        0b10_001_101 => "adc\ta, l".to_string(),
-        0b10_001_110 => "adc\ta, (hl)".to_string(),
-        0b10_001_111 => "adc\ta, a".to_string(),
-
-        0b10_010_000 => "sub\ta, b".to_string(),
-        0b10_010_001 => "sub\ta, c".to_string(),
-        0b10_010_010 => "sub\ta, d".to_string(),
-        0b10_010_011 => "sub\ta, e".to_string(),
-        0b10_010_100 => "sub\ta, h".to_string(),
-        0b10_010_101 => "sub\ta, l".to_string(),
-        0b10_010_110 => "sub\ta, (hl)".to_string(),
-        0b10_010_111 => "sub\ta, a".to_string(),
-
-        0b10_011_000 => "sbc\ta, b".to_string(),
-        0b10_011_001 => "sbc\ta, c".to_string(),
-        0b10_011_010 => "sbc\ta, d".to_string(),
-        0b10_011_011 => "sbc\ta, e".to_string(),
-        0b10_011_100 => "sbc\ta, h".to_string(),
-        0b10_011_101 => "sbc\ta, l".to_string(),
-        0b10_011_110 => "sbc\ta, (hl)".to_string(),
-        0b10_011_111 => "sbc\ta, a".to_string(),
-
-        0b10_100_000 => "and\tb".to_string(),
-        0b10_100_001 => "and\tc".to_string(),
-        0b10_100_010 => "and\td".to_string(),
-        0b10_100_011 => "and\te".to_string(),
-        0b10_100_100 => "and\th".to_string(),
-        0b10_100_101 => "and\tl".to_string(),
-        0b10_100_110 => "and\t(hl)".to_string(),
-        0b10_100_111 => "and\ta".to_string(),
-
-        0b10_101_000 => "xor\tb".to_string(),
-        0b10_101_001 => "xor\tc".to_string(),
-        0b10_101_010 => "xor\td".to_string(),
-        0b10_101_011 => "xor\te".to_string(),
-        0b10_101_100 => "xor\th".to_string(),
-        0b10_101_101 => "xor\tl".to_string(),
-        0b10_101_110 => "xor\t(hl)".to_string(),
-        0b10_101_111 => "xor\ta".to_string(),
-
-        0b10_110_000 => "or\tb".to_string(),
-        0b10_110_001 => "or\tc".to_string(),
-        0b10_110_010 => "or\td".to_string(),
-        0b10_110_011 => "or\te".to_string(),
-        0b10_110_100 => "or\th".to_string(),
-        0b10_110_101 => "or\tl".to_string(),
-        0b10_110_110 => "or\tahl)".to_string(),
-        0b10_110_111 => "or\ta".to_string(),
-
-        // CP A, g
-        0b10_111_000 => "cp\tb".to_string(),
-        0b10_111_001 => "cp\tc".to_string(),
-        0b10_111_010 => "cp\td".to_string(),
-        0b10_111_011 => "cp\te".to_string(),
-        0b10_111_100 => "cp\th".to_string(),
-        0b10_111_101 => "cp\tl".to_string(),
-        0b10_111_110 => "cp\t(hl)".to_string(),
-        0b10_111_111 => "cp\ta".to_string(),
-
-        0b11_000_000 => "ret\tnz".to_string(),
-        0b11_000_001 => "pop\tbc".to_string(),
-        0b11_000_010 => format!("jp\tnz, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_000_011 => format!("jp\t${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_000_100 => format!("call\tnz, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_000_101 => "push\tbc".to_string(),
-        0b11_000_110 => format!("add\ta, ${:02x}", opcodes[1]),
-        0b11_000_111 => "rstt$00".to_string(),
-
-        0b11_001_000 => "ret\tz".to_string(),
-        0b11_001_001 => "ret".to_string(),
-        0b11_001_010 => format!("jp\tz, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_001_011 => bits(&opcodes[1..]),
-        0b11_001_100 => format!("call\tz, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_001_101 => format!("call\t${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_001_110 => format!("adc\ta, ${:02x}", opcodes[1]),
-        0b11_001_111 => "rst\t$08".to_string(),
-
-        0b11_010_000 => "ret\tnc".to_string(),
-        0b11_010_001 => "pop\tde".to_string(),
-        0b11_010_010 => format!("jp\tnc, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_010_011 => format!("out\t(${:02x}, a", opcodes[1]),
-        0b11_010_100 => format!("call\tnc, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_010_101 => "push\tde".to_string(),
-        0b11_010_110 => format!("sub\t${:02x}", opcodes[1]),
-        0b11_010_111 => "rst\t$10".to_string(),
-
-        0b11_011_000 => "ret\tc".to_string(),
-        0b11_011_001 => "exx".to_string(),
-        0b11_011_010 => format!("jp\tc, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_011_011 => format!("in\ta, (${:02x}", opcodes[1]),
-        0b11_011_100 => format!("call\tc, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_011_101 => index(&opcodes[1..], "ix"),
-        0b11_011_110 => format!("sbc\ta, ${:02x}", opcodes[1]),
-        0b11_011_111 => "rst\t$18".to_string(),
-
-        0b11_100_000 => "ret\tpo".to_string(),
-        0b11_100_001 => "pop\thl".to_string(),
-        0b11_100_010 => format!("jp\tpo, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_100_011 => "ex\t(sp), hl".to_string(),
-        0b11_100_100 => format!("call\tpo, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_100_101 => "push\thl".to_string(),
-        0b11_100_110 => format!("and\t${:02x}", opcodes[1]),
-        0b11_100_111 => "rst\t$20".to_string(),
-
-        0b11_101_000 => "ret\tpe".to_string(),
-        0b11_101_001 => "jp\t(hl)".to_string(),
-        0b11_101_010 => format!("jp\tpe, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_101_011 => "ex\tde, hl".to_string(),
-        0b11_101_100 => format!("call\tpe, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_101_101 => extended(&opcodes[1..]),
-        0b11_101_110 => format!("xor\t${:02x}", opcodes[1]),
-        0b11_101_111 => "rst\t$28".to_string(),
-
-        0b11_110_000 => "ret\tp".to_string(),
-        0b11_110_001 => "pop\taf".to_string(),
-        0b11_110_010 => format!("jp\tp, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_110_011 => "di\t".to_string(),
-        0b11_110_100 => format!("call\tp, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_110_101 => "push\taf".to_string(),
-        0b11_110_110 => format!("or\t${:02x}", opcodes[1]),
-        0b11_110_111 => "rst\t$30".to_string(),
-
-        0b11_111_000 => "ret\tm".to_string(),
-        0b11_111_001 => "ld\tsp, hl".to_string(),
-        0b11_111_010 => format!("jp\tm, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_111_011 => "ei\t".to_string(),
-        0b11_111_100 => format!("call\tm, ${:02x}{:02x}", opcodes[2], opcodes[1]),
-        0b11_111_101 => index(&opcodes[1..], "iy"),
-        0b11_111_110 => format!("cp\t${:02x}", opcodes[1]),
-        0b11_111_111 => "rst\t$38".to_string(),
-    }
-}
-
-fn extended(opcodes: &[u8]) -> String {
-    match opcodes[0] {
-        0b00_000_000 => format!("in0\t(${:02x}), b", opcodes[1]),
-        0b00_001_000 => format!("in0\t(${:02x}), c", opcodes[1]),
-        0b00_010_000 => format!("in0\t(${:02x}), d", opcodes[1]),
-        0b00_011_000 => format!("in0\t(${:02x}), e", opcodes[1]),
-        0b00_100_000 => format!("in0\t(${:02x}), h", opcodes[1]),
-        0b00_101_000 => format!("in0\t(${:02x}), l", opcodes[1]),
-        0b00_111_000 => format!("in0\t(${:02x}), a", opcodes[1]),
+/**
+ * Disassembly
+ *
+ * `decode` turns the bytes of an instruction into an `Instruction`: its mnemonic, typed operands, length in bytes, and
+ * the flags it may change. An instruction knows how it affects control flow and where a jump or call goes, so callers
+ * can walk code, and formats itself as text, naming addresses with symbols where it's given them.
+ *
+ * Every Z180 opcode is decoded, including the Z180's own: in0, out0, tst, tstio, mlt, slp and the otim family. Opcodes
+ * the Z180 traps on, such as sll and the undocumented Z80 index register halves, decode as `Mnemonic::Invalid`, which
+ * formats as a db of the instruction's bytes.
+ *
+ * Instructions are written in Zilog syntax, as in `out0 (CNTLA0), a`, or in the upper case, H suffixed hex style of
+ * Hitachi's HD64180 assembler, as in `OUT0 (CNTLA0),A`. The ports of in0 and out0 are named for the Z180's internal I/O
+ * registers.
+ *
+ * Known limitations:
+ *  1. Internal I/O registers are named at their reset addresses, as if ICR had not moved them
+ *  2. The ports of in and out (n) aren't named, as A supplies the high byte of their address
+ */
+use crate::cpu::{Flags, Register};
+use crate::symbols::Symbols;
+
+/// An instruction mnemonic.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Mnemonic {
+    Adc,
+    Add,
+    And,
+    Bit,
+    Call,
+    Ccf,
+    Cp,
+    Cpd,
+    Cpdr,
+    Cpi,
+    Cpir,
+    Cpl,
+    Daa,
+    Dec,
+    Di,
+    Djnz,
+    Ei,
+    Ex,
+    Exx,
+    Halt,
+    Im,
+    In,
+    In0,
+    Inc,
+    Ind,
+    Indr,
+    Ini,
+    Inir,
+    Jp,
+    Jr,
+    Ld,
+    Ldd,
+    Lddr,
+    Ldi,
+    Ldir,
+    Mlt,
+    Neg,
+    Nop,
+    Or,
+    Otdm,
+    Otdmr,
+    Otdr,
+    Otim,
+    Otimr,
+    Otir,
+    Out,
+    Out0,
+    Outd,
+    Outi,
+    Pop,
+    Push,
+    Res,
+    Ret,
+    Reti,
+    Retn,
+    Rl,
+    Rla,
+    Rlc,
+    Rlca,
+    Rld,
+    Rr,
+    Rra,
+    Rrc,
+    Rrca,
+    Rrd,
+    Rst,
+    Sbc,
+    Scf,
+    Set,
+    Slp,
+    Sla,
+    Sra,
+    Srl,
+    Sub,
+    Tst,
+    Tstio,
+    Xor,
+    /// An opcode the Z180 traps on, or the decoder doesn't know.
+    Invalid,
+}
 
-        0b00_000_001 => format!("out0\t(${:02x}), b", opcodes[1]),
-        0b00_001_001 => format!("out0\t(${:02x}), c", opcodes[1]),
-        0b00_010_001 => format!("out0\t(${:02x}), d", opcodes[1]),
-        0b00_011_001 => format!("out0\t(${:02x}), e", opcodes[1]),
-        0b00_100_001 => format!("out0\t(${:02x}), h", opcodes[1]),
-        0b00_101_001 => format!("out0\t(${:02x}), l", opcodes[1]),
-        0b00_111_001 => format!("out0\t(${:02x}), a", opcodes[1]),
+impl Mnemonic {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Mnemonic::Adc => "adc",
+            Mnemonic::Add => "add",
+            Mnemonic::And => "and",
+            Mnemonic::Bit => "bit",
+            Mnemonic::Call => "call",
+            Mnemonic::Ccf => "ccf",
+            Mnemonic::Cp => "cp",
+            Mnemonic::Cpd => "cpd",
+            Mnemonic::Cpdr => "cpdr",
+            Mnemonic::Cpi => "cpi",
+            Mnemonic::Cpir => "cpir",
+            Mnemonic::Cpl => "cpl",
+            Mnemonic::Daa => "daa",
+            Mnemonic::Dec => "dec",
+            Mnemonic::Di => "di",
+            Mnemonic::Djnz => "djnz",
+            Mnemonic::Ei => "ei",
+            Mnemonic::Ex => "ex",
+            Mnemonic::Exx => "exx",
+            Mnemonic::Halt => "halt",
+            Mnemonic::Im => "im",
+            Mnemonic::In => "in",
+            Mnemonic::In0 => "in0",
+            Mnemonic::Inc => "inc",
+            Mnemonic::Ind => "ind",
+            Mnemonic::Indr => "indr",
+            Mnemonic::Ini => "ini",
+            Mnemonic::Inir => "inir",
+            Mnemonic::Jp => "jp",
+            Mnemonic::Jr => "jr",
+            Mnemonic::Ld => "ld",
+            Mnemonic::Ldd => "ldd",
+            Mnemonic::Lddr => "lddr",
+            Mnemonic::Ldi => "ldi",
+            Mnemonic::Ldir => "ldir",
+            Mnemonic::Mlt => "mlt",
+            Mnemonic::Neg => "neg",
+            Mnemonic::Nop => "nop",
+            Mnemonic::Or => "or",
+            Mnemonic::Otdm => "otdm",
+            Mnemonic::Otdmr => "otdmr",
+            Mnemonic::Otdr => "otdr",
+            Mnemonic::Otim => "otim",
+            Mnemonic::Otimr => "otimr",
+            Mnemonic::Otir => "otir",
+            Mnemonic::Out => "out",
+            Mnemonic::Out0 => "out0",
+            Mnemonic::Outd => "outd",
+            Mnemonic::Outi => "outi",
+            Mnemonic::Pop => "pop",
+            Mnemonic::Push => "push",
+            Mnemonic::Res => "res",
+            Mnemonic::Ret => "ret",
+            Mnemonic::Reti => "reti",
+            Mnemonic::Retn => "retn",
+            Mnemonic::Rl => "rl",
+            Mnemonic::Rla => "rla",
+            Mnemonic::Rlc => "rlc",
+            Mnemonic::Rlca => "rlca",
+            Mnemonic::Rld => "rld",
+            Mnemonic::Rr => "rr",
+            Mnemonic::Rra => "rra",
+            Mnemonic::Rrc => "rrc",
+            Mnemonic::Rrca => "rrca",
+            Mnemonic::Rrd => "rrd",
+            Mnemonic::Rst => "rst",
+            Mnemonic::Sbc => "sbc",
+            Mnemonic::Scf => "scf",
+            Mnemonic::Set => "set",
+            Mnemonic::Slp => "slp",
+            Mnemonic::Sla => "sla",
+            Mnemonic::Sra => "sra",
+            Mnemonic::Srl => "srl",
+            Mnemonic::Sub => "sub",
+            Mnemonic::Tst => "tst",
+            Mnemonic::Tstio => "tstio",
+            Mnemonic::Xor => "xor",
+            Mnemonic::Invalid => "db",
+        }
+    }
+}
 
-        0b00_000_100 => format!("tst\tb"),
-        0b00_001_100 => format!("tst\tc"),
-        0b00_010_100 => format!("tst\td"),
-        0b00_011_100 => format!("tst\te"),
-        0b00_100_100 => format!("tst\th"),
-        0b00_101_100 => format!("tst\tl"),
-        0b00_110_100 => format!("tst\t(hl)"),
-        0b00_111_100 => format!("tst\ta"),
-        0b01_100_100 => format!("tst\t{:08b}b", opcodes[1]),
+/// The condition of a conditional jump, call or return.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Condition {
+    NonZero,
+    Zero,
+    NoCarry,
+    Carry,
+    ParityOdd,
+    ParityEven,
+    Plus,
+    Minus,
+}
 
-        0b01_001_101 => "reti".to_string(),
+impl Condition {
+    const ALL: [Condition; 8] = [
+        Condition::NonZero,
+        Condition::Zero,
+        Condition::NoCarry,
+        Condition::Carry,
+        Condition::ParityOdd,
+        Condition::ParityEven,
+        Condition::Plus,
+        Condition::Minus,
+    ];
+
+    /// The condition with a name, in either case.
+    pub fn from_name(name: &str) -> Option<Condition> {
+        Condition::ALL
+            .iter()
+            .copied()
+            .find(|condition| condition.name().eq_ignore_ascii_case(name))
+    }
 
-        0b01_000_100 => "neg".to_string(),
+    pub fn name(&self) -> &'static str {
+        match self {
+            Condition::NonZero => "nz",
+            Condition::Zero => "z",
+            Condition::NoCarry => "nc",
+            Condition::Carry => "c",
+            Condition::ParityOdd => "po",
+            Condition::ParityEven => "pe",
+            Condition::Plus => "p",
+            Condition::Minus => "m",
+        }
+    }
+}
 
-        0b01_000_010 => "sbc\thl, bc".to_string(),
-        0b01_010_010 => "sbc\thl, de".to_string(),
-        0b01_100_010 => "sbc\thl, hl".to_string(),
-        0b01_110_010 => "sbc\thl, sp".to_string(),
+/// An instruction operand.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Operand {
+    /// A register, of 8 or 16 bits.
+    Register(Register),
+    /// Memory addressed by a register pair, as in (hl).
+    Indirect(Register),
+    /// Memory addressed by an index register and a displacement, as in (ix+5).
+    Indexed(Register, i8),
+    /// An 8 bit value.
+    Byte(u8),
+    /// A 16 bit value.
+    Word(u16),
+    /// Memory at an address, as in ($8000).
+    Memory(u16),
+    /// An I/O port, as in ($40).
+    Port(u8),
+    /// The I/O port addressed by BC, written (c).
+    PortC,
+    /// The address a jump, call or restart goes to.
+    Address(u16),
+    /// The displacement of a relative jump from the instruction that follows it.
+    Relative(i8),
+    /// The condition of a conditional instruction.
+    Condition(Condition),
+    /// A bit number.
+    Bit(u8),
+    /// An interrupt mode.
+    Mode(u8),
+}
 
-        0b01_000_011 => format!("ld\t(${:02x}{:02x}), bc", opcodes[2], opcodes[1]),
-        0b01_010_011 => format!("ld\t(${:02x}{:02x}), de", opcodes[2], opcodes[1]),
+/// How an instruction affects the flow of control.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Flow {
+    /// Runs on to the next instruction.
+    Next,
+    /// Jumps elsewhere, always.
+    Jump,
+    /// Jumps elsewhere or runs on, by a condition.
+    Branch,
+    /// Calls a subroutine or restart, if its condition holds.
+    Call,
+    /// Returns from a subroutine or interrupt, if its condition holds.
+    Return,
+    /// Stops until an interrupt or reset.
+    Halt,
+}
 
-        0b01_001_011 => format!("ld\tbc, (${:02x}{:02x})", opcodes[2], opcodes[1]),
-        0b01_011_011 => format!("ld\tde, (${:02x}{:02x})", opcodes[2], opcodes[1]),
+/// A decoded instruction.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Instruction {
+    pub mnemonic: Mnemonic,
+    pub operands: Vec<Operand>,
+    /// The instruction's length in bytes.
+    pub length: usize,
+    /// The flags the instruction may change.
+    pub flags: Flags,
+}
 
-        0b01_110_011 => format!("ld\t(${:02x}{:02x}), sp", opcodes[2], opcodes[1]),
-        0b01_111_011 => format!("ld\tsp, (${:02x}{:02x})", opcodes[2], opcodes[1]),
+impl Instruction {
+    /// How the instruction affects the flow of control.
+    pub fn flow(&self) -> Flow {
+        match self.mnemonic {
+            Mnemonic::Jp | Mnemonic::Jr if self.is_conditional() => Flow::Branch,
+            Mnemonic::Jp | Mnemonic::Jr => Flow::Jump,
+            Mnemonic::Djnz => Flow::Branch,
+            Mnemonic::Call | Mnemonic::Rst => Flow::Call,
+            Mnemonic::Ret | Mnemonic::Reti | Mnemonic::Retn => Flow::Return,
+            Mnemonic::Halt | Mnemonic::Slp => Flow::Halt,
+            _ => Flow::Next,
+        }
+    }
+
+    /// Answer whether the instruction is a conditional jump, call or return, including djnz.
+    pub fn is_conditional(&self) -> bool {
+        self.mnemonic == Mnemonic::Djnz || self.operands.iter().any(|operand| matches!(operand, Operand::Condition(_)))
+    }
 
-        0b01_100_111 => "rrd".to_string(),
-        0b01_101_111 => "rld".to_string(),
+    /// Where a jump, call or restart at `address` goes, if it's known without running it.
+    pub fn target(&self, address: u16) -> Option<u16> {
+        self.operands.iter().find_map(|operand| match *operand {
+            Operand::Address(target) => Some(target),
+            Operand::Relative(offset) => Some(address.wrapping_add(self.length as u16).wrapping_add(offset as u16)),
+            _ => None,
+        })
+    }
 
-        0b10_100_000 => "ldi".to_string(),
-        0b10_110_000 => "ldir".to_string(),
+    /// Format the instruction at `address` in Zilog syntax, as a mnemonic and operands separated by a tab, naming the
+    /// targets of jumps and calls by the nearest symbol, other 16 bit operands only where a symbol names them exactly,
+    /// and the Z180's internal I/O registers by their names.
+    pub fn text(&self, address: u16, symbols: &Symbols) -> String {
+        self.format(Syntax::Zilog, Some(address), symbols)
+    }
 
-        0b10_100_001 => "cpi".to_string(),
-        0b10_110_001 => "cpir".to_string(),
+    /// Format the instruction at `address` as `text` does, in the given syntax.
+    pub fn text_as(&self, syntax: Syntax, address: u16, symbols: &Symbols) -> String {
+        self.format(syntax, Some(address), symbols)
+    }
 
-        0b10_100_010 => "ini".to_string(),
-        0b10_110_010 => "inir".to_string(),
+    fn format(&self, syntax: Syntax, address: Option<u16>, symbols: &Symbols) -> String {
+        let mnemonic = syntax.case(self.mnemonic.name());
+        if self.operands.is_empty() {
+            return mnemonic;
+        }
+        let exact = |value: u16| {
+            symbols
+                .name_of(value)
+                .map(String::from)
+                .unwrap_or_else(|| syntax.hex(value as u32, 4))
+        };
+        let nearest = |value: u16| symbols.describe(value).unwrap_or_else(|| syntax.hex(value as u32, 4));
+        let internal = matches!(self.mnemonic, Mnemonic::In0 | Mnemonic::Out0);
+        let operands: Vec<String> = self
+            .operands
+            .iter()
+            .map(|operand| match *operand {
+                Operand::Register(register) => syntax.case(register_name(register)),
+                Operand::Indirect(register) => format!("({})", syntax.case(register_name(register))),
+                Operand::Indexed(register, displacement) => {
+                    format!("({}{:+})", syntax.case(register_name(register)), displacement)
+                }
+                Operand::Byte(value) => syntax.hex(value as u32, 2),
+                Operand::Word(value) => exact(value),
+                Operand::Memory(address) => format!("({})", exact(address)),
+                Operand::Port(port) => match port_name(port) {
+                    Some(name) if internal => format!("({})", name),
+                    _ => format!("({})", syntax.hex(port as u32, 2)),
+                },
+                Operand::PortC => syntax.case("(c)"),
+                Operand::Address(target) if self.mnemonic == Mnemonic::Rst => syntax.hex(target as u32, 2),
+                Operand::Address(target) => nearest(target),
+                Operand::Relative(offset) => match address {
+                    Some(address) => nearest(self.target(address).unwrap_or_default()),
+                    None => offset.to_string(),
+                },
+                Operand::Condition(condition) => syntax.case(condition.name()),
+                Operand::Bit(bit) => bit.to_string(),
+                Operand::Mode(mode) => mode.to_string(),
+            })
+            .collect();
+        format!("{}\t{}", mnemonic, operands.join(syntax.separator()))
+    }
+}
 
-        0b10_100_011 => "outi".to_string(),
-        0b10_110_011 => "outir".to_string(),
+/// How instructions are written.
+#[derive(Debug, PartialEq, Eq, Copy, Clone)]
+pub enum Syntax {
+    /// Zilog's, in lower case with $ marking hex numbers: ld a, ($f600)
+    Zilog,
+    /// Hitachi's HD64180 assembler's, in upper case with H marking hex numbers: LD A,(0F600H)
+    Hitachi,
+}
 
-        0b10_101_000 => "ldd".to_string(),
-        0b10_111_000 => "lddr".to_string(),
+impl Syntax {
+    pub fn from_name(name: &str) -> Option<Syntax> {
+        match name {
+            "zilog" => Some(Syntax::Zilog),
+            "hitachi" => Some(Syntax::Hitachi),
+            _ => None,
+        }
+    }
 
-        0b10_101_001 => "cpd".to_string(),
-        0b10_111_001 => "cpdr".to_string(),
+    // A mnemonic or register name in this syntax's case.
+    pub(crate) fn case(&self, name: &str) -> String {
+        match self {
+            Syntax::Zilog => name.to_string(),
+            Syntax::Hitachi => name.to_ascii_uppercase(),
+        }
+    }
 
-        0b10_101_010 => "ind".to_string(),
-        0b10_111_010 => "indr".to_string(),
+    /// A number in hex, with at least `digits` digits.
+    pub fn hex(&self, value: u32, digits: usize) -> String {
+        match self {
+            Syntax::Zilog => format!("${:01$x}", value, digits),
+            Syntax::Hitachi => {
+                let hex = format!("{:01$X}H", value, digits);
+                // a number can't start with a letter
+                match hex.starts_with(|c: char| c.is_ascii_alphabetic()) {
+                    true => format!("0{}", hex),
+                    false => hex,
+                }
+            }
+        }
+    }
 
-        0b10_101_011 => "outd".to_string(),
-        0b10_111_011 => "outdr".to_string(),
+    pub(crate) fn separator(&self) -> &'static str {
+        match self {
+            Syntax::Zilog => ", ",
+            Syntax::Hitachi => ",",
+        }
+    }
+}
+
+/// The name of the Z180 internal I/O register at a port, with the registers at their reset addresses, $00 to $3f.
+pub fn port_name(port: u8) -> Option<&'static str> {
+    let name = match port {
+        0x00 => "CNTLA0",
+        0x01 => "CNTLA1",
+        0x02 => "CNTLB0",
+        0x03 => "CNTLB1",
+        0x04 => "STAT0",
+        0x05 => "STAT1",
+        0x06 => "TDR0",
+        0x07 => "TDR1",
+        0x08 => "RDR0",
+        0x09 => "RDR1",
+        0x0a => "CNTR",
+        0x0b => "TRDR",
+        0x0c => "TMDR0L",
+        0x0d => "TMDR0H",
+        0x0e => "RLDR0L",
+        0x0f => "RLDR0H",
+        0x10 => "TCR",
+        0x12 => "ASEXT0",
+        0x13 => "ASEXT1",
+        0x14 => "TMDR1L",
+        0x15 => "TMDR1H",
+        0x16 => "RLDR1L",
+        0x17 => "RLDR1H",
+        0x18 => "FRC",
+        0x1a => "ASTC0L",
+        0x1b => "ASTC0H",
+        0x1c => "ASTC1L",
+        0x1d => "ASTC1H",
+        0x1e => "CMR",
+        0x1f => "CCR",
+        0x20 => "SAR0L",
+        0x21 => "SAR0H",
+        0x22 => "SAR0B",
+        0x23 => "DAR0L",
+        0x24 => "DAR0H",
+        0x25 => "DAR0B",
+        0x26 => "BCR0L",
+        0x27 => "BCR0H",
+        0x28 => "MAR1L",
+        0x29 => "MAR1H",
+        0x2a => "MAR1B",
+        0x2b => "IAR1L",
+        0x2c => "IAR1H",
+        0x2d => "IAR1B",
+        0x2e => "BCR1L",
+        0x2f => "BCR1H",
+        0x30 => "DSTAT",
+        0x31 => "DMODE",
+        0x32 => "DCNTL",
+        0x33 => "IL",
+        0x34 => "ITC",
+        0x36 => "RCR",
+        0x38 => "CBR",
+        0x39 => "BBR",
+        0x3a => "CBAR",
+        0x3e => "OMCR",
+        0x3f => "ICR",
+        _ => return None,
+    };
+    Some(name)
+}
+
+fn register_name(register: Register) -> &'static str {
+    match register {
+        Register::A => "a",
+        Register::F => "f",
+        Register::B => "b",
+        Register::C => "c",
+        Register::D => "d",
+        Register::E => "e",
+        Register::H => "h",
+        Register::L => "l",
+        Register::AF => "af",
+        Register::BC => "bc",
+        Register::DE => "de",
+        Register::HL => "hl",
+        Register::I => "i",
+        Register::R => "r",
+        Register::IX => "ix",
+        Register::IY => "iy",
+        Register::SP => "sp",
+        Register::PC => "pc",
+        Register::AltAF => "af'",
+        Register::AltBC => "bc'",
+        Register::AltDE => "de'",
+        Register::AltHL => "hl'",
+    }
+}
+
+fn is_pair(register: Register) -> bool {
+    matches!(
+        register,
+        Register::AF | Register::BC | Register::DE | Register::HL | Register::IX | Register::IY | Register::SP
+    )
+}
+
+// The 8 bit registers by their three bit code, with (hl) as 6.
+fn reg8(code: u8) -> Operand {
+    match code & 7 {
+        0 => Operand::Register(Register::B),
+        1 => Operand::Register(Register::C),
+        2 => Operand::Register(Register::D),
+        3 => Operand::Register(Register::E),
+        4 => Operand::Register(Register::H),
+        5 => Operand::Register(Register::L),
+        6 => Operand::Indirect(Register::HL),
+        _ => Operand::Register(Register::A),
+    }
+}
+
+// The register pairs by their two bit code, with SP as 3 or, for push and pop, AF.
+fn pair(code: u8, af: bool) -> Register {
+    match code & 3 {
+        0 => Register::BC,
+        1 => Register::DE,
+        2 => Register::HL,
+        _ if af => Register::AF,
+        _ => Register::SP,
+    }
+}
+
+fn word(opcodes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([byte(opcodes, at), byte(opcodes, at + 1)])
+}
+
+fn byte(opcodes: &[u8], at: usize) -> u8 {
+    opcodes.get(at).copied().unwrap_or(0)
+}
+
+/// Decode the instruction starting at opcodes[0]. Bytes past the end of `opcodes` are taken as zero.
+pub fn decode(opcodes: &[u8]) -> Instruction {
+    let (mnemonic, operands) = match opcodes[0] {
+        0xcb => bits(byte(opcodes, 1), reg8(byte(opcodes, 1))),
+        0xed => extended(&opcodes[1..]),
+        0xdd => index(&opcodes[1..], Register::IX),
+        0xfd => index(&opcodes[1..], Register::IY),
+        op => unprefixed(op, opcodes),
+    };
+    finish(mnemonic, operands, opcodes)
+}
+
+fn finish(mnemonic: Mnemonic, operands: Vec<Operand>, opcodes: &[u8]) -> Instruction {
+    let length = length(opcodes);
+    let operands = match mnemonic {
+        Mnemonic::Invalid => (0..length).map(|i| Operand::Byte(byte(opcodes, i))).collect(),
+        _ => operands,
+    };
+    let flags = affected(mnemonic, &operands);
+    Instruction {
+        mnemonic,
+        operands,
+        length,
+        flags,
+    }
+}
 
-        _ => format!("extd\t${:02x}", opcodes[0]),
+// An instruction without a prefix, decoded by the fields of its opcode: xx_yyy_zzz, with yyy split into pp_q.
+fn unprefixed(op: u8, opcodes: &[u8]) -> (Mnemonic, Vec<Operand>) {
+    use Mnemonic::*;
+    use Operand::{Indirect, Memory, Port, Relative};
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let n = Operand::Byte(byte(opcodes, 1));
+    let nn = word(opcodes, 1);
+    let a = Operand::Register(Register::A);
+    let hl = Operand::Register(Register::HL);
+    let rp = Operand::Register(pair(p, false));
+    let condition = Operand::Condition(Condition::ALL[y as usize]);
+    let relative = Relative(byte(opcodes, 1) as i8);
+    match (x, z) {
+        (0, 0) => match y {
+            0 => (Nop, vec![]),
+            1 => (Ex, vec![Operand::Register(Register::AF), Operand::Register(Register::AltAF)]),
+            2 => (Djnz, vec![relative]),
+            3 => (Jr, vec![relative]),
+            _ => (Jr, vec![Operand::Condition(Condition::ALL[y as usize - 4]), relative]),
+        },
+        (0, 1) if q == 0 => (Ld, vec![rp, Operand::Word(nn)]),
+        (0, 1) => (Add, vec![hl, rp]),
+        (0, 2) => {
+            let (register, memory) = match p {
+                0 => (a, Indirect(Register::BC)),
+                1 => (a, Indirect(Register::DE)),
+                2 => (hl, Memory(nn)),
+                _ => (a, Memory(nn)),
+            };
+            match q {
+                0 => (Ld, vec![memory, register]),
+                _ => (Ld, vec![register, memory]),
+            }
+        }
+        (0, 3) => (if q == 0 { Inc } else { Dec }, vec![rp]),
+        (0, 4) => (Inc, vec![reg8(y)]),
+        (0, 5) => (Dec, vec![reg8(y)]),
+        (0, 6) => (Ld, vec![reg8(y), n]),
+        (0, _) => ([Rlca, Rrca, Rla, Rra, Daa, Cpl, Scf, Ccf][y as usize], vec![]),
+        (1, 6) if y == 6 => (Halt, vec![]),
+        (1, _) => (Ld, vec![reg8(y), reg8(z)]),
+        (2, _) => alu(y, reg8(z)),
+        (_, 0) => (Ret, vec![condition]),
+        (_, 1) if q == 0 => (Pop, vec![Operand::Register(pair(p, true))]),
+        (_, 1) => match p {
+            0 => (Ret, vec![]),
+            1 => (Exx, vec![]),
+            2 => (Jp, vec![Indirect(Register::HL)]),
+            _ => (Ld, vec![Operand::Register(Register::SP), hl]),
+        },
+        (_, 2) => (Jp, vec![condition, Operand::Address(nn)]),
+        (_, 3) => match y {
+            0 => (Jp, vec![Operand::Address(nn)]),
+            2 => (Out, vec![Port(byte(opcodes, 1)), a]),
+            3 => (In, vec![a, Port(byte(opcodes, 1))]),
+            4 => (Ex, vec![Indirect(Register::SP), hl]),
+            5 => (Ex, vec![Operand::Register(Register::DE), hl]),
+            6 => (Di, vec![]),
+            _ => (Ei, vec![]),
+        },
+        (_, 4) => (Call, vec![condition, Operand::Address(nn)]),
+        (_, 5) if q == 0 => (Push, vec![Operand::Register(pair(p, true))]),
+        (_, 5) => (Call, vec![Operand::Address(nn)]),
+        (_, 6) => alu(y, n),
+        (_, _) => (Rst, vec![Operand::Address(y as u16 * 8)]),
     }
 }
 
-fn bits(opcodes: &[u8]) -> String {
+// An arithmetic or logical operation on the accumulator, by its three bit code.
+fn alu(code: u8, operand: Operand) -> (Mnemonic, Vec<Operand>) {
+    let a = Operand::Register(Register::A);
+    match code & 7 {
+        0 => (Mnemonic::Add, vec![a, operand]),
+        1 => (Mnemonic::Adc, vec![a, operand]),
+        2 => (Mnemonic::Sub, vec![operand]),
+        3 => (Mnemonic::Sbc, vec![a, operand]),
+        4 => (Mnemonic::And, vec![operand]),
+        5 => (Mnemonic::Xor, vec![operand]),
+        6 => (Mnemonic::Or, vec![operand]),
+        _ => (Mnemonic::Cp, vec![operand]),
+    }
+}
+
+// A CB prefixed rotate, shift or bit operation on an operand.
+fn bits(op: u8, operand: Operand) -> (Mnemonic, Vec<Operand>) {
+    use Mnemonic::*;
+    let bit = Operand::Bit((op >> 3) & 7);
+    match op >> 6 {
+        // there's no sll on the Z180
+        0 if op & 0x38 == 0x30 => (Invalid, vec![]),
+        0 => (
+            [Rlc, Rrc, Rl, Rr, Sla, Sra, Invalid, Srl][(op >> 3) as usize & 7],
+            vec![operand],
+        ),
+        1 => (Bit, vec![bit, operand]),
+        2 => (Res, vec![bit, operand]),
+        _ => (Set, vec![bit, operand]),
+    }
+}
+
+// An ED prefixed instruction, starting at the byte after the prefix.
+fn extended(opcodes: &[u8]) -> (Mnemonic, Vec<Operand>) {
+    use Mnemonic::*;
+    use Operand::Register as R;
+    let op = opcodes[0];
+    let (x, y, z) = (op >> 6, (op >> 3) & 7, op & 7);
+    let (p, q) = (y >> 1, y & 1);
+    let n = byte(opcodes, 1);
+    let rp = R(pair(p, false));
+    match (x, z) {
+        // in0 (n) only sets the flags
+        (0, 0) if y == 6 => (In0, vec![Operand::Port(n)]),
+        (0, 0) => (In0, vec![reg8(y), Operand::Port(n)]),
+        (0, 1) if y != 6 => (Out0, vec![Operand::Port(n), reg8(y)]),
+        (0, 4) => (Tst, vec![reg8(y)]),
+        (1, 0) if y != 6 => (In, vec![reg8(y), Operand::PortC]),
+        (1, 1) if y != 6 => (Out, vec![Operand::PortC, reg8(y)]),
+        (1, 2) => (if q == 0 { Sbc } else { Adc }, vec![R(Register::HL), rp]),
+        (1, 3) if q == 0 => (Ld, vec![Operand::Memory(word(opcodes, 1)), rp]),
+        (1, 3) => (Ld, vec![rp, Operand::Memory(word(opcodes, 1))]),
+        (1, 4) => match y {
+            0 => (Neg, vec![]),
+            4 => (Tst, vec![Operand::Byte(n)]),
+            6 => (Tstio, vec![Operand::Byte(n)]),
+            _ if q == 1 => (Mlt, vec![rp]),
+            _ => (Invalid, vec![]),
+        },
+        (1, 5) if y == 0 => (Retn, vec![]),
+        (1, 5) if y == 1 => (Reti, vec![]),
+        (1, 6) => match y {
+            0 => (Im, vec![Operand::Mode(0)]),
+            2 => (Im, vec![Operand::Mode(1)]),
+            3 => (Im, vec![Operand::Mode(2)]),
+            6 => (Slp, vec![]),
+            _ => (Invalid, vec![]),
+        },
+        (1, 7) => match y {
+            0 => (Ld, vec![R(Register::I), R(Register::A)]),
+            1 => (Ld, vec![R(Register::R), R(Register::A)]),
+            2 => (Ld, vec![R(Register::A), R(Register::I)]),
+            3 => (Ld, vec![R(Register::A), R(Register::R)]),
+            4 => (Rrd, vec![]),
+            5 => (Rld, vec![]),
+            _ => (Invalid, vec![]),
+        },
+        (2, 3) if y < 4 => ([Otim, Otdm, Otimr, Otdmr][y as usize], vec![]),
+        (2, 0..=3) if y >= 4 => {
+            let block = [
+                [Ldi, Cpi, Ini, Outi],
+                [Ldd, Cpd, Ind, Outd],
+                [Ldir, Cpir, Inir, Otir],
+                [Lddr, Cpdr, Indr, Otdr],
+            ];
+            (block[y as usize - 4][z as usize], vec![])
+        }
+        _ => (Invalid, vec![]),
+    }
+}
+
+// A DD or FD prefixed instruction using the index register `ix`, starting at the byte after the prefix. These are
+// the unprefixed instructions on HL or (HL), with the index register or the index register and a displacement in its
+// place; the Z180 traps on the rest.
+fn index(opcodes: &[u8], ix: Register) -> (Mnemonic, Vec<Operand>) {
+    let op = opcodes[0];
+    let displacement = byte(opcodes, 1) as i8;
+    if op == 0xcb {
+        return match byte(opcodes, 2) & 7 {
+            6 => bits(byte(opcodes, 2), Operand::Indexed(ix, displacement)),
+            _ => (Mnemonic::Invalid, vec![]),
+        };
+    }
+    let (mnemonic, operands) = unprefixed(op, opcodes);
+    let mut indexed = false;
+    let operands = operands
+        .into_iter()
+        .map(|operand| match operand {
+            Operand::Register(Register::HL) => {
+                indexed = true;
+                Operand::Register(ix)
+            }
+            Operand::Indirect(Register::HL) => {
+                indexed = true;
+                match mnemonic {
+                    Mnemonic::Jp => Operand::Indirect(ix),
+                    _ => Operand::Indexed(ix, displacement),
+                }
+            }
+            // ld (ix+d), n takes its value after the displacement
+            Operand::Byte(_) => Operand::Byte(byte(opcodes, 2)),
+            operand => operand,
+        })
+        .collect();
+    // ex de, hl has no indexed form
+    if !indexed || op == 0xeb {
+        return (Mnemonic::Invalid, vec![]);
+    }
+    (mnemonic, operands)
+}
+
+// The flags an instruction may change, as opcodes.md lists them. Popping or exchanging AF changes them all.
+fn affected(mnemonic: Mnemonic, operands: &[Operand]) -> Flags {
+    use Mnemonic::*;
+    let szhpn = Flags::SF | Flags::ZF | Flags::HF | Flags::PF | Flags::NF;
+    // 16 bit arithmetic
+    let wide = matches!(operands.first(), Some(Operand::Register(register)) if is_pair(*register));
+    match mnemonic {
+        Add if wide => Flags::HF | Flags::NF | Flags::CF,
+        Add | Adc | Sub | Sbc | Cp | Neg | And | Or | Xor | Tst | Tstio => Flags::all(),
+        Inc | Dec if wide => Flags::empty(),
+        Inc | Dec => szhpn,
+        Rlca | Rrca | Rla | Rra | Scf | Ccf => Flags::HF | Flags::NF | Flags::CF,
+        Rlc | Rrc | Rl | Rr | Sla | Sra | Srl => Flags::all(),
+        Bit | Cpi | Cpd | Cpir | Cpdr | In0 | Rrd | Rld => szhpn,
+        In if operands.contains(&Operand::PortC) => szhpn,
+        Daa => Flags::SF | Flags::ZF | Flags::HF | Flags::PF | Flags::CF,
+        Cpl => Flags::HF | Flags::NF,
+        Ldi | Ldd | Ldir | Lddr => Flags::HF | Flags::PF | Flags::NF,
+        Ini | Ind | Inir | Indr | Outi | Outd | Otir | Otdr | Otim | Otdm | Otimr | Otdmr => Flags::all(),
+        Ld if matches!(
+            operands,
+            [Operand::Register(Register::A), Operand::Register(Register::I | Register::R)]
+        ) =>
+        {
+            szhpn
+        }
+        Pop | Ex if operands.first() == Some(&Operand::Register(Register::AF)) => Flags::all(),
+        _ => Flags::empty(),
+    }
+}
+
+/// Disassemble the instruction starting at opcodes[0], showing relative jumps by their displacement.
+pub fn disasm(opcodes: &[u8]) -> String {
+    decode(opcodes).format(Syntax::Zilog, None, &Symbols::new())
+}
+
+/// Disassemble the instruction at `address`, naming addresses with symbols: the targets of jumps and calls by the
+/// nearest symbol, relative jumps included, and other 16 bit operands only where a symbol names them exactly.
+pub fn disasm_with_symbols(opcodes: &[u8], address: u16, symbols: &Symbols) -> String {
+    decode(opcodes).text(address, symbols)
+}
+
+// The length in bytes of the instruction starting at opcodes[0].
+pub fn length(opcodes: &[u8]) -> usize {
+    let next = opcodes.get(1).copied().unwrap_or(0);
     match opcodes[0] {
-        // RLC g/(HL)
-        0b00_000_000 => "rlc\tb".to_string(),
-        0b00_000_001 => "rlc\tc".to_string(),
-        0b00_000_010 => "rlc\td".to_string(),
-        0b00_000_011 => "rlc\te".to_string(),
-        0b00_000_100 => "rlc\th".to_string(),
-        0b00_000_101 => "rlc\tl".to_string(),
-        0b00_000_110 => "rlc\t(hl)".to_string(),
-        0b00_000_111 => "rlc\ta".to_string(),
-
-        // RRC g/(HL)
-        0b00_001_000 => "rrc\tb".to_string(),
-        0b00_001_001 => "rrc\tc".to_string(),
-        0b00_001_010 => "rrc\td".to_string(),
-        0b00_001_011 => "rrc\te".to_string(),
-        0b00_001_100 => "rrc\th".to_string(),
-        0b00_001_101 => "rrc\tl".to_string(),
-        0b00_001_110 => "rrc\t(hl)".to_string(),
-        0b00_001_111 => "rrc\ta".to_string(),
-
-        // RL g/(HL)
-        0b00_010_000 => "rl\tb".to_string(),
-        0b00_010_001 => "rl\tc".to_string(),
-        0b00_010_010 => "rl\td".to_string(),
-        0b00_010_011 => "rl\te".to_string(),
-        0b00_010_100 => "rl\th".to_string(),
-        0b00_010_101 => "rl\tl".to_string(),
-        0b00_010_110 => "rl\t(hl)".to_string(),
-        0b00_010_111 => "rl\ta".to_string(),
-
-        // RR g/(HL)
-        0b00_011_000 => "rr\tb".to_string(),
-        0b00_011_001 => "rr\tc".to_string(),
-        0b00_011_010 => "rr\td".to_string(),
-        0b00_011_011 => "rr\te".to_string(),
-        0b00_011_100 => "rr\th".to_string(),
-        0b00_011_101 => "rr\tl".to_string(),
-        0b00_011_110 => "rr\t(hl)".to_string(),
-        0b00_011_111 => "rr\ta".to_string(),
-
-        // SLA g/(HL)
-        0b00_100_000 => "sla\tb".to_string(),
-        0b00_100_001 => "sla\tc".to_string(),
-        0b00_100_010 => "sla\td".to_string(),
-        0b00_100_011 => "sla\te".to_string(),
-        0b00_100_100 => "sla\th".to_string(),
-        0b00_100_101 => "sla\tl".to_string(),
-        0b00_100_110 => "sla\t(hl)".to_string(),
-        0b00_100_111 => "sla\ta".to_string(),
-
-        // SRA g/(HL)
-        0b00_101_000 => "sra\tb".to_string(),
-        0b00_101_001 => "sra\tc".to_string(),
-        0b00_101_010 => "sra\td".to_string(),
-        0b00_101_011 => "sra\te".to_string(),
-        0b00_101_100 => "sra\th".to_string(),
-        0b00_101_101 => "sra\tl".to_string(),
-        0b00_101_110 => "sra\t(hl)".to_string(),
-        0b00_101_111 => "sra\ta".to_string(),
-
-        // SLL g/(HL)
-        0b00_110_000 => "sll!\tb".to_string(),
-        0b00_110_001 => "sll!\tc".to_string(),
-        0b00_110_010 => "sll!\td".to_string(),
-        0b00_110_011 => "sll!\te".to_string(),
-        0b00_110_100 => "sll!\th".to_string(),
-        0b00_110_101 => "sll!\tl".to_string(),
-        0b00_110_110 => "sll!\t(hl)".to_string(),
-        0b00_110_111 => "sll!\ta".to_string(),
-
-        // SRA g/(HL)
-        0b00_111_000 => "srl\tb".to_string(),
-        0b00_111_001 => "srl\tc".to_string(),
-        0b00_111_010 => "srl\td".to_string(),
-        0b00_111_011 => "srl\te".to_string(),
-        0b00_111_100 => "srl\th".to_string(),
-        0b00_111_101 => "srl\tl".to_string(),
-        0b00_111_110 => "srl\t(hl)".to_string(),
-        0b00_111_111 => "srl\ta".to_string(),
-
-        _ => format!("bits\t${:02x}", opcodes[0]),
-    }
-}
-
-// Index register opcodes. The opcode sets are identical for IX and IY.
-fn index(opcodes: &[u8], reg: &str) -> String {
+        0xcb => 2,
+        0xed => match next {
+            0x43 | 0x4b | 0x53 | 0x5b | 0x63 | 0x6b | 0x73 | 0x7b => 4,
+            0x64 | 0x74 => 3,             // tst n, tstio n
+            op if op & 0xc6 == 0x00 => 3, // in0 r, (n) and out0 (n), r
+            _ => 2,
+        },
+        0xdd | 0xfd => match next {
+            0xcb | 0x21 | 0x22 | 0x2a | 0x36 => 4,
+            0x34 | 0x35 => 3,
+            0x76 => 2,
+            // operations on (hl) take a displacement when indexed
+            op if op & 0xc7 == 0x46 || op & 0xf8 == 0x70 || op & 0xc7 == 0x86 => 3,
+            op => 1 + base_length(op),
+        },
+        op => base_length(op),
+    }
+}
+
+// The length of an unprefixed instruction.
+fn base_length(op: u8) -> usize {
+    match op {
+        0x01 | 0x11 | 0x21 | 0x31 | 0x22 | 0x2a | 0x32 | 0x3a | 0xc3 | 0xcd => 3,
+        op if op & 0xc7 == 0xc2 || op & 0xc7 == 0xc4 => 3, // jp cc, nn and call cc, nn
+        0x10 | 0x18 | 0x20 | 0x28 | 0x30 | 0x38 | 0xd3 | 0xdb => 2,
+        op if op & 0xc7 == 0x06 || op & 0xc7 == 0xc6 => 2, // ld r, n and alu n
+        _ => 1,
+    }
+}
+
+/// The clock cycles the Z180 takes to run the instruction starting at opcodes[0]. `taken` says whether a conditional
+/// jump, call or return was taken, or a block instruction went round again.
+pub fn tstates(opcodes: &[u8], taken: bool) -> u32 {
+    let next = opcodes.get(1).copied().unwrap_or(0);
+    let branch = |yes, no| if taken { yes } else { no };
     match opcodes[0] {
-        0b00_001_001 => format!("add\t{}, bc", reg),
-        0b00_011_001 => format!("add\t{}, de", reg),
-        0b00_101_001 => format!("add\t{}, {}", reg, reg),
-        0b00_111_001 => format!("add\t{}, sp", reg),
-
-        0b00_100_011 => format!("inc\t{}", reg),
-        0b00_101_011 => format!("dec\t{}", reg),
-
-        0b00_100_001 => format!("ld\t{}, ${:02x}{:02x}", reg, opcodes[2], opcodes[1]),
-        0b00_100_010 => format!("ld\t(${:02x}{:02x}), {}", opcodes[2], opcodes[1], reg),
-        0b00_101_010 => format!("ld\t{}, (${:02x}{:02x})", reg, opcodes[2], opcodes[1]),
-
-        0b00_110_100 => format!("inc\t({}+{})", reg, opcodes[1]),
-        0b00_110_101 => format!("dec\t({}+{})", reg, opcodes[1]),
-
-        0b01_000_110 => format!("ld\tb, ({}+{})", reg, opcodes[1]),
-        0b01_001_110 => format!("ld\tc, ({}+{})", reg, opcodes[1]),
-        0b01_010_110 => format!("ld\td, ({}+{})", reg, opcodes[1]),
-        0b01_011_110 => format!("ld\te, ({}+{})", reg, opcodes[1]),
-        0b01_100_110 => format!("ld\th, ({}+{})", reg, opcodes[1]),
-        0b01_101_110 => format!("ld\tl, ({}+{})", reg, opcodes[1]),
-        0b01_111_110 => format!("ld\ta, ({}+{})", reg, opcodes[1]),
-
-        0b01_110_000 => format!("ld\t({}+{}), b", reg, opcodes[1]),
-        0b01_110_001 => format!("ld\t({}+{}), c", reg, opcodes[1]),
-        0b01_110_010 => format!("ld\t({}+{}), d", reg, opcodes[1]),
-        0b01_110_011 => format!("ld\t({}+{}), e", reg, opcodes[1]),
-        0b01_110_100 => format!("ld\t({}+{}), h", reg, opcodes[1]),
-        0b01_110_101 => format!("ld\t({}+{}), l", reg, opcodes[1]),
-        0b01_110_111 => format!("ld\t({}+{}), a", reg, opcodes[1]),
-
-        0b11_001_011 => index_bits(&opcodes[1..], reg),
-
-        _ => format!("index\t{}, ${:02x}", reg, opcodes[0]),
-    }
-}
-
-fn index_bits(opcodes: &[u8], reg: &str) -> String {
-    match opcodes[1] {
-        0b00_000_110 => format!("rlc\t({}+{})", reg, opcodes[0]),
-        0b00_001_110 => format!("rrc\t({}+{})", reg, opcodes[0]),
-        0b00_010_110 => format!("rl\t({}+{})", reg, opcodes[0]),
-        0b00_011_110 => format!("rr\t({}+{})", reg, opcodes[0]),
-        0b00_100_110 => format!("sla\t({}+{})", reg, opcodes[0]),
-        0b00_101_110 => format!("sra\t({}+{})", reg, opcodes[0]),
-        0b00_110_110 => format!("sll!\t({}+{})", reg, opcodes[0]),
-        0b00_111_110 => format!("srl\t({}+{})", reg, opcodes[0]),
-
-        0b01_000_110 => format!("bit\t0, ({}+{})", reg, opcodes[0]),
-        0b01_001_110 => format!("bit\t1, ({}+{})", reg, opcodes[0]),
-        0b01_010_110 => format!("bit\t2, ({}+{})", reg, opcodes[0]),
-        0b01_011_110 => format!("bit\t3, ({}+{})", reg, opcodes[0]),
-        0b01_100_110 => format!("bit\t4, ({}+{})", reg, opcodes[0]),
-        0b01_101_110 => format!("bit\t5, ({}+{})", reg, opcodes[0]),
-        0b01_110_110 => format!("bit\t6, ({}+{})", reg, opcodes[0]),
-        0b01_111_110 => format!("bit\t7, ({}+{})", reg, opcodes[0]),
-
-        0b10_000_110 => format!("res\t0,({}+{})", reg, opcodes[0]),
-        0b10_001_110 => format!("res\t1,({}+{})", reg, opcodes[0]),
-        0b10_010_110 => format!("res\t2,({}+{})", reg, opcodes[0]),
-        0b10_011_110 => format!("res\t3,({}+{})", reg, opcodes[0]),
-        0b10_100_110 => format!("res\t4,({}+{})", reg, opcodes[0]),
-        0b10_101_110 => format!("res\t5,({}+{})", reg, opcodes[0]),
-        0b10_110_110 => format!("res\t6,({}+{})", reg, opcodes[0]),
-        0b10_111_110 => format!("res\t7,({}+{})", reg, opcodes[0]),
-
-        0b11_000_110 => format!("set\t0, ({}+{})", reg, opcodes[0]),
-        0b11_001_110 => format!("set\t1, ({}+{})", reg, opcodes[0]),
-        0b11_010_110 => format!("set\t2, ({}+{})", reg, opcodes[0]),
-        0b11_011_110 => format!("set\t3, ({}+{})", reg, opcodes[0]),
-        0b11_100_110 => format!("set\t4, ({}+{})", reg, opcodes[0]),
-        0b11_101_110 => format!("set\t5, ({}+{})", reg, opcodes[0]),
-        0b11_110_110 => format!("set\t6, ({}+{})", reg, opcodes[0]),
-        0b11_111_110 => format!("set\t7, ({}+{})", reg, opcodes[0]),
-
-        _ => format!("index bits\t{}, ${:02x}", reg, opcodes[1]),
+        0xcb => match next {
+            op if op & 0xc0 == 0x40 => on_memory(op, 9, 6), // bit b, r
+            op => on_memory(op, 13, 7),                     // rotates, shifts, res and set
+        },
+        0xed => match next {
+            op if op & 0xc7 == 0x00 => 12, // in0 r, (n)
+            op if op & 0xc7 == 0x01 => 13, // out0 (n), r
+            0x34 => 10,                    // tst (hl)
+            op if op & 0xc7 == 0x04 => 7,  // tst r
+            op if op & 0xcf == 0x4c => 17, // mlt rr
+            0x64 => 9,                     // tst n
+            0x74 => 12,                    // tstio n
+            0x76 => 8,                     // slp
+            op if op & 0xc7 == 0x40 => 9,  // in r, (c)
+            op if op & 0xc7 == 0x41 => 10, // out (c), r
+            op if op & 0xc7 == 0x42 => 10, // sbc and adc hl, rr
+            op if op & 0xcf == 0x43 => 19, // ld (nn), rr
+            op if op & 0xcf == 0x4b => 18, // ld rr, (nn)
+            0x45 | 0x4d => 12,             // retn, reti
+            0x67 | 0x6f => 16,             // rrd, rld
+            0x83 | 0x8b => 14,             // otim, otdm
+            0x93 | 0x9b => branch(16, 14), // otimr, otdmr
+            op if op & 0xf4 == 0xa0 => 12, // ldi, cpi, ini, outi and their decrementing forms
+            op if op & 0xf4 == 0xa4 => 6,
+            op if op & 0xf4 == 0xb0 => branch(14, 12),
+            _ => 6, // neg, im, ld i, a and the like
+        },
+        0xdd | 0xfd => match next {
+            0xcb => match opcodes.get(3).copied().unwrap_or(0) {
+                op if op & 0xc0 == 0x40 => 15,
+                _ => 19,
+            },
+            0x21 => 12,
+            0x22 => 19,
+            0x2a => 18,
+            0x23 | 0x2b => 7,
+            op if op & 0xcf == 0x09 => 10,
+            0x34 | 0x35 => 18,
+            0x36 => 15,
+            0xe1 => 12,
+            0xe3 => 19,
+            0xe5 => 14,
+            0xe9 => 6,
+            0xf9 => 7,
+            op if op & 0xf8 == 0x70 => 15, // ld (ix+d), r
+            op if op & 0xc7 == 0x46 || op & 0xc7 == 0x86 => 14,
+            op => base_tstates(op, taken) + 3,
+        },
+        op => base_tstates(op, taken),
+    }
+}
+
+// The clock cycles of a CB prefixed instruction, more for those on (hl).
+fn on_memory(op: u8, memory: u32, register: u32) -> u32 {
+    if op & 0x07 == 0x06 {
+        memory
+    } else {
+        register
+    }
+}
+
+// The clock cycles of an unprefixed instruction.
+fn base_tstates(op: u8, taken: bool) -> u32 {
+    let branch = |yes, no| if taken { yes } else { no };
+    match op {
+        0x00 | 0x76 | 0xd9 | 0xe9 | 0xeb | 0xf3 | 0xfb => 3,
+        0x08 | 0x27 | 0xf9 => 4,
+        op if op & 0xe7 == 0x07 => 3, // rotates of a, cpl, scf, ccf
+        0x10 => branch(9, 7),         // djnz
+        0x18 => 8,
+        op if op & 0xe7 == 0x20 => branch(8, 6), // jr cc
+        op if op & 0xcf == 0x01 => 9,            // ld rr, nn
+        op if op & 0xcf == 0x09 => 7,            // add hl, rr
+        0x02 | 0x12 => 7,
+        0x0a | 0x1a => 6,
+        0x22 => 16,
+        0x2a => 15,
+        0x32 => 13,
+        0x3a => 12,
+        op if op & 0xc7 == 0x03 => 4, // inc and dec rr
+        0x34 | 0x35 => 10,
+        op if op & 0xc6 == 0x04 => 4, // inc and dec r
+        0x36 => 9,
+        op if op & 0xc7 == 0x06 => 6,             // ld r, n
+        op if op & 0xf8 == 0x70 => 7,             // ld (hl), r
+        op if op & 0xc7 == 0x46 => 6,             // ld r, (hl)
+        op if op & 0xc0 == 0x40 => 4,             // ld r, r
+        op if op & 0xc7 == 0x86 => 6,             // alu (hl)
+        op if op & 0xc0 == 0x80 => 4,             // alu r
+        op if op & 0xc7 == 0xc0 => branch(10, 5), // ret cc
+        op if op & 0xcf == 0xc1 => 9,             // pop qq
+        0xc9 => 9,
+        op if op & 0xc7 == 0xc2 => branch(9, 6), // jp cc, nn
+        0xc3 => 9,
+        0xd3 => 10,
+        0xdb => 9,
+        0xe3 => 16,
+        op if op & 0xc7 == 0xc4 => branch(16, 6), // call cc, nn
+        op if op & 0xcf == 0xc5 => 11,            // push qq
+        0xcd => 16,
+        op if op & 0xc7 == 0xc6 => 6, // alu n
+        _ => 11,                      // rst
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::{Flags, Register};
+    use crate::disasm::*;
+    use crate::symbols::Symbols;
+
+    #[test]
+    fn instruction_lengths() {
+        assert_eq!(length(&[0x00]), 1, "nop");
+        assert_eq!(length(&[0x3e, 0x01]), 2, "ld a, n");
+        assert_eq!(length(&[0xcd, 0x00, 0x10]), 3, "call nn");
+        assert_eq!(length(&[0xcc, 0x00, 0x10]), 3, "call z, nn");
+        assert_eq!(length(&[0xcb, 0x47]), 2, "bit 0, a");
+        assert_eq!(length(&[0xed, 0xb0]), 2, "ldir");
+        assert_eq!(length(&[0xed, 0x5b, 0x00, 0x10]), 4, "ld de, (nn)");
+        assert_eq!(length(&[0xed, 0x38, 0x3a]), 3, "in0 a, (n)");
+        assert_eq!(length(&[0xed, 0x4c]), 2, "mlt bc");
+        assert_eq!(length(&[0xdd, 0x7e, 0x05]), 3, "ld a, (ix+d)");
+        assert_eq!(length(&[0xfd, 0x36, 0x05, 0x01]), 4, "ld (iy+d), n");
+        assert_eq!(length(&[0xdd, 0xe5]), 2, "push ix");
+        assert_eq!(length(&[0xdd, 0xcb, 0x05, 0x46]), 4, "bit 0, (ix+d)");
+    }
+
+    #[test]
+    fn instruction_timings() {
+        assert_eq!(tstates(&[0x00], false), 3, "nop");
+        assert_eq!(tstates(&[0x78], false), 4, "ld a, b");
+        assert_eq!(tstates(&[0x7e], false), 6, "ld a, (hl)");
+        assert_eq!(tstates(&[0x77], false), 7, "ld (hl), a");
+        assert_eq!(tstates(&[0x32, 0x00, 0x80], false), 13, "ld (nn), a");
+        assert_eq!(tstates(&[0x20, 0xfe], true), 8, "jr nz taken");
+        assert_eq!(tstates(&[0x20, 0xfe], false), 6, "jr nz not taken");
+        assert_eq!(tstates(&[0xc0], true), 10, "ret nz taken");
+        assert_eq!(tstates(&[0xcd, 0x00, 0x10], false), 16, "call nn");
+        assert_eq!(tstates(&[0xff], false), 11, "rst 38h");
+        assert_eq!(tstates(&[0xcb, 0x46], false), 9, "bit 0, (hl)");
+        assert_eq!(tstates(&[0xed, 0xb0], true), 14, "ldir repeating");
+        assert_eq!(tstates(&[0xed, 0xb0], false), 12, "ldir done");
+        assert_eq!(tstates(&[0xed, 0x4c], false), 17, "mlt bc");
+        assert_eq!(tstates(&[0xed, 0x38, 0x3a], false), 12, "in0 a, (n)");
+        assert_eq!(tstates(&[0xdd, 0x7e, 0x05], false), 14, "ld a, (ix+d)");
+        assert_eq!(tstates(&[0xdd, 0xe5], false), 14, "push ix");
+        assert_eq!(tstates(&[0xdd, 0xcb, 0x05, 0xc6], false), 19, "set 0, (ix+d)");
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        let call = decode(&[0xcc, 0x00, 0x10]);
+        assert_eq!(call.mnemonic, Mnemonic::Call);
+        assert_eq!(call.operands, [Operand::Condition(Condition::Zero), Operand::Address(0x1000)]);
+        assert_eq!(call.length, 3);
+        assert_eq!(call.flow(), Flow::Call);
+        assert!(call.is_conditional());
+        assert_eq!(call.target(0x0100), Some(0x1000));
+        assert!(call.flags.is_empty());
+
+        let djnz = decode(&[0x10, 0xfe]);
+        assert_eq!(djnz.flow(), Flow::Branch);
+        assert_eq!(djnz.target(0x0100), Some(0x0100));
+        assert_eq!(decode(&[0x18, 0x10]).flow(), Flow::Jump);
+        assert_eq!(decode(&[0xe9]).target(0x0100), None, "jp (hl)");
+        assert_eq!(decode(&[0xd8]).flow(), Flow::Return);
+        assert!(!decode(&[0xc9]).is_conditional());
+        assert_eq!(decode(&[0xff]).target(0x0100), Some(0x0038), "rst $38");
+
+        let ld = decode(&[0xdd, 0x70, 0xfb]);
+        assert_eq!(
+            ld.operands,
+            [Operand::Indexed(Register::IX, -5), Operand::Register(Register::B)]
+        );
+        assert_eq!(ld.length, 3);
+
+        assert_eq!(
+            decode(&[0x3c]).flags,
+            Flags::SF | Flags::ZF | Flags::HF | Flags::PF | Flags::NF,
+            "inc a"
+        );
+        assert!(decode(&[0x23]).flags.is_empty(), "inc hl");
+        assert_eq!(decode(&[0x09]).flags, Flags::HF | Flags::NF | Flags::CF, "add hl, bc");
+        assert_eq!(decode(&[0xb8]).flags, Flags::all(), "cp b");
+
+        let invalid = decode(&[0xdd, 0x00]);
+        assert_eq!(invalid.mnemonic, Mnemonic::Invalid);
+        assert_eq!(invalid.operands, [Operand::Byte(0xdd), Operand::Byte(0x00)]);
+    }
+
+    #[test]
+    fn formats_instructions() {
+        assert_eq!(disasm(&[0x00]), "nop");
+        assert_eq!(disasm(&[0x3e, 0x27]), "ld\ta, $27");
+        assert_eq!(disasm(&[0x2a, 0x00, 0x80]), "ld\thl, ($8000)");
+        assert_eq!(disasm(&[0x90]), "sub\tb");
+        assert_eq!(disasm(&[0xd3, 0x40]), "out\t($40), a");
+        assert_eq!(disasm(&[0x08]), "ex\taf, af'");
+        assert_eq!(disasm(&[0x20, 0xfe]), "jr\tnz, -2");
+        assert_eq!(disasm(&[0xcb, 0x7e]), "bit\t7, (hl)");
+        assert_eq!(disasm(&[0xfd, 0xcb, 0x02, 0xc6]), "set\t0, (iy+2)");
+        assert_eq!(disasm(&[0xed, 0x38, 0x34]), "in0\ta, (ITC)");
+        assert_eq!(disasm(&[0xed, 0xb0]), "ldir");
+        assert_eq!(disasm(&[0xef]), "rst\t$28");
+        assert_eq!(disasm(&[0xdd, 0x00]), "db\t$dd, $00");
+    }
+
+    #[test]
+    fn decodes_every_z180_opcode() {
+        let invalid = |prefix: &[u8]| -> Vec<u8> {
+            (0..=255)
+                .filter(|&op| {
+                    let mut opcodes = prefix.to_vec();
+                    opcodes.extend_from_slice(&[op, 0, 0]);
+                    decode(&opcodes).mnemonic == Mnemonic::Invalid
+                })
+                .collect()
+        };
+        assert_eq!(invalid(&[]), [0xdd, 0xfd]);
+        assert_eq!(invalid(&[0xcb]), (0x30..=0x37).collect::<Vec<u8>>(), "sll");
+        assert_eq!(256 - invalid(&[0xed]).len(), 92);
+        // ld r, r and the alu operations on h and l have no indexed form on the Z180
+        assert_eq!(256 - invalid(&[0xdd]).len(), 39);
+
+        assert_eq!(disasm(&[0xed, 0x4c]), "mlt\tbc");
+        assert_eq!(disasm(&[0xed, 0x74, 0x80]), "tstio\t$80");
+        assert_eq!(disasm(&[0xed, 0x76]), "slp");
+        assert_eq!(disasm(&[0xed, 0x93]), "otimr");
+        assert_eq!(disasm(&[0xed, 0x5e]), "im\t2");
+        assert_eq!(disasm(&[0xed, 0x78]), "in\ta, (c)");
+        assert_eq!(disasm(&[0xed, 0xbb]), "otdr");
+        assert_eq!(disasm(&[0xed, 0x57]), "ld\ta, i");
+        assert_eq!(disasm(&[0xdd, 0x36, 0xff, 0x42]), "ld\t(ix-1), $42");
+        assert_eq!(disasm(&[0xfd, 0xbe, 0x03]), "cp\t(iy+3)");
+        assert_eq!(disasm(&[0xdd, 0xe9]), "jp\t(ix)");
+        assert_eq!(disasm(&[0xfd, 0xe3]), "ex\t(sp), iy");
+        assert_eq!(disasm(&[0xdd, 0x66, 0x02]), "ld\th, (ix+2)");
+        assert_eq!(decode(&[0xed, 0x76]).flow(), Flow::Halt);
+        assert_eq!(decode(&[0xed, 0x45]).flow(), Flow::Return);
+        for opcodes in [
+            [0xdd, 0x36, 0, 0],
+            [0xed, 0x74, 0, 0],
+            [0xed, 0x4b, 0, 0],
+            [0xfd, 0xcb, 0, 0x46],
+        ]
+        .iter()
+        {
+            assert_eq!(decode(opcodes).length, length(opcodes));
+        }
+    }
+
+    #[test]
+    fn hitachi_syntax() {
+        let symbols = Symbols::new();
+        let text = |opcodes: &[u8]| decode(opcodes).text_as(Syntax::Hitachi, 0x0100, &symbols);
+        assert_eq!(text(&[0x3a, 0x00, 0xf6]), "LD\tA,(0F600H)");
+        assert_eq!(text(&[0xed, 0x39, 0x00]), "OUT0\t(CNTLA0),A");
+        assert_eq!(text(&[0xdd, 0x7e, 0x05]), "LD\tA,(IX+5)");
+        assert_eq!(text(&[0x18, 0xfe]), "JR\t0100H");
+        assert_eq!(text(&[0xff]), "RST\t38H");
+        assert_eq!(text(&[0x08]), "EX\tAF,AF'");
+        assert_eq!(Syntax::from_name("zilog"), Some(Syntax::Zilog));
+    }
+
+    #[test]
+    fn symbolic_operands() {
+        let mut symbols = Symbols::new();
+        symbols.insert("BIOS_READ", 0xf627);
+        symbols.insert("loop", 0x0100);
+        let dis = |opcodes: &[u8], address| disasm_with_symbols(opcodes, address, &symbols);
+        assert_eq!(dis(&[0xcd, 0x27, 0xf6], 0), "call\tBIOS_READ");
+        assert_eq!(dis(&[0xc2, 0x2a, 0xf6], 0), "jp\tnz, BIOS_READ+3");
+        assert_eq!(dis(&[0x3a, 0x27, 0xf6], 0), "ld\ta, (BIOS_READ)");
+        assert_eq!(dis(&[0x21, 0x2a, 0xf6], 0), "ld\thl, $f62a");
+        assert_eq!(dis(&[0x20, 0xfc], 0x0102), "jr\tnz, loop");
+        assert_eq!(dis(&[0x3e, 0x27], 0), "ld\ta, $27");
+        assert_eq!(dis(&[0xed, 0x39, 0x3a], 0), "out0\t(CBAR), a");
+        assert_eq!(dis(&[0xd3, 0x3a], 0), "out\t($3a), a");
     }
 }
@@ -2,6 +2,7 @@ use std::cell::RefCell;
 use std::num::Wrapping;
 
 use crate::bus::Bus;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
 /**
@@ -104,6 +105,31 @@ impl DMA {
         *self.bcr0h.borrow_mut() = (bcr0 >> 8) as u8;
         *self.bcr0l.borrow_mut() = (bcr0 & 0xff) as u8;
     }
+
+    // Every register, in port order, for snapshots.
+    fn registers(&self) -> [&RefCell<u8>; 19] {
+        [
+            &self.sar0l,
+            &self.sar0h,
+            &self.sar0b,
+            &self.dar0l,
+            &self.dar0h,
+            &self.dar0b,
+            &self.bcr0l,
+            &self.bcr0h,
+            &self.mar1l,
+            &self.mar1h,
+            &self.mar1b,
+            &self.iar1l,
+            &self.iar1h,
+            &self.iar1b,
+            &self.bcr1l,
+            &self.bcr1h,
+            &self.dstat,
+            &self.dmode,
+            &self.dcntl,
+        ]
+    }
 }
 
 impl Peripheral for DMA {
@@ -214,4 +240,17 @@ impl Peripheral for DMA {
             _ => (),
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        for register in self.registers() {
+            state.u8(*register.borrow());
+        }
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        for register in self.registers() {
+            *register.borrow_mut() = state.u8()?;
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,505 @@
+/**
+ * Debugger expressions
+ *
+ * A small C-like expression language for breakpoint conditions and tracepoint messages, evaluated against the state
+ * of the machine. Values are signed 64 bit integers; comparisons and logical operators answer 0 or 1, and anything
+ * other than 0 is true.
+ *
+ *   numbers      42, 0x2a, $2a, 2ah
+ *   registers    a f b c d e h l af bc de hl ix iy sp pc i r af' bc' de' hl'
+ *   flags        sf zf hf pf vf nf cf, each 0 or 1
+ *   memory       byte(addr), word(addr), reading logical memory without side effects
+ *   hits         the number of times the breakpoint has been reached, including this time
+ *   operators    || && | ^ & == != < <= > >= << >> + - * / % and unary ! ~ -
+ *
+ * Names are not case sensitive. Other names are symbols, when parsed with a symbol table, standing for their
+ * addresses. Division by zero answers zero.
+ *
+ * A template is text with expressions in braces, formatted in decimal by default, or as {expr:x} in hex, {expr:4x} in
+ * hex padded with zeros to four digits, or {expr:c} as a character. Braces are written {{ and }}.
+ */
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::cpu::{Register, CPU};
+use crate::symbols::{parse_number, Symbols};
+
+/// What an expression can see when it's evaluated.
+pub struct Context<'a> {
+    pub cpu: &'a CPU,
+    pub bus: &'a Bus,
+    pub hits: u64,
+}
+
+impl<'a> Context<'a> {
+    pub fn new(cpu: &'a CPU, bus: &'a Bus) -> Context<'a> {
+        Context { cpu, bus, hits: 0 }
+    }
+
+    fn byte(&self, address: i64) -> i64 {
+        self.bus.peek(self.cpu.to_physical(address as u16)) as i64
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum UnaryOp {
+    Not,
+    Complement,
+    Negate,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum BinaryOp {
+    Or,
+    And,
+    BitOr,
+    BitXor,
+    BitAnd,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+// Binary operators by precedence, loosest first
+const PRECEDENCE: [&[(&str, BinaryOp)]; 10] = [
+    &[("||", BinaryOp::Or)],
+    &[("&&", BinaryOp::And)],
+    &[("|", BinaryOp::BitOr)],
+    &[("^", BinaryOp::BitXor)],
+    &[("&", BinaryOp::BitAnd)],
+    &[("==", BinaryOp::Equal), ("!=", BinaryOp::NotEqual)],
+    &[
+        ("<=", BinaryOp::LessEqual),
+        (">=", BinaryOp::GreaterEqual),
+        ("<", BinaryOp::Less),
+        (">", BinaryOp::Greater),
+    ],
+    &[("<<", BinaryOp::ShiftLeft), (">>", BinaryOp::ShiftRight)],
+    &[("+", BinaryOp::Add), ("-", BinaryOp::Subtract)],
+    &[("*", BinaryOp::Multiply), ("/", BinaryOp::Divide), ("%", BinaryOp::Remainder)],
+];
+
+const FLAGS: [(&str, u8); 7] = [
+    ("sf", 0x80),
+    ("zf", 0x40),
+    ("hf", 0x10),
+    ("pf", 0x04),
+    ("vf", 0x04),
+    ("nf", 0x02),
+    ("cf", 0x01),
+];
+
+#[derive(Debug, PartialEq, Clone)]
+enum Node {
+    Number(i64),
+    Register(Register),
+    // a flag, by its mask in F
+    Flag(u8),
+    Hits,
+    Byte(Box<Node>),
+    Word(Box<Node>),
+    Unary(UnaryOp, Box<Node>),
+    Binary(BinaryOp, Box<Node>, Box<Node>),
+}
+
+/// A parsed expression, which remembers how it was written.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Expr {
+    source: String,
+    node: Node,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Token {
+    Number(i64),
+    Name(String),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 23] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>", "|", "^", "&", "<", ">", "+", "-", "*", "/", "%", "!", "~", "(", ")",
+    ",",
+];
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c.is_ascii_digit() || c == '$' {
+            let end = rest[1..]
+                .find(|c: char| !c.is_ascii_alphanumeric())
+                .map_or(rest.len(), |end| end + 1);
+            let word = &rest[..end];
+            let number = parse_number(word, 10).ok_or_else(|| format!("invalid number {}", word))?;
+            tokens.push(Token::Number(number as i64));
+            end
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            let end = rest
+                .find(|c: char| !c.is_ascii_alphanumeric() && c != '_')
+                .unwrap_or(rest.len());
+            // the alternate registers end with a quote
+            let end = if rest[end..].starts_with('\'') { end + 1 } else { end };
+            tokens.push(Token::Name(rest[..end].to_ascii_lowercase()));
+            end
+        } else {
+            let symbol = SYMBOLS
+                .iter()
+                .find(|symbol| rest.starts_with(*symbol))
+                .ok_or_else(|| format!("unexpected {}", c))?;
+            tokens.push(Token::Symbol(symbol));
+            symbol.len()
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+struct Parser<'a> {
+    tokens: Vec<Token>,
+    next: usize,
+    symbols: &'a Symbols,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn take(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.next).cloned();
+        self.next += 1;
+        token
+    }
+
+    fn expect(&mut self, symbol: &'static str) -> Result<(), String> {
+        match self.take() {
+            Some(Token::Symbol(s)) if s == symbol => Ok(()),
+            _ => Err(format!("expected {}", symbol)),
+        }
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Node, String> {
+        if level == PRECEDENCE.len() {
+            return self.unary();
+        }
+        let mut left = self.binary(level + 1)?;
+        loop {
+            let op = match self.peek() {
+                Some(Token::Symbol(s)) => PRECEDENCE[level].iter().find(|(symbol, _)| symbol == s).map(|&(_, op)| op),
+                _ => None,
+            };
+            match op {
+                Some(op) => {
+                    self.next += 1;
+                    let right = self.binary(level + 1)?;
+                    left = Node::Binary(op, Box::new(left), Box::new(right));
+                }
+                None => return Ok(left),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> Result<Node, String> {
+        let op = match self.peek() {
+            Some(Token::Symbol("!")) => UnaryOp::Not,
+            Some(Token::Symbol("~")) => UnaryOp::Complement,
+            Some(Token::Symbol("-")) => UnaryOp::Negate,
+            _ => return self.primary(),
+        };
+        self.next += 1;
+        Ok(Node::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.take() {
+            Some(Token::Number(n)) => Ok(Node::Number(n)),
+            Some(Token::Symbol("(")) => {
+                let expr = self.binary(0)?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some(Token::Name(name)) if name == "byte" || name == "word" => {
+                self.expect("(")?;
+                let address = Box::new(self.binary(0)?);
+                self.expect(")")?;
+                Ok(if name == "byte" {
+                    Node::Byte(address)
+                } else {
+                    Node::Word(address)
+                })
+            }
+            Some(Token::Name(name)) if name == "hits" => Ok(Node::Hits),
+            Some(Token::Name(name)) => {
+                if let Some(&(_, mask)) = FLAGS.iter().find(|(flag, _)| *flag == name) {
+                    return Ok(Node::Flag(mask));
+                }
+                if let Ok(reg) = name.parse() {
+                    return Ok(Node::Register(reg));
+                }
+                match self.symbols.lookup(&name) {
+                    Some(address) => Ok(Node::Number(address as i64)),
+                    None => Err(format!("unknown name {}", name)),
+                }
+            }
+            Some(Token::Symbol(s)) => Err(format!("unexpected {}", s)),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(text: &str) -> Result<Expr, String> {
+        Expr::parse_with(text, &Symbols::new())
+    }
+
+    /// Parse an expression in which names other than registers and flags are symbols.
+    pub fn parse_with(text: &str, symbols: &Symbols) -> Result<Expr, String> {
+        let mut parser = Parser {
+            tokens: tokenize(text)?,
+            next: 0,
+            symbols,
+        };
+        let node = parser.binary(0)?;
+        match parser.peek() {
+            None => Ok(Expr {
+                source: text.trim().to_string(),
+                node,
+            }),
+            Some(Token::Number(n)) => Err(format!("unexpected {}", n)),
+            Some(Token::Name(name)) => Err(format!("unexpected {}", name)),
+            Some(Token::Symbol(s)) => Err(format!("unexpected {}", s)),
+        }
+    }
+
+    pub fn eval(&self, ctx: &Context) -> i64 {
+        self.node.eval(ctx)
+    }
+
+    pub fn is_true(&self, ctx: &Context) -> bool {
+        self.eval(ctx) != 0
+    }
+}
+
+impl fmt::Display for Expr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+impl Node {
+    fn eval(&self, ctx: &Context) -> i64 {
+        match self {
+            Node::Number(n) => *n,
+            Node::Register(reg) => ctx.cpu.reg(*reg) as i64,
+            Node::Flag(mask) => (ctx.cpu.reg(Register::F) as u8 & mask != 0) as i64,
+            Node::Hits => ctx.hits as i64,
+            Node::Byte(address) => ctx.byte(address.eval(ctx)),
+            Node::Word(address) => {
+                let address = address.eval(ctx);
+                ctx.byte(address) | ctx.byte(address.wrapping_add(1)) << 8
+            }
+            Node::Unary(op, node) => {
+                let value = node.eval(ctx);
+                match op {
+                    UnaryOp::Not => (value == 0) as i64,
+                    UnaryOp::Complement => !value,
+                    UnaryOp::Negate => value.wrapping_neg(),
+                }
+            }
+            Node::Binary(BinaryOp::Or, left, right) => (left.eval(ctx) != 0 || right.eval(ctx) != 0) as i64,
+            Node::Binary(BinaryOp::And, left, right) => (left.eval(ctx) != 0 && right.eval(ctx) != 0) as i64,
+            Node::Binary(op, left, right) => {
+                let (a, b) = (left.eval(ctx), right.eval(ctx));
+                match op {
+                    BinaryOp::BitOr => a | b,
+                    BinaryOp::BitXor => a ^ b,
+                    BinaryOp::BitAnd => a & b,
+                    BinaryOp::Equal => (a == b) as i64,
+                    BinaryOp::NotEqual => (a != b) as i64,
+                    BinaryOp::Less => (a < b) as i64,
+                    BinaryOp::LessEqual => (a <= b) as i64,
+                    BinaryOp::Greater => (a > b) as i64,
+                    BinaryOp::GreaterEqual => (a >= b) as i64,
+                    BinaryOp::ShiftLeft => a.wrapping_shl(b as u32),
+                    BinaryOp::ShiftRight => a.wrapping_shr(b as u32),
+                    BinaryOp::Add => a.wrapping_add(b),
+                    BinaryOp::Subtract => a.wrapping_sub(b),
+                    BinaryOp::Multiply => a.wrapping_mul(b),
+                    BinaryOp::Divide => a.checked_div(b).unwrap_or(0),
+                    BinaryOp::Remainder => a.checked_rem(b).unwrap_or(0),
+                    BinaryOp::Or | BinaryOp::And => unreachable!(),
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Format {
+    Decimal,
+    Hex(usize),
+    Char,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+enum Segment {
+    Text(String),
+    Value(Expr, Format),
+}
+
+/// Text with expressions to fill in, for tracepoint messages.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Template {
+    source: String,
+    segments: Vec<Segment>,
+}
+
+impl Template {
+    pub fn parse(text: &str) -> Result<Template, String> {
+        Template::parse_with(text, &Symbols::new())
+    }
+
+    /// Parse a template whose expressions may name symbols.
+    pub fn parse_with(text: &str, symbols: &Symbols) -> Result<Template, String> {
+        let mut segments = Vec::new();
+        let mut literal = String::new();
+        let mut rest = text;
+        while let Some(c) = rest.chars().next() {
+            if rest.starts_with("{{") || rest.starts_with("}}") {
+                literal.push(c);
+                rest = &rest[2..];
+            } else if c == '{' {
+                let end = rest.find('}').ok_or("unclosed {")?;
+                let (expr, format) = match rest[1..end].rsplit_once(':') {
+                    Some((expr, format)) => (expr, format),
+                    None => (&rest[1..end], ""),
+                };
+                let format = match format {
+                    "" | "d" => Format::Decimal,
+                    "c" => Format::Char,
+                    hex => match hex.strip_suffix('x') {
+                        Some("") => Format::Hex(0),
+                        Some(width) => Format::Hex(width.parse().map_err(|_| format!("invalid format {}", hex))?),
+                        None => return Err(format!("invalid format {}", hex)),
+                    },
+                };
+                if !literal.is_empty() {
+                    segments.push(Segment::Text(std::mem::take(&mut literal)));
+                }
+                segments.push(Segment::Value(Expr::parse_with(expr, symbols)?, format));
+                rest = &rest[end + 1..];
+            } else if c == '}' {
+                return Err("unmatched }".to_string());
+            } else {
+                literal.push(c);
+                rest = &rest[c.len_utf8()..];
+            }
+        }
+        if !literal.is_empty() {
+            segments.push(Segment::Text(literal));
+        }
+        Ok(Template {
+            source: text.to_string(),
+            segments,
+        })
+    }
+
+    pub fn format(&self, ctx: &Context) -> String {
+        let mut out = String::new();
+        for segment in &self.segments {
+            match segment {
+                Segment::Text(text) => out.push_str(text),
+                Segment::Value(expr, format) => {
+                    let value = expr.eval(ctx);
+                    match format {
+                        Format::Decimal => out.push_str(&value.to_string()),
+                        Format::Hex(width) => out.push_str(&format!("{:0width$x}", value, width = width)),
+                        Format::Char => out.push(value as u8 as char),
+                    }
+                }
+            }
+        }
+        out
+    }
+}
+
+impl fmt::Display for Template {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::expr::*;
+    use crate::ram::RAM;
+
+    fn machine() -> (CPU, Bus) {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(0x8000, &[0x34, 0x12]).unwrap();
+        bus.add(ram);
+        cpu.reset();
+        cpu.write_reg(Register::PC, 0xf627);
+        cpu.write_reg(Register::BC, 0x0002);
+        cpu.write_reg(Register::HL, 0x8000);
+        cpu.write_reg(Register::F, 0x41);
+        (cpu, bus)
+    }
+
+    fn eval(text: &str) -> Result<i64, String> {
+        let (cpu, bus) = machine();
+        let mut ctx = Context::new(&cpu, &bus);
+        ctx.hits = 3;
+        Expr::parse(text).map(|expr| expr.eval(&ctx))
+    }
+
+    #[test]
+    fn expressions() {
+        assert_eq!(eval("pc==0xf627 && c==2"), Ok(1));
+        assert_eq!(eval("PC == $F627 && B != 0"), Ok(0));
+        assert_eq!(eval("1 + 2 * 3 - -4"), Ok(11));
+        assert_eq!(eval("(1 + 2) * 3 << 1 | 1"), Ok(19));
+        assert_eq!(eval("byte(hl) == 0x34 && word(hl + 0) == 0x1234"), Ok(1));
+        assert_eq!(eval("zf && cf && !sf"), Ok(1));
+        assert_eq!(eval("hits % 2"), Ok(1));
+        assert_eq!(eval("~0 & 0xff"), Ok(0xff));
+        assert_eq!(eval("5 / 0"), Ok(0));
+        assert_eq!(eval("hl'"), Ok(0));
+        assert!(eval("pc ==").is_err());
+        assert!(eval("xyz").is_err());
+        assert!(eval("(1").is_err());
+        assert!(eval("1 2").is_err());
+
+        let (cpu, bus) = machine();
+        let mut symbols = Symbols::new();
+        symbols.insert("BIOS_READ", 0xf627);
+        let expr = Expr::parse_with("pc == bios_read", &symbols).unwrap();
+        assert!(expr.is_true(&Context::new(&cpu, &bus)));
+    }
+
+    #[test]
+    fn templates() {
+        let (cpu, bus) = machine();
+        let ctx = Context::new(&cpu, &bus);
+        let template = Template::parse("BIOS_SETDMA({bc:4x}) {{{byte(hl):x}}} {0x41:c}").unwrap();
+        assert_eq!(template.format(&ctx), "BIOS_SETDMA(0002) {34} A");
+        assert_eq!(Expr::parse(" pc == $f627 ").unwrap().to_string(), "pc == $f627");
+        assert_eq!(template.to_string(), "BIOS_SETDMA({bc:4x}) {{{byte(hl):x}}} {0x41:c}");
+        assert!(Template::parse("{bc").is_err());
+        assert!(Template::parse("{bc:q}").is_err());
+    }
+}
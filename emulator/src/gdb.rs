@@ -0,0 +1,466 @@
+/**
+ * GDB remote serial protocol stub
+ *
+ * Serves one GDB (or other RSP client) connection over TCP, driving the CPU through a `Debugger`. Registers follow
+ * GDB's Z80 layout: AF BC DE HL SP PC IX IY AF' BC' DE' HL' IR, each 16 bits, little-endian. Memory addresses are
+ * logical, translated through the MMU.
+ *
+ * Supported packets: ? g G p P m M c s vCont Z0-Z4 z0-z4 k D, plus the queries GDB makes while attaching. Watchpoints
+ * are set on the bus over logical addresses, so they also catch DMA to the memory they watch.
+ *
+ * Known limitations:
+ *  1. There is a single thread, with ID 1
+ */
+use std::collections::HashMap;
+use std::io::{Error, ErrorKind, Read, Write};
+use std::net::TcpStream;
+
+use crate::bus::Bus;
+use crate::cpu::{Register, CPU};
+use crate::debugger::{Debugger, Stop};
+use crate::symbols::parse_hex;
+use crate::watch::{Access, Space, Watchpoint};
+
+// Registers in GDB's order
+const REGISTERS: [Register; 12] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::SP,
+    Register::PC,
+    Register::IX,
+    Register::IY,
+    Register::AltAF,
+    Register::AltBC,
+    Register::AltDE,
+    Register::AltHL,
+];
+
+// GDB's register number for IR, which is split across I and R
+const IR: usize = 12;
+
+// Instructions to run between checks for an interrupt from the client
+const POLL_STEPS: u64 = 10_000;
+
+// The largest packet the stub takes or sends, as advertised in qSupported
+const PACKET_SIZE: u16 = 0x1000;
+
+pub struct GdbStub {
+    stream: TcpStream,
+    debugger: Debugger,
+    no_ack: bool,
+    last_stop: Stop,
+    // bus watchpoint IDs for the Z packets that set them
+    watchpoints: HashMap<(u8, u16, u16), usize>,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b))
+}
+
+fn hex_bytes(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn parse_hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// Parse "addr,len" with an optional trailing ":data" or ",kind".
+fn parse_address_length(s: &str) -> Option<(u16, u16)> {
+    let mut parts = s.splitn(2, ',');
+    let address = parse_hex(parts.next()?)?;
+    let length = parse_hex(parts.next()?)?;
+    Some((address, length))
+}
+
+fn stop_reply(stop: Stop) -> String {
+    match stop {
+        Stop::Watchpoint(hit) => {
+            let kind = match hit.access {
+                Access::Read => "rwatch",
+                _ => "watch",
+            };
+            format!("T05{}:{:04x};", kind, hit.address)
+        }
+        Stop::Interrupted => "S02".to_string(),
+        _ => "S05".to_string(),
+    }
+}
+
+impl GdbStub {
+    pub fn new(stream: TcpStream) -> GdbStub {
+        GdbStub {
+            stream,
+            debugger: Debugger::new(),
+            no_ack: false,
+            last_stop: Stop::Step,
+            watchpoints: HashMap::new(),
+        }
+    }
+
+    fn read_byte(&mut self) -> Result<u8, Error> {
+        let mut byte = [0u8];
+        self.stream.read_exact(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    // Read the next packet, acknowledging it unless acks are off. An interrupt byte outside a packet reads as "\x03".
+    fn read_packet(&mut self) -> Result<String, Error> {
+        loop {
+            match self.read_byte()? {
+                b'$' => (),
+                0x03 => return Ok("\x03".to_string()),
+                _ => continue,
+            }
+            let mut data = Vec::new();
+            loop {
+                match self.read_byte()? {
+                    b'#' => break,
+                    byte => data.push(byte),
+                }
+            }
+            let sum = [self.read_byte()?, self.read_byte()?];
+            let data = String::from_utf8_lossy(&data).into_owned();
+            let valid = std::str::from_utf8(&sum).ok().and_then(parse_hex) == Some(checksum(&data) as u16);
+            if !self.no_ack {
+                self.stream.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(data);
+            }
+        }
+    }
+
+    fn send_packet(&mut self, data: &str) -> Result<(), Error> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        loop {
+            self.stream.write_all(packet.as_bytes())?;
+            if self.no_ack {
+                return Ok(());
+            }
+            // anything but a nak is as good as an ack
+            if self.read_byte()? != b'-' {
+                return Ok(());
+            }
+        }
+    }
+
+    // Check, without blocking, whether the client has sent an interrupt.
+    fn interrupted(&mut self) -> Result<bool, Error> {
+        self.stream.set_nonblocking(true)?;
+        let mut byte = [0u8];
+        let result = match self.stream.read(&mut byte) {
+            Ok(0) => Err(Error::new(ErrorKind::UnexpectedEof, "GDB client disconnected")),
+            Ok(_) => Ok(byte[0] == 0x03),
+            Err(ref e) if e.kind() == ErrorKind::WouldBlock => Ok(false),
+            Err(e) => Err(e),
+        };
+        self.stream.set_nonblocking(false)?;
+        result
+    }
+
+    fn resume(&mut self, cpu: &mut CPU, bus: &mut Bus, step: bool) -> Result<String, Error> {
+        let stop = if step {
+            self.debugger.step(cpu, bus)
+        } else {
+            self.debugger.clear_interrupt();
+            loop {
+                let stop = self.debugger.run_for(cpu, bus, POLL_STEPS);
+                if stop != Stop::Step {
+                    break stop;
+                }
+                if self.interrupted()? {
+                    break Stop::Interrupted;
+                }
+            }
+        };
+        self.last_stop = stop;
+        Ok(stop_reply(stop))
+    }
+
+    fn read_register(&self, cpu: &CPU, n: usize) -> Option<u16> {
+        match n {
+            IR => Some(cpu.reg(Register::I) << 8 | cpu.reg(Register::R)),
+            n => REGISTERS.get(n).map(|&reg| cpu.reg(reg)),
+        }
+    }
+
+    fn write_register(&self, cpu: &mut CPU, n: usize, value: u16) -> bool {
+        match n {
+            IR => {
+                cpu.write_reg(Register::I, value >> 8);
+                cpu.write_reg(Register::R, value & 0xff);
+            }
+            n if n < REGISTERS.len() => cpu.write_reg(REGISTERS[n], value),
+            _ => return false,
+        }
+        true
+    }
+
+    // Set or clear a breakpoint or watchpoint from a Z or z packet.
+    fn point(&mut self, bus: &mut Bus, packet: &str, insert: bool) -> Option<&'static str> {
+        let kind = packet.as_bytes().get(1).copied()?;
+        let (address, length) = parse_address_length(packet.get(3..)?)?;
+        let access = match kind {
+            b'0' | b'1' => {
+                if insert {
+                    self.debugger.add_breakpoint(address);
+                } else {
+                    self.debugger.remove_breakpoint(address);
+                }
+                return Some("OK");
+            }
+            b'2' => Access::Write,
+            b'3' => Access::Read,
+            b'4' => Access::ReadWrite,
+            _ => return Some(""),
+        };
+        let key = (kind, address, length);
+        if insert {
+            let id = bus.add_watchpoint(Watchpoint::new(Space::Logical, address as u32, length as u32, access));
+            if let Some(old) = self.watchpoints.insert(key, id) {
+                bus.remove_watchpoint(old);
+            }
+        } else if let Some(id) = self.watchpoints.remove(&key) {
+            bus.remove_watchpoint(id);
+        }
+        Some("OK")
+    }
+
+    // Handle one packet, answering the reply, or None to end the session.
+    fn handle(&mut self, cpu: &mut CPU, bus: &mut Bus, packet: &str) -> Result<Option<String>, Error> {
+        let error = || "E01".to_string();
+        let reply = match packet.get(0..1).unwrap_or("") {
+            "?" => stop_reply(self.last_stop),
+            "g" => {
+                let mut bytes = Vec::new();
+                for n in 0..=IR {
+                    bytes.extend_from_slice(&self.read_register(cpu, n).unwrap_or(0).to_le_bytes());
+                }
+                hex_bytes(&bytes)
+            }
+            "G" => match parse_hex_bytes(&packet[1..]) {
+                Some(bytes) => {
+                    for (n, word) in bytes.chunks(2).enumerate().filter(|(_, word)| word.len() == 2) {
+                        self.write_register(cpu, n, u16::from_le_bytes([word[0], word[1]]));
+                    }
+                    "OK".to_string()
+                }
+                None => error(),
+            },
+            "p" => match parse_hex(&packet[1..]).and_then(|n| self.read_register(cpu, n as usize)) {
+                Some(value) => hex_bytes(&value.to_le_bytes()),
+                None => error(),
+            },
+            "P" => {
+                let mut parts = packet[1..].splitn(2, '=');
+                let n = parts.next().and_then(parse_hex);
+                let value = parts.next().and_then(parse_hex_bytes);
+                match (n, value) {
+                    (Some(n), Some(value)) if value.len() == 2 => {
+                        if self.write_register(cpu, n as usize, u16::from_le_bytes([value[0], value[1]])) {
+                            "OK".to_string()
+                        } else {
+                            error()
+                        }
+                    }
+                    _ => error(),
+                }
+            }
+            "m" => match parse_address_length(&packet[1..]) {
+                // each byte is two hex digits in the reply
+                Some((_, length)) if length > PACKET_SIZE / 2 => error(),
+                Some((address, length)) => {
+                    let bytes: Vec<u8> = (0..length)
+                        .map(|offset| self.debugger.read_byte(cpu, bus, address.wrapping_add(offset)))
+                        .collect();
+                    hex_bytes(&bytes)
+                }
+                None => error(),
+            },
+            "M" => {
+                let mut parts = packet[1..].splitn(2, ':');
+                let target = parts.next().and_then(parse_address_length);
+                let data = parts.next().and_then(parse_hex_bytes);
+                match (target, data) {
+                    (Some((address, _)), Some(data)) => {
+                        for (offset, byte) in data.into_iter().enumerate() {
+                            bus.mem_write(cpu.to_physical(address.wrapping_add(offset as u16)), byte);
+                        }
+                        "OK".to_string()
+                    }
+                    _ => error(),
+                }
+            }
+            "c" | "s" => {
+                if let Some(address) = parse_hex(&packet[1..]) {
+                    cpu.write_reg(Register::PC, address);
+                }
+                self.resume(cpu, bus, packet.starts_with('s'))?
+            }
+            "Z" | "z" => self
+                .point(bus, packet, packet.starts_with('Z'))
+                .map(str::to_string)
+                .unwrap_or_else(error),
+            "H" | "T" => "OK".to_string(),
+            "k" => return Ok(None),
+            "D" => {
+                self.send_packet("OK")?;
+                return Ok(None);
+            }
+            "\x03" => {
+                self.last_stop = Stop::Interrupted;
+                stop_reply(Stop::Interrupted)
+            }
+            _ => self.query(cpu, bus, packet)?,
+        };
+        Ok(Some(reply))
+    }
+
+    // The longer named packets.
+    fn query(&mut self, cpu: &mut CPU, bus: &mut Bus, packet: &str) -> Result<String, Error> {
+        let reply = if packet.starts_with("qSupported") {
+            format!("PacketSize={:x};QStartNoAckMode+", PACKET_SIZE)
+        } else if packet == "QStartNoAckMode" {
+            "OK".to_string()
+        } else if packet == "vCont?" {
+            "vCont;c;C;s;S".to_string()
+        } else if let Some(actions) = packet.strip_prefix("vCont;") {
+            // all actions apply to the one thread, so the first decides
+            let step = actions.starts_with('s') || actions.starts_with('S');
+            self.resume(cpu, bus, step)?
+        } else {
+            match packet {
+                "qAttached" => "1".to_string(),
+                "qC" => "QC1".to_string(),
+                "qfThreadInfo" => "m1".to_string(),
+                "qsThreadInfo" => "l".to_string(),
+                _ => String::new(),
+            }
+        };
+        Ok(reply)
+    }
+
+    /// Serve the connection until the client kills or detaches from the target, or disconnects.
+    pub fn serve(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Result<(), Error> {
+        loop {
+            let packet = match self.read_packet() {
+                Ok(packet) => packet,
+                Err(ref e) if e.kind() == ErrorKind::UnexpectedEof => return Ok(()),
+                Err(e) => return Err(e),
+            };
+            match self.handle(cpu, bus, &packet)? {
+                Some(reply) => self.send_packet(&reply)?,
+                None => return Ok(()),
+            }
+            // the reply to QStartNoAckMode is the last one acknowledged
+            if packet == "QStartNoAckMode" {
+                self.no_ack = true;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::TcpListener;
+    use std::rc::Rc;
+    use std::sync::mpsc::channel;
+
+    use crate::gdb::*;
+    use crate::ram::RAM;
+
+    // Send a packet and return the reply, acknowledging both ways.
+    fn exchange(stream: &mut TcpStream, packet: &str) -> String {
+        write!(stream, "${}#{:02x}", packet, checksum(packet)).unwrap();
+        let mut byte = [0u8];
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'+', "packet acknowledged");
+        stream.read_exact(&mut byte).unwrap();
+        assert_eq!(byte[0], b'$');
+        let mut reply = Vec::new();
+        loop {
+            stream.read_exact(&mut byte).unwrap();
+            if byte[0] == b'#' {
+                break;
+            }
+            reply.push(byte[0]);
+        }
+        let mut sum = [0u8; 2];
+        stream.read_exact(&mut sum).unwrap();
+        stream.write_all(b"+").unwrap();
+        String::from_utf8(reply).unwrap()
+    }
+
+    #[test]
+    fn gdb_session() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(
+            0x0000,
+            &[
+                0x3e, 0x01, //          0x0000  ld a, 1
+                0x3c, //                0x0002  inc a
+                0x00, //                0x0003  nop
+                0x32, 0x00, 0x80, //    0x0004  ld ($8000), a
+                0x18, 0xf9, //          0x0007  jr $0002
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        cpu.reset();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let (tx, rx) = channel();
+        let client = std::thread::spawn(move || {
+            let mut stream = TcpStream::connect(address).unwrap();
+            for packet in &[
+                "qSupported:swbreak+",
+                "?",
+                "m0,3",
+                "Z0,4,1",
+                "c",
+                "p5",
+                "z0,4,1",
+                "Z2,8000,1",
+                "vCont;c",
+                "p5",
+                "M9000,2:abcd",
+                "m9000,2",
+                "s",
+                "g",
+                "m0,801",
+            ] {
+                tx.send(exchange(&mut stream, packet)).unwrap();
+            }
+            write!(stream, "$k#6b").unwrap();
+        });
+
+        GdbStub::new(listener.accept().unwrap().0).serve(&mut cpu, &mut bus).unwrap();
+        client.join().unwrap();
+        let replies: Vec<String> = rx.iter().collect();
+        assert_eq!(replies[0], "PacketSize=1000;QStartNoAckMode+");
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2], "3e013c");
+        assert_eq!(replies[3], "OK");
+        assert_eq!(replies[4], "S05");
+        assert_eq!(replies[5], "0400", "stopped at the breakpoint");
+        assert_eq!(replies[8], "T05watch:8000;");
+        assert_eq!(replies[9], "0700", "stopped after the store");
+        assert_eq!(replies[11], "abcd");
+        assert_eq!(replies[12], "S05");
+        assert_eq!(&replies[13][2..4], "02", "A");
+        assert_eq!(&replies[13][20..24], "0200", "PC after jr");
+        assert_eq!(replies[14], "E01", "longer than a packet");
+    }
+}
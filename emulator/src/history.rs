@@ -0,0 +1,252 @@
+/**
+ * Execution history
+ *
+ * The debugger goes backwards by restoring an earlier snapshot of the machine and running forward again to the
+ * instruction before the one it wants. That only arrives back at the same place if the run is repeatable, so
+ * everything the machine takes in from outside - bytes arriving on a serial port, whether the port took a byte sent,
+ * the host's clock - goes through the bus's input log. While recording, the log keeps each value as the peripheral
+ * reads it; while replaying, peripherals get the logged values back instead of asking the host again.
+ *
+ * Snapshots are taken every so many instructions, and the oldest are dropped, along with the inputs before them, to
+ * keep a bounded history.
+ *
+ * Known limitations:
+ *  1. Changing registers or memory from the debugger after going back makes the replayed run differ from the recorded
+ *     one, but later snapshots and inputs are kept and will be replayed into it
+ *  2. Only instructions run through the debugger are recorded
+ */
+use std::collections::VecDeque;
+
+use crate::bus::Bus;
+use crate::cpu::CPU;
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Instructions between snapshots, unless set otherwise.
+pub const DEFAULT_INTERVAL: u64 = 100_000;
+
+/// Snapshots kept, unless set otherwise.
+pub const DEFAULT_CAPACITY: usize = 32;
+
+/// What a value from outside the machine is, so a replay hands each value back to the part that asked for it.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Source {
+    /// A byte arrived on an ASCI channel.
+    SerialReceive(u8),
+    /// An ASCI channel's port took the byte sent.
+    SerialSend(u8),
+    /// A PRT channel's count of timer ticks from the host clock.
+    Timer(u8),
+    /// The host's wall clock, in seconds since the Unix epoch.
+    HostClock,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Input {
+    clock: u64,
+    source: Source,
+    value: u64,
+}
+
+/// The values a machine has taken in from outside, and where a replay is up to in them.
+#[derive(Debug, Default)]
+pub struct InputLog {
+    recording: bool,
+    inputs: VecDeque<Input>,
+    // the position of the first input kept, counting all inputs ever logged
+    first: usize,
+    // while replaying, the position of the next input to hand back, and the clock up to which the log is complete
+    replay: Option<usize>,
+    end: u64,
+}
+
+impl InputLog {
+    pub fn new() -> InputLog {
+        Default::default()
+    }
+
+    pub fn set_recording(&mut self, recording: bool) {
+        self.recording = recording;
+        if !recording {
+            self.inputs.clear();
+            self.first = 0;
+            self.replay = None;
+        }
+    }
+
+    pub fn is_replaying(&self) -> bool {
+        self.replay.is_some()
+    }
+
+    /// Whether the machine is replaying at a clock, having gone back to before where the log is complete.
+    pub fn replays(&self, clock: u64) -> bool {
+        self.replay.is_some() && clock <= self.end
+    }
+
+    /// The position the next input logged will have.
+    pub fn position(&self) -> usize {
+        self.first + self.inputs.len()
+    }
+
+    /// Forget the inputs before a position.
+    pub fn discard_before(&mut self, position: usize) {
+        while self.first < position && self.inputs.pop_front().is_some() {
+            self.first += 1;
+        }
+    }
+
+    /// Hand back the logged inputs from a position, until the clock passes where it is now.
+    pub fn replay_from(&mut self, position: usize, clock: u64) {
+        self.end = self.end.max(clock);
+        self.replay = Some(position.max(self.first));
+    }
+
+    /// Answer a value from outside the machine, or none if there was nothing to take in. While replaying, this is the
+    /// logged value for the source at this clock; otherwise it's whatever `live` finds, logged if recording.
+    pub fn input<F: FnOnce() -> Option<u64>>(&mut self, clock: u64, source: Source, live: F) -> Option<u64> {
+        if let Some(next) = self.replay {
+            if clock <= self.end {
+                return match self.inputs.get(next - self.first) {
+                    Some(input) if input.clock == clock && input.source == source => {
+                        self.replay = Some(next + 1);
+                        Some(input.value)
+                    }
+                    _ => None,
+                };
+            }
+            // caught up: anything logged past here is from a run that's been abandoned
+            self.inputs.truncate(next - self.first);
+            self.replay = None;
+        }
+        let value = live();
+        if self.recording {
+            if let Some(value) = value {
+                self.inputs.push_back(Input { clock, source, value });
+            }
+        }
+        value
+    }
+}
+
+struct Snapshot {
+    // instructions run before the snapshot was taken
+    step: u64,
+    // the input log's position when it was taken
+    inputs: usize,
+    state: Vec<u8>,
+}
+
+/// Snapshots taken as the debugger runs the machine, for going back to an earlier instruction.
+pub struct History {
+    interval: u64,
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+    step: u64,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        Self::new(DEFAULT_INTERVAL, DEFAULT_CAPACITY)
+    }
+}
+
+impl History {
+    /// Keep up to `capacity` snapshots, taken every `interval` instructions.
+    pub fn new(interval: u64, capacity: usize) -> History {
+        History {
+            interval: interval.max(1),
+            capacity: capacity.max(1),
+            snapshots: VecDeque::new(),
+            step: 0,
+        }
+    }
+
+    /// The number of instructions run since recording began, which is where the machine is in its history.
+    pub fn step(&self) -> u64 {
+        self.step
+    }
+
+    /// The earliest step the machine can go back to.
+    pub fn oldest(&self) -> Option<u64> {
+        self.snapshots.front().map(|snapshot| snapshot.step)
+    }
+
+    /// The steps at which snapshots were taken, oldest first.
+    pub fn snapshots(&self) -> impl DoubleEndedIterator<Item = u64> + '_ {
+        self.snapshots.iter().map(|snapshot| snapshot.step)
+    }
+
+    /// Note that an instruction is about to run, taking a snapshot first if one is due.
+    pub fn record(&mut self, cpu: &CPU, bus: &Bus) {
+        let due = match self.snapshots.back() {
+            Some(last) => self.step >= last.step + self.interval,
+            None => true,
+        };
+        if due {
+            let mut state = StateWriter::new();
+            cpu.save_state(&mut state);
+            bus.save_state(&mut state);
+            self.snapshots.push_back(Snapshot {
+                step: self.step,
+                inputs: bus.inputs().position(),
+                state: state.into_bytes(),
+            });
+            if self.snapshots.len() > self.capacity {
+                self.snapshots.pop_front();
+                let oldest = self.snapshots.front().unwrap().inputs;
+                bus.inputs().discard_before(oldest);
+            }
+        }
+        self.step += 1;
+    }
+
+    /// Restore the latest snapshot at or before a step, answering the step it was taken at. The machine must then be
+    /// run forward to reach the step itself.
+    pub fn restore(&mut self, cpu: &mut CPU, bus: &Bus, step: u64) -> Result<Option<u64>, StateError> {
+        let snapshot = match self.snapshots.iter().rev().find(|snapshot| snapshot.step <= step) {
+            Some(snapshot) => snapshot,
+            None => return Ok(None),
+        };
+        let clock = bus.clock();
+        let mut state = StateReader::new(&snapshot.state);
+        cpu.load_state(&mut state)?;
+        bus.load_state(&mut state)?;
+        bus.inputs().replay_from(snapshot.inputs, clock);
+        self.step = snapshot.step;
+        Ok(Some(snapshot.step))
+    }
+
+    /// Count an instruction run while replaying, which takes no snapshot.
+    pub(crate) fn replayed(&mut self) {
+        self.step += 1;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::history::*;
+
+    #[test]
+    fn replay_hands_back_logged_inputs() {
+        let mut log = InputLog::new();
+        log.set_recording(true);
+        assert_eq!(log.input(1, Source::SerialReceive(0), || None), None);
+        assert_eq!(log.input(2, Source::SerialReceive(0), || Some(0x41)), Some(0x41));
+        assert_eq!(log.input(2, Source::HostClock, || Some(1000)), Some(1000));
+        assert_eq!(log.position(), 2);
+
+        log.replay_from(0, 3);
+        assert!(log.is_replaying());
+        let live = || panic!("replay asked the host");
+        assert_eq!(log.input(1, Source::SerialReceive(0), live), None);
+        assert_eq!(log.input(2, Source::SerialReceive(0), live), Some(0x41));
+        assert_eq!(log.input(2, Source::HostClock, live), Some(1000));
+        assert_eq!(log.input(3, Source::SerialReceive(0), live), None);
+        assert_eq!(log.input(4, Source::SerialReceive(0), || Some(0x42)), Some(0x42));
+        assert!(!log.is_replaying());
+        assert_eq!(log.position(), 3);
+
+        log.discard_before(1);
+        log.replay_from(0, 4);
+        assert_eq!(log.input(2, Source::HostClock, live), Some(1000));
+    }
+}
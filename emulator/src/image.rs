@@ -0,0 +1,359 @@
+/**
+ * Program images
+ *
+ * An `Image` is a program's bytes at the address they load to, as a ROM or a CP/M .COM file, to be disassembled. Code
+ * is told from data by following the flow of control from entry points: each instruction reached is code, and the
+ * instruction after it is reached unless it always jumps or returns, as are the targets of its jumps and calls. Bytes
+ * never reached are data. Addresses a coverage report lists as run can be followed as entry points too, finding code
+ * only reached through jp (hl) and the like.
+ *
+ * `source` writes the image as source to assemble it again: equates for the symbols it uses outside the image, an
+ * org, and then a line for each instruction and db lines for the data, with labels where symbols name a line. Jump
+ * and call targets, and memory and 16 bit operands within the image, are labelled Lxxxx for code and Dxxxx for data
+ * where no symbol names them.
+ *
+ * Known limitations:
+ *  1. Data following a call or restart, as some restarts take inline, is taken as code
+ *  2. A target inside an instruction gets no label of its own, so is written relative to a label before it
+ *  3. A 16 bit operand that's a constant is labelled if it happens to fall within the image
+ *  4. Instructions with two encodings, such as ld hl, (nn), are written as db when not in the usual encoding
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use crate::disasm::{self, Flow, Instruction, Mnemonic, Operand, Syntax};
+use crate::symbols::Symbols;
+
+// The most bytes written in a db line, and the most characters in a string in one
+const DATA_LINE: usize = 8;
+const STRING_LINE: usize = 32;
+
+// The fewest printable characters in a row written as a string
+const STRING_MIN: usize = 4;
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+enum Byte {
+    Data,
+    // the first byte of an instruction
+    Start,
+    // a later byte of one
+    Operand,
+}
+
+/// A program's bytes, and which of them are code.
+pub struct Image {
+    origin: u16,
+    bytes: Vec<u8>,
+    kinds: Vec<Byte>,
+    entries: BTreeSet<u16>,
+}
+
+impl Image {
+    /// An image of `bytes` loaded at `origin`, all data until traced.
+    pub fn new(origin: u16, bytes: Vec<u8>) -> Result<Image, String> {
+        if origin as usize + bytes.len() > 0x10000 {
+            return Err(format!(
+                "{} bytes loaded at ${:04x} run past the end of memory",
+                bytes.len(),
+                origin
+            ));
+        }
+        Ok(Image {
+            origin,
+            kinds: vec![Byte::Data; bytes.len()],
+            bytes,
+            entries: BTreeSet::new(),
+        })
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    // An address's offset into the image, if it's within it.
+    fn offset(&self, address: u16) -> Option<usize> {
+        let offset = address.wrapping_sub(self.origin) as usize;
+        match offset < self.bytes.len() {
+            true => Some(offset),
+            false => None,
+        }
+    }
+
+    // The instruction at an offset, with zeros for any bytes past the end of the image.
+    fn decode(&self, offset: usize) -> Instruction {
+        let mut opcodes = [0u8; 4];
+        let available = (self.bytes.len() - offset).min(4);
+        opcodes[..available].copy_from_slice(&self.bytes[offset..offset + available]);
+        disasm::decode(&opcodes)
+    }
+
+    /// Answer whether the byte at an address is part of an instruction.
+    pub fn is_code(&self, address: u16) -> bool {
+        self.offset(address).is_some_and(|offset| self.kinds[offset] != Byte::Data)
+    }
+
+    /// Follow the flow of control from an entry point, which is labelled.
+    pub fn enter(&mut self, entry: u16) {
+        if self.offset(entry).is_some() {
+            self.entries.insert(entry);
+        }
+        self.trace(entry);
+    }
+
+    /// Follow the flow of control from an address known to be code, such as one a coverage report lists.
+    pub fn trace(&mut self, start: u16) {
+        let mut pending = vec![start];
+        while let Some(mut address) = pending.pop() {
+            while let Some(offset) = self.offset(address) {
+                let instruction = self.decode(offset);
+                let end = offset + instruction.length;
+                // stop at anything that can't be code, or that overlaps code already found
+                if instruction.mnemonic == Mnemonic::Invalid
+                    || end > self.bytes.len()
+                    || self.kinds[offset..end].iter().any(|kind| *kind != Byte::Data)
+                {
+                    break;
+                }
+                self.kinds[offset] = Byte::Start;
+                for kind in &mut self.kinds[offset + 1..end] {
+                    *kind = Byte::Operand;
+                }
+                let target = instruction.target(address);
+                match instruction.flow() {
+                    Flow::Jump => {
+                        pending.extend(target);
+                        break;
+                    }
+                    Flow::Return if !instruction.is_conditional() => break,
+                    Flow::Branch | Flow::Call => pending.extend(target),
+                    Flow::Next | Flow::Return | Flow::Halt => (),
+                }
+                address = address.wrapping_add(instruction.length as u16);
+            }
+        }
+    }
+
+    // The instructions found, with their addresses, in address order.
+    fn instructions(&self) -> impl Iterator<Item = (u16, Instruction)> + '_ {
+        (0..self.bytes.len())
+            .filter(move |&offset| self.kinds[offset] == Byte::Start)
+            .map(move |offset| (self.origin.wrapping_add(offset as u16), self.decode(offset)))
+    }
+
+    // Answer whether an address starts a line of source: an instruction, or any byte of data.
+    fn starts_line(&self, address: u16) -> bool {
+        self.offset(address).is_some_and(|offset| self.kinds[offset] != Byte::Operand)
+    }
+
+    /// The symbols given, with labels added for the entry points, the targets of jumps and calls, and the memory and
+    /// 16 bit operands that fall at the start of a line within the image.
+    pub fn labels(&self, symbols: &Symbols) -> Symbols {
+        let mut labels = symbols.clone();
+        let label = |address: u16, labels: &mut Symbols| {
+            if self.starts_line(address) && labels.name_of(address).is_none() {
+                let prefix = if self.is_code(address) { 'L' } else { 'D' };
+                labels.insert(&format!("{}{:04X}", prefix, address), address);
+            }
+        };
+        for &entry in &self.entries {
+            label(entry, &mut labels);
+        }
+        for (address, instruction) in self.instructions() {
+            for operand in &instruction.operands {
+                match *operand {
+                    Operand::Address(_) if instruction.mnemonic == Mnemonic::Rst => (),
+                    Operand::Address(_) | Operand::Relative(_) => {
+                        label(instruction.target(address).unwrap_or_default(), &mut labels)
+                    }
+                    Operand::Word(value) | Operand::Memory(value) => label(value, &mut labels),
+                    _ => (),
+                }
+            }
+        }
+        labels
+    }
+
+    /// Write the image as source to assemble, naming addresses with the symbols given and generated labels.
+    pub fn source<W: Write>(&self, syntax: Syntax, symbols: &Symbols, output: &mut W) -> io::Result<()> {
+        let labels = self.labels(symbols);
+        let mut placed: BTreeMap<u16, Vec<&str>> = BTreeMap::new();
+        for (name, address) in labels.iter() {
+            if self.starts_line(address) {
+                placed.entry(address).or_default().push(name);
+            }
+        }
+
+        // the symbols instructions are written with that aren't placed as labels
+        let mut equates: BTreeSet<(u16, &str)> = BTreeSet::new();
+        for (address, instruction) in self.instructions() {
+            for name in referenced(&instruction, address, &labels) {
+                let value = labels.lookup(name).unwrap_or_default();
+                if !self.starts_line(value) {
+                    equates.insert((value, name));
+                }
+            }
+        }
+        for (value, name) in &equates {
+            writeln!(output, "{}\t{}\t{}", name, syntax.case("equ"), syntax.hex(*value as u32, 4))?;
+        }
+        if !equates.is_empty() {
+            writeln!(output)?;
+        }
+
+        writeln!(output, "\t{}\t{}", syntax.case("org"), syntax.hex(self.origin as u32, 4))?;
+        let mut offset = 0;
+        while offset < self.bytes.len() {
+            let address = self.origin.wrapping_add(offset as u16);
+            for name in placed.get(&address).into_iter().flatten() {
+                writeln!(output, "{}:", name)?;
+            }
+            if self.kinds[offset] == Byte::Start {
+                let instruction = self.decode(offset);
+                let text = instruction.text_as(syntax, address, &labels);
+                let bytes = &self.bytes[offset..offset + instruction.length];
+                match is_usual_encoding(bytes) {
+                    true => writeln!(output, "\t{}", text)?,
+                    false => writeln!(output, "\t{}\t; {}", db(syntax, bytes), text.replacen('\t', " ", 1))?,
+                }
+                offset += instruction.length;
+            } else {
+                // data runs up to the next instruction or label
+                let end = (offset + 1..self.bytes.len())
+                    .find(|&end| self.kinds[end] != Byte::Data || placed.contains_key(&self.origin.wrapping_add(end as u16)))
+                    .unwrap_or(self.bytes.len());
+                for line in data_lines(syntax, &self.bytes[offset..end]) {
+                    writeln!(output, "\t{}", line)?;
+                }
+                offset = end;
+            }
+        }
+        Ok(())
+    }
+}
+
+// The symbols an instruction at `address` is written with, as `Instruction::text` names its operands.
+fn referenced<'a>(instruction: &Instruction, address: u16, symbols: &'a Symbols) -> Vec<&'a str> {
+    instruction
+        .operands
+        .iter()
+        .filter_map(|operand| match *operand {
+            Operand::Address(_) if instruction.mnemonic == Mnemonic::Rst => None,
+            Operand::Address(_) | Operand::Relative(_) => {
+                symbols.nearest(instruction.target(address)?).map(|(name, _)| name)
+            }
+            Operand::Word(value) | Operand::Memory(value) => symbols.name_of(value),
+            _ => None,
+        })
+        .collect()
+}
+
+// Answer whether an assembler would encode an instruction's text as these bytes. The ED prefixed forms of
+// ld hl, (nn) and ld (nn), hl are the only other encodings of instructions decoded.
+fn is_usual_encoding(bytes: &[u8]) -> bool {
+    !matches!(bytes, [0xed, 0x63, ..] | [0xed, 0x6b, ..])
+}
+
+fn is_printable(byte: u8) -> bool {
+    // quotes and backslashes mean something in some assemblers' strings
+    (0x20..0x7f).contains(&byte) && byte != b'"' && byte != b'\\'
+}
+
+fn db(syntax: Syntax, bytes: &[u8]) -> String {
+    let values: Vec<String> = bytes.iter().map(|byte| syntax.hex(*byte as u32, 2)).collect();
+    format!("{}\t{}", syntax.case("db"), values.join(syntax.separator()))
+}
+
+// Write data as db lines, with runs of printable characters as strings.
+fn data_lines(syntax: Syntax, bytes: &[u8]) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut items: Vec<String> = Vec::new();
+    let mut width = 0;
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let printable = bytes[offset..].iter().take_while(|byte| is_printable(**byte)).count();
+        if printable >= STRING_MIN {
+            let length = printable.min(STRING_LINE);
+            items.push(format!("\"{}\"", String::from_utf8_lossy(&bytes[offset..offset + length])));
+            width += length;
+            offset += length;
+        } else {
+            items.push(syntax.hex(bytes[offset] as u32, 2));
+            width += 1;
+            offset += 1;
+        }
+        if width >= DATA_LINE || offset == bytes.len() {
+            lines.push(format!("{}\t{}", syntax.case("db"), items.join(syntax.separator())));
+            items.clear();
+            width = 0;
+        }
+    }
+    lines
+}
+
+#[cfg(test)]
+mod test {
+    use crate::image::*;
+
+    #[test]
+    fn separates_code_from_data() {
+        let mut image = Image::new(
+            0x0100,
+            vec![
+                0x11, 0x12, 0x01, //    0x0100  start: ld de, msg
+                0x0e, 0x09, //          0x0103  ld c, 9
+                0xcd, 0x05, 0x00, //    0x0105  call BDOS
+                0xb7, //                0x0108  or a
+                0x20, 0xf5, //          0x0109  jr nz, start
+                0xed, 0x6b, 0x00, 0x80, // 0x010b  ld hl, ($8000)
+                0xc3, 0x00, 0x00, //    0x010f  jp 0
+                b'H', b'i', b'!', b'$', 0x0d, 0x0a, // 0x0112  msg
+                0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, //    0x0118
+            ],
+        )
+        .unwrap();
+        image.enter(0x0100);
+        assert!(image.is_code(0x010f));
+        assert!(!image.is_code(0x0112));
+
+        let mut symbols = Symbols::new();
+        symbols.insert("BDOS", 0x0005);
+        let mut source = Vec::new();
+        image.source(Syntax::Zilog, &symbols, &mut source).unwrap();
+        assert_eq!(
+            String::from_utf8(source).unwrap(),
+            "\
+BDOS\tequ\t$0005
+
+\torg\t$0100
+L0100:
+\tld\tde, D0112
+\tld\tc, $09
+\tcall\tBDOS
+\tor\ta
+\tjr\tnz, L0100
+\tdb\t$ed, $6b, $00, $80\t; ld hl, ($8000)
+\tjp\t$0000
+D0112:
+\tdb\t\"Hi!$\", $0d, $0a, $00, $00
+\tdb\t$00, $00, $00, $00, $00, $00, $00
+"
+        );
+
+        // code reached only through jp (hl) is found from coverage
+        let mut image = Image::new(0x0000, vec![0xe9, 0x3e, 0x01, 0xc9]).unwrap();
+        image.enter(0x0000);
+        assert!(!image.is_code(0x0001));
+        image.trace(0x0001);
+        assert!(image.is_code(0x0003));
+        let mut source = Vec::new();
+        image.source(Syntax::Hitachi, &Symbols::new(), &mut source).unwrap();
+        assert_eq!(
+            String::from_utf8(source).unwrap(),
+            "\tORG\t0000H\nL0000:\n\tJP\t(HL)\n\tLD\tA,01H\n\tRET\n"
+        );
+    }
+}
@@ -2,13 +2,32 @@
 extern crate bitflags;
 
 pub mod asci;
+pub mod asm;
 pub mod board;
 pub mod bus;
+pub mod buslog;
+pub mod coverage;
 pub mod cpu;
+pub mod dap;
+pub mod debugger;
 pub mod disasm;
 pub mod dma;
+pub mod expr;
+pub mod gdb;
+pub mod history;
+pub mod image;
+pub mod link;
+pub mod listing;
+pub mod profile;
 pub mod prt;
 pub mod ram;
+pub mod region;
+pub mod rel;
 pub mod rom;
+pub mod rtc;
 pub mod sdcard;
+pub mod state;
+pub mod symbols;
+pub mod trace;
 pub mod types;
+pub mod watch;
@@ -0,0 +1,696 @@
+/**
+ * Linking REL modules
+ *
+ * A `Linker` lays out Microsoft REL modules in memory as L80 does. Modules are linked in the order they're given,
+ * each module's program segment following the last. Its data segment follows its program segment, unless a data
+ * origin is set to gather data segments together elsewhere. A common block is laid out where the next data segment
+ * would go, the first time a module declares it, and is shared by every module after.
+ *
+ * Libraries are searched for modules defining symbols that are still undefined, linking each that does, and then
+ * searching again for any new symbols those modules need. References to external symbols, and expressions over them,
+ * are resolved by `finish`, once every module is in: until then a symbol may yet be defined by a later module.
+ *
+ * Known limitations:
+ *  1. Modules loading absolute code, or linked past $ffff, may overwrite each other without complaint
+ *  2. Byte stores are truncated to the low byte, with no check the value fits
+ *  3. Libraries a module requests are noted, for the caller to search, rather than found
+ */
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Write};
+
+use crate::rel::{AddressType, Module, Operator, RelEntry};
+use crate::symbols::Symbols;
+
+/// Where a module's segments were laid out.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Placement {
+    pub name: String,
+    pub program: u16,
+    pub program_size: u16,
+    pub data: u16,
+    pub data_size: u16,
+}
+
+#[derive(Debug, Clone)]
+enum Term {
+    Value(u16),
+    External(String),
+    Operator(Operator),
+}
+
+// Words and bytes to fill in once every symbol is defined
+#[derive(Debug)]
+enum Fixup {
+    External { address: u16, name: String },
+    Offset { address: u16, offset: u16 },
+    Expression { address: u16, terms: Vec<Term>, word: bool },
+}
+
+// The module being linked
+struct Current {
+    program_size: u16,
+    data_size: u16,
+    // its index in the placements, once laid out
+    placed: Option<usize>,
+    segment: AddressType,
+    offset: u16,
+    // the origin of the selected common block
+    common: Option<u16>,
+    stack: Vec<Term>,
+}
+
+pub struct Linker {
+    memory: Vec<u8>,
+    extent: Option<(u16, u16)>,
+    next_program: u16,
+    next_data: Option<u16>,
+    placements: Vec<Placement>,
+    // common block names to their origins and sizes
+    commons: BTreeMap<String, (u16, u16)>,
+    // public symbols to their values and the modules defining them
+    publics: BTreeMap<String, (u16, String)>,
+    externals: BTreeSet<String>,
+    fixups: Vec<Fixup>,
+    start: Option<u16>,
+    requests: Vec<String>,
+}
+
+impl Linker {
+    /// A linker laying out program segments from `origin`.
+    pub fn new(origin: u16) -> Linker {
+        Linker {
+            memory: vec![0; 0x10000],
+            extent: None,
+            next_program: origin,
+            next_data: None,
+            placements: Vec::new(),
+            commons: BTreeMap::new(),
+            publics: BTreeMap::new(),
+            externals: BTreeSet::new(),
+            fixups: Vec::new(),
+            start: None,
+            requests: Vec::new(),
+        }
+    }
+
+    /// Lay out data segments and common blocks from `origin`, rather than after each program segment.
+    pub fn data_origin(&mut self, origin: u16) {
+        self.next_data = Some(origin);
+    }
+
+    /// Link a module.
+    pub fn link(&mut self, module: &Module) -> Result<(), String> {
+        let mut current = Current {
+            program_size: 0,
+            data_size: 0,
+            placed: None,
+            segment: AddressType::ProgramRelative,
+            offset: 0,
+            common: None,
+            stack: Vec::new(),
+        };
+
+        for entry in &module.entries {
+            match entry {
+                RelEntry::ProgramName(_) | RelEntry::EntrySymbol(_) | RelEntry::EndFile() => (),
+                RelEntry::RequestLibrary(name) => {
+                    if !self.requests.contains(name) {
+                        self.requests.push(name.clone());
+                    }
+                }
+                RelEntry::DataSize(_, size) => current.data_size = *size,
+                RelEntry::TextSize(_, size) => current.program_size = *size,
+                RelEntry::CommonSize(_, size, name) => self.common(name, *size)?,
+                RelEntry::SelectCommon(name) => {
+                    let &(origin, _) = self
+                        .commons
+                        .get(name)
+                        .ok_or_else(|| format!("{} selects common /{}/ before declaring it", module.name, name))?;
+                    current.common = Some(origin);
+                }
+                RelEntry::Absolute(byte) => {
+                    let address = self.location(module, &mut current)?;
+                    self.write(address, &[*byte]);
+                    current.offset = current.offset.wrapping_add(1);
+                }
+                RelEntry::Relative(kind, value) => {
+                    let value = self.relocate(module, &mut current, *kind, *value)?;
+                    let address = self.location(module, &mut current)?;
+                    self.write(address, &value.to_le_bytes());
+                    current.offset = current.offset.wrapping_add(2);
+                }
+                RelEntry::SetLocation(kind, value) => {
+                    current.segment = *kind;
+                    current.offset = *value;
+                }
+                RelEntry::EntryPoint(kind, value, name) => {
+                    let value = self.relocate(module, &mut current, *kind, *value)?;
+                    if let Some((_, other)) = self.publics.get(name) {
+                        return Err(format!("{} is defined in both {} and {}", name, other, module.name));
+                    }
+                    self.publics.insert(name.clone(), (value, module.name.clone()));
+                }
+                RelEntry::ChainExternal(kind, head, name) => {
+                    self.externals.insert(name.clone());
+                    if *kind != AddressType::Absolute || *head != 0 {
+                        let head = self.relocate(module, &mut current, *kind, *head)?;
+                        for address in self.chain(head, name)? {
+                            self.fixups.push(Fixup::External {
+                                address,
+                                name: name.clone(),
+                            });
+                        }
+                    }
+                }
+                RelEntry::ChainAddress(kind, head) => {
+                    let head = self.relocate(module, &mut current, *kind, *head)?;
+                    let value = self.location(module, &mut current)?;
+                    for address in self.chain(head, &module.name)? {
+                        self.write(address, &value.to_le_bytes());
+                    }
+                }
+                RelEntry::ExtPlusOffset(kind, value) => {
+                    let offset = self.relocate(module, &mut current, *kind, *value)?;
+                    let address = self.location(module, &mut current)?;
+                    self.fixups.push(Fixup::Offset { address, offset });
+                }
+                RelEntry::Operand(kind, value) => {
+                    let value = self.relocate(module, &mut current, *kind, *value)?;
+                    current.stack.push(Term::Value(value));
+                }
+                RelEntry::ExternalOperand(name) => {
+                    self.externals.insert(name.clone());
+                    current.stack.push(Term::External(name.clone()));
+                }
+                RelEntry::Operation(op @ (Operator::StoreByte | Operator::StoreWord)) => {
+                    if current.stack.is_empty() {
+                        return Err(format!("{} stores an empty expression", module.name));
+                    }
+                    let address = self.location(module, &mut current)?;
+                    self.fixups.push(Fixup::Expression {
+                        address,
+                        terms: std::mem::take(&mut current.stack),
+                        word: *op == Operator::StoreWord,
+                    });
+                }
+                RelEntry::Operation(op) => current.stack.push(Term::Operator(*op)),
+                RelEntry::EndModule(kind, value) => {
+                    if *kind != AddressType::Absolute || *value != 0 {
+                        let start = self.relocate(module, &mut current, *kind, *value)?;
+                        self.start.get_or_insert(start);
+                    }
+                }
+            }
+        }
+
+        // even a module loading nothing is laid out, to show in the map
+        self.place(module, &mut current);
+        Ok(())
+    }
+
+    /// Link the modules of a library that define undefined symbols, answering how many were linked.
+    pub fn search(&mut self, library: &[Module]) -> Result<usize, String> {
+        let mut linked = vec![false; library.len()];
+        let mut count = 0;
+        loop {
+            let mut progress = false;
+            for (index, module) in library.iter().enumerate() {
+                if !linked[index] && module.publics().any(|name| self.is_undefined(name)) {
+                    self.link(module)?;
+                    linked[index] = true;
+                    count += 1;
+                    progress = true;
+                }
+            }
+            if !progress {
+                return Ok(count);
+            }
+        }
+    }
+
+    fn is_undefined(&self, name: &str) -> bool {
+        self.externals.contains(name) && !self.publics.contains_key(name)
+    }
+
+    /// The external symbols no module linked so far defines.
+    pub fn undefined(&self) -> Vec<&str> {
+        self.externals
+            .iter()
+            .filter(|name| !self.publics.contains_key(*name))
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Resolve references to external symbols, failing if any are undefined.
+    pub fn finish(&mut self) -> Result<(), String> {
+        let undefined = self.undefined();
+        if !undefined.is_empty() {
+            return Err(format!("undefined symbols: {}", undefined.join(", ")));
+        }
+
+        // offsets are added after all chains are resolved, and expressions stored over both
+        let mut fixups = std::mem::take(&mut self.fixups);
+        fixups.sort_by_key(|fixup| match fixup {
+            Fixup::External { .. } => 0,
+            Fixup::Offset { .. } => 1,
+            Fixup::Expression { .. } => 2,
+        });
+        for fixup in fixups {
+            match fixup {
+                Fixup::External { address, name } => {
+                    let value = self.publics[&name].0;
+                    self.write(address, &value.to_le_bytes());
+                }
+                Fixup::Offset { address, offset } => {
+                    let value = self.word(address).wrapping_add(offset);
+                    self.write(address, &value.to_le_bytes());
+                }
+                Fixup::Expression { address, terms, word } => {
+                    let value = self.evaluate(&terms)?;
+                    if word {
+                        self.write(address, &value.to_le_bytes());
+                    } else {
+                        self.write(address, &[value as u8]);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// All 64k of memory, as linked.
+    pub fn memory(&self) -> &[u8] {
+        &self.memory
+    }
+
+    /// The lowest and highest addresses anything was linked to.
+    pub fn extent(&self) -> Option<(u16, u16)> {
+        self.extent
+    }
+
+    /// The start address, from the first module to give one.
+    pub fn start(&self) -> Option<u16> {
+        self.start
+    }
+
+    /// The libraries modules asked to be searched.
+    pub fn requests(&self) -> &[String] {
+        &self.requests
+    }
+
+    pub fn placements(&self) -> &[Placement] {
+        &self.placements
+    }
+
+    /// The public symbols the linked modules define.
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols = Symbols::new();
+        for (name, &(value, _)) in &self.publics {
+            symbols.insert(name, value);
+        }
+        symbols
+    }
+
+    /// Write a map of where each module and common block went, as comments, and then the public symbols in address
+    /// order as a symbol file.
+    pub fn write_map<W: Write>(&self, w: &mut W) -> io::Result<()> {
+        fn range(origin: u16, size: u16) -> String {
+            match size {
+                0 => "-".to_string(),
+                _ => format!("{:04X}-{:04X}", origin, origin.wrapping_add(size - 1)),
+            }
+        }
+
+        writeln!(w, "; {:<10} {:<10} data", "module", "program")?;
+        for placement in &self.placements {
+            writeln!(
+                w,
+                "; {:<10} {:<10} {}",
+                placement.name,
+                range(placement.program, placement.program_size),
+                range(placement.data, placement.data_size)
+            )?;
+        }
+        for (name, &(origin, size)) in &self.commons {
+            writeln!(w, "; {:<10} {}", format!("/{}/", name), range(origin, size))?;
+        }
+        if let Some(start) = self.start {
+            writeln!(w, "; start at {:04X}", start)?;
+        }
+
+        let mut publics: Vec<(u16, &str)> = self
+            .publics
+            .iter()
+            .map(|(name, &(value, _))| (value, name.as_str()))
+            .collect();
+        publics.sort();
+        for (value, name) in publics {
+            writeln!(w, "{:04X} {}", value, name)?;
+        }
+        Ok(())
+    }
+
+    // Lay out the current module's segments, if not yet done, answering its index in the placements
+    fn place(&mut self, module: &Module, current: &mut Current) -> usize {
+        if let Some(index) = current.placed {
+            return index;
+        }
+        let program = self.next_program;
+        let data = match self.next_data {
+            Some(data) => {
+                self.next_data = Some(data.wrapping_add(current.data_size));
+                self.next_program = program.wrapping_add(current.program_size);
+                data
+            }
+            None => {
+                let data = program.wrapping_add(current.program_size);
+                self.next_program = data.wrapping_add(current.data_size);
+                data
+            }
+        };
+        self.placements.push(Placement {
+            name: module.name.clone(),
+            program,
+            program_size: current.program_size,
+            data,
+            data_size: current.data_size,
+        });
+        current.placed = Some(self.placements.len() - 1);
+        self.placements.len() - 1
+    }
+
+    fn common(&mut self, name: &str, size: u16) -> Result<(), String> {
+        match self.commons.get(name) {
+            Some(&(_, first)) if size > first => Err(format!(
+                "common /{}/ is declared with {} bytes, more than the {} first declared",
+                name, size, first
+            )),
+            Some(_) => Ok(()),
+            None => {
+                let next = self.next_data.as_mut().unwrap_or(&mut self.next_program);
+                let origin = *next;
+                *next = origin.wrapping_add(size);
+                self.commons.insert(name.to_string(), (origin, size));
+                Ok(())
+            }
+        }
+    }
+
+    // The address of a value relative to a segment
+    fn relocate(&mut self, module: &Module, current: &mut Current, kind: AddressType, value: u16) -> Result<u16, String> {
+        let origin = match kind {
+            AddressType::Absolute => 0,
+            AddressType::ProgramRelative => {
+                let index = self.place(module, current);
+                self.placements[index].program
+            }
+            AddressType::DataRelative => {
+                let index = self.place(module, current);
+                self.placements[index].data
+            }
+            AddressType::CommonRelative => current
+                .common
+                .ok_or_else(|| format!("{} uses a common address with no common block selected", module.name))?,
+        };
+        Ok(origin.wrapping_add(value))
+    }
+
+    fn location(&mut self, module: &Module, current: &mut Current) -> Result<u16, String> {
+        self.relocate(module, current, current.segment, current.offset)
+    }
+
+    // The addresses in a chain through memory, from its head to the word holding 0
+    fn chain(&self, head: u16, name: &str) -> Result<Vec<u16>, String> {
+        let mut addresses = vec![head];
+        loop {
+            let next = self.word(*addresses.last().unwrap());
+            if next == 0 {
+                return Ok(addresses);
+            }
+            if addresses.len() > 0x8000 {
+                return Err(format!("the chain for {} never ends", name));
+            }
+            addresses.push(next);
+        }
+    }
+
+    fn evaluate(&self, terms: &[Term]) -> Result<u16, String> {
+        let mut stack: Vec<u16> = Vec::new();
+        for term in terms {
+            let value = match term {
+                Term::Value(value) => *value,
+                Term::External(name) => self.publics[name].0,
+                Term::Operator(op) => {
+                    let unary = matches!(
+                        op,
+                        Operator::HighByte | Operator::LowByte | Operator::Complement | Operator::Negate
+                    );
+                    let b = stack.pop();
+                    let a = if unary { Some(0) } else { stack.pop() };
+                    let (a, b) = a.zip(b).ok_or_else(|| format!("too few operands for {:?}", op))?;
+                    match op {
+                        Operator::HighByte => b >> 8,
+                        Operator::LowByte => b & 0xff,
+                        Operator::Complement => !b,
+                        Operator::Negate => b.wrapping_neg(),
+                        Operator::Subtract => a.wrapping_sub(b),
+                        Operator::Add => a.wrapping_add(b),
+                        Operator::Multiply => a.wrapping_mul(b),
+                        Operator::Divide => a.checked_div(b).ok_or("division by zero")?,
+                        Operator::Modulo => a.checked_rem(b).ok_or("division by zero")?,
+                        Operator::StoreByte | Operator::StoreWord => unreachable!("stores end an expression"),
+                    }
+                }
+            };
+            stack.push(value);
+        }
+        match stack.as_slice() {
+            [value] => Ok(*value),
+            _ => Err(format!("an expression leaves {} values", stack.len())),
+        }
+    }
+
+    fn word(&self, address: u16) -> u16 {
+        u16::from_le_bytes([self.memory[address as usize], self.memory[address.wrapping_add(1) as usize]])
+    }
+
+    fn write(&mut self, address: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            let address = address.wrapping_add(i as u16);
+            self.memory[address as usize] = *byte;
+            self.extent = Some(match self.extent {
+                Some((low, high)) => (low.min(address), high.max(address)),
+                None => (address, address),
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::link::*;
+    use crate::rel::AddressType::{CommonRelative, DataRelative, ProgramRelative};
+    use crate::rel::RelEntry::*;
+
+    fn module(name: &str, mut entries: Vec<RelEntry>) -> Module {
+        entries.insert(0, ProgramName(name.to_string()));
+        Module {
+            name: name.to_string(),
+            entries,
+        }
+    }
+
+    fn main() -> Module {
+        module(
+            "MAIN",
+            vec![
+                DataSize(AddressType::Absolute, 2),
+                TextSize(AddressType::Absolute, 8),
+                SetLocation(ProgramRelative, 0),
+                // call PRINT
+                Absolute(0xcd),
+                Absolute(0x00),
+                Absolute(0x00),
+                // ld hl, BUFFER
+                Absolute(0x21),
+                Relative(DataRelative, 0),
+                // ld a, high(PRINT)+1
+                Absolute(0x3e),
+                ExternalOperand("PRINT".to_string()),
+                Operation(Operator::HighByte),
+                Operand(AddressType::Absolute, 1),
+                Operation(Operator::Add),
+                Operation(Operator::StoreByte),
+                Absolute(0x00),
+                SetLocation(DataRelative, 0),
+                Absolute(0x55),
+                Absolute(0xaa),
+                EntryPoint(ProgramRelative, 0, "START".to_string()),
+                EntryPoint(DataRelative, 0, "BUFFER".to_string()),
+                ChainExternal(ProgramRelative, 1, "PRINT".to_string()),
+                EndModule(ProgramRelative, 0),
+            ],
+        )
+    }
+
+    fn library() -> Vec<Module> {
+        vec![
+            module(
+                "UNUSED",
+                vec![
+                    TextSize(AddressType::Absolute, 1),
+                    Absolute(0x00),
+                    EntryPoint(ProgramRelative, 0, "NOPE".to_string()),
+                    EndModule(AddressType::Absolute, 0),
+                ],
+            ),
+            module(
+                "PUTS",
+                vec![
+                    TextSize(AddressType::Absolute, 3),
+                    Absolute(0xc3),
+                    Absolute(0x00),
+                    Absolute(0x00),
+                    EntryPoint(ProgramRelative, 0, "PUTS".to_string()),
+                    ChainExternal(ProgramRelative, 1, "PUTCH".to_string()),
+                    EndModule(AddressType::Absolute, 0),
+                ],
+            ),
+            module(
+                "PRINT",
+                vec![
+                    TextSize(AddressType::Absolute, 3),
+                    Absolute(0xc3),
+                    Absolute(0x00),
+                    Absolute(0x00),
+                    EntryPoint(ProgramRelative, 0, "PRINT".to_string()),
+                    ChainExternal(ProgramRelative, 1, "PUTS".to_string()),
+                    EndModule(AddressType::Absolute, 0),
+                ],
+            ),
+            module(
+                "PUTCH",
+                vec![
+                    TextSize(AddressType::Absolute, 1),
+                    Absolute(0xc9),
+                    EntryPoint(ProgramRelative, 0, "PUTCH".to_string()),
+                    EndModule(AddressType::Absolute, 0),
+                ],
+            ),
+        ]
+    }
+
+    #[test]
+    fn links_modules_and_searches_libraries() {
+        let mut linker = Linker::new(0x0100);
+        linker.link(&main()).unwrap();
+        assert_eq!(linker.undefined(), vec!["PRINT"]);
+        assert_eq!(linker.search(&library()), Ok(3));
+        linker.finish().unwrap();
+
+        // MAIN's program, then its data, then PRINT, PUTS (found on the second pass) and PUTCH
+        assert_eq!(linker.extent(), Some((0x0100, 0x0110)));
+        assert_eq!(
+            &linker.memory()[0x0100..0x0111],
+            &[0xcd, 0x0a, 0x01, 0x21, 0x08, 0x01, 0x3e, 0x02, 0x55, 0xaa, 0xc3, 0x0d, 0x01, 0xc3, 0x10, 0x01, 0xc9]
+        );
+        assert_eq!(linker.start(), Some(0x0100));
+        let names: Vec<&str> = linker.placements().iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["MAIN", "PRINT", "PUTS", "PUTCH"]);
+
+        let symbols = linker.symbols();
+        assert_eq!(symbols.lookup("BUFFER"), Some(0x0108));
+        assert_eq!(symbols.lookup("NOPE"), None);
+
+        let mut map = Vec::new();
+        linker.write_map(&mut map).unwrap();
+        let map = String::from_utf8(map).unwrap();
+        assert!(map.contains("; MAIN       0100-0107  0108-0109"));
+        assert!(map.contains("; start at 0100"));
+        assert!(map.contains("\n010A PRINT\n"));
+        let mut parsed = Symbols::new();
+        assert_eq!(parsed.parse(&map), 5);
+        assert_eq!(parsed.lookup("PUTCH"), Some(0x0110));
+    }
+
+    #[test]
+    fn lays_out_data_and_common_blocks() {
+        let mut linker = Linker::new(0x0100);
+        linker.data_origin(0x8000);
+        let first = module(
+            "FIRST",
+            vec![
+                CommonSize(AddressType::Absolute, 4, "BLK".to_string()),
+                DataSize(AddressType::Absolute, 1),
+                TextSize(AddressType::Absolute, 8),
+                // two forward references to the same place, chained through the program segment
+                Absolute(0x00),
+                Absolute(0x00),
+                Relative(ProgramRelative, 0),
+                SelectCommon("BLK".to_string()),
+                Relative(CommonRelative, 2),
+                Relative(DataRelative, 0),
+                ChainAddress(ProgramRelative, 2),
+                SetLocation(CommonRelative, 0),
+                Absolute(0x11),
+                EndModule(AddressType::Absolute, 0),
+            ],
+        );
+        let second = module(
+            "SECOND",
+            vec![
+                CommonSize(AddressType::Absolute, 2, "BLK".to_string()),
+                DataSize(AddressType::Absolute, 1),
+                TextSize(AddressType::Absolute, 2),
+                SelectCommon("BLK".to_string()),
+                // dw -(BLK+3)
+                Operand(CommonRelative, 3),
+                Operation(Operator::Negate),
+                Operation(Operator::StoreWord),
+                Absolute(0),
+                Absolute(0),
+                EndModule(AddressType::Absolute, 0),
+            ],
+        );
+        linker.link(&first).unwrap();
+        linker.link(&second).unwrap();
+        linker.finish().unwrap();
+
+        assert_eq!(
+            &linker.memory()[0x0100..0x010a],
+            &[0x08, 0x01, 0x08, 0x01, 0x02, 0x80, 0x04, 0x80, 0xfd, 0x7f]
+        );
+        assert_eq!(linker.memory()[0x8000], 0x11);
+        assert_eq!(linker.placements()[1].data, 0x8005);
+        assert_eq!(linker.start(), None);
+
+        let bigger = module("BIGGER", vec![CommonSize(AddressType::Absolute, 8, "BLK".to_string())]);
+        assert!(linker.link(&bigger).is_err());
+    }
+
+    #[test]
+    fn reports_link_errors() {
+        let mut linker = Linker::new(0x0100);
+        linker.link(&main()).unwrap();
+        assert_eq!(linker.finish(), Err("undefined symbols: PRINT".to_string()));
+        assert_eq!(
+            linker.link(&main()),
+            Err("START is defined in both MAIN and MAIN".to_string())
+        );
+
+        let mut linker = Linker::new(0x0100);
+        let divide = module(
+            "DIVIDE",
+            vec![
+                Operand(AddressType::Absolute, 1),
+                Operand(AddressType::Absolute, 0),
+                Operation(Operator::Divide),
+                Operation(Operator::StoreByte),
+                Absolute(0),
+                EndModule(AddressType::Absolute, 0),
+            ],
+        );
+        linker.link(&divide).unwrap();
+        assert_eq!(linker.finish(), Err("division by zero".to_string()));
+    }
+}
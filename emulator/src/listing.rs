@@ -0,0 +1,233 @@
+/**
+ * Assembler listings
+ *
+ * Maps source lines to the addresses of the code assembled from them and back, using the listing file an assembler
+ * writes alongside its output. A listing line that produced code holds an optional decimal source line number, a
+ * four digit hex address, the assembled bytes as two digit hex pairs, and then the source text:
+ *
+ *   12    0100 3E 05        ld a, 5
+ *   13    0102 CD 10 F6     call bios
+ *
+ * This is the shape of sjasmplus and z88dk listings, among others. Lines with an address but no bytes, such as labels
+ * and equates, don't map to code.
+ *
+ * Known limitations:
+ *  1. A listing maps the lines of a single source file; include files are not followed
+ *  2. Without line numbers, lines are numbered by their position in the listing itself
+ *  3. Addresses are logical; code assembled to run in more than one bank will map ambiguously
+ */
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, PartialEq, Clone)]
+struct Entry {
+    line: u32,
+    address: u16,
+    bytes: Vec<u8>,
+    // the source text, without the address and bytes
+    text: String,
+}
+
+pub struct Listing {
+    path: PathBuf,
+    // entries in listing order, which is also source line order
+    entries: Vec<Entry>,
+    // whether lines are numbered as in the source, rather than by their place in the listing
+    numbered: bool,
+}
+
+fn hex_token(token: &str, digits: usize) -> Option<u16> {
+    if token.len() == digits && token.chars().all(|c| c.is_ascii_hexdigit()) {
+        u16::from_str_radix(token, 16).ok()
+    } else {
+        None
+    }
+}
+
+// An address token, allowing the ' or : some assemblers append.
+fn address_token(token: &str) -> Option<u16> {
+    hex_token(token.trim_end_matches(['\'', ':']), 4)
+}
+
+// A source line number, allowing the + or ~ markers for macro expansions and include levels.
+fn line_token(token: &str) -> Option<u32> {
+    let digits = token.trim_end_matches(|c: char| !c.is_ascii_digit());
+    if digits.is_empty() || !digits.chars().all(|c| c.is_ascii_digit()) {
+        return None;
+    }
+    digits.parse().ok()
+}
+
+// Split a listing line into a source line number, if it has one, an address, the bytes assembled, which may be none,
+// and the source text.
+pub(crate) fn split_line(text: &str) -> Option<(Option<u32>, u16, Vec<u8>, &str)> {
+    // split off a word and what follows it
+    fn word(text: &str) -> (&str, &str) {
+        let text = text.trim_start();
+        let end = text.find(char::is_whitespace).unwrap_or(text.len());
+        (&text[..end], text[end..].trim_start())
+    }
+    let (first, after_first) = word(text);
+    let (second, after_second) = word(after_first);
+    let (line, address, mut rest) = match (line_token(first), address_token(second)) {
+        (Some(line), Some(address)) => (Some(line), address, after_second),
+        _ => (None, address_token(first)?, after_first),
+    };
+    let mut bytes = Vec::new();
+    while rest.len() >= 2 && !rest[2..].starts_with(|c: char| !c.is_whitespace()) {
+        match hex_token(&rest[..2], 2) {
+            Some(byte) => bytes.push(byte as u8),
+            None => break,
+        }
+        rest = rest[2..].trim_start();
+    }
+    Some((line, address, bytes, rest))
+}
+
+// Parse one listing line that assembled to code into a source line number, if it has one, an address, the bytes
+// assembled, and the source text.
+fn parse_line(text: &str) -> Option<(Option<u32>, u16, Vec<u8>, &str)> {
+    match split_line(text)? {
+        (_, _, bytes, _) if bytes.is_empty() => None,
+        parsed => Some(parsed),
+    }
+}
+
+impl Listing {
+    /// Parse the text of a listing. `path` is recorded to match the listing with its source file.
+    pub fn parse<P: Into<PathBuf>>(path: P, text: &str) -> Listing {
+        let mut numbered = false;
+        let entries = text
+            .lines()
+            .enumerate()
+            .filter_map(|(index, text)| {
+                let (line, address, bytes, text) = parse_line(text)?;
+                numbered |= line.is_some();
+                Some(Entry {
+                    line: line.unwrap_or(index as u32 + 1),
+                    address,
+                    bytes,
+                    text: text.trim_end().to_string(),
+                })
+            })
+            .collect();
+        Listing {
+            path: path.into(),
+            entries,
+            numbered,
+        }
+    }
+
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Listing, std::io::Error> {
+        let text = std::fs::read_to_string(&path)?;
+        Ok(Listing::parse(path.as_ref(), &text))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Answer whether lines are numbered as in the source file, rather than by their place in the listing.
+    pub fn is_numbered(&self) -> bool {
+        self.numbered
+    }
+
+    /// Each line that assembled to code, in order, as its line number, address, bytes and source text.
+    pub fn lines(&self) -> impl Iterator<Item = (u32, u16, &[u8], &str)> + '_ {
+        self.entries
+            .iter()
+            .map(|entry| (entry.line, entry.address, entry.bytes.as_slice(), entry.text.as_str()))
+    }
+
+    /// Answer whether this listing describes `source`: the listing itself, or a file with the same name but for its
+    /// extension.
+    pub fn covers(&self, source: &Path) -> bool {
+        if source == self.path {
+            return true;
+        }
+        match (source.file_stem(), self.path.file_stem()) {
+            (Some(a), Some(b)) => a.to_string_lossy().eq_ignore_ascii_case(&b.to_string_lossy()),
+            _ => false,
+        }
+    }
+
+    /// The first line at or after `line` that assembled to code, and the address of that code.
+    pub fn address_of_line(&self, line: u32) -> Option<(u32, u16)> {
+        self.entries
+            .iter()
+            .filter(|entry| entry.line >= line)
+            .min_by_key(|entry| entry.line)
+            .map(|entry| (entry.line, entry.address))
+    }
+
+    /// The source text of a line that assembled to code.
+    pub fn text_of_line(&self, line: u32) -> Option<&str> {
+        self.entries
+            .iter()
+            .find(|entry| entry.line == line)
+            .map(|entry| entry.text.as_str())
+    }
+
+    /// The line whose code includes the byte at `address`.
+    pub fn line_of_address(&self, address: u16) -> Option<u32> {
+        self.entries
+            .iter()
+            .find(|entry| (address.wrapping_sub(entry.address) as usize) < entry.bytes.len())
+            .map(|entry| entry.line)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::listing::*;
+
+    #[test]
+    fn numbered_listing() {
+        let listing = Listing::parse(
+            "bios.lst",
+            "\
+# file opened: bios.asm
+ 1    0000              ; entry point
+ 2    0100              start:
+ 3    0100 3E 05            ld a, 5
+ 4    0102 CD 10 F6         call bios
+ 5    0105                  ; spin
+ 6    0105 18 FE            jr $
+ 7+   0107 DD 21 00 80      ld ix, $8000
+",
+        );
+        assert_eq!(listing.address_of_line(3), Some((3, 0x0100)));
+        assert_eq!(
+            listing.address_of_line(2),
+            Some((3, 0x0100)),
+            "labels move to the next code line"
+        );
+        assert_eq!(listing.address_of_line(5), Some((6, 0x0105)));
+        assert_eq!(listing.address_of_line(8), None);
+        assert_eq!(listing.line_of_address(0x0103), Some(4));
+        assert_eq!(listing.line_of_address(0x010a), Some(7));
+        assert_eq!(listing.line_of_address(0x010b), None);
+        assert_eq!(listing.text_of_line(4), Some("call bios"));
+        assert_eq!(
+            listing.lines().nth(1),
+            Some((4, 0x0102, &[0xcd, 0x10, 0xf6][..], "call bios"))
+        );
+        assert!(listing.is_numbered());
+        assert!(listing.covers(Path::new("BIOS.ASM")));
+        assert!(!listing.covers(Path::new("cpm.asm")));
+    }
+
+    #[test]
+    fn unnumbered_listing() {
+        let listing = Listing::parse(
+            "boot.prn",
+            "\
+0000'                   org 0
+0000' C3 03 00          jp start
+0003' AF        start:  xor a
+",
+        );
+        assert_eq!(listing.address_of_line(1), Some((2, 0x0000)));
+        assert_eq!(listing.line_of_address(0x0003), Some(3));
+        assert!(!listing.is_numbered());
+    }
+}
@@ -0,0 +1,268 @@
+/**
+ * Execution profiling
+ *
+ * A `Profiler` counts the instructions run and the clock cycles they took, at each physical address and in each call
+ * stack, as read from the CPU's shadow call stack. Call `profile` before each instruction and `finish` when the run
+ * ends; each instruction is counted from the CPU's record of it once it has run, against the call stack it ran in.
+ *
+ * A function is the code entered by a call, restart or interrupt, named by the symbol for its entry address. Code run
+ * outside any call is counted against `[top]`. The flat report lists functions by the cycles spent in them, without
+ * and with the functions they called, and then the busiest addresses. The folded stacks have a line per call stack,
+ * its functions from outermost to innermost separated by semicolons and then the cycles spent in it, which is the
+ * input flame graph tools expect.
+ *
+ * Known limitations:
+ *  1. Functions are told apart by their logical entry address, so code banked in at the same address is counted as
+ *     one function
+ *  2. Code reached by jumping into another function, as a tail call, counts as the function that jumped
+ */
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::cpu::{Mode, CPU};
+use crate::symbols::Symbols;
+
+// The number of addresses listed in the flat report
+const REPORT_ADDRESSES: usize = 50;
+
+/// Instructions run and the clock cycles they took.
+#[derive(Debug, Default, PartialEq, Copy, Clone)]
+pub struct Count {
+    pub instructions: u64,
+    pub tstates: u64,
+}
+
+impl Count {
+    fn add(&mut self, tstates: u64) {
+        self.instructions += 1;
+        self.tstates += tstates;
+    }
+
+    fn merge(&mut self, other: Count) {
+        self.instructions += other.instructions;
+        self.tstates += other.tstates;
+    }
+}
+
+/// Counts where a CPU spends its time.
+#[derive(Default)]
+pub struct Profiler {
+    // by physical address, with the logical address last seen there
+    addresses: HashMap<u32, (u16, Count)>,
+    // by the entry addresses of the call stack, outermost first
+    stacks: HashMap<Vec<u16>, Count>,
+    total: Count,
+    // the call stack of the instruction about to run, counted once it has
+    pending: Option<Vec<u16>>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Default::default()
+    }
+
+    /// Count the last instruction, and note the call stack of the one about to run.
+    pub fn profile(&mut self, cpu: &CPU) {
+        self.finish(cpu);
+        if cpu.mode != Mode::OpCodeFetch {
+            return;
+        }
+        self.pending = Some(cpu.call_stack().iter().map(|frame| frame.entry).collect());
+    }
+
+    /// Count the last instruction, at the end of a run.
+    pub fn finish(&mut self, cpu: &CPU) {
+        let (stack, retired) = match (self.pending.take(), cpu.retired()) {
+            (Some(stack), Some(retired)) => (stack, retired),
+            _ => return,
+        };
+        let tstates = retired.tstates as u64;
+        let address = self.addresses.entry(retired.physical).or_default();
+        address.0 = retired.pc;
+        address.1.add(tstates);
+        self.stacks.entry(stack).or_default().add(tstates);
+        self.total.add(tstates);
+    }
+
+    /// Everything counted so far.
+    pub fn total(&self) -> Count {
+        self.total
+    }
+
+    /// What was counted at a physical address.
+    pub fn at(&self, physical: u32) -> Count {
+        self.addresses.get(&physical).map(|(_, count)| *count).unwrap_or_default()
+    }
+
+    /// The counts for each function, by entry address, or None for code outside any call: first what was spent in
+    /// the function itself, then that including the functions it called.
+    pub fn functions(&self) -> HashMap<Option<u16>, (Count, Count)> {
+        let mut functions: HashMap<Option<u16>, (Count, Count)> = HashMap::new();
+        for (stack, count) in &self.stacks {
+            functions.entry(stack.last().copied()).or_default().0.merge(*count);
+            functions.entry(None).or_default().1.merge(*count);
+            for (depth, entry) in stack.iter().enumerate() {
+                // recursion counts once
+                if !stack[..depth].contains(entry) {
+                    functions.entry(Some(*entry)).or_default().1.merge(*count);
+                }
+            }
+        }
+        functions
+    }
+
+    /// Write the flat report.
+    pub fn report<W: Write>(&self, symbols: &Symbols, output: &mut W) -> io::Result<()> {
+        let percent = |tstates: u64| tstates as f64 * 100.0 / self.total.tstates.max(1) as f64;
+        let mut functions: Vec<(Option<u16>, (Count, Count))> = self.functions().into_iter().collect();
+        functions.sort_by(|a, b| (b.1).0.tstates.cmp(&(a.1).0.tstates).then(a.0.cmp(&b.0)));
+        writeln!(
+            output,
+            "{} instructions, {} clock cycles",
+            self.total.instructions, self.total.tstates
+        )?;
+        writeln!(output)?;
+        writeln!(output, "        self      %        total      %  instructions  function")?;
+        for (entry, (own, all)) in functions {
+            writeln!(
+                output,
+                "{:>12} {:>6.2} {:>12} {:>6.2} {:>13}  {}",
+                own.tstates,
+                percent(own.tstates),
+                all.tstates,
+                percent(all.tstates),
+                own.instructions,
+                function_name(entry, symbols)
+            )?;
+        }
+
+        let mut addresses: Vec<(&u32, &(u16, Count))> = self.addresses.iter().collect();
+        addresses.sort_by(|a, b| (b.1).1.tstates.cmp(&(a.1).1.tstates).then(a.0.cmp(b.0)));
+        writeln!(output)?;
+        writeln!(output, "    cycles      %  instructions  physical  logical")?;
+        for (physical, (pc, count)) in addresses.into_iter().take(REPORT_ADDRESSES) {
+            let location = symbols.describe(*pc).map(|name| format!(" <{}>", name)).unwrap_or_default();
+            writeln!(
+                output,
+                "{:>10} {:>6.2} {:>13}    ${:05x}    ${:04x}{}",
+                count.tstates,
+                percent(count.tstates),
+                count.instructions,
+                physical,
+                pc,
+                location
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Write the cycles spent in each call stack, in the folded format flame graph tools read.
+    pub fn folded<W: Write>(&self, symbols: &Symbols, output: &mut W) -> io::Result<()> {
+        let mut lines: Vec<(String, u64)> = self
+            .stacks
+            .iter()
+            .map(|(stack, count)| {
+                let names = std::iter::once(function_name(None, symbols))
+                    .chain(stack.iter().map(|entry| function_name(Some(*entry), symbols)));
+                (names.collect::<Vec<String>>().join(";"), count.tstates)
+            })
+            .collect();
+        lines.sort();
+        for (stack, tstates) in lines {
+            writeln!(output, "{} {}", stack, tstates)?;
+        }
+        Ok(())
+    }
+}
+
+// Name a function by its entry address.
+fn function_name(entry: Option<u16>, symbols: &Symbols) -> String {
+    match entry {
+        Some(entry) => symbols.describe(entry).unwrap_or_else(|| format!("${:04x}", entry)),
+        None => "[top]".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::Bus;
+    use crate::profile::*;
+    use crate::ram::RAM;
+
+    #[test]
+    fn counts_by_function() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(
+            0x0000,
+            &[
+                0x31, 0x00, 0x80, //    0x0000  ld sp, $8000
+                0xcd, 0x08, 0x00, //    0x0003  call inner
+                0x76, //                0x0006  halt
+                0x00, //                0x0007  nop
+                0x00, //                0x0008  inner: nop
+                0x00, //                0x0009  nop
+                0xc9, //                0x000a  ret
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        cpu.reset();
+        let mut profiler = Profiler::new();
+        loop {
+            profiler.profile(&cpu);
+            if cpu.mode != Mode::OpCodeFetch {
+                break;
+            }
+            cpu.cycle(&mut bus);
+        }
+        profiler.finish(&cpu);
+
+        // ld sp 9, call 16, halt 3; nop 3, nop 3, ret 9
+        assert_eq!(
+            profiler.total(),
+            Count {
+                instructions: 6,
+                tstates: 43
+            }
+        );
+        assert_eq!(
+            profiler.at(0x0009),
+            Count {
+                instructions: 1,
+                tstates: 3
+            }
+        );
+        let functions = profiler.functions();
+        assert_eq!(functions[&None].0.tstates, 28);
+        assert_eq!(functions[&None].1.tstates, 43);
+        assert_eq!(
+            functions[&Some(0x0008)],
+            (
+                Count {
+                    instructions: 3,
+                    tstates: 15
+                },
+                Count {
+                    instructions: 3,
+                    tstates: 15
+                }
+            )
+        );
+
+        let mut symbols = Symbols::new();
+        symbols.insert("inner", 0x0008);
+        let mut folded = Vec::new();
+        profiler.folded(&symbols, &mut folded).unwrap();
+        assert_eq!(String::from_utf8(folded).unwrap(), "[top] 28\n[top];inner 15\n");
+
+        let mut report = Vec::new();
+        profiler.report(&symbols, &mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.starts_with("6 instructions, 43 clock cycles\n"));
+        assert!(report.contains("  inner\n"));
+    }
+}
@@ -6,15 +6,19 @@
  *  3. Timer 1 is not implemented
  *
  */
-use std::cell::RefCell;
-use std::time::Instant;
+use std::cell::{Cell, RefCell};
+use std::time::{Duration, Instant};
 
 use crate::bus::Bus;
+use crate::history::Source;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
 pub struct PRT {
     timer0: RefCell<(Instant, u16)>,
     timer1: RefCell<(Instant, u16)>,
+    // timer 0 ticks since it was last started, as last read from the host clock
+    ticks0: Cell<u64>,
     rldr0: RefCell<u16>,
     tmdr0: RefCell<u16>,
     tmdr0t: RefCell<u8>,
@@ -29,6 +33,7 @@ impl PRT {
         PRT {
             timer0: RefCell::new((Instant::now(), 0xffff)),
             timer1: RefCell::new((Instant::now(), 0xffff)),
+            ticks0: Cell::new(0),
             rldr0: RefCell::new(0),
             tmdr0: RefCell::new(0xffff),
             tmdr0t: RefCell::new(0),
@@ -40,26 +45,50 @@ impl PRT {
     }
 }
 
+// Timers count at the system clock divided by 20.
+fn ticks_from_nanos(nanos: u128) -> u64 {
+    (nanos * 18432000 / 20000000000) as u64
+}
+
+// The instant a timer started for it to have counted some ticks by now.
+fn started(ticks: u64) -> Instant {
+    let elapsed = Duration::from_nanos((ticks as u128 * 20000000000 / 18432000) as u64);
+    let now = Instant::now();
+    now.checked_sub(elapsed).unwrap_or(now)
+}
+
 impl Peripheral for PRT {
-    fn cycle(&self, _bus: &Bus) -> Option<Interrupt> {
+    fn cycle(&self, bus: &Bus) -> Option<Interrupt> {
         let mut tcr = self.tcr.borrow_mut();
         if (*tcr & 0b0000_0001) != 0 {
             let (then, base) = *self.timer0.borrow();
-            // get tick count since 'then' - 20e9 for divided by 20, converted to nanoseconds
-            let ticks = then.elapsed().as_nanos() * 18432000 / 20000000000;
+            // get tick count since 'then', taken in only when it changes so a recorded run can be replayed
+            let held = self.ticks0.get();
+            let ticks = bus
+                .input(Source::Timer(0), || {
+                    let ticks = ticks_from_nanos(then.elapsed().as_nanos());
+                    if ticks != held {
+                        Some(ticks)
+                    } else {
+                        None
+                    }
+                })
+                .unwrap_or(held);
+            self.ticks0.set(ticks);
 
             // has the timer overflowed?
-            if ticks >= base as u128 {
+            if ticks >= base as u64 {
                 let rldr = *self.rldr0.borrow();
                 *tcr |= 0b0100_0000;
                 // say ticks is 110, base is 100, reload is 100
                 // ticks - base is 10 overflow
                 // should overflow again in 90
-                let now = (Instant::now(), rldr - ((ticks - base as u128) % (rldr as u128)) as u16);
+                let now = (Instant::now(), rldr - ((ticks - base as u64) % (rldr as u64)) as u16);
                 *self.tmdr0.borrow_mut() = now.1;
                 *self.timer0.borrow_mut() = now;
+                self.ticks0.set(0);
             } else {
-                *self.tmdr0.borrow_mut() = (base as u128 - ticks) as u16;
+                *self.tmdr0.borrow_mut() = (base as u64 - ticks) as u16;
             }
         }
         None
@@ -143,6 +172,7 @@ impl Peripheral for PRT {
                 // If TDE0/TDE1 is changing, reset the state for that timer
                 if data & 0b0000_0001 != *tcr & 0b0000_0001 {
                     *self.timer0.borrow_mut() = (Instant::now(), *self.tmdr0.borrow());
+                    self.ticks0.set(0);
                 }
                 if data & 0b0000_0010 != *tcr & 0b0000_0010 {
                     *self.timer1.borrow_mut() = (Instant::now(), *self.tmdr1.borrow());
@@ -153,4 +183,32 @@ impl Peripheral for PRT {
             _ => (),
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u16(self.timer0.borrow().1);
+        state.u64(self.ticks0.get());
+        state.u16(self.timer1.borrow().1);
+        for register in [&self.rldr0, &self.tmdr0, &self.rldr1, &self.tmdr1] {
+            state.u16(*register.borrow());
+        }
+        for register in [&self.tmdr0t, &self.tmdr1t, &self.tcr] {
+            state.u8(*register.borrow());
+        }
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        // the host clock carries on from where the timers were, as if no time passed while they were away
+        let base0 = state.u16()?;
+        let ticks0 = state.u64()?;
+        *self.timer0.borrow_mut() = (started(ticks0), base0);
+        self.ticks0.set(ticks0);
+        *self.timer1.borrow_mut() = (Instant::now(), state.u16()?);
+        for register in [&self.rldr0, &self.tmdr0, &self.rldr1, &self.tmdr1] {
+            *register.borrow_mut() = state.u16()?;
+        }
+        for register in [&self.tmdr0t, &self.tmdr1t, &self.tcr] {
+            *register.borrow_mut() = state.u8()?;
+        }
+        Ok(())
+    }
 }
@@ -1,51 +1,412 @@
-use std::cell::RefCell;
-use std::cmp::min;
-use std::path::Path;
+use std::cell::{Cell, RefCell};
+use std::path::{Path, PathBuf};
 
-use crate::types::Peripheral;
+use crate::bus::{Bus, CYCLES_PER_SECOND};
+use crate::region::{MemoryRegion, RegionError};
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::types::*;
+
+// A file holding the contents of battery-backed RAM across emulator runs.
+struct Backing {
+    path: PathBuf,
+    dirty: Cell<bool>,
+    flushed: Cell<u64>,
+}
+
+/// What to do when the CPU reads a RAM byte that was never written.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum UninitPolicy {
+    Ignore,
+    Warn,
+    Break,
+}
 
 pub struct RAM {
-    start: u32,
-    size: u32,
-    bytes: RefCell<Vec<u8>>,
+    region: RefCell<MemoryRegion>,
+    backing: Option<Backing>,
+    // shadow bitmap of bytes that have been written, one bit per byte, when tracking is on
+    uninit: Cell<UninitPolicy>,
+    written: RefCell<Vec<u64>>,
+    faults: RefCell<Vec<Fault>>,
 }
 
 impl RAM {
     pub fn new(start: u32, size: u32) -> RAM {
         RAM {
-            start: start,
-            size: size,
-            bytes: RefCell::new(vec![0u8; size as usize]),
+            region: RefCell::new(MemoryRegion::new(start, size)),
+            backing: None,
+            uninit: Cell::new(UninitPolicy::Ignore),
+            written: RefCell::new(Vec::new()),
+            faults: RefCell::new(Vec::new()),
         }
     }
 
-    pub fn write(&self, address: u32, data: &[u8]) {
-        let limit = min(data.len(), (self.size + self.start - address) as usize);
-        self.bytes.borrow_mut()[(address - self.start) as usize..(limit + address as usize)].copy_from_slice(&data[..limit]);
+    /// Create battery-backed RAM whose contents persist in a file. The file is loaded if it exists, and the contents
+    /// are written back once a second of emulated time while modified, on `flush`, and when the RAM is dropped.
+    pub fn battery_backed<P: AsRef<Path>>(start: u32, size: u32, path: P) -> Result<RAM, std::io::Error> {
+        let mut bytes = match std::fs::read(&path) {
+            Ok(bytes) => bytes,
+            Err(ref e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        bytes.resize(size as usize, 0);
+        Ok(RAM {
+            region: RefCell::new(MemoryRegion::with_contents(start, bytes)),
+            backing: Some(Backing {
+                path: path.as_ref().to_path_buf(),
+                dirty: Cell::new(false),
+                flushed: Cell::new(0),
+            }),
+            uninit: Cell::new(UninitPolicy::Ignore),
+            written: RefCell::new(Vec::new()),
+            faults: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Write modified battery-backed contents to the backing file.
+    pub fn flush(&self) -> Result<(), std::io::Error> {
+        if let Some(backing) = &self.backing {
+            if backing.dirty.get() {
+                std::fs::write(&backing.path, self.region.borrow().as_slice())?;
+                backing.dirty.set(false);
+            }
+        }
+        Ok(())
+    }
+
+    /// Fill the RAM with pseudo-random power-on contents. The same seed always produces the same contents.
+    pub fn randomize(&self, seed: u64) {
+        // xorshift64*
+        let mut state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        for byte in self.region.borrow_mut().as_mut_slice().iter_mut() {
+            state ^= state >> 12;
+            state ^= state << 25;
+            state ^= state >> 27;
+            *byte = (state.wrapping_mul(0x2545_f491_4f6c_dd1d) >> 56) as u8;
+        }
     }
 
-    pub fn read(&self, address: u32, data: &mut [u8]) {
-        let limit = min(data.len(), (self.size + self.start - address) as usize);
-        data[..limit].copy_from_slice(&self.bytes.borrow()[(address - self.start) as usize..(limit + address as usize)]);
+    /// Track which bytes have been written, and raise a fault when the CPU reads one that hasn't. Each byte is reported
+    /// once. Enabling tracking forgets any earlier writes.
+    pub fn track_uninitialised(&self, policy: UninitPolicy) {
+        self.uninit.set(policy);
+        let words = if policy == UninitPolicy::Ignore {
+            0
+        } else {
+            (self.region.borrow().size() as usize).div_ceil(64)
+        };
+        *self.written.borrow_mut() = vec![0; words];
+    }
+
+    // Note that a range of bytes has been written.
+    fn touch(&self, offset: usize, len: usize) {
+        if let Some(backing) = &self.backing {
+            backing.dirty.set(true);
+        }
+        if self.uninit.get() != UninitPolicy::Ignore {
+            let mut written = self.written.borrow_mut();
+            for bit in offset..offset + len {
+                written[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+    }
+
+    // Check a byte read by the CPU was written first.
+    fn check_read(&self, offset: usize) {
+        let policy = self.uninit.get();
+        if policy != UninitPolicy::Ignore && self.written.borrow()[offset / 64] & (1 << (offset % 64)) == 0 {
+            let address = self.region.borrow().base() + offset as u32;
+            self.faults.borrow_mut().push(Fault {
+                address,
+                message: format!("read of uninitialised RAM at ${:05x}", address),
+                halt: policy == UninitPolicy::Break,
+            });
+            self.touch(offset, 1);
+        }
+    }
+
+    /// Copy `data` into RAM at physical `address`. The whole block must fit within the RAM.
+    pub fn write(&self, address: u32, data: &[u8]) -> Result<(), RegionError> {
+        let mut region = self.region.borrow_mut();
+        region.write(address, data)?;
+        let offset = (address - region.base()) as usize;
+        drop(region);
+        self.touch(offset, data.len());
+        Ok(())
+    }
+
+    /// Fill `data` from RAM at physical `address`. The whole block must lie within the RAM.
+    pub fn read(&self, address: u32, data: &mut [u8]) -> Result<(), RegionError> {
+        self.region.borrow().read(address, data)
     }
 
     pub fn load_file<P: AsRef<Path>>(&self, address: u32, filename: P) -> Result<(), std::io::Error> {
         let buffer = std::fs::read(filename)?;
-        self.write(address, buffer.as_slice());
+        self.write(address, buffer.as_slice())?;
         Ok(())
     }
 }
 
+impl Drop for RAM {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            println!("Battery-backed RAM flush error {}", e);
+        }
+    }
+}
+
 impl Peripheral for RAM {
-    fn mem_read(&self, address: u32, _m1: bool) -> Option<u8> {
-        if address >= self.start && address <= self.start + self.size {
-            return Some(self.bytes.borrow()[(address - self.start) as usize]);
+    fn cycle(&self, bus: &Bus) -> Option<Interrupt> {
+        if let Some(backing) = &self.backing {
+            let now = bus.clock();
+            if now < backing.flushed.get() {
+                // the clock went back, stepping back or loading a snapshot: count the second from here
+                backing.flushed.set(now);
+            }
+            if backing.dirty.get() && now - backing.flushed.get() >= CYCLES_PER_SECOND {
+                backing.flushed.set(now);
+                if let Err(e) = self.flush() {
+                    println!("Battery-backed RAM flush error {}", e);
+                }
+            }
         }
         None
     }
+
+    fn mem_read(&self, address: u32, _m1: bool) -> Option<u8> {
+        self.region.borrow().get(address)
+    }
+    fn cpu_read(&self, address: u32) {
+        let offset = self.region.borrow().offset(address);
+        if let Some(offset) = offset {
+            self.check_read(offset);
+        }
+    }
+    fn peek(&self, address: u32) -> Option<u8> {
+        self.region.borrow().get(address)
+    }
     fn mem_write(&self, address: u32, data: u8) {
-        if address >= self.start && address <= self.start + self.size {
-            self.bytes.borrow_mut()[(address - self.start) as usize] = data;
+        let offset = self.region.borrow().offset(address);
+        if let Some(offset) = offset {
+            self.region.borrow_mut().set(address, data);
+            self.touch(offset, 1);
+        }
+    }
+    fn faults(&self) -> Vec<Fault> {
+        self.faults.replace(Vec::new())
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(self.region.borrow().as_slice());
+        let written = self.written.borrow();
+        state.u32(written.len() as u32);
+        for word in written.iter() {
+            state.u64(*word);
+        }
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(self.region.borrow_mut().as_mut_slice(), "RAM size")?;
+        let mut written = self.written.borrow_mut();
+        if state.u32()? as usize != written.len() {
+            return Err(StateError::Invalid("RAM tracking"));
+        }
+        for word in written.iter_mut() {
+            *word = state.u64()?;
+        }
+        if let Some(backing) = &self.backing {
+            backing.dirty.set(true);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::{Bus, CYCLES_PER_SECOND};
+    use crate::cpu::{Mode, Register, CPU};
+    use crate::dma::DMA;
+    use crate::ram::*;
+
+    #[test]
+    fn battery_backed_persists() {
+        let path = std::env::temp_dir().join(format!("vtrs20-nvram-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut bus = Bus::new();
+        let ram = Rc::new(RAM::battery_backed(0x7e000, 0x2000, &path).unwrap());
+        bus.add(ram.clone());
+        assert_eq!(ram.mem_read(0x7e010, false), Some(0));
+
+        bus.mem_write(0x7e010, 0xa5);
+        bus.advance(CYCLES_PER_SECOND);
+        bus.cycle();
+        assert_eq!(
+            std::fs::read(&path).unwrap()[0x10],
+            0xa5,
+            "flushed after a second of emulated time"
+        );
+
+        bus.mem_write(0x7e011, 0x5a);
+        drop(bus);
+        drop(ram);
+
+        let ram = RAM::battery_backed(0x7e000, 0x2000, &path).unwrap();
+        assert_eq!(ram.mem_read(0x7e010, false), Some(0xa5));
+        assert_eq!(ram.mem_read(0x7e011, false), Some(0x5a), "flushed on drop");
+        drop(ram);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn steps_back_over_a_flush() {
+        let path = std::env::temp_dir().join(format!("vtrs20-nvram-back-{}.bin", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut bus = Bus::new();
+        let ram = Rc::new(RAM::battery_backed(0x7e000, 0x2000, &path).unwrap());
+        bus.add(ram.clone());
+        let mut snapshot = StateWriter::new();
+        bus.save_state(&mut snapshot);
+        let snapshot = snapshot.into_bytes();
+
+        bus.mem_write(0x7e010, 0xa5);
+        bus.advance(CYCLES_PER_SECOND);
+        bus.cycle();
+        assert_eq!(std::fs::read(&path).unwrap()[0x10], 0xa5);
+
+        bus.load_state(&mut StateReader::new(&snapshot)).unwrap();
+        assert_eq!(bus.clock(), 0);
+        bus.cycle();
+        assert_eq!(
+            std::fs::read(&path).unwrap()[0x10],
+            0xa5,
+            "no flush straight after stepping back"
+        );
+        bus.advance(CYCLES_PER_SECOND);
+        bus.cycle();
+        assert_eq!(
+            std::fs::read(&path).unwrap()[0x10],
+            0x00,
+            "the restored contents flushed a second later"
+        );
+
+        drop(bus);
+        drop(ram);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn bounds_away_from_zero() {
+        let ram = RAM::new(0x7e000, 0x2000);
+        assert_eq!(ram.mem_read(0x7dfff, false), None);
+        assert_eq!(ram.mem_read(0x80000, false), None, "one past the end is not RAM");
+
+        ram.mem_write(0x7ffff, 0x42);
+        assert_eq!(ram.mem_read(0x7ffff, false), Some(0x42));
+
+        ram.write(0x7fffe, &[1, 2]).unwrap();
+        let mut data = [0u8; 2];
+        ram.read(0x7fffe, &mut data).unwrap();
+        assert_eq!(data, [1, 2]);
+
+        assert!(matches!(ram.write(0x7ffff, &[1, 2]), Err(RegionError::Overrun { .. })));
+        assert!(matches!(ram.read(0x100, &mut data), Err(RegionError::OutOfBounds { .. })));
+    }
+
+    #[test]
+    fn randomize_is_seeded() {
+        let a = RAM::new(0, 0x100);
+        let b = RAM::new(0, 0x100);
+        a.randomize(42);
+        b.randomize(42);
+        let (mut da, mut db) = ([0u8; 0x100], [0u8; 0x100]);
+        a.read(0, &mut da).unwrap();
+        b.read(0, &mut db).unwrap();
+        assert_eq!(da[..], db[..]);
+        assert!(da.iter().any(|b| *b != 0));
+
+        b.randomize(43);
+        b.read(0, &mut db).unwrap();
+        assert_ne!(da[..], db[..]);
+    }
+
+    #[test]
+    fn uninitialised_read() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.track_uninitialised(UninitPolicy::Break);
+        ram.write(
+            0x0000,
+            &[
+                0x32, 0x00, 0x80, //    0x0000  ld ($8000), a
+                0x3a, 0x00, 0x80, //    0x0003  ld a, ($8000)
+                0x3a, 0x01, 0x80, //    0x0006  ld a, ($8001)
+            ],
+        )
+        .unwrap();
+        bus.add(ram.clone());
+        cpu.reset();
+
+        cpu.cycle(&mut bus);
+        cpu.cycle(&mut bus);
+        assert_eq!(cpu.mode, Mode::OpCodeFetch);
+        assert!(ram.faults().is_empty());
+
+        cpu.cycle(&mut bus);
+        assert_eq!(cpu.mode, Mode::Break, "reading $8001 breaks");
+        assert_eq!(cpu.reg(Register::PC), 0x0009);
+    }
+
+    #[test]
+    fn uninitialised_read_by_dma() {
+        let mut bus = Bus::new();
+        let ram = Rc::new(RAM::new(0x00000, 0x80000));
+        ram.track_uninitialised(UninitPolicy::Warn);
+        bus.add(ram.clone());
+        bus.add(Rc::new(DMA::new()));
+
+        // DMA one byte from $00100 to $00200
+        for (port, value) in [
+            (0x20, 0x00),
+            (0x21, 0x01),
+            (0x23, 0x00),
+            (0x24, 0x02),
+            (0x26, 1),
+            (0x30, 0x40),
+        ]
+        .iter()
+        {
+            bus.io_write(*port, *value);
+        }
+        bus.cycle();
+        assert!(bus.take_faults().is_empty(), "DMA transfers aren't checked");
+
+        bus.mem_read(0x00200, false);
+        assert!(bus.take_faults().is_empty(), "DMA wrote the destination");
+        bus.mem_read(0x00100, false);
+        assert_eq!(bus.take_faults().len(), 1, "the CPU is still told about the source");
+    }
+
+    #[test]
+    fn uninitialised_read_while_replaying() {
+        let mut bus = Bus::new();
+        let ram = Rc::new(RAM::new(0x00000, 0x1000));
+        ram.track_uninitialised(UninitPolicy::Break);
+        bus.add(ram.clone());
+        bus.inputs().set_recording(true);
+        bus.inputs().replay_from(0, 10);
+
+        bus.mem_read(0x0100, false);
+        assert!(bus.take_faults().is_empty(), "reported when first run");
+
+        while bus.clock() <= 10 {
+            bus.cycle();
         }
+        bus.mem_read(0x0101, false);
+        assert_eq!(bus.take_faults().len(), 1, "past the replay");
     }
 }
@@ -0,0 +1,210 @@
+/**
+ * Memory regions
+ *
+ * A block of bytes mapped at a base address in the physical address space. All accesses are bounds checked: single
+ * byte accessors answer `None` for addresses outside the region, and block transfers fail with a `RegionError` rather
+ * than truncating or panicking.
+ */
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RegionError {
+    /// The access begins outside the region.
+    OutOfBounds { address: u32, base: u32, size: u32 },
+    /// The access begins inside the region but runs past its end.
+    Overrun { address: u32, len: usize, base: u32, size: u32 },
+}
+
+impl fmt::Display for RegionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RegionError::OutOfBounds { address, base, size } => write!(
+                f,
+                "address ${:05x} is outside the region ${:05x}-${:05x}",
+                address,
+                base,
+                *base as u64 + *size as u64
+            ),
+            RegionError::Overrun {
+                address,
+                len,
+                base,
+                size,
+            } => write!(
+                f,
+                "{} bytes at ${:05x} run past the end of the region ${:05x}-${:05x}",
+                len,
+                address,
+                base,
+                *base as u64 + *size as u64
+            ),
+        }
+    }
+}
+
+impl std::error::Error for RegionError {}
+
+impl From<RegionError> for std::io::Error {
+    fn from(e: RegionError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, e)
+    }
+}
+
+pub struct MemoryRegion {
+    base: u32,
+    bytes: Vec<u8>,
+}
+
+impl MemoryRegion {
+    /// Create a zero filled region of `size` bytes at `base`.
+    pub fn new(base: u32, size: u32) -> MemoryRegion {
+        MemoryRegion::with_contents(base, vec![0; size as usize])
+    }
+
+    /// Create a region at `base` holding `contents`.
+    pub fn with_contents(base: u32, contents: Vec<u8>) -> MemoryRegion {
+        MemoryRegion { base, bytes: contents }
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> u32 {
+        self.bytes.len() as u32
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        self.offset(address).is_some()
+    }
+
+    /// The offset of `address` into the region, if the region contains it.
+    pub fn offset(&self, address: u32) -> Option<usize> {
+        let offset = address.checked_sub(self.base)? as usize;
+        if offset < self.bytes.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    // The offset range for a block access, or why it can't be made.
+    fn span(&self, address: u32, len: usize) -> Result<std::ops::Range<usize>, RegionError> {
+        let offset = match self.offset(address) {
+            Some(offset) => offset,
+            None if len == 0 && address as u64 == self.base as u64 + self.bytes.len() as u64 => self.bytes.len(),
+            None => {
+                return Err(RegionError::OutOfBounds {
+                    address,
+                    base: self.base,
+                    size: self.size(),
+                })
+            }
+        };
+        if len > self.bytes.len() - offset {
+            return Err(RegionError::Overrun {
+                address,
+                len,
+                base: self.base,
+                size: self.size(),
+            });
+        }
+        Ok(offset..offset + len)
+    }
+
+    pub fn get(&self, address: u32) -> Option<u8> {
+        self.offset(address).map(|offset| self.bytes[offset])
+    }
+
+    /// Store a byte, answering false if the address is outside the region.
+    pub fn set(&mut self, address: u32, data: u8) -> bool {
+        match self.offset(address) {
+            Some(offset) => {
+                self.bytes[offset] = data;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Fill `data` from the region, starting at `address`.
+    pub fn read(&self, address: u32, data: &mut [u8]) -> Result<(), RegionError> {
+        let span = self.span(address, data.len())?;
+        data.copy_from_slice(&self.bytes[span]);
+        Ok(())
+    }
+
+    /// Copy `data` into the region, starting at `address`.
+    pub fn write(&mut self, address: u32, data: &[u8]) -> Result<(), RegionError> {
+        let span = self.span(address, data.len())?;
+        self.bytes[span].copy_from_slice(data);
+        Ok(())
+    }
+
+    /// Borrow `len` bytes of the region starting at `address`.
+    pub fn slice(&self, address: u32, len: usize) -> Result<&[u8], RegionError> {
+        let span = self.span(address, len)?;
+        Ok(&self.bytes[span])
+    }
+
+    /// Mutably borrow `len` bytes of the region starting at `address`.
+    pub fn slice_mut(&mut self, address: u32, len: usize) -> Result<&mut [u8], RegionError> {
+        let span = self.span(address, len)?;
+        Ok(&mut self.bytes[span])
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        &mut self.bytes
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use proptest::prelude::*;
+
+    use crate::region::*;
+
+    proptest! {
+        #[test]
+        fn contains_exactly_its_range(base in 0u32..0x10_0000, size in 0u32..0x1000, address: u32) {
+            let region = MemoryRegion::new(base, size);
+            let inside = address >= base && (address as u64) < base as u64 + size as u64;
+            prop_assert_eq!(region.contains(address), inside);
+            prop_assert_eq!(region.get(address).is_some(), inside);
+        }
+
+        #[test]
+        fn high_regions_do_not_overflow(size in 1u32..0x1000, address: u32) {
+            let base = u32::MAX - size + 1;
+            let region = MemoryRegion::new(base, size);
+            prop_assert_eq!(region.contains(address), address >= base);
+        }
+
+        #[test]
+        fn block_access_is_checked(base in 0u32..0x10_0000, size in 0u32..0x1000, at in 0u32..0x2000, len in 0usize..0x100) {
+            let mut region = MemoryRegion::new(base, size);
+            let address = base.wrapping_add(at).wrapping_sub(0x800);
+            let data: Vec<u8> = (0..len).map(|i| i as u8).collect();
+            let fits = address >= base && address as u64 + len as u64 <= base as u64 + size as u64;
+
+            prop_assert_eq!(region.write(address, &data).is_ok(), fits);
+            let mut back = vec![0u8; len];
+            prop_assert_eq!(region.read(address, &mut back).is_ok(), fits);
+            if fits {
+                prop_assert_eq!(back, data);
+            }
+        }
+
+        #[test]
+        fn set_then_get(base in 0u32..0x10_0000, size in 1u32..0x1000, at in 0u32..0x1000, data: u8) {
+            let mut region = MemoryRegion::new(base, size);
+            let address = base + at;
+            prop_assert_eq!(region.set(address, data), at < size);
+            prop_assert_eq!(region.get(address), if at < size { Some(data) } else { None });
+        }
+    }
+}
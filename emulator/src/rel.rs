@@ -0,0 +1,521 @@
+/**
+ * Microsoft REL files
+ *
+ * The relocatable object files M80 writes, and L80 links, are a stream of link items read a bit at a time:
+ *
+ * - a 0 bit is followed by 8 bits loaded at the location counter: 0-xxxxxxxx
+ * - a 1 bit is followed by two bits:
+ *     - 00 introduces a special link item,
+ *     - 01 a program relative word, loaded after being offset by the program segment origin: 1-01-xxxxxxxxxxxxxxxx
+ *     - 10 a data relative word, offset by the data segment origin: 1-10-xxxxxxxxxxxxxxxx
+ *     - 11 a common relative word, offset by the origin of the selected common block: 1-11-xxxxxxxxxxxxxxxx
+ *
+ * A special link item is a 4 bit control field followed by an optional value, a 2 bit address type (absolute,
+ * program, data or common relative, as above) and a 16 bit address, low byte first, and an optional name of a 3 bit
+ * count and that many 8 bit characters: 1-00-cccc-tt-xxxxxxxxxxxxxxxx-nnn-<characters>.
+ *
+ * These take a name only:
+ *  - 0000 entry symbol: the module defines the name, so is linked if a library holding it is searched
+ *  - 0001 select common block: common relative items that follow are in the named block
+ *  - 0010 program name: the name of the module
+ *  - 0011 request library search: the named library should be searched
+ *  - 0100 extension: a name starting "A" is an operator, "B" an external symbol operand, and "C" an operand that's
+ *    an address type byte and a word, together making up an expression evaluated on a stack
+ *
+ * These take a value and a name:
+ *  - 0101 define common size: the size of the named common block
+ *  - 0110 chain external: the head of a chain through the module, ending in absolute 0, of words to replace with the
+ *    value of the named external symbol
+ *  - 0111 define entry point: the value of the named public symbol
+ *
+ * These take a value only:
+ *  - 1001 external plus offset: the next word loaded has the value added after chains are resolved
+ *  - 1010 define data size: the size of the module's data segment
+ *  - 1011 set location counter: continue loading at the value, in the segment of its address type
+ *  - 1100 chain address: the head of a chain of words to replace with the location counter
+ *  - 1101 define program size: the size of the module's program segment
+ *  - 1110 end module: the value, if not absolute 0, is the start address; the next module starts on a byte boundary
+ *
+ * And 1111, end file, takes neither and follows the last module in the file.
+ *
+ * Known limitations:
+ *  1. Control 1000, unused by M80, is rejected
+ *  2. Names are read as ASCII, lossily
+ *  3. The end of file is padded to a byte, not to the 128 byte record M80 writes
+ */
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use bitstream_io::{BigEndian, BitReader, BitWriter};
+
+#[derive(Debug, PartialEq, Eq, Hash, Copy, Clone)]
+pub enum AddressType {
+    Absolute,
+    ProgramRelative,
+    DataRelative,
+    CommonRelative,
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Operator {
+    StoreByte,
+    StoreWord,
+    HighByte,
+    LowByte,
+    Complement,
+    Negate,
+    Subtract,
+    Add,
+    Multiply,
+    Divide,
+    Modulo,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum RelEntry {
+    Absolute(u8),
+    Relative(AddressType, u16),
+    EntrySymbol(String),
+    SelectCommon(String),
+    ProgramName(String),
+    RequestLibrary(String),
+    Operand(AddressType, u16),
+    ExternalOperand(String),
+    Operation(Operator),
+    CommonSize(AddressType, u16, String),
+    ChainExternal(AddressType, u16, String),
+    EntryPoint(AddressType, u16, String),
+    ExtPlusOffset(AddressType, u16),
+    DataSize(AddressType, u16),
+    SetLocation(AddressType, u16),
+    ChainAddress(AddressType, u16),
+    TextSize(AddressType, u16),
+    EndModule(AddressType, u16),
+    EndFile(),
+}
+
+/// The link items of one module, from its program name to its end.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Module {
+    pub name: String,
+    pub entries: Vec<RelEntry>,
+}
+
+impl Module {
+    /// The names of the public symbols the module defines.
+    pub fn publics(&self) -> impl Iterator<Item = &str> + '_ {
+        self.entries.iter().filter_map(|entry| match entry {
+            RelEntry::EntrySymbol(name) | RelEntry::EntryPoint(_, _, name) => Some(name.as_str()),
+            _ => None,
+        })
+    }
+}
+
+fn invalid(s: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, s)
+}
+
+fn read_name<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<Vec<u8>, std::io::Error> {
+    let len: u8 = reader.read(3)?;
+    let mut buf = vec![0; len as usize];
+    reader.read_bytes(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_string<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<String, std::io::Error> {
+    Ok(String::from_utf8_lossy(&read_name(reader)?).into_owned())
+}
+
+fn read_type<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<AddressType, std::io::Error> {
+    let rel: u8 = reader.read(2)?;
+    address_type(rel).ok_or_else(|| invalid("bad relative bits".to_string()))
+}
+
+fn address_type(bits: u8) -> Option<AddressType> {
+    match bits {
+        0b00 => Some(AddressType::Absolute),
+        0b01 => Some(AddressType::ProgramRelative),
+        0b10 => Some(AddressType::DataRelative),
+        0b11 => Some(AddressType::CommonRelative),
+        _ => None,
+    }
+}
+
+fn read_word<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<u16, std::io::Error> {
+    // words are written low byte first
+    Ok(reader.read::<u16>(16)?.swap_bytes())
+}
+
+fn rel_op(op: u8) -> Result<Operator, std::io::Error> {
+    match op {
+        0x01 => Ok(Operator::StoreByte),
+        0x02 => Ok(Operator::StoreWord),
+        0x03 => Ok(Operator::HighByte),
+        0x04 => Ok(Operator::LowByte),
+        0x05 => Ok(Operator::Complement),
+        0x06 => Ok(Operator::Negate),
+        0x07 => Ok(Operator::Subtract),
+        0x08 => Ok(Operator::Add),
+        0x09 => Ok(Operator::Multiply),
+        0x0a => Ok(Operator::Divide),
+        0x0b => Ok(Operator::Modulo),
+        o => Err(invalid(format!("invalid operator {:02x}", o))),
+    }
+}
+
+/// Reads the next link item.
+pub fn read_entry<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<RelEntry, std::io::Error> {
+    if reader.read_bit()? {
+        let rel: u8 = reader.read(2)?;
+        match rel {
+            0b01 => Ok(RelEntry::Relative(AddressType::ProgramRelative, read_word(reader)?)),
+            0b10 => Ok(RelEntry::Relative(AddressType::DataRelative, read_word(reader)?)),
+            0b11 => Ok(RelEntry::Relative(AddressType::CommonRelative, read_word(reader)?)),
+            _ => {
+                let control: u8 = reader.read(4)?;
+                match control {
+                    0b0000 => Ok(RelEntry::EntrySymbol(read_string(reader)?)),
+                    0b0001 => Ok(RelEntry::SelectCommon(read_string(reader)?)),
+                    0b0010 => Ok(RelEntry::ProgramName(read_string(reader)?)),
+                    0b0011 => Ok(RelEntry::RequestLibrary(read_string(reader)?)),
+                    0b0100 => {
+                        // an operand's value is any two bytes, so isn't read as a string
+                        let s = read_name(reader)?;
+                        match s.as_slice() {
+                            [b'A', op] => Ok(RelEntry::Operation(rel_op(*op)?)),
+                            [b'B', name @ ..] => Ok(RelEntry::ExternalOperand(String::from_utf8_lossy(name).into_owned())),
+                            [b'C', t, vlo, vhi] => match address_type(*t) {
+                                Some(t) => Ok(RelEntry::Operand(t, (*vhi as u16) << 8 | (*vlo as u16))),
+                                None => Err(invalid(format!("invalid operand extension {:02x?}", s))),
+                            },
+                            [] => Err(invalid("invalid null extension".to_string())),
+                            _ => Err(invalid(format!("unknown extension {:02x?}", s))),
+                        }
+                    }
+                    0b0101 => Ok(RelEntry::CommonSize(
+                        read_type(reader)?,
+                        read_word(reader)?,
+                        read_string(reader)?,
+                    )),
+                    0b0110 => Ok(RelEntry::ChainExternal(
+                        read_type(reader)?,
+                        read_word(reader)?,
+                        read_string(reader)?,
+                    )),
+                    0b0111 => Ok(RelEntry::EntryPoint(
+                        read_type(reader)?,
+                        read_word(reader)?,
+                        read_string(reader)?,
+                    )),
+                    0b1001 => Ok(RelEntry::ExtPlusOffset(read_type(reader)?, read_word(reader)?)),
+                    0b1010 => Ok(RelEntry::DataSize(read_type(reader)?, read_word(reader)?)),
+                    0b1011 => Ok(RelEntry::SetLocation(read_type(reader)?, read_word(reader)?)),
+                    0b1100 => Ok(RelEntry::ChainAddress(read_type(reader)?, read_word(reader)?)),
+                    0b1101 => Ok(RelEntry::TextSize(read_type(reader)?, read_word(reader)?)),
+                    0b1110 => {
+                        let val = Ok(RelEntry::EndModule(read_type(reader)?, read_word(reader)?));
+                        reader.byte_align();
+                        val
+                    }
+                    0b1111 => Ok(RelEntry::EndFile()),
+                    _ => Err(invalid(format!("bad control byte {:04b}", control))),
+                }
+            }
+        }
+    } else {
+        Ok(RelEntry::Absolute(reader.read(8)?))
+    }
+}
+
+fn write_string<W: Write>(writer: &mut BitWriter<W, BigEndian>, s: &str) -> Result<(), std::io::Error> {
+    if s.len() > 7 {
+        return Err(invalid(format!("name {} is longer than 7 characters", s)));
+    }
+    writer.write(3, s.len() as u8)?;
+    writer.write_bytes(s.as_bytes())
+}
+
+fn write_value<W: Write>(writer: &mut BitWriter<W, BigEndian>, kind: AddressType, value: u16) -> Result<(), std::io::Error> {
+    let bits = match kind {
+        AddressType::Absolute => 0b00,
+        AddressType::ProgramRelative => 0b01,
+        AddressType::DataRelative => 0b10,
+        AddressType::CommonRelative => 0b11,
+    };
+    writer.write(2, bits as u8)?;
+    writer.write(16, value.swap_bytes())
+}
+
+fn write_special<W: Write>(writer: &mut BitWriter<W, BigEndian>, control: u8) -> Result<(), std::io::Error> {
+    writer.write(3, 0b100u8)?;
+    writer.write(4, control)
+}
+
+fn op_code(op: Operator) -> u8 {
+    match op {
+        Operator::StoreByte => 0x01,
+        Operator::StoreWord => 0x02,
+        Operator::HighByte => 0x03,
+        Operator::LowByte => 0x04,
+        Operator::Complement => 0x05,
+        Operator::Negate => 0x06,
+        Operator::Subtract => 0x07,
+        Operator::Add => 0x08,
+        Operator::Multiply => 0x09,
+        Operator::Divide => 0x0a,
+        Operator::Modulo => 0x0b,
+    }
+}
+
+/// Writes a link item, as `read_entry` reads it.
+pub fn write_entry<W: Write>(writer: &mut BitWriter<W, BigEndian>, entry: &RelEntry) -> Result<(), std::io::Error> {
+    match entry {
+        RelEntry::Absolute(byte) => {
+            writer.write_bit(false)?;
+            writer.write(8, *byte)
+        }
+        RelEntry::Relative(AddressType::Absolute, _) => Err(invalid("an absolute word is two absolute bytes".to_string())),
+        RelEntry::Relative(kind, value) => {
+            writer.write_bit(true)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::EntrySymbol(name) => {
+            write_special(writer, 0b0000)?;
+            write_string(writer, name)
+        }
+        RelEntry::SelectCommon(name) => {
+            write_special(writer, 0b0001)?;
+            write_string(writer, name)
+        }
+        RelEntry::ProgramName(name) => {
+            write_special(writer, 0b0010)?;
+            write_string(writer, name)
+        }
+        RelEntry::RequestLibrary(name) => {
+            write_special(writer, 0b0011)?;
+            write_string(writer, name)
+        }
+        RelEntry::Operation(op) => {
+            write_special(writer, 0b0100)?;
+            writer.write(3, 2u8)?;
+            writer.write_bytes(&[b'A', op_code(*op)])
+        }
+        RelEntry::ExternalOperand(name) => {
+            write_special(writer, 0b0100)?;
+            write_string(writer, &format!("B{}", name))
+        }
+        RelEntry::Operand(kind, value) => {
+            let [lo, hi] = value.to_le_bytes();
+            write_special(writer, 0b0100)?;
+            writer.write(3, 4u8)?;
+            writer.write_bytes(&[b'C', *kind as u8, lo, hi])
+        }
+        RelEntry::CommonSize(kind, value, name) => {
+            write_special(writer, 0b0101)?;
+            write_value(writer, *kind, *value)?;
+            write_string(writer, name)
+        }
+        RelEntry::ChainExternal(kind, value, name) => {
+            write_special(writer, 0b0110)?;
+            write_value(writer, *kind, *value)?;
+            write_string(writer, name)
+        }
+        RelEntry::EntryPoint(kind, value, name) => {
+            write_special(writer, 0b0111)?;
+            write_value(writer, *kind, *value)?;
+            write_string(writer, name)
+        }
+        RelEntry::ExtPlusOffset(kind, value) => {
+            write_special(writer, 0b1001)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::DataSize(kind, value) => {
+            write_special(writer, 0b1010)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::SetLocation(kind, value) => {
+            write_special(writer, 0b1011)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::ChainAddress(kind, value) => {
+            write_special(writer, 0b1100)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::TextSize(kind, value) => {
+            write_special(writer, 0b1101)?;
+            write_value(writer, *kind, *value)
+        }
+        RelEntry::EndModule(kind, value) => {
+            write_special(writer, 0b1110)?;
+            write_value(writer, *kind, *value)?;
+            writer.byte_align()
+        }
+        RelEntry::EndFile() => write_special(writer, 0b1111),
+    }
+}
+
+/// Reads the modules of a REL file or library, up to its end file item.
+pub fn read_modules<R: Read>(input: R) -> Result<Vec<Module>, std::io::Error> {
+    let mut reader = BitReader::endian(input, BigEndian);
+    let mut modules = Vec::new();
+    let mut entries = Vec::new();
+    loop {
+        match read_entry(&mut reader)? {
+            RelEntry::EndFile() => break,
+            entry @ RelEntry::EndModule(..) => {
+                entries.push(entry);
+                let name = entries
+                    .iter()
+                    .find_map(|entry| match entry {
+                        RelEntry::ProgramName(name) => Some(name.clone()),
+                        _ => None,
+                    })
+                    .unwrap_or_default();
+                modules.push(Module {
+                    name,
+                    entries: std::mem::take(&mut entries),
+                });
+            }
+            entry => entries.push(entry),
+        }
+    }
+    if !entries.is_empty() {
+        return Err(invalid("end of file inside a module".to_string()));
+    }
+    Ok(modules)
+}
+
+/// Reads the modules of the REL file at `path`.
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<Module>, std::io::Error> {
+    read_modules(BufReader::new(File::open(path)?))
+}
+
+/// Writes modules as a REL file or library, ending it with an end file item.
+pub fn write_modules<W: Write>(output: W, modules: &[Module]) -> Result<(), std::io::Error> {
+    let mut writer = BitWriter::endian(output, BigEndian);
+    for module in modules {
+        for entry in &module.entries {
+            write_entry(&mut writer, entry)?;
+        }
+    }
+    write_entry(&mut writer, &RelEntry::EndFile())?;
+    writer.byte_align()?;
+    writer.into_writer().flush()
+}
+
+/// Writes modules to a REL file at `path`.
+pub fn save<P: AsRef<Path>>(path: P, modules: &[Module]) -> Result<(), std::io::Error> {
+    write_modules(BufWriter::new(File::create(path)?), modules)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rel::AddressType::*;
+    use crate::rel::*;
+
+    // MAIN calls PRINT in a second module, as M80 would write them
+    const TWO_MODULES: &str = "8513505253a5000013404006680000c98c808055052494e548e800055354\
+                               4152549c8000008554149253952680200c98e800055052494e549c000000\
+                               9e";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    fn write(modules: &[Module]) -> Vec<u8> {
+        let mut output = Vec::new();
+        write_modules(&mut output, modules).unwrap();
+        output
+    }
+
+    #[test]
+    fn reads_modules() {
+        let modules = read_modules(bytes(TWO_MODULES).as_slice()).unwrap();
+        assert_eq!(
+            modules,
+            vec![
+                Module {
+                    name: "MAIN".to_string(),
+                    entries: vec![
+                        RelEntry::ProgramName("MAIN".to_string()),
+                        RelEntry::DataSize(Absolute, 0),
+                        RelEntry::TextSize(Absolute, 4),
+                        RelEntry::Absolute(0xcd),
+                        RelEntry::Absolute(0x00),
+                        RelEntry::Absolute(0x00),
+                        RelEntry::Absolute(0xc9),
+                        RelEntry::ChainExternal(ProgramRelative, 1, "PRINT".to_string()),
+                        RelEntry::EntryPoint(ProgramRelative, 0, "START".to_string()),
+                        RelEntry::EndModule(ProgramRelative, 0),
+                    ],
+                },
+                Module {
+                    name: "PRINT".to_string(),
+                    entries: vec![
+                        RelEntry::ProgramName("PRINT".to_string()),
+                        RelEntry::TextSize(Absolute, 1),
+                        RelEntry::Absolute(0xc9),
+                        RelEntry::EntryPoint(ProgramRelative, 0, "PRINT".to_string()),
+                        RelEntry::EndModule(Absolute, 0),
+                    ],
+                },
+            ]
+        );
+        assert_eq!(modules[0].publics().collect::<Vec<_>>(), vec!["START"]);
+        assert_eq!(write(&modules), bytes(TWO_MODULES));
+    }
+
+    #[test]
+    fn round_trips_every_entry() {
+        let name = |s: &str| s.to_string();
+        let module = Module {
+            name: name("EVERY"),
+            entries: vec![
+                RelEntry::ProgramName(name("EVERY")),
+                RelEntry::EntrySymbol(name("FIRST")),
+                RelEntry::RequestLibrary(name("MATHLIB")),
+                RelEntry::CommonSize(Absolute, 0x20, name("BLK")),
+                RelEntry::DataSize(Absolute, 0x10),
+                RelEntry::TextSize(Absolute, 0x1234),
+                RelEntry::SelectCommon(name("BLK")),
+                RelEntry::Absolute(0xff),
+                RelEntry::Relative(ProgramRelative, 0x0102),
+                RelEntry::Relative(DataRelative, 0x8000),
+                RelEntry::Relative(CommonRelative, 0xffff),
+                RelEntry::ExternalOperand(name("EXT")),
+                RelEntry::Operand(CommonRelative, 0xff80),
+                RelEntry::Operation(Operator::Modulo),
+                RelEntry::Operation(Operator::StoreWord),
+                RelEntry::ExtPlusOffset(Absolute, 3),
+                RelEntry::SetLocation(DataRelative, 4),
+                RelEntry::ChainAddress(ProgramRelative, 7),
+                RelEntry::ChainExternal(DataRelative, 2, name("EXT")),
+                RelEntry::EntryPoint(CommonRelative, 1, name("FIRST")),
+                RelEntry::EndModule(ProgramRelative, 0x0100),
+            ],
+        };
+        let modules = vec![module.clone(), module];
+        assert_eq!(read_modules(write(&modules).as_slice()).unwrap(), modules);
+        assert_eq!(read_modules(write(&[]).as_slice()).unwrap(), vec![]);
+    }
+
+    #[test]
+    fn rejects_what_cannot_be_written_or_read() {
+        let module = |entry| Module {
+            name: String::new(),
+            entries: vec![entry, RelEntry::EndModule(Absolute, 0)],
+        };
+        let mut output = Vec::new();
+        assert!(write_modules(&mut output, &[module(RelEntry::Relative(Absolute, 0))]).is_err());
+        assert!(write_modules(&mut output, &[module(RelEntry::ProgramName("TOOLONGX".to_string()))]).is_err());
+
+        // a module cut off before its end, and a file cut off before its end file item
+        let bytes = bytes(TWO_MODULES);
+        assert!(read_modules(&bytes[..8]).is_err());
+        assert!(read_modules(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
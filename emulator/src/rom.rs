@@ -1,41 +1,343 @@
-use std::cell::RefCell;
-use std::thread;
-use std::time::{Duration, Instant};
+/**
+ * Flash ROM
+ *
+ * Models the software command protocols of the SST39SF0x0 sector-erase and AT29C0x0 page-write flash families. Program
+ * and erase operations take time on the emulated clock, during which reads return DQ7 data polling and DQ6 toggle
+ * status instead of array data.
+ *
+ * Known limitations:
+ *  1. Hardware data protection and the AT29C software data protection disable sequence are not implemented
+ *  2. Status bits other than DQ7 and DQ6 read as zero while busy
+ *  3. Page bytes that aren't loaded in an AT29C page write are erased to $ff
+ */
+use std::cell::{Cell, RefCell};
+use std::fmt;
 
-use crate::bus::Bus;
+use crate::bus::{cycles_from_micros, Bus};
+use crate::region::MemoryRegion;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
+/// The flash chip a ROM models.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Chip {
+    SST39SF010,
+    SST39SF020,
+    SST39SF040,
+    AT29C010,
+    AT29C020,
+    AT29C040,
+}
+
+impl Chip {
+    /// Choose the smallest SST39SF part that can hold `size` bytes.
+    pub fn for_size(size: usize) -> Chip {
+        if size <= 128 * 1024 {
+            Chip::SST39SF010
+        } else if size <= 256 * 1024 {
+            Chip::SST39SF020
+        } else {
+            Chip::SST39SF040
+        }
+    }
+
+    /// Look up a chip by its part number, ignoring case.
+    pub fn from_name(name: &str) -> Option<Chip> {
+        match name.to_ascii_uppercase().as_str() {
+            "SST39SF010" => Some(Chip::SST39SF010),
+            "SST39SF020" => Some(Chip::SST39SF020),
+            "SST39SF040" => Some(Chip::SST39SF040),
+            "AT29C010" => Some(Chip::AT29C010),
+            "AT29C020" => Some(Chip::AT29C020),
+            "AT29C040" => Some(Chip::AT29C040),
+            _ => None,
+        }
+    }
+
+    /// The capacity of the chip in bytes.
+    pub fn size(&self) -> u32 {
+        match self {
+            Chip::SST39SF010 | Chip::AT29C010 => 128 * 1024,
+            Chip::SST39SF020 | Chip::AT29C020 => 256 * 1024,
+            Chip::SST39SF040 | Chip::AT29C040 => 512 * 1024,
+        }
+    }
+
+    /// The manufacturer and device ID bytes reported in software ID mode.
+    pub fn id(&self) -> (u8, u8) {
+        match self {
+            Chip::SST39SF010 => (0xbf, 0xb5),
+            Chip::SST39SF020 => (0xbf, 0xb6),
+            Chip::SST39SF040 => (0xbf, 0xb7),
+            Chip::AT29C010 => (0x1f, 0xd5),
+            Chip::AT29C020 => (0x1f, 0xda),
+            Chip::AT29C040 => (0x1f, 0xa4),
+        }
+    }
+
+    // AT29C parts write whole pages; SST39SF parts program single bytes.
+    fn page_size(&self) -> Option<u32> {
+        match self {
+            Chip::AT29C010 => Some(128),
+            Chip::AT29C020 | Chip::AT29C040 => Some(256),
+            _ => None,
+        }
+    }
+
+    fn id_command(&self) -> u8 {
+        if self.page_size().is_some() {
+            0x60
+        } else {
+            0x90
+        }
+    }
+
+    // Maximum operation times in microseconds, from the datasheets.
+    fn program_time(&self) -> u64 {
+        if self.page_size().is_some() {
+            10_000
+        } else {
+            20
+        }
+    }
+
+    fn chip_erase_time(&self) -> u64 {
+        if self.page_size().is_some() {
+            20_000
+        } else {
+            100_000
+        }
+    }
+}
+
+// SST39SF sector size
+const SECTOR: u32 = 0x1000;
+
+// AT29C byte load cycle time: a page write begins once no byte has been loaded for this long
+const BYTE_LOAD_TIMEOUT: u64 = 150;
+
+// SST39SF sector erase time
+const SECTOR_ERASE_TIME: u64 = 25_000;
+
+/// A breach of the flash command protocol by the software driving it.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Violation {
+    /// A write arrived while a program or erase operation was in progress, and was ignored.
+    WriteWhileBusy { address: u32, data: u8 },
+    /// A command sequence was abandoned part way through.
+    AbortedCommand { address: u32, data: u8 },
+    /// A write was made outside of any command sequence, and was ignored.
+    UnlockedWrite { address: u32, data: u8 },
+    /// A byte program tried to turn a zero bit back into a one without an erase.
+    Overprogram { address: u32, was: u8, data: u8 },
+    /// A page load wrote outside the page selected by the first byte loaded, and was ignored.
+    PageCrossing { address: u32, data: u8 },
+}
+
+impl Violation {
+    pub fn address(&self) -> u32 {
+        match self {
+            Violation::WriteWhileBusy { address, .. }
+            | Violation::AbortedCommand { address, .. }
+            | Violation::UnlockedWrite { address, .. }
+            | Violation::Overprogram { address, .. }
+            | Violation::PageCrossing { address, .. } => *address,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Violation::WriteWhileBusy { address, data } => {
+                write!(f, "write of ${:02x} to ${:05x} while busy", data, address)
+            }
+            Violation::AbortedCommand { address, data } => {
+                write!(f, "command sequence aborted by ${:02x} to ${:05x}", data, address)
+            }
+            Violation::UnlockedWrite { address, data } => {
+                write!(f, "write of ${:02x} to ${:05x} without a command sequence", data, address)
+            }
+            Violation::Overprogram { address, was, data } => {
+                write!(f, "program of ${:02x} over unerased ${:02x} at ${:05x}", data, was, address)
+            }
+            Violation::PageCrossing { address, data } => {
+                write!(f, "page load of ${:02x} to ${:05x} outside the loading page", data, address)
+            }
+        }
+    }
+}
+
 #[derive(PartialEq, Copy, Clone)]
 enum Mode {
-    READ,
-    ID,
-    PROGRAM,
-    WRITING,
-    COMMAND1,
-    COMMAND2,
-    ERASE1,
-    ERASE2,
-    ERASE3,
+    Read,
+    Id,
+    Command1,
+    Command2,
+    Program,
+    Load,
+    Erase1,
+    Erase2,
+    Erase3,
+    Busy,
 }
 
+// Every mode, numbered for snapshots.
+const MODES: [Mode; 10] = [
+    Mode::Read,
+    Mode::Id,
+    Mode::Command1,
+    Mode::Command2,
+    Mode::Program,
+    Mode::Load,
+    Mode::Erase1,
+    Mode::Erase2,
+    Mode::Erase3,
+    Mode::Busy,
+];
+
 pub struct ROM {
-    start: u32,
-    size: u32,
-    bytes: RefCell<Vec<u8>>,
+    chip: Chip,
+    region: RefCell<MemoryRegion>,
     is_masking: RefCell<bool>,
     mode: RefCell<Mode>,
-    begun: RefCell<Instant>,
+    // emulated clock at the most recent machine cycle
+    now: Cell<u64>,
+    // the clock value at which a busy operation completes, or a page load times out
+    until: Cell<u64>,
+    // the value of DQ7 while busy, and the current state of the DQ6 toggle bit
+    poll: Cell<u8>,
+    toggle: Cell<u8>,
+    // AT29C page load: the page being loaded and the bytes loaded so far
+    page: RefCell<Vec<(u32, u8)>>,
+    violations: RefCell<Vec<Violation>>,
 }
 
 impl ROM {
-    pub fn new(base: u32, contents: Vec<u8>) -> ROM {
-        ROM {
-            start: base,
-            size: contents.len() as u32,
-            bytes: RefCell::new(contents),
+    /// Create a ROM at `base`, modelling the smallest SST39SF part that can hold `contents`.
+    pub fn new(base: u32, contents: Vec<u8>) -> Result<ROM, String> {
+        let chip = Chip::for_size(contents.len());
+        ROM::with_chip(base, chip, contents)
+    }
+
+    /// Create a ROM at `base` modelling a specific chip. The contents are padded with erased bytes to the size of the
+    /// chip, and must not be larger than it.
+    pub fn with_chip(base: u32, chip: Chip, mut contents: Vec<u8>) -> Result<ROM, String> {
+        if contents.len() > chip.size() as usize {
+            return Err(format!(
+                "a {} byte image doesn't fit in the {}k {:?}",
+                contents.len(),
+                chip.size() / 1024,
+                chip
+            ));
+        }
+        contents.resize(chip.size() as usize, 0xff);
+        Ok(ROM {
+            chip,
+            region: RefCell::new(MemoryRegion::with_contents(base, contents)),
             is_masking: RefCell::new(true),
-            mode: RefCell::new(Mode::READ),
-            begun: RefCell::new(Instant::now()),
+            mode: RefCell::new(Mode::Read),
+            now: Cell::new(0),
+            until: Cell::new(0),
+            poll: Cell::new(0),
+            toggle: Cell::new(0),
+            page: RefCell::new(Vec::new()),
+            violations: RefCell::new(Vec::new()),
+        })
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// True while a program or erase operation is in progress.
+    pub fn is_busy(&self) -> bool {
+        *self.mode.borrow() == Mode::Busy
+    }
+
+    /// Remove and return the protocol violations seen since the last call.
+    pub fn take_violations(&self) -> Vec<Violation> {
+        self.violations.replace(Vec::new())
+    }
+
+    fn violation(&self, violation: Violation) {
+        self.violations.borrow_mut().push(violation);
+    }
+
+    // The offset of an address into the chip, if the chip is selected.
+    fn offset(&self, address: u32) -> Option<u32> {
+        self.region.borrow().offset(address).map(|offset| offset as u32)
+    }
+
+    // Commands are decoded on A14-A0 only.
+    fn is_command(&self, address: u32, command: u32) -> bool {
+        matches!(self.offset(address), Some(offset) if offset & 0x7fff == command)
+    }
+
+    fn set_mode(&self, mode: Mode) {
+        *self.mode.borrow_mut() = mode;
+    }
+
+    fn begin_busy(&self, micros: u64, dq7: u8) {
+        self.until.set(self.now.get() + cycles_from_micros(micros));
+        self.poll.set(dq7 & 0x80);
+        self.set_mode(Mode::Busy);
+    }
+
+    fn program(&self, offset: u32, data: u8) {
+        let mut region = self.region.borrow_mut();
+        let base = region.base();
+        let bytes = region.as_mut_slice();
+        let was = bytes[offset as usize];
+        if was & data != data {
+            self.violation(Violation::Overprogram {
+                address: base + offset,
+                was,
+                data,
+            });
+        }
+        bytes[offset as usize] = was & data;
+    }
+
+    fn load(&self, offset: u32, data: u8) {
+        let page_size = self.chip.page_size().unwrap_or(1);
+        let mut page = self.page.borrow_mut();
+        match page.first() {
+            Some((first, _)) if first / page_size != offset / page_size => {
+                self.violation(Violation::PageCrossing {
+                    address: self.region.borrow().base() + offset,
+                    data,
+                });
+            }
+            _ => page.push((offset, data)),
+        }
+        self.until.set(self.now.get() + cycles_from_micros(BYTE_LOAD_TIMEOUT));
+    }
+
+    // The byte load timer expired: erase the page, write what was loaded, and go busy for the write cycle.
+    fn write_page(&self) {
+        let page = self.page.replace(Vec::new());
+        let page_size = self.chip.page_size().unwrap_or(1);
+        let mut last = 0;
+        if let Some((first, _)) = page.first() {
+            let base = (first / page_size * page_size) as usize;
+            let mut region = self.region.borrow_mut();
+            let bytes = region.as_mut_slice();
+            for byte in &mut bytes[base..base + page_size as usize] {
+                *byte = 0xff;
+            }
+            for (offset, data) in page {
+                bytes[offset as usize] = data;
+                last = data;
+            }
+        }
+        self.begin_busy(self.chip.program_time(), !last);
+    }
+
+    fn abort(&self, address: u32, data: u8) {
+        if self.offset(address).is_some() {
+            self.violation(Violation::AbortedCommand { address, data });
+            self.set_mode(Mode::Read);
         }
     }
 }
@@ -45,12 +347,15 @@ impl Peripheral for ROM {
         *self.is_masking.borrow_mut() = true;
     }
 
-    fn cycle(&self, _bus: &Bus) -> Option<Interrupt> {
-        // programming modes are exited after 10ms
-        if *self.mode.borrow() == Mode::WRITING {
-            if self.begun.borrow().elapsed() > Duration::from_millis(10) {
-                *self.mode.borrow_mut() = Mode::READ;
-            }
+    fn cycle(&self, bus: &Bus) -> Option<Interrupt> {
+        let now = bus.clock();
+        self.now.set(now);
+
+        let mode = *self.mode.borrow();
+        if mode == Mode::Busy && now >= self.until.get() {
+            self.set_mode(Mode::Read);
+        } else if mode == Mode::Load && now >= self.until.get() {
+            self.write_page();
         }
 
         None
@@ -69,106 +374,304 @@ impl Peripheral for ROM {
             addr |= 0b1000_0000_0000_0000_0000;
         }
 
-        if addr >= self.start && addr <= self.start + self.size {
-            let mut val = self.bytes.borrow()[(addr - self.start) as usize];
-
-            match *self.mode.borrow() {
-                Mode::READ => (),
-                Mode::ID => val = if addr & 1 == 0 { 0xbf } else { 0xd6 },
-                Mode::PROGRAM => (),
-                Mode::WRITING => val = (val ^ 0x80) & 0x80,
-                Mode::COMMAND1 => (),
-                Mode::COMMAND2 => (),
-                Mode::ERASE1 => (),
-                Mode::ERASE2 => (),
-                Mode::ERASE3 => (),
+        let offset = self.offset(addr)?;
+        match *self.mode.borrow() {
+            Mode::Id => {
+                let (manufacturer, device) = self.chip.id();
+                Some(if offset & 1 == 0 { manufacturer } else { device })
             }
+            Mode::Busy => {
+                // DQ7 reads the complement of the data being written, and DQ6 toggles on every read
+                let toggle = self.toggle.get() ^ 0b0100_0000;
+                self.toggle.set(toggle);
+                Some(self.poll.get() | toggle)
+            }
+            _ => Some(self.region.borrow().as_slice()[offset as usize]),
+        }
+    }
 
-            return Some(val);
+    fn peek(&self, address: u32) -> Option<u8> {
+        let addr = if *self.is_masking.borrow() {
+            address | 0b1000_0000_0000_0000_0000
+        } else {
+            address
+        };
+        let offset = self.offset(addr)?;
+        match *self.mode.borrow() {
+            Mode::Id => {
+                let (manufacturer, device) = self.chip.id();
+                Some(if offset & 1 == 0 { manufacturer } else { device })
+            }
+            _ => Some(self.region.borrow().as_slice()[offset as usize]),
         }
-        None
     }
 
     fn mem_write(&self, address: u32, data: u8) {
         let mode = *self.mode.borrow();
         match mode {
-            Mode::READ => {
-                if address == self.start + 0x5555 && data == 0xaa {
-                    *self.mode.borrow_mut() = Mode::COMMAND1;
+            Mode::Read => {
+                if self.is_command(address, 0x5555) && data == 0xaa {
+                    self.set_mode(Mode::Command1);
+                } else if self.offset(address).is_some() && data != 0xf0 {
+                    self.violation(Violation::UnlockedWrite { address, data });
                 }
             }
-            Mode::ID => {
-                if address >= self.start && address <= self.start + self.size && data == 0xf0 {
-                    *self.mode.borrow_mut() = Mode::READ;
+            Mode::Id => {
+                if self.is_command(address, 0x5555) && data == 0xaa {
+                    self.set_mode(Mode::Command1);
+                } else if self.offset(address).is_some() && data == 0xf0 && self.chip.page_size().is_none() {
+                    self.set_mode(Mode::Read);
                 }
             }
-            Mode::PROGRAM => {
-                if address >= self.start && address <= self.start + self.size {
-                    //println!("Write byte {:04x} to {:05x}", data, address);
-                    self.bytes.borrow_mut()[(address - self.start) as usize] = data;
-                    *self.mode.borrow_mut() = Mode::WRITING;
-                    *self.begun.borrow_mut() = Instant::now();
+            Mode::Command1 => {
+                if self.is_command(address, 0x2aaa) && data == 0x55 {
+                    self.set_mode(Mode::Command2);
+                } else {
+                    self.abort(address, data);
                 }
             }
-            Mode::WRITING =>
-            //(), // nothing is accepted during programming
-            {
-                if address >= self.start && address <= self.start + self.size {
-                    println!("Attempt to write byte {:04x} to {:05x} during WRITING", data, address);
-                    let one_ms = Duration::from_millis(100000);
-                    thread::sleep(one_ms);
+            Mode::Command2 => {
+                if self.is_command(address, 0x5555) && data == 0xa0 {
+                    self.set_mode(if self.chip.page_size().is_some() {
+                        Mode::Load
+                    } else {
+                        Mode::Program
+                    });
+                    self.until.set(u64::MAX);
+                } else if self.is_command(address, 0x5555) && data == 0x80 {
+                    self.set_mode(Mode::Erase1);
+                } else if self.is_command(address, 0x5555) && data == self.chip.id_command() {
+                    self.set_mode(Mode::Id);
+                } else if self.is_command(address, 0x5555) && data == 0xf0 {
+                    self.set_mode(Mode::Read);
+                } else {
+                    self.abort(address, data);
                 }
             }
-            Mode::COMMAND1 => {
-                if address == self.start + 0x2aaa && data == 0x55 {
-                    *self.mode.borrow_mut() = Mode::COMMAND2;
-                } else if address >= self.start && address <= self.start + self.size {
-                    println!("COMMAND1 exited with byte {:04x} to {:05x}", data, address);
-                    *self.mode.borrow_mut() = Mode::READ;
+            Mode::Program => {
+                if let Some(offset) = self.offset(address) {
+                    self.program(offset, data);
+                    self.begin_busy(self.chip.program_time(), !data);
                 }
             }
-            Mode::COMMAND2 => {
-                if address == self.start + 0x5555 && data == 0xA0 {
-                    *self.mode.borrow_mut() = Mode::PROGRAM;
-                } else if address == self.start + 0x5555 && data == 0x80 {
-                    *self.mode.borrow_mut() = Mode::ERASE1;
-                } else if address == self.start + 0x5555 && data == 0x90 {
-                    *self.mode.borrow_mut() = Mode::ID;
-                } else if address >= self.start && address <= self.start + self.size {
-                    println!("COMMAND2 exited with byte {:04x} to {:05x}", data, address);
-                    *self.mode.borrow_mut() = Mode::READ;
+            Mode::Load => {
+                if let Some(offset) = self.offset(address) {
+                    self.load(offset, data);
                 }
             }
-            Mode::ERASE1 => {
-                if address == self.start + 0x5555 && data == 0xaa {
-                    *self.mode.borrow_mut() = Mode::ERASE2;
-                } else if address >= self.start && address <= self.start + self.size {
-                    *self.mode.borrow_mut() = Mode::READ;
+            Mode::Erase1 => {
+                if self.is_command(address, 0x5555) && data == 0xaa {
+                    self.set_mode(Mode::Erase2);
+                } else {
+                    self.abort(address, data);
                 }
             }
-            Mode::ERASE2 => {
-                if address == self.start + 0x2aaa && data == 0x55 {
-                    *self.mode.borrow_mut() = Mode::ERASE3;
-                } else if address >= self.start && address <= self.start + self.size {
-                    *self.mode.borrow_mut() = Mode::READ;
+            Mode::Erase2 => {
+                if self.is_command(address, 0x2aaa) && data == 0x55 {
+                    self.set_mode(Mode::Erase3);
+                } else {
+                    self.abort(address, data);
                 }
             }
-            Mode::ERASE3 => {
-                if address == self.start + 0x5555 && data == 0x10 {
+            Mode::Erase3 => {
+                if self.is_command(address, 0x5555) && data == 0x10 {
                     // erase the lot, pow!
-                    self.bytes.borrow_mut().iter_mut().map(|x| *x = 0xff).count();
-                    *self.mode.borrow_mut() = Mode::WRITING;
-                } else if address >= self.start && address <= self.start + self.size && data == 0x30 {
-                    let mut bytes = self.bytes.borrow_mut();
-                    let x = (address - self.start) as usize;
-                    for addr in (x & !0xfff)..(x & !0xfff) + 0x1000 {
-                        bytes[addr] = 0xff;
+                    for byte in self.region.borrow_mut().as_mut_slice().iter_mut() {
+                        *byte = 0xff;
+                    }
+                    self.begin_busy(self.chip.chip_erase_time(), 0);
+                } else if self.offset(address).is_some() && data == 0x30 && self.chip.page_size().is_none() {
+                    let sector = (self.offset(address).unwrap_or(0) & !(SECTOR - 1)) as usize;
+                    for byte in &mut self.region.borrow_mut().as_mut_slice()[sector..sector + SECTOR as usize] {
+                        *byte = 0xff;
                     }
-                    *self.mode.borrow_mut() = Mode::WRITING;
-                } else if address >= self.start && address <= self.start + self.size {
-                    *self.mode.borrow_mut() = Mode::READ;
+                    self.begin_busy(SECTOR_ERASE_TIME, 0);
+                } else {
+                    self.abort(address, data);
+                }
+            }
+            Mode::Busy => {
+                if self.offset(address).is_some() {
+                    self.violation(Violation::WriteWhileBusy { address, data });
                 }
             }
         }
     }
+
+    fn faults(&self) -> Vec<Fault> {
+        self.take_violations()
+            .into_iter()
+            .map(|violation| Fault {
+                address: violation.address(),
+                message: format!("flash {}", violation),
+                halt: false,
+            })
+            .collect()
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.bytes(self.region.borrow().as_slice());
+        state.bool(*self.is_masking.borrow());
+        state.u8(MODES.iter().position(|&mode| mode == *self.mode.borrow()).unwrap() as u8);
+        state.u64(self.now.get());
+        state.u64(self.until.get());
+        state.u8(self.poll.get());
+        state.u8(self.toggle.get());
+        let page = self.page.borrow();
+        state.u32(page.len() as u32);
+        for &(offset, data) in page.iter() {
+            state.u32(offset);
+            state.u8(data);
+        }
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        state.bytes_into(self.region.borrow_mut().as_mut_slice(), "ROM size")?;
+        *self.is_masking.borrow_mut() = state.bool()?;
+        let mode = MODES.get(state.u8()? as usize).ok_or(StateError::Invalid("flash mode"))?;
+        self.set_mode(*mode);
+        self.now.set(state.u64()?);
+        self.until.set(state.u64()?);
+        self.poll.set(state.u8()?);
+        self.toggle.set(state.u8()?);
+        let mut page = self.page.borrow_mut();
+        page.clear();
+        for _ in 0..state.u32()? {
+            page.push((state.u32()?, state.u8()?));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::bus::{cycles_from_micros, Bus};
+    use crate::rom::*;
+
+    fn command(rom: &ROM, cmd: u8) {
+        rom.mem_write(0x85555, 0xaa);
+        rom.mem_write(0x82aaa, 0x55);
+        rom.mem_write(0x85555, cmd);
+    }
+
+    fn setup(chip: Chip) -> (Bus, Rc<ROM>) {
+        let mut bus = Bus::new();
+        let rom = Rc::new(ROM::with_chip(0x80000, chip, vec![0xff; 0x1000]).unwrap());
+        bus.add(rom.clone());
+        rom.mem_read(0x80000, true);
+        (bus, rom)
+    }
+
+    fn run(bus: &Bus, micros: u64) {
+        bus.advance(cycles_from_micros(micros));
+        bus.cycle();
+    }
+
+    #[test]
+    fn reports_chip_id() {
+        for chip in &[Chip::SST39SF010, Chip::SST39SF020, Chip::SST39SF040] {
+            let (_bus, rom) = setup(*chip);
+            command(&rom, 0x90);
+            assert_eq!(rom.mem_read(0x80000, false), Some(chip.id().0));
+            assert_eq!(rom.mem_read(0x80001, false), Some(chip.id().1));
+            rom.mem_write(0x80000, 0xf0);
+            assert_eq!(rom.mem_read(0x80000, false), Some(0xff));
+        }
+
+        let (_bus, rom) = setup(Chip::AT29C020);
+        command(&rom, 0x60);
+        assert_eq!(rom.mem_read(0x80000, false), Some(0x1f));
+        assert_eq!(rom.mem_read(0x80001, false), Some(0xda));
+        command(&rom, 0xf0);
+        assert_eq!(rom.mem_read(0x80001, false), Some(0xff));
+    }
+
+    #[test]
+    fn byte_program_polls() {
+        let (bus, rom) = setup(Chip::SST39SF040);
+        command(&rom, 0xa0);
+        rom.mem_write(0x80100, 0x5a);
+        assert!(rom.is_busy());
+
+        let first = rom.mem_read(0x80100, false).unwrap();
+        let second = rom.mem_read(0x80100, false).unwrap();
+        assert_eq!(first & 0x80, 0x80, "DQ7 is the complement of the data written");
+        assert_ne!(first & 0x40, second & 0x40, "DQ6 toggles while busy");
+
+        run(&bus, 20);
+        assert!(!rom.is_busy());
+        assert_eq!(rom.mem_read(0x80100, false), Some(0x5a));
+        assert!(rom.take_violations().is_empty());
+    }
+
+    #[test]
+    fn sector_erase() {
+        let (bus, rom) = setup(Chip::SST39SF040);
+        command(&rom, 0xa0);
+        rom.mem_write(0x81234, 0x00);
+        run(&bus, 20);
+
+        command(&rom, 0x80);
+        command(&rom, 0x00);
+        assert_eq!(rom.take_violations().len(), 1, "0x00 is not an erase command");
+
+        command(&rom, 0x80);
+        rom.mem_write(0x85555, 0xaa);
+        rom.mem_write(0x82aaa, 0x55);
+        rom.mem_write(0x81000, 0x30);
+        assert_eq!(rom.mem_read(0x81234, false).unwrap() & 0x80, 0);
+        run(&bus, 1000);
+        assert!(rom.is_busy(), "sector erase takes 25ms");
+        run(&bus, 25_000);
+        assert_eq!(rom.mem_read(0x81234, false), Some(0xff));
+    }
+
+    #[test]
+    fn page_write() {
+        let (bus, rom) = setup(Chip::AT29C010);
+        command(&rom, 0xa0);
+        rom.mem_write(0x80080, 0x12);
+        rom.mem_write(0x80081, 0x34);
+        rom.mem_write(0x80100, 0x56);
+        run(&bus, 150);
+        assert!(rom.is_busy());
+        run(&bus, 10_000);
+        assert_eq!(rom.mem_read(0x80080, false), Some(0x12));
+        assert_eq!(rom.mem_read(0x80081, false), Some(0x34));
+        assert_eq!(rom.mem_read(0x80100, false), Some(0xff));
+        assert_eq!(
+            rom.take_violations(),
+            vec![Violation::PageCrossing {
+                address: 0x80100,
+                data: 0x56
+            }]
+        );
+    }
+
+    #[test]
+    fn write_while_busy() {
+        let (bus, rom) = setup(Chip::SST39SF010);
+        command(&rom, 0x80);
+        command(&rom, 0x10);
+        rom.mem_write(0x80000, 0x00);
+        assert_eq!(
+            rom.take_violations(),
+            vec![Violation::WriteWhileBusy {
+                address: 0x80000,
+                data: 0x00
+            }]
+        );
+        run(&bus, 100_000);
+        assert_eq!(rom.mem_read(0x80000, false), Some(0xff));
+    }
+
+    #[test]
+    fn image_must_fit_the_chip() {
+        assert!(ROM::with_chip(0x80000, Chip::AT29C010, vec![0xff; 0x20001]).is_err());
+        let rom = ROM::with_chip(0x80000, Chip::AT29C020, vec![0x00; 0x20001]).unwrap();
+        assert_eq!(rom.mem_read(0x80000 + 0x20000, false), Some(0x00));
+        assert_eq!(rom.mem_read(0x80000 + 0x20001, false), Some(0xff), "padded with erased bytes");
+    }
 }
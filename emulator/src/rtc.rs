@@ -0,0 +1,528 @@
+/**
+ * DS1302 real-time clock
+ *
+ * The DS1302 is bit-banged through a single output latch and input buffer, wired as on the RomWBW boards:
+ *
+ *   bit 7  data to the RTC (write)
+ *   bit 6  SCLK
+ *   bit 5  /WE, data direction: 0 while the CPU drives the data line
+ *   bit 4  CE
+ *   bit 0  data from the RTC (read)
+ *
+ * A transaction raises CE, clocks a command byte in LSB first on rising SCLK edges, then either clocks data bytes in
+ * on rising edges or reads them out after falling edges. Burst commands (address 31) transfer all clock registers or
+ * all RAM bytes in sequence. Clock writes are applied when CE falls.
+ *
+ * The time comes from the host clock, a fixed instant, or a starting instant advanced by emulated time. The host clock
+ * is read ten times a second of emulated time, through the bus, so a recorded run can be replayed.
+ *
+ * Known limitations:
+ *  1. The day of week register is derived from the date; writes to it are ignored
+ *  2. Years are 2000-2099
+ *  3. The trickle charger register is stored but has no effect
+ */
+use std::cell::{Cell, RefCell};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::bus::{Bus, CYCLES_PER_SECOND};
+use crate::history::Source;
+use crate::state::{StateError, StateReader, StateWriter};
+use crate::types::*;
+
+const DATA_OUT: u8 = 0b1000_0000;
+const SCLK: u8 = 0b0100_0000;
+const CE: u8 = 0b0001_0000;
+
+// How often the host clock is read, in clock cycles
+const HOST_SAMPLE: u64 = CYCLES_PER_SECOND / 10;
+
+/// Where the RTC gets the time from. Instants are seconds since the Unix epoch.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum TimeSource {
+    /// The host's wall clock.
+    Host,
+    /// A fixed instant that never advances.
+    Fixed(i64),
+    /// An instant at power on, advanced by emulated time so runs are repeatable.
+    Emulated(i64),
+}
+
+impl TimeSource {
+    /// Parse `host`, `fixed:SECONDS` or `emulated:SECONDS`.
+    pub fn from_name(name: &str) -> Option<TimeSource> {
+        let mut parts = name.splitn(2, ':');
+        match (parts.next(), parts.next().map(str::parse)) {
+            (Some("host"), None) => Some(TimeSource::Host),
+            (Some("fixed"), Some(Ok(secs))) => Some(TimeSource::Fixed(secs)),
+            (Some("emulated"), Some(Ok(secs))) => Some(TimeSource::Emulated(secs)),
+            (Some("emulated"), None) => Some(TimeSource::Emulated(0)),
+            _ => None,
+        }
+    }
+}
+
+pub struct RTC {
+    port: u16,
+    source: TimeSource,
+    // emulated clock at the most recent machine cycle, and the host clock as last read
+    now: Cell<u64>,
+    host: Cell<i64>,
+    // seconds added to the source by writes to the clock, and the time the clock stopped at if CH is set
+    offset: Cell<i64>,
+    halted: Cell<Option<i64>>,
+    hour12: Cell<bool>,
+    write_protect: Cell<bool>,
+    trickle: Cell<u8>,
+    ram: RefCell<[u8; 31]>,
+    // serial interface: the last latch value, the command once shifted in, bits of the current byte, and the byte
+    // index within a burst
+    latch: Cell<u8>,
+    command: Cell<Option<u8>>,
+    shift: Cell<u8>,
+    bits: Cell<u8>,
+    index: Cell<u8>,
+    // clock registers captured at the start of a transaction, and whether the transaction changed them
+    registers: RefCell<[u8; 8]>,
+    modified: Cell<bool>,
+    data: Cell<u8>,
+}
+
+fn bcd(value: u32) -> u8 {
+    (((value / 10) << 4) | (value % 10)) as u8
+}
+
+fn from_bcd(value: u8) -> u32 {
+    (value >> 4) as u32 * 10 + (value & 0x0f) as u32
+}
+
+// Days since 1970-01-01 to (year, month, day), and back again.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    (yoe + era * 400 + if month <= 2 { 1 } else { 0 }, month, day)
+}
+
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year.rem_euclid(400);
+    let mp = (month as i64 + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + day as i64 - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+fn host_time() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+impl RTC {
+    pub fn new(port: u16, source: TimeSource) -> RTC {
+        RTC {
+            port,
+            source,
+            now: Cell::new(0),
+            host: Cell::new(host_time()),
+            offset: Cell::new(0),
+            halted: Cell::new(None),
+            hour12: Cell::new(false),
+            write_protect: Cell::new(false),
+            trickle: Cell::new(0x5c),
+            ram: RefCell::new([0; 31]),
+            latch: Cell::new(0),
+            command: Cell::new(None),
+            shift: Cell::new(0),
+            bits: Cell::new(0),
+            index: Cell::new(0),
+            registers: RefCell::new([0; 8]),
+            modified: Cell::new(false),
+            data: Cell::new(1),
+        }
+    }
+
+    fn source_time(&self) -> i64 {
+        match self.source {
+            TimeSource::Host => self.host.get(),
+            TimeSource::Fixed(secs) => secs,
+            TimeSource::Emulated(secs) => secs + (self.now.get() / CYCLES_PER_SECOND) as i64,
+        }
+    }
+
+    /// The time the RTC is currently showing, in seconds since the Unix epoch.
+    pub fn time(&self) -> i64 {
+        self.halted.get().unwrap_or_else(|| self.source_time() + self.offset.get())
+    }
+
+    /// Set the time the RTC shows. The clock keeps running from the new time.
+    pub fn set_time(&self, secs: i64) {
+        self.offset.set(secs - self.source_time());
+        if self.halted.get().is_some() {
+            self.halted.set(Some(secs));
+        }
+    }
+
+    // The clock registers 0-7 for the current time.
+    fn clock_registers(&self) -> [u8; 8] {
+        let time = self.time();
+        let days = time.div_euclid(86400);
+        let secs = time.rem_euclid(86400) as u32;
+        let (year, month, day) = civil_from_days(days);
+        let hour = secs / 3600;
+        let hours = if self.hour12.get() {
+            let pm = if hour >= 12 { 0x20 } else { 0 };
+            0x80 | pm | bcd((hour + 11) % 12 + 1)
+        } else {
+            bcd(hour)
+        };
+        [
+            bcd(secs % 60) | if self.halted.get().is_some() { 0x80 } else { 0 },
+            bcd(secs / 60 % 60),
+            hours,
+            bcd(day),
+            bcd(month),
+            ((days + 4).rem_euclid(7) + 1) as u8,
+            bcd(year.rem_euclid(100) as u32),
+            if self.write_protect.get() { 0x80 } else { 0 },
+        ]
+    }
+
+    // Apply a set of written clock registers.
+    fn commit(&self, registers: &[u8; 8]) {
+        let hours = registers[2];
+        let hour = if hours & 0x80 != 0 {
+            from_bcd(hours & 0x1f) % 12 + if hours & 0x20 != 0 { 12 } else { 0 }
+        } else {
+            from_bcd(hours & 0x3f)
+        };
+        let days = days_from_civil(
+            2000 + from_bcd(registers[6]) as i64,
+            from_bcd(registers[4]),
+            from_bcd(registers[3]),
+        );
+        let secs = days * 86400 + (hour * 3600 + from_bcd(registers[1] & 0x7f) * 60 + from_bcd(registers[0] & 0x7f)) as i64;
+        self.hour12.set(hours & 0x80 != 0);
+        self.set_time(secs);
+        if registers[0] & 0x80 != 0 {
+            self.halted.set(Some(secs));
+        } else {
+            self.halted.set(None);
+        }
+    }
+
+    // The register or RAM address a transaction byte reads or writes, if any: a clock burst covers only the eight clock
+    // registers, and not the trickle charger register after them.
+    fn register(&self, command: u8) -> Option<usize> {
+        let address = (command >> 1) & 0x1f;
+        if address != 31 {
+            Some(address as usize)
+        } else if command & 0x40 == 0 && self.index.get() >= 8 {
+            None
+        } else {
+            Some(self.index.get() as usize)
+        }
+    }
+
+    fn read_byte(&self, command: u8) -> u8 {
+        let register = match self.register(command) {
+            Some(register) => register,
+            None => return 0,
+        };
+        if command & 0x40 != 0 {
+            self.ram.borrow().get(register).copied().unwrap_or(0)
+        } else {
+            match register {
+                0..=7 => self.registers.borrow()[register],
+                8 => self.trickle.get(),
+                _ => 0,
+            }
+        }
+    }
+
+    fn write_byte(&self, command: u8, data: u8) {
+        let register = match self.register(command) {
+            Some(register) => register,
+            None => return,
+        };
+        if register == 7 && command & 0x40 == 0 {
+            self.write_protect.set(data & 0x80 != 0);
+            self.registers.borrow_mut()[7] = data & 0x80;
+            return;
+        }
+        if self.write_protect.get() {
+            return;
+        }
+        if command & 0x40 != 0 {
+            if let Some(byte) = self.ram.borrow_mut().get_mut(register) {
+                *byte = data;
+            }
+        } else if register < 7 {
+            self.registers.borrow_mut()[register] = data;
+            self.modified.set(true);
+        } else if register == 8 {
+            self.trickle.set(data);
+        }
+    }
+
+    fn begin(&self) {
+        self.command.set(None);
+        self.shift.set(0);
+        self.bits.set(0);
+        self.index.set(0);
+        self.modified.set(false);
+        *self.registers.borrow_mut() = self.clock_registers();
+    }
+
+    fn end(&self) {
+        if self.modified.get() {
+            let registers = *self.registers.borrow();
+            self.commit(&registers);
+        }
+        self.command.set(None);
+        self.modified.set(false);
+    }
+
+    fn rising(&self, data: u8) {
+        let bit = if data & DATA_OUT != 0 { 1 } else { 0 };
+        let bits = self.bits.get();
+        match self.command.get() {
+            Some(command) if command & 1 != 0 => {}
+            command => {
+                let shift = self.shift.get() | (bit << bits);
+                if bits < 7 {
+                    self.shift.set(shift);
+                    self.bits.set(bits + 1);
+                    return;
+                }
+                self.shift.set(0);
+                self.bits.set(0);
+                match command {
+                    // commands without bit 7 set are ignored, and the rest of the transaction with them
+                    None if shift & 0x80 == 0 => self.command.set(Some(0x01)),
+                    None => self.command.set(Some(shift)),
+                    Some(command) => {
+                        self.write_byte(command, shift);
+                        self.index.set(self.index.get() + 1);
+                    }
+                }
+            }
+        }
+    }
+
+    fn falling(&self) {
+        if let Some(command) = self.command.get() {
+            if command & 0x81 == 0x81 {
+                let bits = self.bits.get();
+                if bits == 8 {
+                    self.index.set(self.index.get() + 1);
+                    self.bits.set(0);
+                }
+                let bits = self.bits.get();
+                self.data.set((self.read_byte(command) >> bits) & 1);
+                self.bits.set(bits + 1);
+            }
+        }
+    }
+}
+
+impl Peripheral for RTC {
+    fn cycle(&self, bus: &Bus) -> Option<Interrupt> {
+        let now = bus.clock();
+        // the clock moves on an instruction at a time, so sample whenever it crosses into the next period
+        let sample = now / HOST_SAMPLE != self.now.get() / HOST_SAMPLE;
+        self.now.set(now);
+        // the host clock is taken in only when it changes, so a recorded run can be replayed
+        if self.source == TimeSource::Host && sample {
+            let held = self.host.get();
+            let secs = bus.input(Source::HostClock, || {
+                let secs = host_time();
+                if secs != held {
+                    Some(secs as u64)
+                } else {
+                    None
+                }
+            });
+            if let Some(secs) = secs {
+                self.host.set(secs as i64);
+            }
+        }
+        None
+    }
+
+    fn io_read(&self, address: u16) -> Option<u8> {
+        if address == self.port {
+            Some(0xfe | self.data.get())
+        } else {
+            None
+        }
+    }
+
+    fn io_write(&self, address: u16, data: u8) {
+        if address != self.port {
+            return;
+        }
+        let last = self.latch.replace(data);
+        match (last & CE != 0, data & CE != 0) {
+            (false, true) => self.begin(),
+            (true, false) => self.end(),
+            (true, true) => {
+                if last & SCLK == 0 && data & SCLK != 0 {
+                    self.rising(data);
+                } else if last & SCLK != 0 && data & SCLK == 0 {
+                    self.falling();
+                }
+            }
+            (false, false) => {}
+        }
+    }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u64(self.now.get());
+        state.i64(self.host.get());
+        state.i64(self.offset.get());
+        state.bool(self.halted.get().is_some());
+        state.i64(self.halted.get().unwrap_or(0));
+        state.bool(self.hour12.get());
+        state.bool(self.write_protect.get());
+        state.u8(self.trickle.get());
+        state.bytes(&*self.ram.borrow());
+        state.u8(self.latch.get());
+        state.bool(self.command.get().is_some());
+        state.u8(self.command.get().unwrap_or(0));
+        state.u8(self.shift.get());
+        state.u8(self.bits.get());
+        state.u8(self.index.get());
+        state.bytes(&*self.registers.borrow());
+        state.bool(self.modified.get());
+        state.u8(self.data.get());
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        self.now.set(state.u64()?);
+        self.host.set(state.i64()?);
+        self.offset.set(state.i64()?);
+        let halted = state.bool()?;
+        let secs = state.i64()?;
+        self.halted.set(Some(secs).filter(|_| halted));
+        self.hour12.set(state.bool()?);
+        self.write_protect.set(state.bool()?);
+        self.trickle.set(state.u8()?);
+        state.bytes_into(&mut *self.ram.borrow_mut(), "RTC RAM")?;
+        self.latch.set(state.u8()?);
+        let command = state.bool()?;
+        let value = state.u8()?;
+        self.command.set(Some(value).filter(|_| command));
+        self.shift.set(state.u8()?);
+        self.bits.set(state.u8()?);
+        self.index.set(state.u8()?);
+        state.bytes_into(&mut *self.registers.borrow_mut(), "RTC registers")?;
+        self.modified.set(state.bool()?);
+        self.data.set(state.u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::bus::{Bus, CYCLES_PER_SECOND};
+    use crate::rtc::*;
+
+    const PORT: u16 = 0x70;
+
+    // Run one transaction: clock out a command and any data bytes, then read `read` bytes back.
+    fn transact(rtc: &RTC, command: u8, data: &[u8], read: usize) -> Vec<u8> {
+        rtc.io_write(PORT, 0);
+        rtc.io_write(PORT, CE);
+        for byte in [command].iter().chain(data) {
+            for bit in 0..8 {
+                let out = if byte & (1 << bit) != 0 { DATA_OUT } else { 0 };
+                rtc.io_write(PORT, CE | out);
+                rtc.io_write(PORT, CE | SCLK | out);
+            }
+        }
+        let mut result = Vec::new();
+        for _ in 0..read {
+            let mut byte = 0;
+            for bit in 0..8 {
+                rtc.io_write(PORT, CE | 0x20);
+                byte |= (rtc.io_read(PORT).unwrap() & 1) << bit;
+                rtc.io_write(PORT, CE | SCLK | 0x20);
+            }
+            result.push(byte);
+        }
+        rtc.io_write(PORT, 0);
+        result
+    }
+
+    #[test]
+    fn reads_fixed_time() {
+        // Sunday 2021-03-14 15:09:26
+        let rtc = RTC::new(PORT, TimeSource::Fixed(1615734566));
+        assert_eq!(
+            transact(&rtc, 0xbf, &[], 8),
+            vec![0x26, 0x09, 0x15, 0x14, 0x03, 0x01, 0x21, 0x00]
+        );
+        assert_eq!(transact(&rtc, 0x85, &[], 1), vec![0x15], "single register read");
+        assert_eq!(transact(&rtc, 0x91, &[], 1), vec![0x5c], "trickle charger register");
+        assert_eq!(
+            transact(&rtc, 0xbf, &[], 9)[8],
+            0,
+            "the clock burst stops at the clock registers"
+        );
+    }
+
+    #[test]
+    fn writes_set_the_time() {
+        let rtc = RTC::new(PORT, TimeSource::Fixed(0));
+        // 11:59:58 PM on Thursday 2099-12-31, in 12-hour mode
+        transact(&rtc, 0xbe, &[0x58, 0x59, 0xb1, 0x31, 0x12, 0x05, 0x99, 0x00], 0);
+        assert_eq!(rtc.time(), 4102444798);
+        assert_eq!(transact(&rtc, 0x85, &[], 1), vec![0xb1]);
+
+        transact(&rtc, 0xbe, &[0x58, 0x59, 0xb1, 0x31, 0x12, 0x05, 0x99, 0x00, 0xa5], 0);
+        assert_eq!(
+            transact(&rtc, 0x91, &[], 1),
+            vec![0x5c],
+            "a ninth burst byte isn't the trickle charger"
+        );
+        transact(&rtc, 0x90, &[0xa5], 0);
+        assert_eq!(transact(&rtc, 0x91, &[], 1), vec![0xa5]);
+
+        transact(&rtc, 0x8e, &[0x80], 0);
+        transact(&rtc, 0x80, &[0x00], 0);
+        assert_eq!(rtc.time(), 4102444798, "write protected");
+    }
+
+    #[test]
+    fn emulated_time_advances() {
+        let mut bus = Bus::new();
+        let rtc = std::rc::Rc::new(RTC::new(PORT, TimeSource::Emulated(1615734566)));
+        bus.add(rtc.clone());
+        bus.advance(CYCLES_PER_SECOND * 2);
+        bus.cycle();
+        assert_eq!(transact(&rtc, 0x81, &[], 1), vec![0x28]);
+
+        // halting the clock stops it
+        transact(&rtc, 0x80, &[0xa8], 0);
+        bus.advance(CYCLES_PER_SECOND);
+        bus.cycle();
+        assert_eq!(transact(&rtc, 0x81, &[], 1), vec![0xa8]);
+    }
+
+    #[test]
+    fn ram_burst() {
+        let rtc = RTC::new(PORT, TimeSource::Fixed(0));
+        transact(&rtc, 0xc2, &[0x42], 0);
+        assert_eq!(transact(&rtc, 0xc3, &[], 1), vec![0x42]);
+        transact(&rtc, 0xfe, &[1, 2, 3], 0);
+        assert_eq!(transact(&rtc, 0xff, &[], 4), vec![1, 2, 3, 0]);
+    }
+}
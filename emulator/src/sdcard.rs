@@ -1,8 +1,13 @@
 use std::cell::RefCell;
-use std::collections::VecDeque;
+use std::collections::{BTreeSet, VecDeque};
 
+use crate::region::MemoryRegion;
+use crate::state::{StateError, StateReader, StateWriter};
 use crate::types::*;
 
+// The value of every byte of a block that has never been written
+const ERASED: u8 = 0xe5;
+
 #[derive(Clone, Copy, PartialEq, Debug)]
 enum CardState {
     Command,
@@ -11,6 +16,14 @@ enum CardState {
     Writing,
 }
 
+// Every state, numbered for snapshots.
+const STATES: [CardState; 4] = [
+    CardState::Command,
+    CardState::ACommand,
+    CardState::TokenWait,
+    CardState::Writing,
+];
+
 pub struct SDCard {
     spi_ctrl: RefCell<u8>,
     spi_data: RefCell<u8>,
@@ -19,12 +32,14 @@ pub struct SDCard {
     idle: RefCell<bool>,
     state: RefCell<CardState>,
     write: RefCell<(usize, usize)>,
-    sectors: RefCell<Vec<u8>>,
+    sectors: RefCell<MemoryRegion>,
+    // the offsets of blocks that have been written, which are all snapshots need to keep of the card
+    written: RefCell<BTreeSet<u32>>,
 }
 
 impl SDCard {
     pub fn new() -> SDCard {
-        let v = vec![0xe5; 16 * 1024 * 1024 * 4];
+        let v = MemoryRegion::with_contents(0, vec![ERASED; 16 * 1024 * 1024 * 4]);
         SDCard {
             spi_ctrl: RefCell::new(0),
             spi_data: RefCell::new(0xff),
@@ -34,6 +49,19 @@ impl SDCard {
             state: RefCell::new(CardState::Command),
             write: RefCell::new((0, 0)),
             sectors: RefCell::new(v),
+            written: RefCell::new(BTreeSet::new()),
+        }
+    }
+
+    // The byte offset of the block addressed by a read or write command, if the card holds that block. The card's
+    // first block is at block address 8192.
+    fn block(&self, cmd: &[u8]) -> Option<u32> {
+        let addr = ((cmd[1] as u64) << 24) | ((cmd[2] as u64) << 16) | ((cmd[3] as u64) << 8) | (cmd[4] as u64);
+        let start = addr.checked_sub(8192)? * 512;
+        if start + 512 <= self.sectors.borrow().size() as u64 {
+            Some(start as u32)
+        } else {
+            None
         }
     }
 
@@ -54,33 +82,33 @@ impl SDCard {
             17 => {
                 if *self.idle.borrow() {
                     response.push_back(0x05);
-                } else {
+                } else if let Some(start) = self.block(cmd) {
                     response.push_back(0xff);
                     response.push_back(0x00);
                     response.push_back(0xff);
                     response.push_back(0xff);
                     response.push_back(0xfe);
-                    let addr =
-                        ((cmd[1] as usize) << 24) | ((cmd[2] as usize) << 16) | ((cmd[3] as usize) << 8) | (cmd[4] as usize);
-                    let start = (addr - 8192) * 512;
-                    let sector = &(*self.sectors.borrow())[start..start + 512];
-                    for x in sector {
+                    let sectors = self.sectors.borrow();
+                    for x in sectors.slice(start, 512).unwrap_or(&[]) {
                         response.push_back(*x);
                     }
                     // no-one checks the CRC anyway, right?
                     response.push_back(0x00);
                     response.push_back(0x00);
+                } else {
+                    // R1 address error
+                    response.push_back(0x20);
                 }
             }
             24 => {
                 if *self.idle.borrow() {
                     response.push_back(0x05);
-                } else {
+                } else if let Some(start) = self.block(cmd) {
                     response.push_back(0x00);
-                    let addr =
-                        ((cmd[1] as usize) << 24) | ((cmd[2] as usize) << 16) | ((cmd[3] as usize) << 8) | (cmd[4] as usize);
                     *self.state.borrow_mut() = CardState::TokenWait;
-                    *self.write.borrow_mut() = (512, (addr - 8192) * 512);
+                    *self.write.borrow_mut() = (512, start as usize);
+                } else {
+                    response.push_back(0x20);
                 }
             }
             55 => {
@@ -119,7 +147,8 @@ impl SDCard {
     fn do_write(&self, data: u8) {
         let mut write = self.write.borrow_mut();
         let mut response = self.spi_response.borrow_mut();
-        (*self.sectors.borrow_mut())[write.1] = data;
+        self.sectors.borrow_mut().set(write.1 as u32, data);
+        self.written.borrow_mut().insert(write.1 as u32 / 512 * 512);
         *write = (write.0 - 1, write.1 + 1);
         if write.0 == 0 {
             *self.state.borrow_mut() = CardState::Command;
@@ -201,4 +230,87 @@ impl Peripheral for SDCard {
             _ => {}
         }
     }
+
+    fn save_state(&self, state: &mut StateWriter) {
+        state.u8(*self.spi_ctrl.borrow());
+        state.u8(*self.spi_data.borrow());
+        state.bytes(&self.spi_command.borrow());
+        let response: Vec<u8> = self.spi_response.borrow().iter().copied().collect();
+        state.bytes(&response);
+        state.bool(*self.idle.borrow());
+        state.u8(STATES.iter().position(|&s| s == *self.state.borrow()).unwrap() as u8);
+        let (left, offset) = *self.write.borrow();
+        state.u32(left as u32);
+        state.u32(offset as u32);
+        let sectors = self.sectors.borrow();
+        let written = self.written.borrow();
+        state.u32(written.len() as u32);
+        for &block in written.iter() {
+            state.u32(block);
+            state.bytes(sectors.slice(block, 512).unwrap());
+        }
+    }
+
+    fn load_state(&self, state: &mut StateReader) -> Result<(), StateError> {
+        *self.spi_ctrl.borrow_mut() = state.u8()?;
+        *self.spi_data.borrow_mut() = state.u8()?;
+        *self.spi_command.borrow_mut() = state.bytes()?.to_vec();
+        *self.spi_response.borrow_mut() = state.bytes()?.iter().copied().collect();
+        *self.idle.borrow_mut() = state.bool()?;
+        *self.state.borrow_mut() = *STATES.get(state.u8()? as usize).ok_or(StateError::Invalid("SD card state"))?;
+        *self.write.borrow_mut() = (state.u32()? as usize, state.u32()? as usize);
+
+        // blocks written since the snapshot go back to being erased
+        let mut sectors = self.sectors.borrow_mut();
+        let mut written = self.written.borrow_mut();
+        for &block in written.iter() {
+            for byte in sectors.slice_mut(block, 512).unwrap() {
+                *byte = ERASED;
+            }
+        }
+        written.clear();
+        for _ in 0..state.u32()? {
+            let block = state.u32()?;
+            let contents = sectors
+                .slice_mut(block, 512)
+                .map_err(|_| StateError::Invalid("SD card block"))?;
+            state.bytes_into(contents, "SD card block")?;
+            written.insert(block);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::sdcard::*;
+
+    // Clock a byte through the SPI port with the card selected, and return the byte shifted back.
+    fn xfer(card: &SDCard, data: u8) -> u8 {
+        card.io_write(0xf1, 0x03);
+        card.io_write(0xf2, data);
+        card.io_read(0xf2).unwrap()
+    }
+
+    fn command(card: &SDCard, cmd: u8, arg: u32) -> u8 {
+        for byte in [0x40 | cmd].iter().chain(arg.to_be_bytes().iter()).chain([0x01].iter()) {
+            xfer(card, *byte);
+        }
+        xfer(card, 0xff)
+    }
+
+    #[test]
+    fn out_of_range_blocks_are_rejected() {
+        let card = SDCard::new();
+        command(&card, 55, 0);
+        while xfer(&card, 0xff) != 0xff {}
+        command(&card, 41, 0);
+        while xfer(&card, 0xff) != 0xff {}
+
+        assert_eq!(command(&card, 17, 0), 0x20, "below the first block");
+        while xfer(&card, 0xff) != 0xff {}
+        assert_eq!(command(&card, 24, 8192 + 0x20000), 0x20, "past the end of the card");
+        while xfer(&card, 0xff) != 0xff {}
+        assert_eq!(command(&card, 17, 8192 + 0x1ffff), 0xff, "last block reads");
+    }
 }
@@ -0,0 +1,286 @@
+/**
+ * Machine state
+ *
+ * Snapshots of the CPU and everything on the bus, so the debugger can go back to an earlier point in a run. Each part
+ * of the machine writes its state as a sequence of values and reads it back in the same order, so a snapshot only
+ * makes sense to a machine put together the same way as the one that took it.
+ *
+ * Values are little-endian, and byte strings carry a 32 bit length.
+ *
+ * Saved state files hold one snapshot, after a header of eight magic bytes and a 32 bit format version. The version
+ * changes whenever any part's state changes shape, and files of other versions are refused rather than misread.
+ */
+use std::convert::TryInto;
+use std::fmt;
+
+use crate::bus::Bus;
+use crate::cpu::CPU;
+
+// Saved state files start with this
+const MAGIC: &[u8; 8] = b"VTRS20\x1aS";
+
+/// The version of the saved state format written by `save`.
+pub const VERSION: u32 = 1;
+
+/// A snapshot that couldn't be read back.
+#[derive(Debug, PartialEq, Clone)]
+pub enum StateError {
+    /// The snapshot ended before everything in it had been read.
+    Truncated,
+    /// A value was out of range for what it describes, or a part found state it didn't expect.
+    Invalid(&'static str),
+    /// A saved state file didn't start with the magic bytes.
+    NotAState,
+    /// A saved state file is of a format version this emulator can't read.
+    Version(u32),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            StateError::Truncated => write!(f, "machine state is truncated"),
+            StateError::Invalid(what) => write!(f, "machine state has an invalid {}", what),
+            StateError::NotAState => write!(f, "not a saved machine state"),
+            StateError::Version(version) => write!(
+                f,
+                "saved machine state is version {}, but only version {} can be loaded",
+                version, VERSION
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+impl From<StateError> for std::io::Error {
+    fn from(e: StateError) -> std::io::Error {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, e)
+    }
+}
+
+/// Collects the values making up a snapshot.
+#[derive(Debug, Default, Clone)]
+pub struct StateWriter {
+    bytes: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        Default::default()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+
+    pub fn u8(&mut self, value: u8) {
+        self.bytes.push(value);
+    }
+
+    pub fn bool(&mut self, value: bool) {
+        self.u8(value as u8);
+    }
+
+    pub fn u16(&mut self, value: u16) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u32(&mut self, value: u32) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, value: u64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, value: i64) {
+        self.bytes.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// A byte string, read back whole by `StateReader::bytes`.
+    pub fn bytes(&mut self, value: &[u8]) {
+        self.u32(value.len() as u32);
+        self.bytes.extend_from_slice(value);
+    }
+}
+
+/// Hands back the values of a snapshot in the order they were written.
+pub struct StateReader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> StateReader<'a> {
+        StateReader { bytes }
+    }
+
+    /// True once every value has been read.
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    fn take<const N: usize>(&mut self) -> Result<[u8; N], StateError> {
+        if self.bytes.len() < N {
+            return Err(StateError::Truncated);
+        }
+        let (value, rest) = self.bytes.split_at(N);
+        self.bytes = rest;
+        Ok(value.try_into().unwrap())
+    }
+
+    pub fn u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn bool(&mut self) -> Result<bool, StateError> {
+        match self.u8()? {
+            0 => Ok(false),
+            1 => Ok(true),
+            _ => Err(StateError::Invalid("flag")),
+        }
+    }
+
+    pub fn u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take()?))
+    }
+
+    pub fn u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64, StateError> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    pub fn bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.u32()? as usize;
+        if self.bytes.len() < len {
+            return Err(StateError::Truncated);
+        }
+        let (value, rest) = self.bytes.split_at(len);
+        self.bytes = rest;
+        Ok(value)
+    }
+
+    /// Read a byte string into a buffer that must be exactly its length, as when restoring memory.
+    pub fn bytes_into(&mut self, buffer: &mut [u8], what: &'static str) -> Result<(), StateError> {
+        let value = self.bytes()?;
+        if value.len() != buffer.len() {
+            return Err(StateError::Invalid(what));
+        }
+        buffer.copy_from_slice(value);
+        Ok(())
+    }
+}
+
+/// Save a machine's state in the saved state file format.
+pub fn save(cpu: &CPU, bus: &Bus) -> Vec<u8> {
+    let mut state = StateWriter::new();
+    state.bytes.extend_from_slice(MAGIC);
+    state.u32(VERSION);
+    cpu.save_state(&mut state);
+    bus.save_state(&mut state);
+    state.into_bytes()
+}
+
+/// Load a machine's state from the saved state file format. The machine must be put together the same way as the one
+/// that saved it; if it isn't, it may be left partly loaded.
+pub fn load(cpu: &mut CPU, bus: &Bus, bytes: &[u8]) -> Result<(), StateError> {
+    if !bytes.starts_with(MAGIC) {
+        return Err(StateError::NotAState);
+    }
+    let mut state = StateReader::new(&bytes[MAGIC.len()..]);
+    match state.u32()? {
+        VERSION => (),
+        version => return Err(StateError::Version(version)),
+    }
+    cpu.load_state(&mut state)?;
+    bus.load_state(&mut state)?;
+    if !state.is_empty() {
+        return Err(StateError::Invalid("length"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::cpu::Register;
+    use crate::ram::RAM;
+    use crate::state::*;
+
+    #[test]
+    fn values_round_trip() {
+        let mut writer = StateWriter::new();
+        writer.u8(0x12);
+        writer.bool(true);
+        writer.u16(0x3456);
+        writer.u64(u64::MAX - 1);
+        writer.i64(-2);
+        writer.bytes(b"hello");
+        let bytes = writer.into_bytes();
+
+        let mut reader = StateReader::new(&bytes);
+        assert_eq!(reader.u8(), Ok(0x12));
+        assert_eq!(reader.bool(), Ok(true));
+        assert_eq!(reader.u16(), Ok(0x3456));
+        assert_eq!(reader.u64(), Ok(u64::MAX - 1));
+        assert_eq!(reader.i64(), Ok(-2));
+        let mut buffer = [0; 4];
+        assert_eq!(reader.bytes_into(&mut buffer, "buffer"), Err(StateError::Invalid("buffer")));
+        assert!(reader.is_empty());
+        assert_eq!(reader.u8(), Err(StateError::Truncated));
+    }
+
+    #[test]
+    fn machine_round_trips() {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(
+            0x0000,
+            &[
+                0x3e, 0x00, //          0x0000  ld a, 0
+                0x3c, //                0x0002  inc a
+                0x32, 0x00, 0x80, //    0x0003  ld ($8000), a
+                0x08, //                0x0006  ex af, af'
+                0x18, 0xf9, //          0x0007  jr $0002
+            ],
+        )
+        .unwrap();
+        bus.add(ram);
+        cpu.reset();
+        cpu.write_reg(Register::SP, 0x8000);
+        bus.io_write(0x39, 0x01);
+        for _ in 0..10 {
+            cpu.cycle(&mut bus);
+        }
+        let saved = save(&cpu, &bus);
+        let clock = bus.clock();
+        let registers = (cpu.reg(Register::PC), cpu.reg(Register::AF), cpu.reg(Register::AltAF));
+
+        for _ in 0..10 {
+            cpu.cycle(&mut bus);
+        }
+        bus.io_write(0x39, 0x00);
+        load(&mut cpu, &bus, &saved).unwrap();
+        assert_eq!(
+            (cpu.reg(Register::PC), cpu.reg(Register::AF), cpu.reg(Register::AltAF)),
+            registers
+        );
+        assert_eq!(bus.clock(), clock);
+        assert_eq!(bus.io_read(0x39), 0x01, "MMU registers");
+        assert_eq!(cpu.to_physical(0x1000), 0x2000);
+
+        let mut old = saved.clone();
+        old[8] = 0;
+        assert_eq!(load(&mut cpu, &bus, &old), Err(StateError::Version(0)));
+        assert_eq!(load(&mut cpu, &bus, b"not a state"), Err(StateError::NotAState));
+        assert_eq!(load(&mut cpu, &bus, &saved[..saved.len() - 1]), Err(StateError::Truncated));
+    }
+}
@@ -0,0 +1,271 @@
+/**
+ * Symbol tables
+ *
+ * Names for addresses, loaded from the files assemblers and linkers write, so that the debugger can show BIOS_READ+3
+ * rather than $f62a, and take names where it takes addresses. Files are read a line at a time, and each line may
+ * define symbols in any of these shapes:
+ *
+ *   BIOS_READ: EQU 0x0000F627     sjasmplus --sym, and EQU or = lines generally
+ *   _main = $0100 ; addr, public  z88dk .map
+ *   F627 BIOS_READ  F62A BIOS_WR  L80 and zmac .sym, address and name pairs, either way round
+ *   12  F627 C3 00 E8  BIOS_READ: an assembler listing, naming the address of a label
+ *
+ * Values in EQU and = lines are decimal unless marked hex with $, 0x or a trailing h. Values in pairs are always hex,
+ * and a pair of two hex words is skipped as ambiguous.
+ *
+ * Known limitations:
+ *  1. Symbols are logical addresses, with no notion of which bank they belong to
+ *  2. Names are matched without regard to case, as most Z80 assemblers do
+ *  3. An address is described relative to the nearest symbol at most $400 bytes below it
+ */
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+
+use crate::listing;
+
+// How far past a symbol an address can be and still be described relative to it
+const MAX_OFFSET: u16 = 0x400;
+
+#[derive(Debug, Default, Clone)]
+pub struct Symbols {
+    // lower cased names to their addresses
+    by_name: HashMap<String, u16>,
+    // addresses to the first name given to each, as written
+    by_address: BTreeMap<u16, String>,
+}
+
+pub(crate) fn is_name(word: &str) -> bool {
+    let mut chars = word.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || "_.?@".contains(c))
+        && chars.all(|c| c.is_ascii_alphanumeric() || "_.?@$".contains(c))
+}
+
+/// Parse a number as it's written in a command, expression or symbol file: hex when marked with $, 0x or a trailing h,
+/// decimal when marked with #, and otherwise in the default `radix`.
+pub fn parse_number(word: &str, radix: u32) -> Option<u32> {
+    let (digits, radix) = if let Some(hex) = word
+        .strip_prefix('$')
+        .or_else(|| word.strip_prefix("0x"))
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_suffix('h'))
+        .or_else(|| word.strip_suffix('H'))
+    {
+        (hex, 16)
+    } else if let Some(decimal) = word.strip_prefix('#') {
+        (decimal, 10)
+    } else {
+        (word, radix)
+    };
+    u32::from_str_radix(digits, radix).ok()
+}
+
+// A number in an EQU or = line, decimal unless marked hex.
+fn parse_value(word: &str) -> Option<u16> {
+    match parse_number(word, 10)? {
+        value if value > 0xffff => None,
+        value => Some(value as u16),
+    }
+}
+
+/// Parse a hex address of up to four digits, bare as symbol files write them, or marked with $, 0x or a trailing h.
+pub fn parse_hex(word: &str) -> Option<u16> {
+    let hex = word
+        .strip_prefix('$')
+        .or_else(|| word.strip_prefix("0x"))
+        .or_else(|| word.strip_prefix("0X"))
+        .or_else(|| word.strip_suffix('h'))
+        .or_else(|| word.strip_suffix('H'))
+        .unwrap_or(word);
+    if hex.is_empty() || hex.len() > 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    u16::from_str_radix(hex, 16).ok()
+}
+
+impl Symbols {
+    pub fn new() -> Symbols {
+        Default::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.by_name.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.by_name.len()
+    }
+
+    /// Add a symbol, replacing any symbol of the same name.
+    pub fn insert(&mut self, name: &str, address: u16) {
+        if let Some(old) = self.by_name.insert(name.to_ascii_lowercase(), address) {
+            if self.by_address.get(&old).is_some_and(|n| n.eq_ignore_ascii_case(name)) {
+                self.by_address.remove(&old);
+                // fall back to another name for the old address, if there is one
+                if let Some((other, _)) = self.by_name.iter().find(|&(_, &a)| a == old) {
+                    self.by_address.insert(old, other.clone());
+                }
+            }
+        }
+        self.by_address.entry(address).or_insert_with(|| name.to_string());
+    }
+
+    pub fn lookup(&self, name: &str) -> Option<u16> {
+        self.by_name.get(&name.to_ascii_lowercase()).copied()
+    }
+
+    /// The name of the symbol at exactly `address`.
+    pub fn name_of(&self, address: u16) -> Option<&str> {
+        self.by_address.get(&address).map(String::as_str)
+    }
+
+    /// The nearest symbol at or below `address`, and how far past it the address is.
+    pub fn nearest(&self, address: u16) -> Option<(&str, u16)> {
+        let (&base, name) = self.by_address.range(..=address).next_back()?;
+        let offset = address - base;
+        if offset > MAX_OFFSET {
+            return None;
+        }
+        Some((name, offset))
+    }
+
+    /// Describe an address as NAME or NAME+OFFSET, with a decimal offset.
+    pub fn describe(&self, address: u16) -> Option<String> {
+        match self.nearest(address)? {
+            (name, 0) => Some(name.to_string()),
+            (name, offset) => Some(format!("{}+{}", name, offset)),
+        }
+    }
+
+    /// All symbols, in address order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u16)> + '_ {
+        let mut symbols: Vec<(&str, u16)> = self
+            .by_name
+            .iter()
+            .map(|(lower, &address)| {
+                // prefer the name as written, where we have it
+                match self.by_address.get(&address) {
+                    Some(name) if name.eq_ignore_ascii_case(lower) => (name.as_str(), address),
+                    _ => (lower.as_str(), address),
+                }
+            })
+            .collect();
+        symbols.sort_by_key(|&(name, address)| (address, name));
+        symbols.into_iter()
+    }
+
+    /// Add the symbols defined in some text, answering how many there were.
+    pub fn parse(&mut self, text: &str) -> usize {
+        let mut count = 0;
+        for line in text.lines() {
+            for (name, address) in Symbols::parse_line(line) {
+                self.insert(name, address);
+                count += 1;
+            }
+        }
+        count
+    }
+
+    /// Add the symbols defined in a file, answering how many there were.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> Result<usize, std::io::Error> {
+        let text = std::fs::read_to_string(path)?;
+        Ok(self.parse(&text))
+    }
+
+    fn parse_line(line: &str) -> Vec<(&str, u16)> {
+        // a label in a listing
+        if let Some((_, address, _, source)) = listing::split_line(line) {
+            let label = source.split_whitespace().next().unwrap_or("");
+            match label.strip_suffix(':') {
+                Some(name) if is_name(name) => return vec![(name, address)],
+                _ => (),
+            }
+        }
+
+        let line = line.split(';').next().unwrap_or("");
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            // NAME EQU VALUE, NAME: EQU VALUE, NAME = VALUE
+            [name, op, value, ..] if op.eq_ignore_ascii_case("equ") || *op == "=" => {
+                let name = name.trim_end_matches(':');
+                match parse_value(value) {
+                    Some(address) if is_name(name) => vec![(name, address)],
+                    _ => vec![],
+                }
+            }
+            // pairs of an address and a name, either way round
+            words if !words.is_empty() && words.len() % 2 == 0 => {
+                // relocatable addresses may be marked with a quote
+                let address = |word: &str| parse_hex(word.trim_end_matches(['\'', '"']));
+                let pairs: Option<Vec<(&str, u16)>> = words
+                    .chunks(2)
+                    .map(|pair| match (address(pair[0]), address(pair[1])) {
+                        (Some(address), None) if is_name(pair[1]) => Some((pair[1], address)),
+                        (None, Some(address)) if is_name(pair[0]) => Some((pair[0], address)),
+                        _ => None,
+                    })
+                    .collect();
+                pairs.unwrap_or_default()
+            }
+            _ => vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::symbols::*;
+
+    #[test]
+    fn symbol_files() {
+        let mut symbols = Symbols::new();
+        let count = symbols.parse(
+            "\
+BIOS_READ: EQU 0x0000F627
+bios_wboot equ 0f603h
+_main                           = $0100 ; addr, public, , main, code_compiler, main.c:5
+F62A BIOS_WRITE  BIOS_HOME F618
+BEEF CAFE
+  7    E000 C3 5C E0    ZCPR:  jp start
+  8    E003             ; not a label
+",
+        );
+        assert_eq!(count, 6);
+        assert_eq!(symbols.lookup("bios_read"), Some(0xf627));
+        assert_eq!(symbols.lookup("BIOS_WBOOT"), Some(0xf603));
+        assert_eq!(symbols.lookup("_main"), Some(0x0100));
+        assert_eq!(symbols.lookup("BIOS_WRITE"), Some(0xf62a));
+        assert_eq!(symbols.lookup("BIOS_HOME"), Some(0xf618));
+        assert_eq!(symbols.lookup("zcpr"), Some(0xe000));
+        assert_eq!(symbols.lookup("BEEF"), None);
+
+        assert_eq!(symbols.name_of(0xf627), Some("BIOS_READ"));
+        assert_eq!(symbols.describe(0xf629), Some("BIOS_READ+2".to_string()));
+        assert_eq!(symbols.describe(0xf62d), Some("BIOS_WRITE+3".to_string()));
+        assert_eq!(symbols.describe(0x00ff), None);
+        assert_eq!(symbols.describe(0x0600), None, "too far past _main");
+
+        symbols.insert("bios_read", 0xf700);
+        assert_eq!(symbols.name_of(0xf627), None);
+        assert_eq!(symbols.iter().last(), Some(("bios_read", 0xf700)));
+    }
+
+    #[test]
+    fn numbers() {
+        for radix in [10, 16].iter() {
+            assert_eq!(parse_number("$2a", *radix), Some(0x2a));
+            assert_eq!(parse_number("0x2A", *radix), Some(0x2a));
+            assert_eq!(parse_number("2ah", *radix), Some(0x2a));
+            assert_eq!(parse_number("#42", *radix), Some(42));
+        }
+        assert_eq!(parse_number("42", 10), Some(42));
+        assert_eq!(parse_number("42", 16), Some(0x42));
+        assert_eq!(parse_number("2a", 10), None);
+        assert_eq!(parse_number("$", 16), None);
+
+        assert_eq!(parse_hex("F627"), Some(0xf627));
+        assert_eq!(parse_hex("$f627"), Some(0xf627));
+        assert_eq!(parse_hex("0100h"), Some(0x0100));
+        assert_eq!(parse_hex("12345"), None, "more than a 16 bit address");
+        assert_eq!(parse_hex("main"), None);
+    }
+}
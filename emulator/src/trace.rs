@@ -0,0 +1,476 @@
+/**
+ * Instruction tracing
+ *
+ * A `Tracer` writes a record for each instruction the CPU runs: where it ran, logically and physically, its bytes and
+ * disassembly, the registers before it ran, the memory and I/O accesses it made, and the clock cycles it took. Call
+ * `trace` before each instruction and `finish` when the run ends; each record is completed when the next instruction
+ * begins.
+ *
+ * Records are written as text, one line each in the style of the monitor; as JSON Lines, one object each, which also
+ * give the mnemonic and the target of a jump or call; or in a compact binary format. A binary trace starts with eight
+ * magic bytes and a 32 bit version, then each record is, in little-endian order: the u64 clock, u16 PC, u32 physical
+ * PC, a u8 count and the instruction's bytes, the u16 registers AF, BC, DE, HL, IX, IY, SP, AF', BC', DE' and HL', a u8
+ * cycle count, and a u16 count of accesses, each a u8 kind (1 for a write, plus 2 for I/O, plus 4 if made by DMA), a
+ * u32 address, and the u8 value.
+ *
+ * Filters limit the trace to instructions in a range of logical addresses, in a 64K bank of physical memory, or in
+ * the code following a symbol up to the next. With a ring buffer, only the last so many records are kept, and they're
+ * written when the CPU halts or the run finishes.
+ *
+ * Known limitations:
+ *  1. Operand reads are left out only when they're from the instruction's own bytes; an instruction that reads itself
+ *     as data has that read left out too
+ */
+use std::collections::VecDeque;
+use std::io::{self, Write};
+
+use serde_json::json;
+
+use crate::bus::{Bus, Origin, Transaction};
+use crate::cpu::{Mode, Register, CPU};
+use crate::debugger::Debugger;
+use crate::disasm;
+use crate::symbols::Symbols;
+use crate::watch::{Access, Space};
+
+// Binary traces start with this
+const MAGIC: &[u8; 8] = b"VTRS20\x1aT";
+
+/// The version of the binary trace format.
+pub const VERSION: u32 = 1;
+
+// The registers recorded with each instruction
+const REGISTERS: [Register; 11] = [
+    Register::AF,
+    Register::BC,
+    Register::DE,
+    Register::HL,
+    Register::IX,
+    Register::IY,
+    Register::SP,
+    Register::AltAF,
+    Register::AltBC,
+    Register::AltDE,
+    Register::AltHL,
+];
+
+/// How trace records are written.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Format {
+    Text,
+    Json,
+    Binary,
+}
+
+impl Format {
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "text" => Some(Format::Text),
+            "json" | "jsonl" => Some(Format::Json),
+            "binary" => Some(Format::Binary),
+            _ => None,
+        }
+    }
+}
+
+/// Which instructions are traced.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Filter {
+    /// Logical addresses from the first to the second, inclusive.
+    Range(u16, u16),
+    /// A 64K bank of physical memory.
+    Bank(u8),
+}
+
+impl Filter {
+    /// Parse a filter as START-END in hex, as bank:N, or as a symbol, which covers the code up to the next symbol.
+    pub fn parse(spec: &str, symbols: &Symbols) -> Result<Filter, String> {
+        let hex = |s: &str| u16::from_str_radix(s.trim_start_matches('$').trim_start_matches("0x"), 16);
+        if let Some(bank) = spec.strip_prefix("bank:") {
+            let bank = hex(bank).map_err(|_| format!("invalid bank {}", bank))?;
+            return match bank {
+                0..=0x0f => Ok(Filter::Bank(bank as u8)),
+                _ => Err(format!("there's no bank {:x} in 1M of physical memory", bank)),
+            };
+        }
+        if let Some((start, end)) = spec.split_once('-') {
+            if let (Ok(start), Ok(end)) = (hex(start), hex(end)) {
+                return Ok(Filter::Range(start, end));
+            }
+        }
+        let start = symbols.lookup(spec).ok_or_else(|| format!("unknown symbol {}", spec))?;
+        let end = symbols
+            .iter()
+            .map(|(_, address)| address)
+            .find(|&address| address > start)
+            .map_or(0xffff, |next| next - 1);
+        Ok(Filter::Range(start, end))
+    }
+
+    pub fn matches(&self, pc: u16, physical: u32) -> bool {
+        match *self {
+            Filter::Range(start, end) => (start..=end).contains(&pc),
+            Filter::Bank(bank) => physical >> 16 == bank as u32,
+        }
+    }
+}
+
+/// One instruction as it ran.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Record {
+    /// The bus clock when the instruction began.
+    pub clock: u64,
+    pub pc: u16,
+    pub physical: u32,
+    pub bytes: Vec<u8>,
+    /// AF, BC, DE, HL, IX, IY, SP, AF', BC', DE' and HL', before the instruction ran.
+    pub registers: [u16; 11],
+    pub transactions: Vec<Transaction>,
+    pub tstates: u32,
+}
+
+impl Record {
+    fn begin(cpu: &CPU, bus: &Bus) -> Record {
+        let pc = cpu.reg(Register::PC);
+        let opcodes = cpu.current_opcodes(bus);
+        let len = disasm::decode(&opcodes).length;
+        Record {
+            clock: bus.clock(),
+            pc,
+            physical: cpu.to_physical(pc),
+            bytes: opcodes[..len].to_vec(),
+            registers: REGISTERS.map(|register| cpu.reg(register)),
+            transactions: Vec::new(),
+            tstates: 0,
+        }
+    }
+
+    // Fill in what the instruction did, now that it's run.
+    fn complete(&mut self, cpu: &CPU, bus: &Bus) {
+        let len = self.bytes.len() as u32;
+        let physical = self.physical;
+        let fetch = |t: &Transaction| {
+            t.origin == Origin::Fetch
+                || t.origin == Origin::Cpu
+                    && t.space == Space::Physical
+                    && t.access == Access::Read
+                    && t.address.wrapping_sub(physical) < len
+        };
+        self.transactions = bus.take_transactions().into_iter().filter(|t| !fetch(t)).collect();
+        self.tstates = cpu.retired().map_or(0, |retired| retired.tstates);
+    }
+}
+
+/// Writes trace records for the instructions a CPU runs.
+pub struct Tracer<W: Write> {
+    format: Format,
+    output: W,
+    filters: Vec<Filter>,
+    // with a ring buffer, how many records to keep, and the records kept
+    ring: Option<usize>,
+    kept: VecDeque<Record>,
+    pending: Option<Record>,
+    started: bool,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(format: Format, output: W) -> Tracer<W> {
+        Tracer {
+            format,
+            output,
+            filters: Vec::new(),
+            ring: None,
+            kept: VecDeque::new(),
+            pending: None,
+            started: false,
+        }
+    }
+
+    /// Keep only the last `capacity` records, writing them when the CPU halts or the trace finishes.
+    pub fn ring(mut self, capacity: usize) -> Tracer<W> {
+        self.ring = Some(capacity.max(1));
+        self
+    }
+
+    /// Trace instructions the filter matches. With no filters, every instruction is traced.
+    pub fn add_filter(&mut self, filter: Filter) {
+        self.filters.push(filter);
+    }
+
+    pub fn output(&self) -> &W {
+        &self.output
+    }
+
+    /// Complete the record of the last instruction, and begin one for the instruction about to run.
+    pub fn trace(&mut self, cpu: &CPU, bus: &Bus, debugger: &Debugger) -> io::Result<()> {
+        bus.collect_transactions(true);
+        self.complete(cpu, bus, debugger)?;
+        if cpu.mode != Mode::OpCodeFetch {
+            return self.dump(debugger);
+        }
+        let pc = cpu.reg(Register::PC);
+        let physical = cpu.to_physical(pc);
+        if self.filters.is_empty() || self.filters.iter().any(|filter| filter.matches(pc, physical)) {
+            self.pending = Some(Record::begin(cpu, bus));
+        }
+        Ok(())
+    }
+
+    /// Complete the record of the last instruction and write out anything kept.
+    pub fn finish(&mut self, cpu: &CPU, bus: &Bus, debugger: &Debugger) -> io::Result<()> {
+        self.complete(cpu, bus, debugger)?;
+        self.dump(debugger)?;
+        bus.collect_transactions(false);
+        self.output.flush()
+    }
+
+    fn complete(&mut self, cpu: &CPU, bus: &Bus, debugger: &Debugger) -> io::Result<()> {
+        let mut record = match self.pending.take() {
+            Some(record) => record,
+            None => {
+                bus.take_transactions();
+                return Ok(());
+            }
+        };
+        record.complete(cpu, bus);
+        match self.ring {
+            Some(capacity) => {
+                if self.kept.len() == capacity {
+                    self.kept.pop_front();
+                }
+                self.kept.push_back(record);
+                Ok(())
+            }
+            None => self.write(&record, debugger),
+        }
+    }
+
+    // Write out the records kept in the ring buffer.
+    fn dump(&mut self, debugger: &Debugger) -> io::Result<()> {
+        while let Some(record) = self.kept.pop_front() {
+            self.write(&record, debugger)?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, record: &Record, debugger: &Debugger) -> io::Result<()> {
+        match self.format {
+            Format::Text => writeln!(self.output, "{}", text(record, debugger)),
+            Format::Json => writeln!(self.output, "{}", json(record, debugger)),
+            Format::Binary => {
+                if !self.started {
+                    self.output.write_all(MAGIC)?;
+                    self.output.write_all(&VERSION.to_le_bytes())?;
+                    self.started = true;
+                }
+                self.output.write_all(&binary(record))
+            }
+        }
+    }
+}
+
+fn text(record: &Record, debugger: &Debugger) -> String {
+    let [af, bc, de, hl, ix, iy, sp, ..] = record.registers;
+    let flags = af as u8;
+    let flag = |bit: u8, set: char| if flags & bit != 0 { set } else { set.to_ascii_lowercase() };
+    let location = debugger
+        .symbols()
+        .describe(record.pc)
+        .map(|name| format!(" <{}>", name))
+        .unwrap_or_default();
+    let accesses: Vec<String> = record
+        .transactions
+        .iter()
+        .map(|t| match t.origin {
+            Origin::Dma => format!("dma {}", t),
+            _ => t.to_string(),
+        })
+        .collect();
+    let accesses = if accesses.is_empty() {
+        String::new()
+    } else {
+        format!("    [{}]", accesses.join(", "))
+    };
+    let source = match debugger.source_line(record.pc) {
+        Some((listing, line)) => format!(
+            "    ; {}:{} {}",
+            listing.path().file_name().unwrap_or_default().to_string_lossy(),
+            line,
+            listing.text_of_line(line).unwrap_or("")
+        ),
+        None => String::new(),
+    };
+    format!(
+        "{:>10} PC=${:04x}[${:05x}]{}, SP=${:04x} \
+         A=${:02x} BC=${:04x} DE=${:04x} HL=${:04x} IX=${:04x} IY=${:04x} \
+         {}{}-{}-{}{}{} {:>2}T    {}{}{}",
+        record.clock,
+        record.pc,
+        record.physical,
+        location,
+        sp,
+        af >> 8,
+        bc,
+        de,
+        hl,
+        ix,
+        iy,
+        flag(0b1000_0000, 'S'),
+        flag(0b0100_0000, 'Z'),
+        flag(0b0001_0000, 'H'),
+        flag(0b0000_0100, 'P'),
+        flag(0b0000_0010, 'N'),
+        flag(0b0000_0001, 'C'),
+        record.tstates,
+        disasm::decode(&record.bytes).text(record.pc, debugger.symbols()),
+        accesses,
+        source,
+    )
+}
+
+fn json(record: &Record, debugger: &Debugger) -> serde_json::Value {
+    let [af, bc, de, hl, ix, iy, sp, af_, bc_, de_, hl_] = record.registers;
+    let accesses: Vec<serde_json::Value> = record
+        .transactions
+        .iter()
+        .map(|t| json!({ "kind": t.kind(), "origin": t.origin.name(), "address": t.address, "value": t.value }))
+        .collect();
+    let instruction = disasm::decode(&record.bytes);
+    let disassembly = instruction.text(record.pc, debugger.symbols());
+    let mut value = json!({
+        "clock": record.clock,
+        "pc": record.pc,
+        "physical": record.physical,
+        "bytes": record.bytes,
+        "disassembly": disassembly.replace('\t', " "),
+        "mnemonic": instruction.mnemonic.name(),
+        "registers": {
+            "af": af, "bc": bc, "de": de, "hl": hl, "ix": ix, "iy": iy, "sp": sp,
+            "af'": af_, "bc'": bc_, "de'": de_, "hl'": hl_,
+        },
+        "accesses": accesses,
+        "tstates": record.tstates,
+    });
+    if let Some(target) = instruction.target(record.pc) {
+        value["target"] = json!(target);
+    }
+    if let Some(name) = debugger.symbols().describe(record.pc) {
+        value["symbol"] = json!(name);
+    }
+    if let Some((listing, line)) = debugger.source_line(record.pc) {
+        value["source"] = json!({ "path": listing.path(), "line": line });
+    }
+    value
+}
+
+fn binary(record: &Record) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(48 + record.transactions.len() * 6);
+    bytes.extend_from_slice(&record.clock.to_le_bytes());
+    bytes.extend_from_slice(&record.pc.to_le_bytes());
+    bytes.extend_from_slice(&record.physical.to_le_bytes());
+    bytes.push(record.bytes.len() as u8);
+    bytes.extend_from_slice(&record.bytes);
+    for register in &record.registers {
+        bytes.extend_from_slice(&register.to_le_bytes());
+    }
+    bytes.push(record.tstates as u8);
+    bytes.extend_from_slice(&(record.transactions.len() as u16).to_le_bytes());
+    for t in &record.transactions {
+        let write = (t.access != Access::Read) as u8;
+        let io = (t.space == Space::Io) as u8;
+        let dma = (t.origin == Origin::Dma) as u8;
+        bytes.push(write | io << 1 | dma << 2);
+        bytes.extend_from_slice(&t.address.to_le_bytes());
+        bytes.push(t.value);
+    }
+    bytes
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::ram::RAM;
+    use crate::trace::*;
+
+    // Run a program to its halt, tracing it.
+    fn run<W: Write>(mut tracer: Tracer<W>, program: &[u8]) -> Tracer<W> {
+        let mut bus = Bus::new();
+        let mut cpu = CPU::new(&mut bus);
+        let ram = Rc::new(RAM::new(0x0000, 0x10000));
+        ram.write(0x0000, program).unwrap();
+        bus.add(ram);
+        cpu.reset();
+        let debugger = Debugger::new();
+        loop {
+            tracer.trace(&cpu, &bus, &debugger).unwrap();
+            if cpu.mode != Mode::OpCodeFetch {
+                break;
+            }
+            cpu.cycle(&mut bus);
+        }
+        tracer.finish(&cpu, &bus, &debugger).unwrap();
+        tracer
+    }
+
+    const PROGRAM: [u8; 10] = [
+        0x3e, 0x42, //          0x0000  ld a, $42
+        0x32, 0x00, 0x80, //    0x0002  ld ($8000), a
+        0xd3, 0x40, //          0x0005  out ($40), a
+        0x3c, //                0x0007  inc a
+        0x3c, //                0x0008  inc a
+        0x76, //                0x0009  halt
+    ];
+
+    #[test]
+    fn json_records() {
+        let tracer = run(Tracer::new(Format::Json, Vec::new()), &PROGRAM);
+        let lines: Vec<serde_json::Value> = tracer
+            .output()
+            .split(|&b| b == b'\n')
+            .filter(|line| !line.is_empty())
+            .map(|line| serde_json::from_slice(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(lines[1]["pc"], 2);
+        assert_eq!(lines[1]["bytes"], json!([0x32, 0x00, 0x80]));
+        assert_eq!(lines[1]["registers"]["af"].as_u64().unwrap() >> 8, 0x42);
+        assert_eq!(
+            lines[1]["accesses"],
+            json!([{ "kind": "wr", "origin": "cpu", "address": 0x8000, "value": 0x42 }])
+        );
+        assert_eq!(lines[1]["tstates"], 13);
+        assert_eq!(
+            lines[2]["accesses"],
+            json!([{ "kind": "out", "origin": "cpu", "address": 0x4240, "value": 0x42 }])
+        );
+        assert_eq!(lines[1]["mnemonic"], "ld");
+        assert_eq!(lines[5]["disassembly"], "halt");
+    }
+
+    #[test]
+    fn ring_keeps_the_last_records() {
+        let mut tracer = Tracer::new(Format::Binary, Vec::new()).ring(2);
+        tracer.add_filter(Filter::Range(0x0000, 0x0008));
+        let tracer = run(tracer, &PROGRAM);
+        let bytes = tracer.output();
+        assert!(bytes.starts_with(MAGIC));
+        // two single byte inc instructions with no accesses, after the header
+        let record = 8 + 2 + 4 + 1 + 1 + 22 + 1 + 2;
+        assert_eq!(bytes.len(), 12 + record * 2);
+        assert_eq!(&bytes[12 + 8..12 + 10], &[0x07, 0x00]);
+    }
+
+    #[test]
+    fn filters() {
+        let mut symbols = Symbols::new();
+        symbols.insert("start", 0x0100);
+        symbols.insert("next", 0x0180);
+        assert_eq!(Filter::parse("start", &symbols), Ok(Filter::Range(0x0100, 0x017f)));
+        assert_eq!(Filter::parse("next", &symbols), Ok(Filter::Range(0x0180, 0xffff)));
+        assert_eq!(Filter::parse("100-1ff", &symbols), Ok(Filter::Range(0x0100, 0x01ff)));
+        assert_eq!(Filter::parse("bank:7", &symbols), Ok(Filter::Bank(7)));
+        assert!(Filter::Bank(7).matches(0x1000, 0x71000));
+        assert!(Filter::parse("nowhere", &symbols).is_err());
+    }
+}
@@ -1,4 +1,5 @@
-use crate::bus::Bus;
+use crate::bus::{Bus, Transaction};
+use crate::state::{StateError, StateReader, StateWriter};
 
 use enumset::EnumSetType;
 
@@ -18,6 +19,15 @@ pub enum Interrupt {
     ASCI1,
 }
 
+/// A problem a peripheral noticed while servicing the bus, such as a read of uninitialised memory. The CPU reports
+/// faults against the instruction that caused them, and stops if asked to halt.
+#[derive(Debug, PartialEq, Clone)]
+pub struct Fault {
+    pub address: u32,
+    pub message: String,
+    pub halt: bool,
+}
+
 pub trait Peripheral {
     fn reset(&self) {}
     fn cycle(&self, _bus: &Bus) -> Option<Interrupt> {
@@ -26,9 +36,29 @@ pub trait Peripheral {
     fn mem_read(&self, _address: u32, _m1: bool) -> Option<u8> {
         None
     }
+    // Read memory for a debugger or tracer, without any of the side effects of a bus read.
+    fn peek(&self, address: u32) -> Option<u8> {
+        self.mem_read(address, false)
+    }
+    // Told of a memory read answered by this peripheral when the CPU made it, rather than a DMA transfer.
+    fn cpu_read(&self, _address: u32) {}
     fn mem_write(&self, _address: u32, _data: u8) {}
     fn io_read(&self, _address: u16) -> Option<u8> {
         None
     }
     fn io_write(&self, _address: u16, _data: u8) {}
+    fn faults(&self) -> Vec<Fault> {
+        Vec::new()
+    }
+    // Write the peripheral's state into a machine snapshot, and read it back in the same order. Configuration fixed
+    // when the peripheral is created isn't part of its state.
+    fn save_state(&self, _state: &mut StateWriter) {}
+    fn load_state(&self, _state: &mut StateReader) -> Result<(), StateError> {
+        Ok(())
+    }
+}
+
+/// Something shown every memory and I/O access made on the bus, such as a bus log.
+pub trait Tap {
+    fn access(&self, transaction: &Transaction);
 }
@@ -0,0 +1,114 @@
+/*!
+ * Watchpoints
+ *
+ * Watchpoints catch reads and writes as they cross the bus, so accesses by the CPU and by DMA are caught alike. They
+ * cover a range of physical memory, logical memory, or I/O ports, and may be limited to accesses whose value meets a
+ * condition. The bus records a hit for every matching access; the debugger takes them after each instruction.
+ *
+ * A logical range matches a physical access to any address the MMU currently maps into the range, which is the
+ * address the CPU used, or for DMA the address the CPU would use to reach the same byte.
+ */
+
+/// The address space a watchpoint covers.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Space {
+    Physical,
+    Logical,
+    Io,
+}
+
+/// The kinds of access a watchpoint catches. Hits report `Read` or `Write`.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+/// A test on the value read or written.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub enum Condition {
+    Any,
+    Equals(u8),
+    NotEquals(u8),
+    /// The value, masked, equals the second byte.
+    Masked(u8, u8),
+}
+
+impl Condition {
+    pub fn test(&self, value: u8) -> bool {
+        match *self {
+            Condition::Any => true,
+            Condition::Equals(v) => value == v,
+            Condition::NotEquals(v) => value != v,
+            Condition::Masked(mask, v) => value & mask == v,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Watchpoint {
+    pub space: Space,
+    pub start: u32,
+    pub len: u32,
+    pub access: Access,
+    pub condition: Condition,
+}
+
+impl Watchpoint {
+    pub fn new(space: Space, start: u32, len: u32, access: Access) -> Watchpoint {
+        Watchpoint {
+            space,
+            start,
+            len: len.max(1),
+            access,
+            condition: Condition::Any,
+        }
+    }
+
+    /// Limit the watchpoint to accesses whose value meets a condition.
+    pub fn when(self, condition: Condition) -> Watchpoint {
+        Watchpoint { condition, ..self }
+    }
+
+    pub fn contains(&self, address: u32) -> bool {
+        address.wrapping_sub(self.start) < self.len
+    }
+
+    /// Answer whether an access of a kind, which is `Read` or `Write`, with a value, is one this watchpoint catches.
+    pub fn catches(&self, access: Access, value: u8) -> bool {
+        (self.access == access || self.access == Access::ReadWrite) && self.condition.test(value)
+    }
+}
+
+/// An access a watchpoint caught.
+#[derive(Debug, PartialEq, Copy, Clone)]
+pub struct Hit {
+    /// The ID the bus gave the watchpoint.
+    pub id: usize,
+    pub space: Space,
+    pub access: Access,
+    /// The address in the watchpoint's space.
+    pub address: u32,
+    pub value: u8,
+}
+
+#[cfg(test)]
+mod test {
+    use crate::watch::*;
+
+    #[test]
+    fn catches_accesses() {
+        let wp = Watchpoint::new(Space::Physical, 0x8000, 4, Access::Write).when(Condition::Masked(0x0f, 0x05));
+        assert!(wp.contains(0x8003));
+        assert!(!wp.contains(0x8004));
+        assert!(!wp.contains(0x7fff));
+        assert!(wp.catches(Access::Write, 0xa5));
+        assert!(!wp.catches(Access::Write, 0xa6));
+        assert!(!wp.catches(Access::Read, 0xa5));
+
+        let wp = Watchpoint::new(Space::Io, 0x70, 0, Access::ReadWrite);
+        assert_eq!(wp.len, 1);
+        assert!(wp.catches(Access::Read, 0) && wp.catches(Access::Write, 0));
+    }
+}
@@ -721,12 +721,12 @@ mod cpu_test {
 
     fn print_cpu(cpu: &CPU, bus: &mut Bus) {
         let opcodes = [
-            bus.mem_read(cpu.reg(Register::PC) as u32), // assume identity MMU
-            bus.mem_read(cpu.reg(Register::PC) as u32 + 1),
-            bus.mem_read(cpu.reg(Register::PC) as u32 + 2),
-            bus.mem_read(cpu.reg(Register::PC) as u32 + 3),
-            bus.mem_read(cpu.reg(Register::PC) as u32 + 4),
-            bus.mem_read(cpu.reg(Register::PC) as u32 + 5),
+            bus.mem_read(cpu.reg(Register::PC) as u32, false), // assume identity MMU
+            bus.mem_read(cpu.reg(Register::PC) as u32 + 1, false),
+            bus.mem_read(cpu.reg(Register::PC) as u32 + 2, false),
+            bus.mem_read(cpu.reg(Register::PC) as u32 + 3, false),
+            bus.mem_read(cpu.reg(Register::PC) as u32 + 4, false),
+            bus.mem_read(cpu.reg(Register::PC) as u32 + 5, false),
         ];
         let flags = cpu.reg(Register::F);
         println!(
@@ -735,8 +735,8 @@ mod cpu_test {
                 {}{}-{}-{}{}{}       {}",
             cpu.reg(Register::PC),
             opcodes[0],
-            bus.mem_read(0x103),
-            bus.mem_read(0x104),
+            bus.mem_read(0x103, false),
+            bus.mem_read(0x104, false),
             cpu.reg(Register::A),
             cpu.reg(Register::BC),
             cpu.reg(Register::DE),
@@ -781,7 +781,8 @@ mod cpu_test {
                 0x36, 0x76, //                  LD   (hl),0x76 ; HALT
                 0xC3, 0x00, 0x01, //            JP   0x100
             ],
-        );
+        )
+        .unwrap();
         ram.load_file(0x100, "tests/zexdoc.com").expect("Loading ZEXDOC test binary");
 
         bus.add(ram.clone());
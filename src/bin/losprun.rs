@@ -1,3 +1,5 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::rc::Rc;
 
 use clap::{App, Arg};
@@ -5,35 +7,12 @@ use clap::{App, Arg};
 use emulator::asci::*;
 use emulator::bus::Bus;
 use emulator::cpu::{Mode, Register, CPU};
+use emulator::debugger::Debugger;
 use emulator::dma::*;
+use emulator::profile::Profiler;
 use emulator::prt::*;
 use emulator::ram::*;
-
-fn print_cpu(cpu: &mut CPU, bus: &mut Bus) {
-    let mut opcodes: [u8; 4] = [0, 0, 0, 0];
-    cpu.get_current_opcodes(bus, &mut opcodes);
-    let flags = cpu.reg(Register::F);
-    println!(
-        "PC=${:04x}, SP=${:04x} \
-                A=${:02x} BC=${:04x} DE=${:04x} HL=${:04x} IX=${:04x} IY=${:04x} \
-                {}{}-{}-{}{}{}    {}",
-        cpu.reg(Register::PC),
-        cpu.reg(Register::SP),
-        cpu.reg(Register::A),
-        cpu.reg(Register::BC),
-        cpu.reg(Register::DE),
-        cpu.reg(Register::HL),
-        cpu.reg(Register::IX),
-        cpu.reg(Register::IY),
-        if flags & 0b1000_0000 != 0 { 'S' } else { 's' },
-        if flags & 0b0100_0000 != 0 { 'Z' } else { 'z' },
-        if flags & 0b0001_0000 != 0 { 'H' } else { 'h' },
-        if flags & 0b0000_0100 != 0 { 'P' } else { 'p' },
-        if flags & 0b0000_0010 != 0 { 'N' } else { 'n' },
-        if flags & 0b0000_0001 != 0 { 'C' } else { 'c' },
-        emulator::disasm::disasm(&opcodes),
-    );
-}
+use emulator::trace::{Format, Tracer};
 
 fn print_ident(cpu: &mut CPU, bus: &mut Bus) {
     let mut addr = cpu.reg(Register::HL) as u32;
@@ -52,6 +31,20 @@ fn main() -> Result<(), std::io::Error> {
         .version("1.0")
         .about("Run the Losp interpreter")
         .arg(Arg::with_name("BIN").required(true).index(1))
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("FILE")
+                .help("Count where the interpreter spends its time, writing a report by function and address to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile-folded")
+                .long("profile-folded")
+                .value_name("FILE")
+                .help("Count where the interpreter spends its time, writing folded call stacks for flame graphs to FILE")
+                .takes_value(true),
+        )
         .get_matches();
 
     let mut bus = Bus::new();
@@ -83,15 +76,18 @@ fn main() -> Result<(), std::io::Error> {
 
     let mut last_input = '-';
 
+    // keep the last instruction, to show where the interpreter halted
+    let debugger = Debugger::new();
+    let mut tracer = Tracer::new(Format::Text, std::io::stdout()).ring(1);
+    let mut profiler = Profiler::new();
+    let profiling = matches.is_present("profile") || matches.is_present("profile-folded");
+
     loop {
         let pc = cpu.reg(Register::PC);
         if pc == 0x10e {
             last_input = input.next().unwrap_or('\x1a');
             cpu.write_reg(Register::A, last_input as u16);
         }
-        //if pc >= 0x467 && pc <= 0x4a5 {
-        //print_cpu(&mut cpu, &mut bus);
-        //}
         if pc == 0x10f {
             let tok = cpu.reg(Register::A);
             match tok {
@@ -124,10 +120,26 @@ fn main() -> Result<(), std::io::Error> {
         if cpu.mode != Mode::OpCodeFetch {
             break;
         }
+        tracer.trace(&cpu, &bus, &debugger)?;
+        if profiling {
+            profiler.profile(&cpu);
+        }
         cpu.cycle(&mut bus);
     }
     println!("HALT on input {}", last_input);
-    print_cpu(&mut cpu, &mut bus);
+    tracer.finish(&cpu, &bus, &debugger)?;
+
+    profiler.finish(&cpu);
+    if let Some(path) = matches.value_of("profile") {
+        let mut output = BufWriter::new(File::create(path)?);
+        profiler.report(debugger.symbols(), &mut output)?;
+        output.flush()?;
+    }
+    if let Some(path) = matches.value_of("profile-folded") {
+        let mut output = BufWriter::new(File::create(path)?);
+        profiler.folded(debugger.symbols(), &mut output)?;
+        output.flush()?;
+    }
 
     Ok(())
 }
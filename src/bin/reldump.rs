@@ -0,0 +1,178 @@
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+
+use clap::{App, Arg};
+
+use emulator::rel::{self, AddressType, Module, Operator, RelEntry};
+
+// An address with the mark M80 listings give its segment
+fn address(kind: AddressType, value: u16) -> String {
+    match kind {
+        AddressType::Absolute => format!("{:04X} ", value),
+        AddressType::ProgramRelative => format!("{:04X}'", value),
+        AddressType::DataRelative => format!("{:04X}\"", value),
+        AddressType::CommonRelative => format!("{:04X}!", value),
+    }
+}
+
+fn operator(op: Operator) -> &'static str {
+    match op {
+        Operator::StoreByte => "byte",
+        Operator::StoreWord => "word",
+        Operator::HighByte => "high",
+        Operator::LowByte => "low",
+        Operator::Complement => "not",
+        Operator::Negate => "neg",
+        Operator::Subtract => "-",
+        Operator::Add => "+",
+        Operator::Multiply => "*",
+        Operator::Divide => "/",
+        Operator::Modulo => "mod",
+    }
+}
+
+// What a module loaded where, to follow the chains through it
+#[derive(Default)]
+struct Loaded {
+    bytes: HashMap<(AddressType, u16), u8>,
+    relocated: HashMap<(AddressType, u16), AddressType>,
+}
+
+impl Loaded {
+    // The addresses in a chain, from its head to the word holding absolute 0
+    fn chain(&self, kind: AddressType, head: u16) -> Vec<String> {
+        let mut links = Vec::new();
+        let mut link = (kind, head);
+        while links.len() < 0x1000 {
+            links.push(address(link.0, link.1).trim_end().to_string());
+            let byte = |offset: u16| *self.bytes.get(&(link.0, link.1.wrapping_add(offset))).unwrap_or(&0);
+            let next = u16::from_le_bytes([byte(0), byte(1)]);
+            let next_kind = *self.relocated.get(&link).unwrap_or(&AddressType::Absolute);
+            if next_kind == AddressType::Absolute && next == 0 {
+                break;
+            }
+            link = (next_kind, next);
+        }
+        links
+    }
+}
+
+// A line of the dump: where in the module, if anywhere, what, and the details
+fn line<W: Write>(w: &mut W, at: &str, what: &str, detail: &str) -> Result<(), std::io::Error> {
+    writeln!(w, "  {:<5}  {:<9} {}", at, what, detail)
+}
+
+fn dump<W: Write>(w: &mut W, module: &Module, show_bytes: bool) -> Result<(), std::io::Error> {
+    let mut loaded = Loaded::default();
+    let mut location = (AddressType::ProgramRelative, 0u16);
+    for entry in &module.entries {
+        match entry {
+            RelEntry::Absolute(byte) => {
+                loaded.bytes.insert(location, *byte);
+                location.1 = location.1.wrapping_add(1);
+            }
+            RelEntry::Relative(kind, value) => {
+                let [lo, hi] = value.to_le_bytes();
+                loaded.bytes.insert(location, lo);
+                loaded.bytes.insert((location.0, location.1.wrapping_add(1)), hi);
+                loaded.relocated.insert(location, *kind);
+                location.1 = location.1.wrapping_add(2);
+            }
+            RelEntry::SetLocation(kind, value) => location = (*kind, *value),
+            _ => (),
+        }
+    }
+
+    writeln!(w, "module {}", module.name)?;
+    let mut location = (AddressType::ProgramRelative, 0u16);
+    let mut run: Vec<u8> = Vec::new();
+    let mut run_start = location;
+    let mut expression: Vec<String> = Vec::new();
+    for entry in &module.entries {
+        if !run.is_empty() && (!matches!(entry, RelEntry::Absolute(_)) || run.len() == 16) {
+            let hex: Vec<String> = run.iter().map(|byte| format!("{:02X}", byte)).collect();
+            line(w, &address(run_start.0, run_start.1), "bytes", &hex.join(" "))?;
+            run.clear();
+        }
+        let here = address(location.0, location.1);
+        match entry {
+            RelEntry::Absolute(byte) => {
+                if show_bytes {
+                    if run.is_empty() {
+                        run_start = location;
+                    }
+                    run.push(*byte);
+                }
+                location.1 = location.1.wrapping_add(1);
+            }
+            RelEntry::Relative(kind, value) => {
+                line(w, &here, "relocate", &address(*kind, *value))?;
+                location.1 = location.1.wrapping_add(2);
+            }
+            RelEntry::SetLocation(kind, value) => location = (*kind, *value),
+            RelEntry::ProgramName(_) => (),
+            RelEntry::EntrySymbol(name) => line(w, "", "entry", name)?,
+            RelEntry::RequestLibrary(name) => line(w, "", "request", name)?,
+            RelEntry::TextSize(_, size) => line(w, "", "program", &format!("{:04X} bytes", size))?,
+            RelEntry::DataSize(_, size) => line(w, "", "data", &format!("{:04X} bytes", size))?,
+            RelEntry::CommonSize(_, size, name) => line(w, "", "common", &format!("/{}/ {:04X} bytes", name, size))?,
+            RelEntry::SelectCommon(name) => line(w, "", "select", &format!("/{}/", name))?,
+            RelEntry::EntryPoint(kind, value, name) => {
+                line(w, "", "public", &format!("{} = {}", name, address(*kind, *value).trim_end()))?
+            }
+            RelEntry::ChainExternal(kind, head, name) => {
+                if *kind == AddressType::Absolute && *head == 0 {
+                    line(w, "", "external", &format!("{}, unreferenced", name))?;
+                } else {
+                    line(
+                        w,
+                        "",
+                        "external",
+                        &format!("{} at {}", name, loaded.chain(*kind, *head).join(" ")),
+                    )?;
+                }
+            }
+            RelEntry::ChainAddress(kind, head) => {
+                line(w, &here, "forward", &format!("from {}", loaded.chain(*kind, *head).join(" ")))?
+            }
+            RelEntry::ExtPlusOffset(kind, value) => line(w, &here, "offset", address(*kind, *value).trim_end())?,
+            RelEntry::ExternalOperand(name) => expression.push(name.clone()),
+            RelEntry::Operand(kind, value) => expression.push(address(*kind, *value).trim_end().to_string()),
+            RelEntry::Operation(op @ (Operator::StoreByte | Operator::StoreWord)) => {
+                line(w, &here, operator(*op), &expression.join(" "))?;
+                expression.clear();
+            }
+            RelEntry::Operation(op) => expression.push(operator(*op).to_string()),
+            RelEntry::EndModule(kind, value) => {
+                if *kind != AddressType::Absolute || *value != 0 {
+                    line(w, "", "start", address(*kind, *value).trim_end())?;
+                }
+            }
+            RelEntry::EndFile() => (),
+        }
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let matches = App::new("Virtual TRS-20 - REL Dump")
+        .version("1.0")
+        .about("Print the modules in Microsoft REL files: their segments, symbols and fixups")
+        .arg(Arg::with_name("REL").required(true).multiple(true).index(1))
+        .arg(
+            Arg::with_name("bytes")
+                .short("b")
+                .long("bytes")
+                .help("Print the absolute bytes loaded, too"),
+        )
+        .get_matches();
+
+    let mut output = BufWriter::new(std::io::stdout());
+    for path in matches.values_of("REL").into_iter().flatten() {
+        writeln!(output, "; {}", path)?;
+        for module in rel::load(path)? {
+            dump(&mut output, &module, matches.is_present("bytes"))?;
+        }
+    }
+    output.flush()
+}
@@ -1,7 +1,6 @@
-use bitstream_io::{BigEndian, BitReader};
 use std::ffi::OsStr;
 use std::fs::File;
-use std::io::{Read, Write};
+use std::io::{BufWriter, Write};
 use std::os::unix::ffi::OsStrExt;
 use std::os::unix::io::FromRawFd;
 use std::path::Path;
@@ -13,384 +12,50 @@ use std::{io, thread};
 use clap::{App, Arg, OsValues};
 
 use emulator::bus::Bus;
+use emulator::coverage::Coverage;
 use emulator::cpu::{Mode, Register, CPU};
+use emulator::debugger::Debugger;
 use emulator::dma::*;
+use emulator::link::Linker;
+use emulator::listing::Listing;
 use emulator::prt::*;
 use emulator::ram::*;
-
-/*
-  The information in a .REL file (Microsoft format) is in a bit stream:
-- if the first bit is 0 then the next 8 bits will be loaded according to the
-  value of the value of the location counter.  (To illustrate: 0-xxxx-xxxx).
-- if the first bit is 1 then the next two bits will indicate the following:
-     - 00 indicates a "special link item",
-     - 01 indicates program relative; the next 16 bits will be loaded after
-          being offset by the program segment origin
-          (to illustrate: 0-01-xxxx-xxxx-xxxx-xxxx),
-     - 10 indicates data relative; the next 16 bits will be loaded after
-          being offset by the data segment origin
-          (to illustrate: 0-10-xxxx-xxxx-xxxx-xxxx),
-     - 11 indicates common relative; the next 16 bits will be loaded after
-          being offset by the origin of the currently selected common block
-          (to illustrate: 0-11-xxxx-xxxx-xxxx-xxxx).
-The "special link item" consists of:
-- 4 bit CONTROL field which selects one of 16 special link items
-     - optional VALUE field consisting of a 2 bit address type field and a
-       16 bit address field.  The address type field is as follows:
-          - 00 indicates absolute address,
-          - 01 indicates program relative address,
-          - 10 indicates data relative address,
-          - 11 indicates common relative address,
-     - optional NAME field consisting of a 3 bit name byte count followed
-       by the name in 8 bit ascii characters.
-To illustrate, we have the bit stream as follows:
-  CONTROL field followed by a VALUE filed and a NAME field
-          1-00-xxxx-yy-zzzzZZZZzzzzZZZZ-XXX-<ascii characters>,
-  CONTROL field followed by a NAME field only
-          1-00-xxxx-XXX-<ascii characters>,
-  CONTROL field followed by a VALUE field only
-          1-00-xxxx-yy-zzzzZZZZzzzzZZZZ.
-The following special link items are followed by the NAME field only:
-     - 0000 entry symbol; the symbol indicated in the name field is defined
-       in this module, so module should be linked if the current file is
-       being searched,
-     - 0001 select common block; the linker will use the location counter
-       associated with the common block indicated in the name field for
-       subsequent common relative items,
-     - 0010 program name; the name of the relocatable module,
-     - 0011 - not used,
-     - 0100 - not used,
-The following special link items are followed by VALUE and NAME fields:
-     - 0101 define common size; the value field determines the amount of
-       memory to be reserved for the common block described in the name field,
-     - 0110 chain external; the value field contains the head of chain
-       which ends with 0.  Each element of the chain is to be replaced with
-       the value of the external symbol described in the name field,
-     - 0111 define entry point; the value of the symbol in the name field
-       is defined by the value field,
-     - 1000 - not used.
-The following special link items are followed by a VALUE field only:
-     - 1001 external plus offset; the following 2 bytes in the current
-       segment must be offset by the value of the value field after all
-       chains have been processed,
-     - 1010 define data size; the value field contains number of bytes in
-       the data segment of the current module,
-     - 1011 set location counter to the value determined by the value field,
-     - 1100 chain address; the value field contains the head of a chain
-       which ends with 0.  Each element of the chain is to be replaced with
-       the current value of the location counter,
-     - 1101 define program size; the value field contains the number of
-       bytes in the program segment of the current module,
-     - 1110 end of the current module; if the value field contains a value
-       other than 0 then it is to be used as the start address for the
-       program to be linked.  The next item in the file will start at the
-       next byte boundary (the unused bits are "padded" with 0s).
-The following special link item has no VALUE field or NAME field:
-     - 1111 end of the file; follows the end module item of the last module
-       in the file.
- */
-
-#[derive(Debug)]
-enum AddressType {
-    Absolute,
-    ProgramRelative,
-    DataRelative,
-    CommonRelative,
-}
-
-#[derive(Debug)]
-enum Operator {
-    StoreByte,
-    StoreWord,
-    HighByte,
-    LowByte,
-    Complement,
-    Negate,
-    Subtract,
-    Add,
-    Multiply,
-    Divide,
-    Modulo,
-}
-
-#[derive(Debug)]
-enum RelEntry {
-    Absolute(u8),
-    Relative(AddressType, u16),
-    EntrySymbol(String),
-    SelectCommon(String),
-    ProgramName(String),
-    Operand(AddressType, u16),
-    ExternalOperand(String),
-    Operation(Operator),
-    CommonSize(AddressType, u16, String),
-    ChainExternal(AddressType, u16, String),
-    EntryPoint(AddressType, u16, String),
-    ExtPlusOffset(AddressType, u16),
-    DataSize(AddressType, u16),
-    SetLocation(AddressType, u16),
-    ChainAddress(AddressType, u16),
-    TextSize(AddressType, u16),
-    EndModule(AddressType, u16),
-    EndFile(),
-}
-
-fn load_rel_string<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<String, std::io::Error> {
-    let len: u8 = reader.read(3)?;
-    let mut buf = Vec::with_capacity(len as usize);
-    buf.resize(len as usize, 0);
-    reader.read_bytes(&mut buf)?;
-    Ok(String::from_utf8_lossy(&buf).into_owned())
-}
-
-fn load_rel_type<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<AddressType, std::io::Error> {
-    let rel: u8 = reader.read(2)?;
-    match rel {
-        0b00 => Ok(AddressType::Absolute),
-        0b01 => Ok(AddressType::ProgramRelative),
-        0b10 => Ok(AddressType::DataRelative),
-        0b11 => Ok(AddressType::CommonRelative),
-        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "bad relative bits")),
-    }
-}
-
-fn load_rel_word<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<u16, std::io::Error> {
-    Ok(u16::from_be(reader.read(16)?))
-}
-
-fn rel_op(op: Option<&str>) -> Result<Operator, std::io::Error> {
-    match op {
-        Some("\x01") => Ok(Operator::StoreByte),
-        Some("\x02") => Ok(Operator::StoreWord),
-        Some("\x03") => Ok(Operator::HighByte),
-        Some("\x04") => Ok(Operator::LowByte),
-        Some("\x05") => Ok(Operator::Complement),
-        Some("\x06") => Ok(Operator::Negate),
-        Some("\x07") => Ok(Operator::Subtract),
-        Some("\x08") => Ok(Operator::Add),
-        Some("\x09") => Ok(Operator::Multiply),
-        Some("\x0a") => Ok(Operator::Divide),
-        Some("\x0b") => Ok(Operator::Modulo),
-        Some(o) => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            format!("invalid operator {}", o),
-        )),
-        None => Err(std::io::Error::new(
-            std::io::ErrorKind::InvalidData,
-            "missing extension operator",
-        )),
-    }
-}
-
-fn load_rel_entry<R: Read>(reader: &mut BitReader<R, BigEndian>) -> Result<RelEntry, std::io::Error> {
-    if reader.read_bit()? {
-        let rel: u8 = reader.read(2)?;
-        match rel {
-            0b01 => Ok(RelEntry::Relative(AddressType::ProgramRelative, load_rel_word(reader)?)),
-            0b10 => Ok(RelEntry::Relative(AddressType::DataRelative, load_rel_word(reader)?)),
-            0b11 => Ok(RelEntry::Relative(AddressType::CommonRelative, load_rel_word(reader)?)),
-            _ => {
-                let control: u8 = reader.read(4)?;
-                match control {
-                    0b0000 => Ok(RelEntry::EntrySymbol(load_rel_string(reader)?)),
-                    0b0001 => Ok(RelEntry::SelectCommon(load_rel_string(reader)?)),
-                    0b0010 => Ok(RelEntry::ProgramName(load_rel_string(reader)?)),
-                    0b0100 => {
-                        let s = load_rel_string(reader)?;
-                        match s.get(..1) {
-                            Some("A") => Ok(RelEntry::Operation(rel_op(s.get(1..))?)),
-                            Some("B") => Ok(RelEntry::ExternalOperand(s.get(1..).unwrap_or("").to_string())),
-                            Some("C") => match s.as_bytes() {
-                                [_, 0, vlo, vhi] => {
-                                    Ok(RelEntry::Operand(AddressType::Absolute, (*vhi as u16) << 8 | (*vlo as u16)))
-                                }
-                                [_, 1, vlo, vhi] => Ok(RelEntry::Operand(
-                                    AddressType::ProgramRelative,
-                                    (*vhi as u16) << 8 | (*vlo as u16),
-                                )),
-                                _ => Err(std::io::Error::new(
-                                    std::io::ErrorKind::InvalidData,
-                                    format!("invalid operand extension {}", s),
-                                )),
-                            },
-                            Some(t) => Err(std::io::Error::new(
-                                std::io::ErrorKind::InvalidData,
-                                format!("unknown extension {}", t),
-                            )),
-                            None => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid null extension")),
-                        }
-                    }
-                    0b0101 => Ok(RelEntry::CommonSize(
-                        load_rel_type(reader)?,
-                        load_rel_word(reader)?,
-                        load_rel_string(reader)?,
-                    )),
-                    0b0110 => Ok(RelEntry::ChainExternal(
-                        load_rel_type(reader)?,
-                        load_rel_word(reader)?,
-                        load_rel_string(reader)?,
-                    )),
-                    0b0111 => Ok(RelEntry::EntryPoint(
-                        load_rel_type(reader)?,
-                        load_rel_word(reader)?,
-                        load_rel_string(reader)?,
-                    )),
-                    0b1001 => Ok(RelEntry::ExtPlusOffset(load_rel_type(reader)?, load_rel_word(reader)?)),
-                    0b1010 => Ok(RelEntry::DataSize(load_rel_type(reader)?, load_rel_word(reader)?)),
-                    0b1011 => Ok(RelEntry::SetLocation(load_rel_type(reader)?, load_rel_word(reader)?)),
-                    0b1100 => Ok(RelEntry::ChainAddress(load_rel_type(reader)?, load_rel_word(reader)?)),
-                    0b1101 => Ok(RelEntry::TextSize(load_rel_type(reader)?, load_rel_word(reader)?)),
-                    0b1110 => {
-                        let val = Ok(RelEntry::EndModule(load_rel_type(reader)?, load_rel_word(reader)?));
-                        reader.byte_align();
-                        val
-                    }
-                    0b1111 => Ok(RelEntry::EndFile()),
-                    _ => Err(std::io::Error::new(
-                        std::io::ErrorKind::InvalidData,
-                        format!("bad control byte {:04b}", control),
-                    )),
-                }
-            }
-        }
-    } else {
-        Ok(RelEntry::Absolute(reader.read(8)?))
-    }
-}
+use emulator::rel;
+use emulator::symbols::Symbols;
+use emulator::trace::{Filter, Format, Tracer};
 
 fn io_err(s: &str) -> std::io::Error {
     std::io::Error::new(std::io::ErrorKind::InvalidData, s)
 }
 
 /**
- * Loads a REL file into RAM, relocating code to base <addr>.
- *
- * Does not support a data segment.
+ * Links a REL file at base <addr>, loads it into RAM, and adds its entry points to <symbols>.
  */
-fn load_rel(ram: &RAM, addr: u32, src: &str) -> Result<(), std::io::Error> {
-    let f = File::open(src)?;
-    let mut reader = BitReader::endian(f, BigEndian);
-
-    let mut location = addr;
-
-    let mut operands = Vec::new();
-    let mut stores = Vec::new();
-
-    /*
-     * Extension("C\u{1}\u{0}\u{0}")        base+offset operand: base=addr, offset=0
-     * Extension("C\u{0}\u{0}\u{1}")        base+offset operand: base=0, offset=256
-     * Extension("A\n")                     operation: DIV
-     * Extension("C\u{0}\u{1}\u{0}")        base+offset operand: base=0, offset=1
-     * Extension("A\u{7}")                  operation: MINUS
-     * Extension("A\u{1}")                  operation: BYTE
-     */
-
-    loop {
-        let x = load_rel_entry(&mut reader)?;
-        match x {
-            RelEntry::ProgramName(name) => println!("Loading {} from {}...", name, src),
-            RelEntry::EntrySymbol(name) => println!("  Entry symbol is {}", name),
-            RelEntry::DataSize(_, 0) => (), // data is unsupported: a zero size is okay, anything else is not
-            RelEntry::TextSize(_, sz) => println!("  Text size is {}", sz),
-            RelEntry::Absolute(b) => {
-                ram.write(location, &[b]);
-                location = location + 1;
-            }
-            RelEntry::Relative(AddressType::ProgramRelative, w) => {
-                let val = w + addr as u16;
-                ram.write(location, &val.to_le_bytes());
-                location = location + 2;
-            }
-            RelEntry::SetLocation(AddressType::ProgramRelative, l) => {
-                location = l as u32 + addr;
-            }
-            RelEntry::EntryPoint(_, _, _) => (),
-            RelEntry::EndModule(_, _) => (),
-            RelEntry::EndFile() => break,
-            RelEntry::Operand(AddressType::Absolute, v) => operands.push(v),
-            RelEntry::Operand(AddressType::ProgramRelative, v) => operands.push(v + addr as u16),
-            RelEntry::Operation(Operator::StoreByte) => {
-                let o = operands
-                    .pop()
-                    .ok_or(io_err("Attempt to store byte with no operands on the stack"))?;
-                stores.push((location, o as u8));
-            }
-            //RelEntry::Operation(Operator::StoreWord) => {
-            //RelEntry::Operation(Operator::HighByte) => {
-            //RelEntry::Operation(Operator::LowByte) => {
-            //RelEntry::Operation(Operator::Complement) => {
-            //RelEntry::Operation(Operator::Negate) => {
-            RelEntry::Operation(Operator::Subtract) => {
-                let o2 = operands
-                    .pop()
-                    .ok_or(io_err("Attempt to subtract with no operands on the stack"))?;
-                let o1 = operands
-                    .pop()
-                    .ok_or(io_err("Attempt to subtract with only one operand on the stack"))?;
-                operands.push(o1 - o2);
-            }
-            //RelEntry::Operation(Operator::Add) => {
-            //RelEntry::Operation(Operator::Multiply) => {
-            RelEntry::Operation(Operator::Divide) => {
-                let o2 = operands
-                    .pop()
-                    .ok_or(io_err("Attempt to divide with no operands on the stack"))?;
-                let o1 = operands
-                    .pop()
-                    .ok_or(io_err("Attempt to divide with only one operand on the stack"))?;
-                operands.push(o1 / o2);
-            }
-            //RelEntry::Operation(Operator::Modulo) => {
-            _ => {
-                return Err(std::io::Error::new(
-                    std::io::ErrorKind::InvalidData,
-                    format!("Unsupported item {:?}", x),
-                ))
-            }
-        }
+fn load_rel(ram: &RAM, addr: u16, src: &str, symbols: &mut Symbols) -> Result<(), std::io::Error> {
+    let mut linker = Linker::new(addr);
+    for module in rel::load(src)? {
+        println!("Loading {} from {}...", module.name, src);
+        linker.link(&module).map_err(|e| io_err(&e))?;
     }
+    linker.finish().map_err(|e| io_err(&e))?;
 
-    // byte stores must happen after other decoding - the bitstream doesn't expect location to
-    // increment in an extension, so the extension will be followed by an absolute byte write
-    for (address, byte) in stores {
-        ram.write(address, &[byte]);
+    if let Some((low, high)) = linker.extent() {
+        ram.write(low as u32, &linker.memory()[low as usize..=high as usize])?;
+    }
+    for (name, address) in linker.symbols().iter() {
+        symbols.insert(name, address);
     }
 
     Ok(())
 }
 
-#[allow(dead_code)]
-fn print_cpu(cpu: &mut CPU, bus: &mut Bus) {
-    let mut opcodes: [u8; 4] = [0, 0, 0, 0];
-    cpu.get_current_opcodes(bus, &mut opcodes);
-    let flags = cpu.reg(Register::F);
-    println!(
-        "PC=${:04x}, SP=${:04x} \
-                A=${:02x} BC=${:04x} DE=${:04x} HL=${:04x} IX=${:04x} IY=${:04x} \
-                {}{}-{}-{}{}{}    {}",
-        cpu.reg(Register::PC),
-        cpu.reg(Register::SP),
-        cpu.reg(Register::A),
-        cpu.reg(Register::BC),
-        cpu.reg(Register::DE),
-        cpu.reg(Register::HL),
-        cpu.reg(Register::IX),
-        cpu.reg(Register::IY),
-        if flags & 0b1000_0000 != 0 { 'S' } else { 's' },
-        if flags & 0b0100_0000 != 0 { 'Z' } else { 'z' },
-        if flags & 0b0001_0000 != 0 { 'H' } else { 'h' },
-        if flags & 0b0000_0100 != 0 { 'P' } else { 'p' },
-        if flags & 0b0000_0010 != 0 { 'N' } else { 'n' },
-        if flags & 0b0000_0001 != 0 { 'C' } else { 'c' },
-        emulator::disasm::disasm(&opcodes),
-    );
-}
-
 #[allow(dead_code)]
 fn dump_mem(ram: &RAM, addr: u32) {
     for row in 0..15 {
         let mut data: [u8; 16] = [0; 16];
-        ram.read(addr + (row * 16), &mut data);
+        if ram.read(addr + (row * 16), &mut data).is_err() {
+            break;
+        }
         print!("{:05x}  ", addr + (row * 16));
         for byte in 0..8 {
             print!("{:02x} ", data[byte]);
@@ -499,7 +164,7 @@ fn write_files(ram: &RAM, files: OsValues) -> Result<(), std::io::Error> {
                     if size > e * 32768 + 2048 * 14 { (block + 14) as u8 } else { 0 },
                     if size > e * 32768 + 2048 * 15 { (block + 15) as u8 } else { 0 },
                 ];
-                ram.write(0x20000 + (extent * 32) as u32, &data);
+                ram.write(0x20000 + (extent * 32) as u32, &data)?;
                 extent = extent + 1;
             }
 
@@ -544,6 +209,46 @@ fn main() -> Result<(), std::io::Error> {
                 .help("Add a file to the RAM disk")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("Trace each instruction to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-filter")
+                .long("trace-filter")
+                .value_name("START-END|bank:N|SYMBOL")
+                .help("Trace only instructions in a hex address range, a 64K physical bank, or a symbol's code")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("listing")
+                .long("listing")
+                .value_name("FILE")
+                .help("Load an assembler listing, for its labels and to map coverage to source lines")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .value_name("FILE")
+                .help("Record which instructions run, writing an lcov tracefile over the loaded listings to FILE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coverage-report")
+                .long("coverage-report")
+                .value_name("FILE")
+                .help("Record which instructions run, writing each with its symbol and branches taken to FILE")
+                .takes_value(true),
+        )
         .get_matches();
 
     let mut bus = Bus::new();
@@ -551,8 +256,13 @@ fn main() -> Result<(), std::io::Error> {
     let ram = Rc::new(RAM::new(0x00000, 0x80000));
 
     // load ZSDOS
-    load_rel(&ram, 0xe000, "zcpr.rel")?;
-    load_rel(&ram, 0xe800, "zsdos.rel")?;
+    let mut debugger = Debugger::new();
+    load_rel(&ram, 0xe000, "zcpr.rel", debugger.symbols_mut())?;
+    load_rel(&ram, 0xe800, "zsdos.rel", debugger.symbols_mut())?;
+    for path in matches.values_of("listing").into_iter().flatten() {
+        debugger.add_listing(Listing::load(path)?);
+        debugger.symbols_mut().load(path)?;
+    }
 
     // Fill in a BIOS
     ram.write(
@@ -576,7 +286,7 @@ fn main() -> Result<(), std::io::Error> {
             0xc9, 0, 0, // list status: 0xf62d,
             0xc9, 0, 0, // sector translate: 0xf630
         ],
-    );
+    )?;
 
     // disk parameters at 0xf633
     ram.write(
@@ -585,11 +295,11 @@ fn main() -> Result<(), std::io::Error> {
             0, 0, 0, 0, 0, 0, 0, 0, 0x83, 0xf8, 0x43, 0xf6, 0, 0, 0x30, 0xf9, // DPH
             16, 0, 4, 15, 1, 191, 0, 127, 0, 0b11000000, 0, 0, 0, 0, 0, // DPB
         ],
-    );
+    )?;
 
     // erase the RAM disk
     for base in 0x20000..0x7FFFF {
-        ram.write(base, &[0xe5]);
+        ram.write(base, &[0xe5])?;
     }
 
     // write files into the RAM disk
@@ -611,7 +321,7 @@ fn main() -> Result<(), std::io::Error> {
         Some(com) => {
             ram.load_file(0x100, com)?;
             cpu.write_reg(Register::PC, 0x100);
-            ram.write(0, &[0x76, 0x03, 0xf6, 0x00, 0x00, 0xc3, 0x06, 0xe8]);
+            ram.write(0, &[0x76, 0x03, 0xf6, 0x00, 0x00, 0xc3, 0x06, 0xe8])?;
         }
         None => cpu.write_reg(Register::PC, 0xf600),
     }
@@ -627,6 +337,22 @@ fn main() -> Result<(), std::io::Error> {
     let mut sector = 0;
     let mut dma = 0;
 
+    let mut tracer = match matches.value_of("trace") {
+        Some(path) => {
+            let mut tracer = Tracer::new(Format::Text, BufWriter::new(File::create(path)?));
+            for spec in matches.values_of("trace-filter").into_iter().flatten() {
+                tracer.add_filter(Filter::parse(spec, debugger.symbols()).map_err(|e| io_err(&e))?);
+            }
+            Some(tracer)
+        }
+        None => None,
+    };
+    let mut coverage = if matches.is_present("coverage") || matches.is_present("coverage-report") {
+        Some(Coverage::new())
+    } else {
+        None
+    };
+
     loop {
         let pc = cpu.reg(Register::PC);
         match pc {
@@ -637,7 +363,7 @@ fn main() -> Result<(), std::io::Error> {
             }
             0xf603 => {
                 // warm boot
-                ram.write(0, &[0xc3, 0x03, 0xf6, 0x00, 0x00, 0xc3, 0x06, 0xe8]);
+                ram.write(0, &[0xc3, 0x03, 0xf6, 0x00, 0x00, 0xc3, 0x06, 0xe8])?;
                 cpu.write_reg(Register::C, 0);
                 cpu.write_reg(Register::PC, 0xe000);
             }
@@ -647,7 +373,7 @@ fn main() -> Result<(), std::io::Error> {
                     input = input_ch.try_recv().ok();
                 }
                 cpu.write_reg(Register::A, if input.is_none() { 0x00 } else { 0xff });
-                ram.write(0xf606, &[0xc9]);
+                ram.write(0xf606, &[0xc9])?;
             }
             0xf609 => {
                 // console in
@@ -656,45 +382,45 @@ fn main() -> Result<(), std::io::Error> {
                 }
                 cpu.write_reg(Register::A, input.unwrap_or('\0') as u16);
                 input = None;
-                ram.write(0xf609, &[0xc9]);
+                ram.write(0xf609, &[0xc9])?;
             }
             0xf60c => {
                 // console out
                 write!(stdout, "{}", (cpu.reg(Register::C) as u8 & 0x7f) as char)?;
-                ram.write(0xf60c, &[0xc9]);
+                ram.write(0xf60c, &[0xc9])?;
             }
             0xf60f => {} // list out
             0xf612 => {} // punch out
             0xf615 => {
                 // reader in
                 cpu.write_reg(Register::A, 0x1a);
-                ram.write(0xf615, &[0xc9]);
+                ram.write(0xf615, &[0xc9])?;
             }
             0xf618 => {
                 // home disk
                 track = 0;
-                ram.write(0xf618, &[0xc9]);
+                ram.write(0xf618, &[0xc9])?;
             }
             0xf61b => {
                 // select disk
                 let disk = cpu.reg(Register::C);
                 cpu.write_reg(Register::HL, if disk == 0 { 0xf633 } else { 0 });
-                ram.write(0xf61b, &[0xc9]);
+                ram.write(0xf61b, &[0xc9])?;
             }
             0xf61e => {
                 // select track
                 track = cpu.reg(Register::BC);
-                ram.write(0xf61e, &[0xc9]);
+                ram.write(0xf61e, &[0xc9])?;
             }
             0xf621 => {
                 // select sector
                 sector = cpu.reg(Register::BC);
-                ram.write(0xf621, &[0xc9]);
+                ram.write(0xf621, &[0xc9])?;
             }
             0xf624 => {
                 // set dma address
                 dma = cpu.reg(Register::BC);
-                ram.write(0xf624, &[0xc9]);
+                ram.write(0xf624, &[0xc9])?;
             }
             0xf627 => {
                 // read 128 bytes
@@ -702,41 +428,58 @@ fn main() -> Result<(), std::io::Error> {
                 println!("DISK READ: track {}, sector {}, offset={:05x}", track, sector, offset);
                 let mut buf = Vec::with_capacity(128);
                 buf.resize(128, 0);
-                ram.read(offset, &mut buf);
-                ram.write(dma as u32, &buf);
-                cpu.write_reg(Register::A, 0);
-                ram.write(0xf627, &[0xc9]);
+                let result = ram.read(offset, &mut buf).and_then(|_| ram.write(dma as u32, &buf));
+                cpu.write_reg(Register::A, if result.is_ok() { 0 } else { 1 });
+                ram.write(0xf627, &[0xc9])?;
             }
             0xf62a => {
                 // write 128 bytes
                 let offset = ((track as u32) * 16 + (sector as u32)) * 128 + 0x20000;
                 let mut buf = Vec::with_capacity(128);
                 buf.resize(128, 0);
-                ram.read(dma as u32, &mut buf);
-                ram.write(offset, &buf);
-                cpu.write_reg(Register::A, 0);
-                ram.write(0xf62a, &[0xc9]);
+                let result = ram.read(dma as u32, &mut buf).and_then(|_| ram.write(offset, &buf));
+                cpu.write_reg(Register::A, if result.is_ok() { 0 } else { 1 });
+                ram.write(0xf62a, &[0xc9])?;
             }
             0xf62d => {
                 // list status
                 cpu.write_reg(Register::A, 0);
-                ram.write(0xf62d, &[0xc9]);
+                ram.write(0xf62d, &[0xc9])?;
             }
             0xf630 => {
                 // sector translate
                 cpu.write_reg(Register::HL, cpu.reg(Register::BC));
-                ram.write(0xf630, &[0xc9]);
+                ram.write(0xf630, &[0xc9])?;
             }
             _ => (),
         }
-        if (pc >= 0x100 && pc <= 0xb00) || pc >= 0xe000 || pc == 0x0005 {
-            //print_cpu(&mut cpu, &mut bus);
+        if let Some(tracer) = &mut tracer {
+            tracer.trace(&cpu, &bus, &debugger)?;
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.record(&cpu);
         }
         if cpu.mode != Mode::OpCodeFetch {
             break;
         }
         cpu.cycle(&mut bus);
     }
+    if let Some(tracer) = &mut tracer {
+        tracer.finish(&cpu, &bus, &debugger)?;
+    }
+    if let Some(coverage) = &mut coverage {
+        coverage.finish(&cpu);
+        if let Some(path) = matches.value_of("coverage") {
+            let mut output = BufWriter::new(File::create(path)?);
+            coverage.lcov(debugger.symbols(), debugger.listings(), &mut output)?;
+            output.flush()?;
+        }
+        if let Some(path) = matches.value_of("coverage-report") {
+            let mut output = BufWriter::new(File::create(path)?);
+            coverage.report(debugger.symbols(), &mut output)?;
+            output.flush()?;
+        }
+    }
     //dump_mem(&ram, 0x920);
     //dump_mem(&ram, 0xe3e0);
 
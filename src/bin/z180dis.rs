@@ -0,0 +1,128 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use clap::{App, Arg};
+
+use emulator::coverage::Coverage;
+use emulator::disasm::Syntax;
+use emulator::image::Image;
+use emulator::symbols::{parse_hex, Symbols};
+
+fn io_err(s: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, s)
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let matches = App::new("Virtual TRS-20 - Z180 Disassembler")
+        .version("1.0")
+        .about("Disassemble a ROM or .COM file to source that assembles again")
+        .arg(Arg::with_name("IMAGE").required(true).index(1))
+        .arg(
+            Arg::with_name("origin")
+                .short("o")
+                .long("origin")
+                .value_name("ADDR")
+                .help("The hex address the image loads at, by default $0100 for a .COM file and $0000 otherwise")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("entry")
+                .short("e")
+                .long("entry")
+                .value_name("ADDR|SYMBOL")
+                .help("Follow the code from a hex address or symbol, by default the origin")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .short("s")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Load symbols from a symbol file, map or listing, to name addresses with")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .value_name("FILE")
+                .help("Take the instructions a coverage report from --coverage-report lists as run as code")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("syntax")
+                .long("syntax")
+                .value_name("SYNTAX")
+                .help("Write instructions in Zilog or Hitachi syntax")
+                .possible_values(&["zilog", "hitachi"])
+                .default_value("zilog")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .long("output")
+                .value_name("FILE")
+                .help("Write the source to FILE rather than to standard output")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let path = Path::new(matches.value_of("IMAGE").unwrap());
+    let is_com = path
+        .extension()
+        .is_some_and(|extension| extension.eq_ignore_ascii_case("com"));
+    let origin = match matches.value_of("origin") {
+        Some(origin) => parse_hex(origin).ok_or_else(|| io_err(&format!("invalid origin {}", origin)))?,
+        None if is_com => 0x0100,
+        None => 0x0000,
+    };
+
+    let mut symbols = Symbols::new();
+    for path in matches.values_of("symbols").into_iter().flatten() {
+        symbols.load(path)?;
+    }
+
+    let mut image = Image::new(origin, std::fs::read(path)?).map_err(|e| io_err(&e))?;
+    let mut entries = Vec::new();
+    for entry in matches.values_of("entry").into_iter().flatten() {
+        let address = symbols
+            .lookup(entry)
+            .or_else(|| parse_hex(entry))
+            .ok_or_else(|| io_err(&format!("{} is neither a hex address nor a symbol", entry)))?;
+        entries.push(address);
+    }
+    if entries.is_empty() {
+        entries.push(origin);
+    }
+    for &entry in &entries {
+        image.enter(entry);
+    }
+    if let Some(coverage) = matches.value_of("coverage") {
+        let coverage = Coverage::from_report(&std::fs::read_to_string(coverage)?).map_err(|e| io_err(&e))?;
+        for address in coverage.addresses() {
+            image.trace(address);
+        }
+    }
+
+    let syntax = Syntax::from_name(matches.value_of("syntax").unwrap()).unwrap();
+    let mut output: Box<dyn Write> = match matches.value_of("output") {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(BufWriter::new(std::io::stdout())),
+    };
+    let entries: Vec<String> = entries.iter().map(|entry| syntax.hex(*entry as u32, 4)).collect();
+    writeln!(output, "; disassembled from {}", path.display())?;
+    writeln!(
+        output,
+        "; {} bytes at {}, entered at {}",
+        image.len(),
+        syntax.hex(origin as u32, 4),
+        entries.join(", ")
+    )?;
+    writeln!(output)?;
+    image.source(syntax, &symbols, &mut output)?;
+    output.flush()
+}
@@ -0,0 +1,115 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+use clap::{App, Arg};
+
+use emulator::link::Linker;
+use emulator::rel;
+use emulator::symbols::parse_hex;
+
+fn io_err(s: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, s)
+}
+
+fn main() -> Result<(), std::io::Error> {
+    let matches = App::new("Virtual TRS-20 - REL Linker")
+        .version("1.0")
+        .about("Link Microsoft REL files, as M80 writes them, into a binary image and symbol map")
+        .arg(
+            Arg::with_name("REL")
+                .help("The REL files to link, in order")
+                .required(true)
+                .multiple(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name("library")
+                .short("l")
+                .long("library")
+                .value_name("FILE")
+                .help("Search a REL library for modules defining undefined symbols")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("origin")
+                .short("p")
+                .long("origin")
+                .value_name("ADDR")
+                .help("The hex address to link program segments from")
+                .default_value("0100")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("data")
+                .short("d")
+                .long("data")
+                .value_name("ADDR")
+                .help("The hex address to link data segments and common blocks from, rather than after each module")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("output")
+                .short("o")
+                .long("output")
+                .value_name("FILE")
+                .help("Write memory from the origin to the last byte linked to FILE, as a .COM file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map")
+                .short("m")
+                .long("map")
+                .value_name("FILE")
+                .help("Write where each module went and the public symbols to FILE")
+                .takes_value(true),
+        )
+        .get_matches();
+
+    let hex = |name: &str| -> Result<Option<u16>, std::io::Error> {
+        match matches.value_of(name) {
+            Some(value) => match parse_hex(value) {
+                Some(value) => Ok(Some(value)),
+                None => Err(io_err(&format!("invalid {} address {}", name, value))),
+            },
+            None => Ok(None),
+        }
+    };
+    let origin = hex("origin")?.unwrap();
+
+    let mut linker = Linker::new(origin);
+    if let Some(data) = hex("data")? {
+        linker.data_origin(data);
+    }
+    for path in matches.values_of("REL").into_iter().flatten() {
+        for module in rel::load(path)? {
+            linker.link(&module).map_err(|e| io_err(&format!("{}: {}", path, e)))?;
+        }
+    }
+    for path in matches.values_of("library").into_iter().flatten() {
+        let library = rel::load(path)?;
+        linker.search(&library).map_err(|e| io_err(&format!("{}: {}", path, e)))?;
+    }
+    for request in linker.requests() {
+        eprintln!(
+            "warning: library {} was requested but only those given with --library are searched",
+            request
+        );
+    }
+    linker.finish().map_err(|e| io_err(&e))?;
+
+    if let Some(path) = matches.value_of("map") {
+        let mut map = BufWriter::new(File::create(path)?);
+        linker.write_map(&mut map)?;
+        map.flush()?;
+    }
+    if let Some(path) = matches.value_of("output") {
+        let image = match linker.extent() {
+            Some((_, high)) if high >= origin => &linker.memory()[origin as usize..=high as usize],
+            _ => &[],
+        };
+        std::fs::write(path, image)?;
+    }
+    Ok(())
+}
@@ -1,71 +1,68 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
 use std::rc::Rc;
-use std::{thread, time};
+use std::sync::atomic::Ordering;
 
 use clap::{App, Arg};
 
-use emulator::asci::*;
-use emulator::bus::Bus;
-use emulator::cpu::{Mode, Register, CPU};
-use emulator::dma::*;
-use emulator::prt::*;
-use emulator::ram::*;
-use emulator::rom::*;
-use emulator::sdcard::*;
-
-fn print_cpu(cpu: &mut CPU, bus: &mut Bus) {
-    let mut opcodes: [u8; 4] = [0, 0, 0, 0];
-    cpu.get_current_opcodes(bus, &mut opcodes);
-    let flags = cpu.reg(Register::F);
-    println!(
-        "PC=${:04x}, SP=${:04x} \
-                A=${:02x} BC=${:04x} DE=${:04x} HL=${:04x} IX=${:04x} IY=${:04x} \
-                {}{}-{}-{}{}{}    {}",
-        cpu.reg(Register::PC),
-        cpu.reg(Register::SP),
-        cpu.reg(Register::A),
-        cpu.reg(Register::BC),
-        cpu.reg(Register::DE),
-        cpu.reg(Register::HL),
-        cpu.reg(Register::IX),
-        cpu.reg(Register::IY),
-        if flags & 0b1000_0000 != 0 { 'S' } else { 's' },
-        if flags & 0b0100_0000 != 0 { 'Z' } else { 'z' },
-        if flags & 0b0001_0000 != 0 { 'H' } else { 'h' },
-        if flags & 0b0000_0100 != 0 { 'P' } else { 'p' },
-        if flags & 0b0000_0010 != 0 { 'N' } else { 'n' },
-        if flags & 0b0000_0001 != 0 { 'C' } else { 'c' },
-        emulator::disasm::disasm(&opcodes),
-    );
+use emulator::board::{Board, Config};
+use emulator::buslog::{self, BusLog};
+use emulator::coverage::Coverage;
+use emulator::cpu::{Mode, Register};
+use emulator::dap::DapServer;
+use emulator::debugger::{Breakpoint, Debugger};
+use emulator::expr::{Expr, Template};
+use emulator::gdb::GdbStub;
+use emulator::listing::Listing;
+use emulator::profile::Profiler;
+use emulator::ram::UninitPolicy;
+use emulator::rom::Chip;
+use emulator::rtc::TimeSource;
+use emulator::symbols::Symbols;
+use emulator::trace::{Filter, Format, Tracer};
+
+mod monitor;
+
+// The CP/M BIOS entry points worth logging, as tracepoints
+const BIOS_CALLS: [(u16, &str); 10] = [
+    (0xf600, "BIOS_REBOOT"),
+    (0xf603, "BIOS_WBOOT"),
+    (0xf618, "BIOS_HOME"),
+    (0xf61b, "BIOS_SELDSK({c})"),
+    (0xf61e, "BIOS_SETTRK({bc})"),
+    (0xf621, "BIOS_SETSEC({bc})"),
+    (0xf624, "BIOS_SETDMA({bc:4x})"),
+    (0xf627, "BIOS_READ"),
+    (0xf62a, "BIOS_WRITE"),
+    (0xf630, "BIOS_SECTRN({bc}, {de})"),
+];
+
+// Parse an address given in hex or as a symbol.
+fn parse_address(address: &str, symbols: &Symbols) -> Result<u16, String> {
+    u16::from_str_radix(address.trim_start_matches('$').trim_start_matches("0x"), 16)
+        .ok()
+        .or_else(|| symbols.lookup(address))
+        .ok_or_else(|| format!("invalid address {}", address))
 }
 
-fn print_bios_call(cpu: &mut CPU, _bus: &mut Bus, pc: u16) {
-    match pc {
-        0xf600 => println!("BIOS_REBOOT"),
-        0xf603 => println!("BIOS_WBOOT"),
-        0xf606 => {} // console status, who cares
-        0xf609 => {} // console in
-        0xf60c => {} // console out
-        0xf60f => {} // list out
-        0xf612 => {} // punch out
-        0xf615 => {} // reader out
-        0xf618 => println!("BIOS_HOME"),
-        0xf61b => println!("BIOS_SELDSK({})", cpu.reg(Register::C)),
-        0xf61e => println!("BIOS_SETTRK({})", cpu.reg(Register::BC)),
-        0xf621 => println!("BIOS_SETSEC({})", cpu.reg(Register::BC)),
-        0xf624 => println!("BIOS_SETDMA({:04x})", cpu.reg(Register::BC)),
-        0xf627 => println!("BIOS_READ"),
-        0xf62a => println!("BIOS_WRITE"),
-        0xf62d => {} // list status
-        0xf630 => println!("BIOS_SECTRN({}, {})", cpu.reg(Register::BC), cpu.reg(Register::DE)),
-        _ => {}
-    }
+// Parse a tracepoint given as ADDR[ if COND]:MESSAGE, with a hex address or a symbol.
+fn parse_tracepoint(spec: &str, symbols: &Symbols) -> Result<(u16, Breakpoint), String> {
+    let (point, message) = spec.split_once(':').ok_or_else(|| format!("{} has no message", spec))?;
+    let (address, condition) = match point.split_once(" if ") {
+        Some((address, condition)) => (address, Some(Expr::parse_with(condition, symbols)?)),
+        None => (point, None),
+    };
+    let address = parse_address(address.trim(), symbols)?;
+    let mut breakpoint = Breakpoint::trace(Template::parse_with(message, symbols)?);
+    breakpoint.condition = condition;
+    Ok((address, breakpoint))
 }
 
 fn main() -> Result<(), std::io::Error> {
     let matches = App::new("Virtual TRS-20")
         .version("1.0")
         .about("Emulate the TRS-20 SBC")
-        .arg(Arg::with_name("ROM").required(true).index(1))
+        .arg(Arg::with_name("ROM").required_unless("dap").index(1))
         .arg(
             Arg::with_name("tty")
                 .short("t")
@@ -74,80 +71,411 @@ fn main() -> Result<(), std::io::Error> {
                 .help("Tie ASCI0 to a TTY device")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("flash")
+                .long("flash")
+                .value_name("CHIP")
+                .help("Flash part to model for the ROM")
+                .possible_values(&["sst39sf010", "sst39sf020", "sst39sf040", "at29c010", "at29c020", "at29c040"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("nvram")
+                .long("nvram")
+                .value_name("START:SIZE:FILE")
+                .help("Back a RAM region with a file, addresses in hex")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("seed")
+                .long("ram-seed")
+                .value_name("SEED")
+                .help("Fill RAM with seeded random contents at power on")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("uninit")
+                .long("uninit")
+                .value_name("ACTION")
+                .help("Warn or break when the CPU reads RAM that was never written")
+                .possible_values(&["warn", "break"])
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rtc")
+                .long("rtc")
+                .value_name("SOURCE")
+                .help("Add a DS1302 RTC at port $70 reading host, fixed:SECONDS or emulated[:SECONDS] time")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Load symbols from a .sym, .map or listing file, to name addresses in traces and the monitor")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("listing")
+                .long("listing")
+                .value_name("FILE")
+                .help("Load an assembler listing, to show source lines in traces and step by line in the monitor")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tracepoint")
+                .long("tracepoint")
+                .value_name("ADDR[ if COND]:MESSAGE")
+                .help("Log MESSAGE, with {EXPR} replaced, whenever the CPU reaches ADDR and COND holds")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-bios")
+                .long("trace-bios")
+                .help("Log calls to the CP/M BIOS"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help("Trace each instruction to FILE, or - for stdout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-format")
+                .long("trace-format")
+                .value_name("FORMAT")
+                .help("Write the trace as text, json lines or binary")
+                .possible_values(&["text", "json", "binary"])
+                .default_value("text"),
+        )
+        .arg(
+            Arg::with_name("trace-filter")
+                .long("trace-filter")
+                .value_name("START-END|bank:N|SYMBOL")
+                .help("Trace only instructions in a hex address range, a 64K physical bank, or a symbol's code")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("trace-ring")
+                .long("trace-ring")
+                .value_name("N")
+                .help("Keep only the last N instructions traced, writing them when the CPU halts or on Ctrl-C")
+                .requires("trace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile")
+                .long("profile")
+                .value_name("FILE")
+                .help("Count where the CPU spends its time, reporting by function and address to FILE on a halt or Ctrl-C")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("profile-folded")
+                .long("profile-folded")
+                .value_name("FILE")
+                .help("Count where the CPU spends its time, writing flame graph folded stacks to FILE on a halt or Ctrl-C")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coverage")
+                .long("coverage")
+                .value_name("FILE")
+                .help("Record which instructions run, writing an lcov file over the listings to FILE on a halt or Ctrl-C")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("coverage-report")
+                .long("coverage-report")
+                .value_name("FILE")
+                .help("Record which instructions run, writing each with its symbol and branches to FILE on a halt or Ctrl-C")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("buslog")
+                .long("buslog")
+                .value_name("FILE")
+                .help("Log each memory and I/O access to FILE, or - for stdout")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("buslog-filter")
+                .long("buslog-filter")
+                .value_name("mem:ADDR[-ADDR]|io:PORT[-PORT]")
+                .help("Log only accesses to a range of physical memory or I/O ports, in hex")
+                .multiple(true)
+                .number_of_values(1)
+                .requires("buslog")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("buslog-fetches")
+                .long("buslog-fetches")
+                .help("Log opcode fetches too")
+                .requires("buslog"),
+        )
+        .arg(
+            Arg::with_name("load-state")
+                .long("load-state")
+                .value_name("FILE")
+                .help("Start from a machine state saved by a run with the same board options")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save-state")
+                .long("save-state")
+                .value_name("FILE")
+                .help("Save the machine state when the CPU halts or on Ctrl-C, or when it reaches --save-at")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("save-at")
+                .long("save-at")
+                .value_name("ADDR")
+                .help("Save the machine state the first time the CPU reaches ADDR, a hex address or symbol")
+                .requires("save-state")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("gdb")
+                .long("gdb")
+                .value_name("PORT")
+                .help("Wait for a GDB remote connection on a localhost TCP port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dap")
+                .long("dap")
+                .value_name("PORT|stdio")
+                .help(
+                    "Serve the Debug Adapter Protocol on a localhost TCP port or stdio; \
+                     the launch request configures the board",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("monitor")
+                .short("m")
+                .long("monitor")
+                .help("Start in the interactive monitor"),
+        )
         .get_matches();
 
-    let mut bus = Bus::new();
-    let mut cpu = CPU::new(&mut bus);
-    let ram = Rc::new(RAM::new(0x00000, 0x80000));
-    let rom_data = std::fs::read(matches.value_of("ROM").unwrap())?;
-    let rom = Rc::new(ROM::new(0x80000, rom_data));
-
-    bus.add(rom); // ROM is first to allow address masking to work
-    bus.add(ram.clone());
-
-    let prt = Rc::new(PRT::new());
-    bus.add(prt);
+    if let Some(dap) = matches.value_of("dap") {
+        if dap == "stdio" {
+            return DapServer::new(std::io::stdin(), std::io::stdout()).serve();
+        }
+        let port: u16 = dap
+            .parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "DAP port must be a number or stdio"))?;
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for a debug adapter client on port {}", port);
+        let (stream, _) = listener.accept()?;
+        return DapServer::tcp(stream)?.serve();
+    }
 
-    let dma = Rc::new(DMA::new());
-    bus.add(dma);
+    let mut config = Config::new(matches.value_of("ROM").unwrap());
+    config.flash = matches.value_of("flash").and_then(Chip::from_name);
+    config.nvram = matches.values_of("nvram").into_iter().flatten().map(String::from).collect();
+    if let Some(seed) = matches.value_of("seed") {
+        let seed = seed
+            .parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "RAM seed must be a number"))?;
+        config.ram_seed = Some(seed);
+    }
+    config.uninit = match matches.value_of("uninit") {
+        Some("warn") => UninitPolicy::Warn,
+        Some("break") => UninitPolicy::Break,
+        _ => UninitPolicy::Ignore,
+    };
+    if let Some(source) = matches.value_of("rtc") {
+        let source = TimeSource::from_name(source).ok_or_else(|| {
+            std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("invalid RTC source {}", source))
+        })?;
+        config.rtc = Some(source);
+    }
+    config.tty = matches.value_of("tty").map(String::from);
 
-    let sdcard = Rc::new(SDCard::new());
-    bus.add(sdcard);
+    let (mut cpu, mut bus) = config.build()?;
+    let buslog = match matches.value_of("buslog") {
+        Some(path) => {
+            let output: Box<dyn Write> = match path {
+                "-" => Box::new(std::io::stdout()),
+                path => Box::new(BufWriter::new(File::create(path)?)),
+            };
+            let mut log = BusLog::new(output);
+            for spec in matches.values_of("buslog-filter").into_iter().flatten() {
+                let filter =
+                    buslog::Filter::parse(spec).map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                log.add_filter(filter);
+            }
+            log.log_fetches(matches.is_present("buslog-fetches"));
+            let log = Rc::new(log);
+            bus.add_tap(log.clone());
+            Some(log)
+        }
+        None => None,
+    };
+    if let Some(path) = matches.value_of("load-state") {
+        Board::new(&mut cpu, &mut bus).load_state(path)?;
+    }
 
-    match matches.value_of("tty") {
-        Some(tty) => {
-            let uart = ASCI::new(Channel::CH0, tty);
-            bus.add(Rc::new(uart));
+    let mut debugger = Debugger::new();
+    for path in matches.values_of("symbols").into_iter().flatten() {
+        debugger.symbols_mut().load(path)?;
+    }
+    for path in matches.values_of("listing").into_iter().flatten() {
+        debugger.add_listing(Listing::load(path)?);
+        debugger.symbols_mut().load(path)?;
+    }
+    if matches.is_present("trace-bios") {
+        for (address, message) in BIOS_CALLS.iter() {
+            debugger.set_breakpoint(*address, Breakpoint::trace(Template::parse(message).unwrap()));
         }
-        None => (),
+    }
+    for spec in matches.values_of("tracepoint").into_iter().flatten() {
+        let (address, breakpoint) = parse_tracepoint(spec, debugger.symbols())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        debugger.set_breakpoint(address, breakpoint);
+    }
+    let save_at = match matches.value_of("save-at") {
+        Some(address) => Some(
+            parse_address(address, debugger.symbols())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        ),
+        None => None,
+    };
+    let mut save_state = matches.value_of("save-state");
+    let mut tracer = match matches.value_of("trace") {
+        Some(path) => {
+            let output: Box<dyn Write> = match path {
+                "-" => Box::new(std::io::stdout()),
+                path => Box::new(BufWriter::new(File::create(path)?)),
+            };
+            let format = Format::from_name(matches.value_of("trace-format").unwrap()).unwrap();
+            let mut tracer = Tracer::new(format, output);
+            if let Some(ring) = matches.value_of("trace-ring") {
+                let ring = ring
+                    .parse()
+                    .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "trace ring must be a number"))?;
+                tracer = tracer.ring(ring);
+            }
+            for spec in matches.values_of("trace-filter").into_iter().flatten() {
+                let filter = Filter::parse(spec, debugger.symbols())
+                    .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+                tracer.add_filter(filter);
+            }
+            Some(tracer)
+        }
+        None => None,
+    };
+    let mut profiler = if matches.is_present("profile") || matches.is_present("profile-folded") {
+        Some(Profiler::new())
+    } else {
+        None
+    };
+    let mut coverage = if matches.is_present("coverage") || matches.is_present("coverage-report") {
+        Some(Coverage::new())
+    } else {
+        None
+    };
+
+    if let Some(port) = matches.value_of("gdb") {
+        let port: u16 = port
+            .parse()
+            .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidInput, "GDB port must be a number"))?;
+        let listener = std::net::TcpListener::bind(("127.0.0.1", port))?;
+        println!("Waiting for GDB on port {}", port);
+        let (stream, _) = listener.accept()?;
+        return GdbStub::new(stream).serve(&mut cpu, &mut bus);
     }
 
-    cpu.reset();
+    if matches.is_present("monitor") {
+        return monitor::Monitor::new(debugger).run(&mut cpu, &mut bus);
+    }
 
-    // to implement a simple debugger:
-    // https://docs.rs/rustyline/6.2.0/rustyline/
-    // https://docs.rs/ctrlc/3.1.5/ctrlc/
-    //
-    // or
-    // https://microsoft.github.io/debug-adapter-protocol/specification#Types_Capabilities
-    //
-    // consider reading .lst file with symtab
+    // Ctrl-C ends the run as a halt does, so that the trace, profile, coverage and state are still written
+    let interrupt = debugger.interrupt_handle();
+    let handler = interrupt.clone();
+    ctrlc::set_handler(move || handler.store(true, Ordering::SeqCst)).map_err(std::io::Error::other)?;
 
-    let mut tracing = false;
-    let mut booted = false;
     loop {
         let pc = cpu.reg(Register::PC);
-        if pc == 0x101 && booted {
-            tracing = true;
-
-            let delay = time::Duration::from_millis(100);
-            thread::sleep(delay);
-        }
-        if tracing {
-            print_cpu(&mut cpu, &mut bus);
-            let one_ms = time::Duration::from_millis(1);
-            thread::sleep(one_ms);
-        }
         if pc == 0xf600 {
             println!("BIOS has booted");
-            booted = true;
-            tracing = false;
         }
         if pc == 0xf603 {
             println!("BIOS has warm booted");
         }
-        if pc >= 0xf979 && pc < 0xf9f5 {
-            //print_cpu(&mut cpu, &mut bus);
+        if let Some(tracer) = &mut tracer {
+            tracer.trace(&cpu, &bus, &debugger)?;
+        }
+        if let Some(profiler) = &mut profiler {
+            profiler.profile(&cpu);
+        }
+        if let Some(coverage) = &mut coverage {
+            coverage.record(&cpu);
         }
-        // print BIOS calls
-        if pc >= 0xF600 && pc < 0xF633 && false {
-            print_bios_call(&mut cpu, &mut bus, pc);
+        if save_at == Some(pc) {
+            if let Some(path) = save_state.take() {
+                Board::new(&mut cpu, &mut bus).save_state(path)?;
+                println!("Saved machine state to {}", path);
+            }
         }
-        if cpu.mode != Mode::OpCodeFetch {
+        debugger.check(&cpu, &bus);
+        if cpu.mode != Mode::OpCodeFetch || interrupt.load(Ordering::SeqCst) {
             break;
         }
         cpu.cycle(&mut bus);
     }
+    if let Some(tracer) = &mut tracer {
+        tracer.finish(&cpu, &bus, &debugger)?;
+    }
+    if let Some(log) = &buslog {
+        log.finish()?;
+    }
+    if let Some(profiler) = &mut profiler {
+        profiler.finish(&cpu);
+        if let Some(path) = matches.value_of("profile") {
+            let mut output = BufWriter::new(File::create(path)?);
+            profiler.report(debugger.symbols(), &mut output)?;
+            output.flush()?;
+        }
+        if let Some(path) = matches.value_of("profile-folded") {
+            let mut output = BufWriter::new(File::create(path)?);
+            profiler.folded(debugger.symbols(), &mut output)?;
+            output.flush()?;
+        }
+    }
+    if let Some(coverage) = &mut coverage {
+        coverage.finish(&cpu);
+        if let Some(path) = matches.value_of("coverage") {
+            let mut output = BufWriter::new(File::create(path)?);
+            coverage.lcov(debugger.symbols(), debugger.listings(), &mut output)?;
+            output.flush()?;
+        }
+        if let Some(path) = matches.value_of("coverage-report") {
+            let mut output = BufWriter::new(File::create(path)?);
+            coverage.report(debugger.symbols(), &mut output)?;
+            output.flush()?;
+        }
+    }
+    if let (Some(path), None) = (save_state, save_at) {
+        Board::new(&mut cpu, &mut bus).save_state(path)?;
+        println!("Saved machine state to {}", path);
+    }
 
     Ok(())
 }
@@ -0,0 +1,673 @@
+/**
+ * Interactive monitor
+ *
+ * A command line debugger over the emulated machine. Ctrl-C interrupts a running program and returns to the prompt.
+ * Numbers are hexadecimal, optionally written as $1234, 0x1234 or 1234h; a leading # makes a number decimal. Logical
+ * addresses may also be given as a symbol, plus or minus an offset, as in BIOS_READ+3.
+ * Breakpoint conditions and tracepoint messages are debugger expressions, in which numbers are decimal unless written
+ * as $1234 or 0x1234.
+ */
+use std::path::Path;
+use std::sync::atomic::Ordering;
+
+use rustyline::error::ReadlineError;
+use rustyline::Editor;
+
+use emulator::board::Board;
+use emulator::bus::Bus;
+use emulator::cpu::{FrameKind, Register, CPU};
+use emulator::debugger::{Breakpoint, Debugger, Stop};
+use emulator::expr::{Expr, Template};
+use emulator::history::History;
+use emulator::listing::Listing;
+use emulator::symbols;
+use emulator::watch::{Access, Condition, Space, Watchpoint};
+
+const HELP: &str = "\
+step [N]                   s   execute N instructions (default 1)
+next                       n   execute one instruction, stepping over calls
+stepl                      sl  run to the next source line
+nextl                      nl  run to the next source line, stepping over calls
+until [FILE:]LINE|ADDR     u   run until reaching a source line or address
+continue                   c   run until a breakpoint, halt, or Ctrl-C
+rstep [N]                  rs  go back N instructions (default 1)
+rcontinue                  rc  go back to the last breakpoint or watchpoint stop
+record [on|off]                show, start, or stop recording history for going back
+save FILE                      save the machine state to a file
+load FILE                      load a machine state saved with save or --save-state
+regs                       r   show registers
+backtrace                  bt  show the calls, restarts and interrupts leading to PC
+set REG VALUE                  set a register
+dump [ADDR] [LEN]          x   dump logical memory
+dumpp [ADDR] [LEN]         xp  dump physical memory
+edit ADDR BYTE...          e   write bytes to logical memory
+editp ADDR BYTE...         ep  write bytes to physical memory
+dis [ADDR] [N]             d   disassemble N instructions (default 10)
+asm ADDR INSTRUCTION       a   assemble an instruction into logical memory
+break ADDR [if COND]       b   set a breakpoint, stopping only when COND is true
+cond ADDR [COND]               set or clear a breakpoint's condition
+trace ADDR MESSAGE         t   log MESSAGE, with {EXPR} replaced, instead of stopping
+delete ADDR                    delete a breakpoint or tracepoint
+breaks                     bl  list breakpoints
+watch RW ADDR [LEN] [=V]   w   watch logical memory for r, w or rw access, of value V
+watchp RW ADDR [LEN] [=V]  wp  watch physical memory
+watchio RW PORT [=V]       wio watch an I/O port
+unwatch ID                     delete a watchpoint
+watches                    wl  list watchpoints
+symbols [TEXT]             sym list symbols, or those whose names contain TEXT
+symfile FILE                   load symbols from a .sym, .map or listing file
+listing FILE                   load an assembler listing for source lines and its labels
+in PORT                        read an I/O port
+out PORT VALUE                 write an I/O port
+quit                       q   leave the monitor
+An empty line repeats step, next, stepl, nextl, rstep, dump or dis.
+History is recorded from the start, keeping the last few million instructions.";
+
+// The size of the physical address space
+const PHYSICAL_SIZE: u32 = 0x100000;
+
+pub struct Monitor {
+    debugger: Debugger,
+    // where dump and dis carry on from when repeated
+    dump_next: u16,
+    dumpp_next: u32,
+    dis_next: u16,
+    last: String,
+}
+
+// Parse a number: hexadecimal by default, decimal with a leading #.
+fn parse_number(s: &str) -> Result<u32, String> {
+    symbols::parse_number(s, 16).ok_or_else(|| format!("invalid number {}", s))
+}
+
+fn parse_u16(s: &str) -> Result<u16, String> {
+    let n = parse_number(s)?;
+    if n > 0xffff {
+        return Err(format!("{} is out of range", s));
+    }
+    Ok(n as u16)
+}
+
+// Parse a physical address, which the Z180 has 20 bits of.
+fn parse_physical(s: &str) -> Result<u32, String> {
+    let n = parse_number(s)?;
+    if n >= PHYSICAL_SIZE {
+        return Err(format!("{} is beyond physical memory", s));
+    }
+    Ok(n)
+}
+
+// Check a run of bytes from a physical address stays within physical memory.
+fn check_physical(address: u32, len: u32) -> Result<(), String> {
+    if len > PHYSICAL_SIZE - address {
+        return Err(format!(
+            "{:05x} bytes from {:05x} runs past the end of physical memory",
+            len, address
+        ));
+    }
+    Ok(())
+}
+
+fn parse_u8(s: &str) -> Result<u8, String> {
+    let n = parse_number(s)?;
+    if n > 0xff {
+        return Err(format!("{} is out of range", s));
+    }
+    Ok(n as u8)
+}
+
+fn parse_access(s: &str) -> Result<Access, String> {
+    match s {
+        "r" => Ok(Access::Read),
+        "w" => Ok(Access::Write),
+        "rw" => Ok(Access::ReadWrite),
+        _ => Err(format!("{} isn't r, w or rw", s)),
+    }
+}
+
+fn format_address(space: Space, address: u32) -> String {
+    match space {
+        Space::Logical => format!("${:04x}", address),
+        Space::Physical => format!("${:05x}", address),
+        Space::Io => format!("port ${:02x}", address),
+    }
+}
+
+// The text of a line after its first n words.
+fn rest_of(line: &str, n: usize) -> &str {
+    let mut rest = line.trim_start();
+    for _ in 0..n {
+        let end = rest.find(char::is_whitespace).unwrap_or(rest.len());
+        rest = rest[end..].trim_start();
+    }
+    rest.trim_end()
+}
+
+fn required<'a>(arg: Option<&'a str>, what: &str) -> Result<&'a str, String> {
+    arg.ok_or_else(|| format!("missing {}", what))
+}
+
+fn print_registers(cpu: &CPU) {
+    let flags = cpu.reg(Register::F);
+    println!(
+        "A=${:02x} BC=${:04x} DE=${:04x} HL=${:04x} IX=${:04x} IY=${:04x} SP=${:04x} PC=${:04x} I=${:02x} R=${:02x}",
+        cpu.reg(Register::A),
+        cpu.reg(Register::BC),
+        cpu.reg(Register::DE),
+        cpu.reg(Register::HL),
+        cpu.reg(Register::IX),
+        cpu.reg(Register::IY),
+        cpu.reg(Register::SP),
+        cpu.reg(Register::PC),
+        cpu.reg(Register::I),
+        cpu.reg(Register::R),
+    );
+    println!(
+        "F={}{}-{}-{}{}{}",
+        if flags & 0b1000_0000 != 0 { 'S' } else { 's' },
+        if flags & 0b0100_0000 != 0 { 'Z' } else { 'z' },
+        if flags & 0b0001_0000 != 0 { 'H' } else { 'h' },
+        if flags & 0b0000_0100 != 0 { 'P' } else { 'p' },
+        if flags & 0b0000_0010 != 0 { 'N' } else { 'n' },
+        if flags & 0b0000_0001 != 0 { 'C' } else { 'c' },
+    );
+}
+
+// Print a hex and ASCII dump of bytes read by `read`, sixteen to a line.
+fn print_dump<F: Fn(u32) -> u8>(address: u32, len: u32, read: F) {
+    for row in (0..len).step_by(16) {
+        let bytes: Vec<u8> = (row..len.min(row + 16))
+            .map(|offset| read(address.wrapping_add(offset)))
+            .collect();
+        let hex: Vec<String> = bytes.iter().map(|b| format!("{:02x}", b)).collect();
+        let ascii: String = bytes
+            .iter()
+            .map(|&b| if (0x20..0x7f).contains(&b) { b as char } else { '.' })
+            .collect();
+        println!("{:05x}  {:<48} {}", address.wrapping_add(row), hex.join(" "), ascii);
+    }
+}
+
+impl Monitor {
+    pub fn new(debugger: Debugger) -> Monitor {
+        Monitor {
+            debugger,
+            dump_next: 0,
+            dumpp_next: 0,
+            dis_next: 0,
+            last: String::new(),
+        }
+    }
+
+    // Parse a logical address: a number, or a symbol with an optional +OFFSET or -OFFSET.
+    fn address(&self, s: &str) -> Result<u16, String> {
+        if let Ok(address) = parse_u16(s) {
+            return Ok(address);
+        }
+        let (name, offset) = match s.find(['+', '-']) {
+            Some(i) => (&s[..i], &s[i..]),
+            None => (s, ""),
+        };
+        let base = self
+            .debugger
+            .symbols()
+            .lookup(name)
+            .ok_or_else(|| format!("unknown address {}", s))?;
+        match (offset.strip_prefix('+'), offset.strip_prefix('-')) {
+            (Some(n), _) => Ok(base.wrapping_add(parse_u16(n)?)),
+            (_, Some(n)) => Ok(base.wrapping_sub(parse_u16(n)?)),
+            _ => Ok(base),
+        }
+    }
+
+    // Describe a logical address by its nearest symbol, if it has one.
+    fn describe(&self, address: u16) -> String {
+        match self.debugger.symbols().describe(address) {
+            Some(name) => format!("${:04x} ({})", address, name),
+            None => format!("${:04x}", address),
+        }
+    }
+
+    // Parse a source line, as LINE in the listing for the code at PC or FILE:LINE, into the address of its code.
+    fn line_address(&self, cpu: &CPU, s: &str) -> Result<Option<u16>, String> {
+        let (file, line) = match s.rsplit_once(':') {
+            Some((file, line)) => (Some(file), line),
+            None => (None, s),
+        };
+        let line: u32 = match line.parse() {
+            Ok(line) => line,
+            Err(_) if file.is_none() => return Ok(None),
+            Err(_) => return Err(format!("invalid line {}", line)),
+        };
+        let listings = self.debugger.listings();
+        let listing = match file {
+            Some(file) => listings.iter().find(|listing| listing.covers(Path::new(file))),
+            None => self
+                .debugger
+                .source_line(cpu.reg(Register::PC))
+                .map(|(listing, _)| listing)
+                .or_else(|| listings.first()),
+        }
+        .ok_or_else(|| format!("no listing for {}", s))?;
+        match listing.address_of_line(line) {
+            Some((_, address)) => Ok(Some(address)),
+            None => Err(format!("no code at or after {}", s)),
+        }
+    }
+
+    // Disassemble one instruction at a logical address, answering its length.
+    fn print_instruction(&self, cpu: &CPU, bus: &Bus, address: u16) -> u16 {
+        let opcodes: Vec<u8> = (0..4)
+            .map(|i| self.debugger.read_byte(cpu, bus, address.wrapping_add(i)))
+            .collect();
+        let instruction = emulator::disasm::decode(&opcodes);
+        let len = instruction.length;
+        let hex: Vec<String> = opcodes[..len].iter().map(|b| format!("{:02x}", b)).collect();
+        let marker = if self.debugger.breakpoints().any(|b| b == address) {
+            '*'
+        } else {
+            ' '
+        };
+        if let Some(name) = self.debugger.symbols().name_of(address) {
+            println!("{}:", name);
+        }
+        println!(
+            "{}{:04x} ({:05x})  {:<12} {}",
+            marker,
+            address,
+            cpu.to_physical(address),
+            hex.join(" "),
+            instruction.text(address, self.debugger.symbols())
+        );
+        len as u16
+    }
+
+    fn report(&mut self, cpu: &CPU, bus: &Bus, stop: Stop) {
+        match stop {
+            Stop::Step => (),
+            Stop::Breakpoint(address) => println!("Breakpoint at {}", self.describe(address)),
+            Stop::Watchpoint(hit) => println!(
+                "Watchpoint {}: {} ${:02x} {} {}",
+                hit.id,
+                if hit.access == Access::Read { "read" } else { "wrote" },
+                hit.value,
+                if hit.access == Access::Read { "from" } else { "to" },
+                format_address(hit.space, hit.address)
+            ),
+            Stop::Halt => println!("CPU halted"),
+            Stop::Break => println!("Execution stopped by a fault"),
+            Stop::Interrupted => println!("Interrupted"),
+            Stop::Beginning => println!("At the oldest recorded state"),
+        }
+        let pc = cpu.reg(Register::PC);
+        if let Some((listing, line)) = self.debugger.source_line(pc) {
+            let name = listing.path().file_name().unwrap_or_default().to_string_lossy();
+            println!("{}:{}  {}", name, line, listing.text_of_line(line).unwrap_or(""));
+        }
+        self.dis_next = pc.wrapping_add(self.print_instruction(cpu, bus, pc));
+    }
+
+    // Run one command line, answering false when the user wants to leave.
+    fn command(&mut self, cpu: &mut CPU, bus: &mut Bus, line: &str) -> Result<bool, String> {
+        let mut words = line.split_whitespace();
+        let command = match words.next() {
+            Some(command) => command,
+            None => return Ok(true),
+        };
+        let args: Vec<&str> = words.collect();
+        let arg = |n: usize| args.get(n).copied();
+        match command {
+            "s" | "step" => {
+                let count = arg(0).map(parse_number).transpose()?.unwrap_or(1);
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.debugger.step(cpu, bus);
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.report(cpu, bus, stop);
+            }
+            "n" | "next" => {
+                let stop = self.debugger.next(cpu, bus);
+                self.report(cpu, bus, stop);
+            }
+            "sl" | "stepl" => {
+                let stop = self.debugger.step_line(cpu, bus);
+                self.report(cpu, bus, stop);
+            }
+            "nl" | "nextl" => {
+                let stop = self.debugger.next_line(cpu, bus);
+                self.report(cpu, bus, stop);
+            }
+            "u" | "until" => {
+                let target = required(arg(0), "line or address")?;
+                let address = match self.line_address(cpu, target)? {
+                    Some(address) => address,
+                    None => self.address(target)?,
+                };
+                let stop = self.debugger.run_to(cpu, bus, address);
+                self.report(cpu, bus, stop);
+            }
+            "c" | "continue" => {
+                let stop = self.debugger.run(cpu, bus);
+                self.report(cpu, bus, stop);
+            }
+            "rs" | "rstep" => {
+                let count = arg(0).map(parse_number).transpose()?.unwrap_or(1);
+                let mut stop = Stop::Step;
+                for _ in 0..count {
+                    stop = self.debugger.step_back(cpu, bus);
+                    if stop != Stop::Step {
+                        break;
+                    }
+                }
+                self.report(cpu, bus, stop);
+            }
+            "rc" | "rcontinue" => {
+                let stop = self.debugger.reverse_run(cpu, bus);
+                self.report(cpu, bus, stop);
+            }
+            "record" => {
+                match arg(0) {
+                    Some("on") => self.debugger.record(bus, History::default()),
+                    Some("off") => self.debugger.stop_recording(bus),
+                    Some(word) => return Err(format!("{} isn't on or off", word)),
+                    None => (),
+                }
+                match self.debugger.history() {
+                    Some(history) => println!(
+                        "Recording, able to go back {} instructions",
+                        history.step() - history.oldest().unwrap_or(history.step())
+                    ),
+                    None => println!("Not recording"),
+                }
+            }
+            "save" => {
+                let path = required(arg(0), "file")?;
+                Board::new(cpu, bus)
+                    .save_state(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                println!("Saved machine state to {}", path);
+            }
+            "load" => {
+                let path = required(arg(0), "file")?;
+                Board::new(cpu, bus)
+                    .load_state(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                // the history recorded so far led somewhere else
+                if self.debugger.history().is_some() {
+                    self.debugger.record(bus, History::default());
+                }
+                self.report(cpu, bus, Stop::Step);
+            }
+            "r" | "regs" => {
+                print_registers(cpu);
+                self.print_instruction(cpu, bus, cpu.reg(Register::PC));
+            }
+            "bt" | "backtrace" => {
+                println!("#0  {}", self.describe(cpu.reg(Register::PC)));
+                for (depth, frame) in cpu.call_stack().iter().rev().enumerate() {
+                    let how = match frame.kind {
+                        FrameKind::Call => "call",
+                        FrameKind::Restart => "rst",
+                        FrameKind::Interrupt => "interrupt to",
+                    };
+                    println!(
+                        "#{}  {}  {} {}",
+                        depth + 1,
+                        self.describe(frame.call_site()),
+                        how,
+                        self.describe(frame.entry)
+                    );
+                }
+            }
+            "set" => {
+                let name = required(arg(0), "register")?;
+                let reg: Register = name.parse().map_err(|_| format!("unknown register {}", name))?;
+                cpu.write_reg(reg, parse_u16(required(arg(1), "value")?)?);
+                print_registers(cpu);
+            }
+            "x" | "dump" => {
+                let address = arg(0).map(|a| self.address(a)).transpose()?.unwrap_or(self.dump_next);
+                let len = arg(1).map(parse_u16).transpose()?.unwrap_or(0x80);
+                print_dump(address as u32, len as u32, |a| bus.peek(cpu.to_physical(a as u16)));
+                self.dump_next = address.wrapping_add(len);
+            }
+            "xp" | "dumpp" => {
+                let address = arg(0).map(parse_physical).transpose()?.unwrap_or(self.dumpp_next);
+                let len = arg(1).map(parse_number).transpose()?.unwrap_or(0x80);
+                check_physical(address, len)?;
+                print_dump(address, len, |a| bus.peek(a));
+                self.dumpp_next = (address + len) % PHYSICAL_SIZE;
+            }
+            "e" | "edit" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|byte| parse_u8(byte))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    bus.mem_write(cpu.to_physical(address.wrapping_add(i as u16)), byte);
+                }
+            }
+            "ep" | "editp" => {
+                let address = parse_physical(required(arg(0), "address")?)?;
+                let bytes = args[1..]
+                    .iter()
+                    .map(|byte| parse_u8(byte))
+                    .collect::<Result<Vec<u8>, String>>()?;
+                check_physical(address, bytes.len() as u32)?;
+                for (i, byte) in bytes.into_iter().enumerate() {
+                    bus.mem_write(address + i as u32, byte);
+                }
+            }
+            "d" | "dis" => {
+                let mut address = arg(0).map(|a| self.address(a)).transpose()?.unwrap_or(self.dis_next);
+                let count = arg(1).map(parse_number).transpose()?.unwrap_or(10);
+                for _ in 0..count {
+                    address = address.wrapping_add(self.print_instruction(cpu, bus, address));
+                }
+                self.dis_next = address;
+            }
+            "a" | "asm" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                required(arg(1), "instruction")?;
+                let assembly = emulator::asm::assemble_at(rest_of(line, 2), address, self.debugger.symbols())?;
+                for (i, byte) in assembly.bytes.iter().enumerate() {
+                    bus.mem_write(cpu.to_physical(assembly.origin.wrapping_add(i as u16)), *byte);
+                }
+                let mut address = assembly.origin;
+                while (address.wrapping_sub(assembly.origin) as usize) < assembly.bytes.len() {
+                    address = address.wrapping_add(self.print_instruction(cpu, bus, address));
+                }
+                self.dis_next = address;
+            }
+            "b" | "break" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                match arg(1) {
+                    Some("if") => {
+                        let condition = Expr::parse_with(rest_of(line, 3), self.debugger.symbols())?;
+                        self.debugger.set_breakpoint(address, Breakpoint::when(condition));
+                    }
+                    Some(word) => return Err(format!("unexpected {}", word)),
+                    None if !self.debugger.add_breakpoint(address) => {
+                        println!("Breakpoint already set at ${:04x}", address)
+                    }
+                    None => (),
+                }
+            }
+            "cond" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                let condition = match rest_of(line, 2) {
+                    "" => None,
+                    text => Some(Expr::parse_with(text, self.debugger.symbols())?),
+                };
+                match self.debugger.breakpoint_mut(address) {
+                    Some(breakpoint) => breakpoint.condition = condition,
+                    None => println!("No breakpoint at ${:04x}", address),
+                }
+            }
+            "t" | "trace" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                let message = match rest_of(line, 2) {
+                    "" => return Err("missing message".to_string()),
+                    text => Template::parse_with(text, self.debugger.symbols())?,
+                };
+                match self.debugger.breakpoint_mut(address) {
+                    Some(breakpoint) => breakpoint.message = Some(message),
+                    None => self.debugger.set_breakpoint(address, Breakpoint::trace(message)),
+                }
+            }
+            "delete" => {
+                let address = self.address(required(arg(0), "address")?)?;
+                if !self.debugger.remove_breakpoint(address) {
+                    println!("No breakpoint at ${:04x}", address);
+                }
+            }
+            "bl" | "breaks" => {
+                for address in self.debugger.breakpoints() {
+                    let breakpoint = self.debugger.breakpoint(address).unwrap();
+                    let mut text = format!("{}  hits {}", self.describe(address), breakpoint.hits);
+                    if let Some(condition) = &breakpoint.condition {
+                        text += &format!("  if {}", condition);
+                    }
+                    if let Some(message) = &breakpoint.message {
+                        text += &format!("  trace {}", message);
+                    }
+                    println!("{}", text);
+                }
+            }
+            "w" | "watch" | "wp" | "watchp" | "wio" | "watchio" => {
+                let space = match command {
+                    "w" | "watch" => Space::Logical,
+                    "wp" | "watchp" => Space::Physical,
+                    _ => Space::Io,
+                };
+                let access = parse_access(required(arg(0), "access")?)?;
+                let start = match space {
+                    Space::Logical => self.address(required(arg(1), "address")?)? as u32,
+                    Space::Physical => parse_physical(required(arg(1), "address")?)?,
+                    Space::Io => parse_u16(required(arg(1), "port")?)? as u32,
+                };
+                // the optional length and value may come in either order, the value marked with =
+                let mut len = 1;
+                let mut condition = Condition::Any;
+                for word in args.iter().skip(2) {
+                    match word.strip_prefix('=') {
+                        Some(value) => condition = Condition::Equals(parse_u8(value)?),
+                        None if space != Space::Io => len = parse_number(word)?,
+                        None => return Err(format!("unexpected {}", word)),
+                    }
+                }
+                let id = bus.add_watchpoint(Watchpoint::new(space, start, len, access).when(condition));
+                println!("Watchpoint {}", id);
+            }
+            "unwatch" => {
+                let id = required(arg(0), "watchpoint")?;
+                let id = id.parse().map_err(|_| format!("invalid watchpoint {}", id))?;
+                if !bus.remove_watchpoint(id) {
+                    println!("No watchpoint {}", id);
+                }
+            }
+            "wl" | "watches" => {
+                for (id, wp) in bus.watchpoints() {
+                    let access = match wp.access {
+                        Access::Read => "r",
+                        Access::Write => "w",
+                        Access::ReadWrite => "rw",
+                    };
+                    let value = match wp.condition {
+                        Condition::Equals(value) => format!(" =${:02x}", value),
+                        _ => String::new(),
+                    };
+                    println!(
+                        "{:3} {:<2} {} +{:x}{}",
+                        id,
+                        access,
+                        format_address(wp.space, wp.start),
+                        wp.len,
+                        value
+                    );
+                }
+            }
+            "sym" | "symbols" => {
+                let filter = arg(0).unwrap_or("").to_ascii_lowercase();
+                for (name, address) in self.debugger.symbols().iter() {
+                    if name.to_ascii_lowercase().contains(&filter) {
+                        println!("${:04x}  {}", address, name);
+                    }
+                }
+            }
+            "symfile" => {
+                let path = required(arg(0), "file")?;
+                let count = self
+                    .debugger
+                    .symbols_mut()
+                    .load(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                println!("Loaded {} symbols from {}", count, path);
+            }
+            "listing" => {
+                let path = required(arg(0), "file")?;
+                let listing = Listing::load(path).map_err(|e| format!("{}: {}", path, e))?;
+                self.debugger
+                    .symbols_mut()
+                    .load(path)
+                    .map_err(|e| format!("{}: {}", path, e))?;
+                self.debugger.add_listing(listing);
+            }
+            "in" => {
+                let port = parse_u16(required(arg(0), "port")?)?;
+                println!("${:02x}", bus.io_read(port));
+            }
+            "out" => {
+                let port = parse_u16(required(arg(0), "port")?)?;
+                bus.io_write(port, parse_u8(required(arg(1), "value")?)?);
+            }
+            "q" | "quit" => return Ok(false),
+            "h" | "help" | "?" => println!("{}", HELP),
+            _ => return Err(format!("unknown command {}, try help", command)),
+        }
+        Ok(true)
+    }
+
+    /// Read and run commands until the user quits.
+    pub fn run(&mut self, cpu: &mut CPU, bus: &mut Bus) -> Result<(), std::io::Error> {
+        let interrupt = self.debugger.interrupt_handle();
+        ctrlc::set_handler(move || interrupt.store(true, Ordering::SeqCst)).map_err(std::io::Error::other)?;
+
+        let mut editor = Editor::<()>::new();
+        self.debugger.record(bus, History::default());
+        self.report(cpu, bus, Stop::Step);
+        loop {
+            let line = match editor.readline("> ") {
+                Ok(line) => line,
+                Err(ReadlineError::Interrupted) => continue,
+                Err(ReadlineError::Eof) => return Ok(()),
+                Err(e) => return Err(std::io::Error::other(e)),
+            };
+            let line = if line.trim().is_empty() {
+                self.last.clone()
+            } else {
+                editor.add_history_entry(line.as_str());
+                line
+            };
+            match self.command(cpu, bus, &line) {
+                Ok(true) => (),
+                Ok(false) => return Ok(()),
+                Err(e) => println!("{}", e),
+            }
+            // steps repeat as they were, dumps and disassembly carry on from where they stopped
+            self.last = match line.split_whitespace().next() {
+                Some(command)
+                    if ["s", "step", "n", "next", "sl", "stepl", "nl", "nextl", "rs", "rstep"].contains(&command) =>
+                {
+                    line
+                }
+                Some(command) if ["x", "dump", "xp", "dumpp", "d", "dis"].contains(&command) => command.to_string(),
+                _ => String::new(),
+            };
+        }
+    }
+}
@@ -0,0 +1,28 @@
+/*!
+ * Assembly in Rust source
+ *
+ * `asm!` assembles Z180 source, given as a string literal, as the crate using it compiles, into an array of the
+ * bytes assembled. Tests can then give the code they run as assembly rather than as opcodes:
+ *
+ *   ram.write(0x0000, &z180asm::asm!("xor a \n dec a \n daa")).unwrap();
+ *
+ * The source is assembled by `emulator::asm::assemble`, from address 0 or its first org, so it may use labels,
+ * expressions and directives. An error assembling it is a compile error at the literal.
+ */
+extern crate proc_macro;
+
+use proc_macro::TokenStream;
+use quote::quote;
+use syn::{parse_macro_input, LitStr};
+
+#[proc_macro]
+pub fn asm(input: TokenStream) -> TokenStream {
+    let source = parse_macro_input!(input as LitStr);
+    match emulator::asm::assemble(&source.value()) {
+        Ok(assembly) => {
+            let bytes = assembly.bytes;
+            quote!([#(#bytes),*]).into()
+        }
+        Err(e) => syn::Error::new(source.span(), e).to_compile_error().into(),
+    }
+}
@@ -350,7 +350,8 @@ pub fn zex_execute(state: &ZexState, mask: u8, crc: u32) -> u32 {
             (state.instruction >> 8) as u8,
             (state.instruction >> 0) as u8,
         ],
-    );
+    )
+    .unwrap();
 
     // Set up the CPU
     cpu.reset();
@@ -391,8 +392,8 @@ pub fn zex_execute(state: &ZexState, mask: u8, crc: u32) -> u32 {
         cpu.reg(Register::IX),
         cpu.reg(Register::IY),
         DebugFlags::new(cpu.reg(Register::F) as u8),
-        ram.mem_read(0x103).unwrap_or(0),
-        ram.mem_read(0x104).unwrap_or(0),
+        ram.mem_read(0x103, false).unwrap_or(0),
+        ram.mem_read(0x104, false).unwrap_or(0),
         state.instruction,
         crc);
     }
@@ -404,8 +405,8 @@ pub fn zex_execute(state: &ZexState, mask: u8, crc: u32) -> u32 {
 
     // Update the CRC
     let mut result = crc;
-    result = updcrc(result, ram.mem_read(0x103).unwrap_or(0));
-    result = updcrc(result, ram.mem_read(0x104).unwrap_or(0));
+    result = updcrc(result, ram.mem_read(0x103, false).unwrap_or(0));
+    result = updcrc(result, ram.mem_read(0x104, false).unwrap_or(0));
     result = updcrc(result, (cpu.reg(Register::IY) >> 0) as u8);
     result = updcrc(result, (cpu.reg(Register::IY) >> 8) as u8);
     result = updcrc(result, (cpu.reg(Register::IX) >> 0) as u8);
@@ -474,8 +475,8 @@ pub fn zex_execute(state: &ZexState, mask: u8, crc: u32) -> u32 {
             || cpu80.get_index16(z80emu::Prefix::Xdd) != cpu.reg(Register::IX)
             || cpu80.get_index16(z80emu::Prefix::Yfd) != cpu.reg(Register::IY)
             || cpu80.get_sp() != cpu.reg(Register::SP)
-            || bus80.mem[0x103] != ram.mem_read(0x103).unwrap_or(0)
-            || bus80.mem[0x104] != ram.mem_read(0x104).unwrap_or(0)
+            || bus80.mem[0x103] != ram.mem_read(0x103, false).unwrap_or(0)
+            || bus80.mem[0x104] != ram.mem_read(0x104, false).unwrap_or(0)
         {
             println!("{}\n{}", instr, prestate);
             println!("_PC=0113  PC={:04X}  SP={:04X}  A={:02X}  BC={:04X}  DE={:04X}  HL={:04X}  IX={:04X}  IY={:04X}  F={}  (103)={:02X} {:02X}  (PC)={:08X}  crc={:08x}",
@@ -488,8 +489,8 @@ pub fn zex_execute(state: &ZexState, mask: u8, crc: u32) -> u32 {
             cpu.reg(Register::IX),
             cpu.reg(Register::IY),
             DebugFlags::new(cpu.reg(Register::F) as u8),
-            ram.mem_read(0x103).unwrap_or(0),
-            ram.mem_read(0x104).unwrap_or(0),
+            ram.mem_read(0x103, false).unwrap_or(0),
+            ram.mem_read(0x104, false).unwrap_or(0),
             state.instruction,
             result);
             println!("          PC={:04X}  SP={:04X}  A={:02X}  BC={:04X}  DE={:04X}  HL={:04X}  IX={:04X}  IY={:04X}  F={}  (103)={:02X} {:02X}",